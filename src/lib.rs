@@ -10,7 +10,7 @@ struct WakatimeExtension {
     cached_wakatime_cli_binary_path: Option<PathBuf>,
 }
 
-fn is_absolute_path_wasm(path: &PathBuf) -> bool {
+fn is_absolute_path_wasm(path: &Path) -> bool {
     let Some(path_str) = path.to_str() else {
         return false;
     };
@@ -19,13 +19,12 @@ fn is_absolute_path_wasm(path: &PathBuf) -> bool {
         zed::Os::Windows => {
             // Windows: Check if the path is an absolute path (e.g., C:\ or C:/)
             let bytes = path_str.as_bytes();
-            if bytes.len() >= 3 {
-                if bytes[0].is_ascii_alphabetic()
-                    && bytes[1] == b':'
-                    && (bytes[2] == b'\\' || bytes[2] == b'/')
-                {
-                    return true;
-                }
+            if bytes.len() >= 3
+                && bytes[0].is_ascii_alphabetic()
+                && bytes[1] == b':'
+                && (bytes[2] == b'\\' || bytes[2] == b'/')
+            {
+                return true;
             }
             // Windows：Check if it is a UNC path (e.g., \\server\share)
             path_str.starts_with(r"\\")
@@ -244,6 +243,26 @@ impl zed::Extension for WakatimeExtension {
             env: worktree.shell_env(),
         })
     }
+
+    // Zed merges the `initialization_options` of the user's settings into
+    // these. Extensions can't handle the server's custom notifications, so it
+    // is told not to send them.
+    fn language_server_initialization_options(
+        &mut self,
+        _language_server_id: &LanguageServerId,
+        _worktree: &Worktree,
+    ) -> Result<Option<zed::serde_json::Value>> {
+        Ok(Some(zed::serde_json::json!({
+            "extension": {
+                "version": env!("CARGO_PKG_VERSION"),
+                "features": {
+                    "wakatime/statusBarText": false,
+                    "wakatime/weeklySummary": false,
+                    "wakatime/goalProgress": false,
+                },
+            },
+        })))
+    }
 }
 
 zed::register_extension!(WakatimeExtension);
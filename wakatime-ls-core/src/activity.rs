@@ -0,0 +1,258 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Local, NaiveDate};
+use serde::{Deserialize, Serialize};
+
+use crate::{config, heartbeat::Outcome, paths};
+
+// Every heartbeat sent with the `activity_log` setting, one JSON object per
+// line, so activity can be looked back on without asking the API.
+//
+// Example:
+// {"time":"2026-10-14T15:05:39+02:00","entity":"/home/alice/src/app/main.rs","project":"app","branch":"main","language":"Rust","category":null,"is_write":true,"outcome":"sent"}
+pub fn path() -> Option<PathBuf> {
+    Some(
+        config::wakatime_home()?
+            .join(".wakatime")
+            .join("zed-wakatime-activity.jsonl"),
+    )
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    pub time: DateTime<Local>,
+    pub entity: String,
+    pub project: Option<String>,
+    pub branch: Option<String>,
+    pub language: Option<String>,
+    pub category: Option<String>,
+    pub is_write: bool,
+    pub outcome: Outcome,
+}
+
+// The size of the activity log over which its oldest records are dropped, down
+// to half of it, so it doesn't grow without bound.
+const MAX_SIZE: u64 = 64 * 1024 * 1024;
+
+// Append a record to the log, which only its owner may read, as it holds the
+// path of every file worked on.
+pub fn append(path: &Path, record: &Record) -> io::Result<()> {
+    append_capped(path, record, MAX_SIZE)
+}
+
+fn append_capped(path: &Path, record: &Record, max_size: u64) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut line = serde_json::to_string(record)?;
+    line.push('\n');
+    let mut options = OpenOptions::new();
+    options.create(true).append(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(0o600);
+        // Logs written before it was private.
+        if fs::metadata(path).is_ok_and(|metadata| metadata.permissions().mode() & 0o077 != 0) {
+            fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+        }
+    }
+    let mut file = options.open(path)?;
+    file.write_all(line.as_bytes())?;
+
+    if file.metadata()?.len() > max_size {
+        trim(path, max_size / 2)?;
+    }
+    Ok(())
+}
+
+// Keep the newest whole lines of the log that fit in `size` bytes.
+fn trim(path: &Path, size: u64) -> io::Result<()> {
+    let content = fs::read(path)?;
+    let start = content
+        .len()
+        .saturating_sub(usize::try_from(size).unwrap_or(usize::MAX));
+    let start = match content[start..].iter().position(|&byte| byte == b'\n') {
+        Some(newline) if start > 0 => start + newline + 1,
+        _ => start,
+    };
+    paths::write_private(path, &content[start..])
+}
+
+// The records from `from` to `to` included, in local dates. Lines that can't
+// be read, such as one cut short by a crash, are skipped.
+pub fn read(path: &Path, from: NaiveDate, to: NaiveDate) -> io::Result<Vec<Record>> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+
+    Ok(content
+        .lines()
+        .filter_map(|line| serde_json::from_str::<Record>(line).ok())
+        .filter(|record| (from..=to).contains(&record.time.date_naive()))
+        .collect())
+}
+
+// The argument of the `wakatime.exportActivity` command, whose fields may all
+// be left out. Without a `path`, the export is returned instead of written.
+//
+// Example:
+// {"from":"2026-10-01","to":"2026-10-31","format":"csv","path":"~/invoices/october.csv"}
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ExportParams {
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+    #[serde(default)]
+    pub format: Format,
+    pub path: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Csv,
+    #[default]
+    Json,
+}
+
+impl Format {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "csv" => Some(Self::Csv),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+}
+
+// The heartbeats recorded over a range of days, today's by default, written
+// to the `path` of the params, whose canonical form is returned, or else
+// returned themselves. An export is written only where no file is, and only
+// its owner may read it.
+pub fn export_range(params: &ExportParams) -> Result<String, String> {
+    let path = path().ok_or("no home directory to find the activity log in")?;
+    let to = params.to.unwrap_or_else(|| Local::now().date_naive());
+    let from = params.from.unwrap_or(to);
+
+    let records =
+        read(&path, from, to).map_err(|err| format!("could not read {}: {err}", path.display()))?;
+    let export = export(&records, params.format);
+
+    match params.path {
+        Some(ref output) => {
+            let output = paths::canonicalize(Path::new(output));
+            paths::create_private(&output, export.as_bytes())
+                .map_err(|err| format!("could not write {}: {err}", output.display()))?;
+            Ok(output.display().to_string())
+        }
+        None => Ok(export),
+    }
+}
+
+// Example, as CSV:
+// time,entity,project,branch,language,category,is_write,outcome
+// 2026-10-14T15:05:39+02:00,/home/alice/src/app/main.rs,app,main,Rust,,true,sent
+pub fn export(records: &[Record], format: Format) -> String {
+    match format {
+        Format::Json => serde_json::to_string_pretty(records).unwrap_or_default() + "\n",
+        Format::Csv => {
+            let mut csv =
+                String::from("time,entity,project,branch,language,category,is_write,outcome\n");
+
+            for record in records {
+                let fields = [
+                    record.time.to_rfc3339(),
+                    record.entity.clone(),
+                    record.project.clone().unwrap_or_default(),
+                    record.branch.clone().unwrap_or_default(),
+                    record.language.clone().unwrap_or_default(),
+                    record.category.clone().unwrap_or_default(),
+                    record.is_write.to_string(),
+                    record.outcome.as_str().to_string(),
+                ];
+                let fields: Vec<_> = fields.iter().map(|field| csv_field(field)).collect();
+                csv.push_str(&fields.join(","));
+                csv.push('\n');
+            }
+
+            csv
+        }
+    }
+}
+
+// Quoted when it holds a separator, a quote or a line break, with quotes
+// doubled.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    fn record(entity: &str) -> Record {
+        Record {
+            time: Local::now(),
+            entity: entity.to_string(),
+            project: None,
+            branch: None,
+            language: None,
+            category: None,
+            is_write: false,
+            outcome: Outcome::Sent,
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("wakatime-ls-{name}-{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn the_oldest_records_are_dropped_once_the_log_is_full() {
+        let path = temp_dir("activity-cap").join("activity.jsonl");
+        let size = serde_json::to_string(&record("/src/0.rs")).unwrap().len() as u64 + 1;
+
+        for index in 0..10 {
+            append_capped(&path, &record(&format!("/src/{index}.rs")), size * 4).unwrap();
+        }
+
+        let today = Local::now().date_naive();
+        let entities: Vec<String> = read(&path, today, today)
+            .unwrap()
+            .into_iter()
+            .map(|record| record.entity)
+            .collect();
+        assert_eq!(entities, ["/src/8.rs", "/src/9.rs"]);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+
+    #[test]
+    fn exports_dont_replace_files() {
+        let path = temp_dir("activity-export").join("october.csv");
+        fs::write(&path, "invoice").unwrap();
+
+        assert!(paths::create_private(&path, b"export").is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "invoice");
+    }
+}
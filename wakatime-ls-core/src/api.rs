@@ -0,0 +1,434 @@
+#[cfg(feature = "http")]
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+    time::Instant,
+};
+use std::{env, time::Duration};
+
+#[cfg(feature = "http")]
+use base64::{engine::general_purpose::STANDARD, Engine};
+#[cfg(feature = "http")]
+use chrono::Utc;
+#[cfg(feature = "http")]
+use serde_json::Value;
+use url::Url;
+
+use crate::{budget, config::WakatimeConfig};
+#[cfg(feature = "http")]
+use crate::{cli, metrics, skew};
+
+pub const DEFAULT_API_URL: &str = "https://api.wakatime.com/api/v1";
+
+pub const HACKATIME_API_URL: &str = "https://hackatime.hackclub.com/api/hackatime/v1";
+
+// Endpoints that get pasted along with the API URL, as found in the docs of
+// the API, although wakatime-cli adds them itself.
+const ENDPOINTS: [&str; 3] = [
+    "/users/current/heartbeats.bulk",
+    "/users/current/heartbeats",
+    "/heartbeats",
+];
+
+// Successful responses by URL and API key, shared by every server in the
+// process, so that frequent refreshes don't spend the rate limit of the API.
+#[cfg(feature = "http")]
+static CACHE: LazyLock<Mutex<HashMap<CacheKey, Cached>>> = LazyLock::new(Default::default);
+
+#[cfg(feature = "http")]
+type CacheKey = (String, Option<String>);
+
+#[cfg(feature = "http")]
+struct Cached {
+    body: Value,
+    fetched: Instant,
+    validators: Validators,
+}
+
+// What the API tells a response apart with, sent back to ask whether it
+// changed.
+#[cfg(feature = "http")]
+#[derive(Debug, Clone, Default)]
+struct Validators {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+// The servers the `backend` setting can name instead of an API URL. Hackatime
+// takes the same requests and headers as WakaTime, below its own path.
+//
+// Example:
+// "hackatime" -> https://hackatime.hackclub.com/api/hackatime/v1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    Wakatime,
+    Hackatime,
+}
+
+impl Backend {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "wakatime" => Some(Self::Wakatime),
+            "hackatime" => Some(Self::Hackatime),
+            _ => None,
+        }
+    }
+
+    pub fn api_url(self) -> &'static str {
+        match self {
+            Self::Wakatime => DEFAULT_API_URL,
+            Self::Hackatime => HACKATIME_API_URL,
+        }
+    }
+}
+
+// The WakaTime API, or a compatible one such as Hackatime, with the API key
+// wakatime-cli would send to it.
+#[derive(Debug, Clone)]
+pub struct Api {
+    pub url: String,
+    pub key: Option<String>,
+    pub timeouts: Timeouts,
+}
+
+// How long requests to the API wait for a connection, and for the whole
+// response. Self-hosted servers behind a VPN can take longer than these.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    pub connect: Duration,
+    pub request: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect: Duration::from_secs(10),
+            request: Duration::from_secs(30),
+        }
+    }
+}
+
+impl Api {
+    // Resolved in the same order as heartbeats: the given settings, which
+    // wakatime-cli is handed over its config, then the `[settings]` section of
+    // the wakatime config, then the `WAKATIME_API_KEY` environment variable for
+    // the key. The request timeout is the config's
+    // `timeout`, in seconds, when it has one.
+    pub fn resolve(key: Option<&str>, url: Option<&str>, config: &WakatimeConfig) -> Self {
+        let key = key
+            .map(str::to_string)
+            .or_else(|| config.get("settings", "api_key").map(str::to_string))
+            .or_else(|| env::var("WAKATIME_API_KEY").ok())
+            .filter(|key| !key.is_empty());
+
+        let url = url
+            .or_else(|| config.get("settings", "api_url"))
+            .filter(|url| !url.is_empty())
+            .unwrap_or(DEFAULT_API_URL);
+        let url = normalize_url(url)
+            .map(|(url, _)| url)
+            .unwrap_or_else(|_| url.trim_end_matches('/').to_string());
+
+        let mut timeouts = Timeouts::default();
+        if let Some(timeout) = config
+            .get("settings", "timeout")
+            .and_then(|timeout| timeout.trim().parse().ok())
+            .filter(|&timeout| timeout > 0)
+        {
+            timeouts.request = Duration::from_secs(timeout);
+        }
+
+        Self { url, key, timeouts }
+    }
+
+    // Where the API's users see their stats: the WakaTime dashboard, or for
+    // Hackatime and self-hosted servers the site the API is part of.
+    //
+    // Example:
+    // https://api.wakatime.com/api/v1                  -> https://wakatime.com/dashboard
+    // https://hackatime.hackclub.com/api/hackatime/v1 -> https://hackatime.hackclub.com/
+    // https://example.com/wakapi/api                  -> https://example.com/wakapi/
+    pub fn dashboard_url(&self) -> String {
+        let Ok(mut url) = Url::parse(&self.url) else {
+            return self.url.clone();
+        };
+
+        if matches!(url.host_str(), Some("wakatime.com" | "api.wakatime.com")) {
+            return "https://wakatime.com/dashboard".to_string();
+        }
+
+        let root = url
+            .path()
+            .find("/api")
+            .map(|end| url.path()[..end].to_string());
+        url.set_path(&format!("{}/", root.unwrap_or_default()));
+        url.set_query(None);
+        url.to_string()
+    }
+
+    // GET a path below the API URL, returning the HTTP status and the JSON
+    // body. Errors are for requests that got no response at all, or weren't
+    // sent as they were over the budget of API requests.
+    //
+    // Example:
+    // users/current/statusbar/today -> https://api.wakatime.com/api/v1/users/current/statusbar/today
+    #[cfg(feature = "http")]
+    pub async fn get(&self, path: &str) -> Result<(u16, Value), String> {
+        if !budget::try_acquire() {
+            return Err(over_budget());
+        }
+
+        let url = format!("{}/{path}", self.url);
+        let response = self.send(&url, Validators::default()).await?;
+
+        let status = response.status().as_u16();
+        Ok((status, body(&url, response).await?))
+    }
+
+    // Like `get`, but a successful response is reused for `ttl`, then only
+    // fetched again if the API says it changed, when it answers with an
+    // `ETag` or a `Last-Modified` header.
+    #[cfg(feature = "http")]
+    pub async fn get_cached(&self, path: &str, ttl: Duration) -> Result<(u16, Value), String> {
+        let url = format!("{}/{path}", self.url);
+        let cache_key = (url.clone(), self.key.clone());
+
+        let validators = {
+            let cache = CACHE.lock().map_err(|err| err.to_string())?;
+            match cache.get(&cache_key) {
+                Some(cached) if cached.fetched.elapsed() < ttl => {
+                    return Ok((200, cached.body.clone()));
+                }
+                // Better late than nothing.
+                Some(cached) if !budget::try_acquire() => {
+                    return Ok((200, cached.body.clone()));
+                }
+                Some(cached) => cached.validators.clone(),
+                None if !budget::try_acquire() => return Err(over_budget()),
+                None => Validators::default(),
+            }
+        };
+
+        let response = self.send(&url, validators).await?;
+        let status = response.status().as_u16();
+
+        if status == 304 {
+            let mut cache = CACHE.lock().map_err(|err| err.to_string())?;
+            if let Some(cached) = cache.get_mut(&cache_key) {
+                cached.fetched = Instant::now();
+                return Ok((200, cached.body.clone()));
+            }
+        }
+
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        let validators = Validators {
+            etag: header(reqwest::header::ETAG),
+            last_modified: header(reqwest::header::LAST_MODIFIED),
+        };
+
+        let body = body(&url, response).await?;
+        if (200..300).contains(&status) {
+            let cached = Cached {
+                body: body.clone(),
+                fetched: Instant::now(),
+                validators,
+            };
+            CACHE
+                .lock()
+                .map_err(|err| err.to_string())?
+                .insert(cache_key, cached);
+        }
+
+        Ok((status, body))
+    }
+
+    #[cfg(feature = "http")]
+    async fn send(&self, url: &str, validators: Validators) -> Result<reqwest::Response, String> {
+        let mut request = cli::http_client_connecting_within(self.timeouts.connect)?
+            .get(url)
+            .timeout(self.timeouts.request);
+
+        if let Some(ref key) = self.key {
+            request = request.header(
+                reqwest::header::AUTHORIZATION,
+                format!("Basic {}", STANDARD.encode(key)),
+            );
+        }
+
+        if let Some(etag) = validators.etag {
+            request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = validators.last_modified {
+            request = request.header(reqwest::header::IF_MODIFIED_SINCE, last_modified);
+        }
+
+        let start = Instant::now();
+        let response = request.send().await;
+        metrics::observe_api(start.elapsed());
+
+        // The API dated the response about halfway through the request.
+        if let Some(date) = response
+            .as_ref()
+            .ok()
+            .and_then(|response| response.headers().get(reqwest::header::DATE)?.to_str().ok())
+        {
+            let received = Utc::now() - start.elapsed() / 2;
+            skew::record(date, received);
+        }
+
+        response.map_err(|err| format!("could not reach {url}: {}", error_chain(&err)))
+    }
+}
+
+// An API URL as it tends to be pasted, fixed where the intent is clear, with
+// what was wrong with it. Errors are for URLs that can't be an API at all.
+//
+// Example:
+// https://hackatime.hackclub.com/api/hackatime/v1/      -> https://hackatime.hackclub.com/api/hackatime/v1
+// api.wakatime.com/api/v1/users/current/heartbeats      -> https://api.wakatime.com/api/v1, with 2 problems
+// https://hackatime.hackclub.com                        -> https://hackatime.hackclub.com/api/hackatime/v1, with 1 problem
+// ftp://example.com/api                                 -> error
+pub fn normalize_url(url: &str) -> Result<(String, Vec<String>), String> {
+    let mut problems = Vec::new();
+
+    let mut url = url.trim().to_string();
+    if !url.contains("://") {
+        url = format!("https://{url}");
+        problems.push("has no scheme, https:// is assumed".to_string());
+    }
+
+    let parsed = Url::parse(&url).map_err(|err| err.to_string())?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return Err(format!("{}:// is not http:// or https://", parsed.scheme()));
+    }
+    let Some(host) = parsed.host_str() else {
+        return Err("it has no host".to_string());
+    };
+
+    let mut url = url.trim_end_matches('/').to_string();
+    if let Some(endpoint) = ENDPOINTS.iter().find(|endpoint| url.ends_with(*endpoint)) {
+        url.truncate(url.len() - endpoint.len());
+        problems.push(format!(
+            "ends with {endpoint}, which wakatime-cli adds itself"
+        ));
+    }
+
+    let path_is_empty = Url::parse(&url).is_ok_and(|url| url.path() == "/");
+    if path_is_empty {
+        let api = match host {
+            "wakatime.com" | "api.wakatime.com" => Some(DEFAULT_API_URL),
+            "hackatime.hackclub.com" => Some(HACKATIME_API_URL),
+            _ => None,
+        };
+        match api {
+            Some(api) => {
+                problems.push(format!("has no API path, {api} is assumed"));
+                url = api.to_string();
+            }
+            None => problems
+                .push("has no path, where APIs usually have one such as /api/v1".to_string()),
+        }
+    }
+
+    Ok((url, problems))
+}
+
+// A UUID, optionally prefixed with `waka_`, as WakaTime issues them. Other
+// servers such as Hackatime may issue keys in any format.
+//
+// Example:
+// waka_0b6f1c3e-5d2a-4f7e-9c8b-1a2b3c4d5e6f
+pub fn is_uuid_key(key: &str) -> bool {
+    let uuid = key.strip_prefix("waka_").unwrap_or(key);
+    let groups: Vec<&str> = uuid.split('-').collect();
+
+    groups.iter().map(|group| group.len()).eq([8, 4, 4, 4, 12])
+        && groups
+            .iter()
+            .all(|group| group.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+pub fn over_budget() -> String {
+    format!(
+        "over the budget of {} API requests a minute",
+        budget::per_minute()
+    )
+}
+
+#[cfg(feature = "http")]
+async fn body(url: &str, response: reqwest::Response) -> Result<Value, String> {
+    let body = response
+        .bytes()
+        .await
+        .map_err(|err| format!("could not read the response of {url}: {err}"))?;
+
+    Ok(serde_json::from_slice(&body).unwrap_or(Value::Null))
+}
+
+// reqwest leaves the cause, such as a refused connection, to the error source.
+#[cfg(feature = "http")]
+fn error_chain(err: &dyn std::error::Error) -> String {
+    let mut message = err.to_string();
+    let mut source = err.source();
+
+    while let Some(err) = source {
+        message.push_str(&format!(": {err}"));
+        source = err.source();
+    }
+
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pasted_urls_are_normalized() {
+        for (url, normalized, problems) in [
+            (
+                "waka.example.com/api/v1",
+                "https://waka.example.com/api/v1",
+                1,
+            ),
+            ("https://hackatime.hackclub.com", HACKATIME_API_URL, 1),
+            ("https://waka.example.com", "https://waka.example.com", 1),
+            (
+                "https://hackatime.hackclub.com/api/hackatime/v1/",
+                HACKATIME_API_URL,
+                0,
+            ),
+            ("https://api.wakatime.com/api/v1", DEFAULT_API_URL, 0),
+            (
+                "http://localhost:3000/api/v1/users/current/heartbeats.bulk",
+                "http://localhost:3000/api/v1",
+                1,
+            ),
+            (
+                " api.wakatime.com/api/v1/users/current/heartbeats/ ",
+                DEFAULT_API_URL,
+                2,
+            ),
+        ] {
+            let (url, found) = normalize_url(url).unwrap();
+            assert_eq!(
+                (url.as_str(), found.len()),
+                (normalized, problems),
+                "{found:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn urls_that_cant_be_an_api_are_refused() {
+        for url in ["ftp://example.com/api", "file:///api/v1", "https://"] {
+            assert!(normalize_url(url).is_err(), "{url}");
+        }
+    }
+}
@@ -0,0 +1,124 @@
+#[cfg(feature = "http")]
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api::Api,
+    cli::Cli,
+    today::{self, Total},
+};
+
+// How long the last 7 days' stats from the API are reused.
+#[cfg(feature = "http")]
+const CACHE_TTL: Duration = Duration::from_secs(5 * 60);
+
+// The params of `wakatime/projects` and `wakatime/languages`.
+//
+// Example:
+// {}                -> today's time per project or language
+// {"range":"week"}  -> the last 7 days' time per project or language
+#[derive(Debug, Clone, Deserialize)]
+pub struct BreakdownParams {
+    #[serde(default)]
+    pub range: Range,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Range {
+    #[default]
+    Today,
+    Week,
+}
+
+// What the time is grouped by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Group {
+    Project,
+    Language,
+}
+
+impl Group {
+    // The list of them in the API's responses.
+    #[cfg(feature = "http")]
+    fn key(self) -> &'static str {
+        match self {
+            Self::Project => "projects",
+            Self::Language => "languages",
+        }
+    }
+}
+
+// Example:
+// {"name":"zed-hackatime","text":"52 mins","total_seconds":3120}
+// {"name":"Rust","text":"1 hr 10 mins","total_seconds":4200}
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TimeSpent {
+    pub name: String,
+    pub text: String,
+    pub total_seconds: u64,
+}
+
+impl TimeSpent {
+    fn new((name, total): (String, Total)) -> Self {
+        Self {
+            name,
+            text: total.text,
+            total_seconds: total.total_seconds,
+        }
+    }
+}
+
+// The time spent on each project or language, the most first. Today's comes
+// from the same place as the status bar's, so it works without an API key
+// through wakatime-cli, while the last 7 days' stats need one.
+pub async fn fetch(
+    api: &Api,
+    wakatime_cli: Option<&Cli>,
+    group: Group,
+    range: Range,
+) -> Result<Vec<TimeSpent>, String> {
+    let totals = match range {
+        Range::Today => {
+            let today = today::fetch(api, wakatime_cli).await?;
+            match group {
+                Group::Project => today.projects,
+                Group::Language => today.languages,
+            }
+        }
+        #[cfg(feature = "http")]
+        Range::Week => week(api, group).await?,
+        #[cfg(not(feature = "http"))]
+        Range::Week => {
+            return Err(
+                "the last 7 days' stats need the API, which this build can't ask".to_string(),
+            )
+        }
+    };
+
+    let mut spent: Vec<_> = totals.into_iter().map(TimeSpent::new).collect();
+    spent.sort_by_key(|spent| std::cmp::Reverse(spent.total_seconds));
+    Ok(spent)
+}
+
+#[cfg(feature = "http")]
+async fn week(api: &Api, group: Group) -> Result<Vec<(String, Total)>, String> {
+    if api.key.is_none() {
+        return Err("no API key to ask for the last 7 days".to_string());
+    }
+
+    let (status, body) = api
+        .get_cached("users/current/stats/last_7_days", CACHE_TTL)
+        .await?;
+    if !(200..300).contains(&status) {
+        return Err(format!("{} answered HTTP {status}", api.url));
+    }
+
+    let list = &body["data"][group.key()];
+    if !list.is_array() {
+        return Err(format!("{} answered without {}", api.url, group.key()));
+    }
+
+    Ok(today::named_totals(list))
+}
@@ -0,0 +1,103 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        LazyLock, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+// Unless the `api_requests_per_minute` setting says otherwise.
+pub const DEFAULT_PER_MINUTE: u32 = 60;
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+// The requests this process made to the API in the last minute, whether
+// itself or through wakatime-cli, shared by every server in it.
+static BUDGET: LazyLock<Budget> = LazyLock::new(|| Budget {
+    per_minute: AtomicU32::new(DEFAULT_PER_MINUTE),
+    requests: Mutex::new(VecDeque::new()),
+});
+
+struct Budget {
+    // 0 for no limit.
+    per_minute: AtomicU32,
+    requests: Mutex<VecDeque<Instant>>,
+}
+
+pub fn set_per_minute(per_minute: u32) {
+    BUDGET.per_minute.store(per_minute, Ordering::Relaxed);
+}
+
+pub fn per_minute() -> u32 {
+    BUDGET.per_minute.load(Ordering::Relaxed)
+}
+
+// Take one request from the budget, or return false when the last minute
+// already used all of it.
+//
+// Example, with 60 requests a minute:
+// 60 requests from 9:00:00 to 9:00:30 -> the next one is refused until 9:01:00
+pub fn try_acquire() -> bool {
+    BUDGET.try_acquire(Instant::now())
+}
+
+impl Budget {
+    fn try_acquire(&self, now: Instant) -> bool {
+        let per_minute = self.per_minute.load(Ordering::Relaxed);
+        if per_minute == 0 {
+            return true;
+        }
+
+        let Ok(mut requests) = self.requests.lock() else {
+            return true;
+        };
+
+        while requests
+            .front()
+            .is_some_and(|&request| now.duration_since(request) >= WINDOW)
+        {
+            requests.pop_front();
+        }
+
+        if requests.len() >= per_minute as usize {
+            return false;
+        }
+
+        requests.push_back(now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn budget(per_minute: u32) -> Budget {
+        Budget {
+            per_minute: AtomicU32::new(per_minute),
+            requests: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    #[test]
+    fn refills_a_minute_after_each_request() {
+        let budget = budget(2);
+        let start = Instant::now();
+        let at = |seconds| start + Duration::from_secs(seconds);
+
+        assert!(budget.try_acquire(at(0)));
+        assert!(budget.try_acquire(at(30)));
+        assert!(!budget.try_acquire(at(59)));
+        assert!(budget.try_acquire(at(60)));
+        assert!(!budget.try_acquire(at(89)));
+        assert!(budget.try_acquire(at(90)));
+    }
+
+    #[test]
+    fn zero_is_no_limit() {
+        let budget = budget(0);
+        let now = Instant::now();
+        assert!((0..1000).all(|_| budget.try_acquire(now)));
+    }
+}
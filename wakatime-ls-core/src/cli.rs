@@ -0,0 +1,991 @@
+use std::{
+    env, fmt, fs, io,
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::SystemTime,
+};
+
+use serde_json::{json, Value};
+#[cfg(feature = "http")]
+use sha2::{Digest, Sha256};
+#[cfg(feature = "http")]
+use std::time::Duration;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{config, paths};
+
+#[cfg(feature = "http")]
+pub const REPOSITORY: &str = "wakatime/wakatime-cli";
+
+// Older releases don't understand every argument sent with a heartbeat, such
+// as `--project-folder`.
+pub const MINIMUM_VERSION: &str = "v1.38.0";
+
+// The configs `key_config` wrote in this process, by the hash of their key,
+// to be removed once the key is no longer used.
+static KEY_CONFIGS: Mutex<Vec<(u64, PathBuf)>> = Mutex::new(Vec::new());
+
+// The largest request accepted by `drop_offline`, a batch of heartbeats.
+const MAX_SYNC_REQUEST: usize = 16 * 1024 * 1024;
+
+// How far the offline queue of wakatime-cli may grow, and what gives way once
+// it is full.
+//
+// Example:
+// heartbeats = 50000, 50000 queued -> full
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueueLimit {
+    pub heartbeats: Option<u64>,
+    pub bytes: Option<u64>,
+    pub eviction: Eviction,
+}
+
+// Example:
+// reject_new -> heartbeats that can't be sent are dropped instead of queued
+// drop_oldest -> the oldest queued heartbeats are dropped to make room
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Eviction {
+    #[default]
+    RejectNew,
+    DropOldest,
+}
+
+impl Eviction {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "reject_new" => Some(Self::RejectNew),
+            "drop_oldest" => Some(Self::DropOldest),
+            _ => None,
+        }
+    }
+}
+
+impl QueueLimit {
+    pub fn is_set(&self) -> bool {
+        self.heartbeats.is_some() || self.bytes.is_some()
+    }
+
+    pub fn is_full(&self, heartbeats: u64, bytes: Option<u64>) -> bool {
+        self.heartbeats.is_some_and(|limit| heartbeats >= limit)
+            || self
+                .bytes
+                .zip(bytes)
+                .is_some_and(|(limit, bytes)| bytes >= limit)
+    }
+
+    // How many of the `queued` heartbeats, oldest first, to drop for the rest
+    // to be within the limits.
+    //
+    // Example, with heartbeats = 3:
+    // 5 queued -> 2
+    pub fn excess(&self, queued: &[Value]) -> usize {
+        let over_count = self.heartbeats.map_or(0, |limit| {
+            queued
+                .len()
+                .saturating_sub(usize::try_from(limit).unwrap_or(usize::MAX))
+        });
+        let over_size = self.bytes.map_or(0, |limit| {
+            let mut size: u64 = queued.iter().map(json_size).sum();
+            queued
+                .iter()
+                .take_while(|heartbeat| {
+                    let over = size > limit;
+                    size -= json_size(heartbeat);
+                    over
+                })
+                .count()
+        });
+
+        over_count.max(over_size)
+    }
+}
+
+fn json_size(value: &Value) -> u64 {
+    value.to_string().len() as u64
+}
+
+// A wakatime-cli binary and how to run it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cli {
+    pub path: PathBuf,
+    // Run on the host through `flatpak-spawn`, for a binary the Flatpak
+    // sandbox can't see or execute.
+    pub on_host: bool,
+}
+
+impl Cli {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            on_host: false,
+        }
+    }
+
+    pub fn command(&self) -> tokio::process::Command {
+        let mut command = if self.on_host {
+            let mut command = tokio::process::Command::new("flatpak-spawn");
+            command.arg("--host").arg(&self.path);
+            command
+        } else {
+            tokio::process::Command::new(&self.path)
+        };
+
+        // Snap gives every app its own `$HOME`, where wakatime-cli wouldn't
+        // find `~/.wakatime.cfg`.
+        let wakatime_home = env::var_os("WAKATIME_HOME").filter(|home| !home.is_empty());
+        if sandbox() == Some(Sandbox::Snap) && wakatime_home.is_none() {
+            if let Some(home) = config::home_dir() {
+                command.env("WAKATIME_HOME", home);
+            }
+        }
+
+        command
+    }
+
+    // The version reported by `wakatime-cli --version`, or `None` if it
+    // doesn't run.
+    pub async fn version(&self) -> Option<String> {
+        let output = self
+            .command()
+            .arg("--version")
+            .output()
+            .await
+            .ok()
+            .filter(|output| output.status.success())?;
+
+        let version = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        Some(version).filter(|version| !version.is_empty())
+    }
+
+    // A command sending heartbeats with `key`, from the editor's settings or a
+    // profile, over an `api_key` in the wakatime config. wakatime-cli ranks
+    // `WAKATIME_API_KEY` below its config, and `--key` shows up in `ps` to
+    // other users, so it is given a copy of the config with the key in it
+    // instead, see `key_config`. `flatpak-spawn` doesn't pass the environment
+    // on to the host either, but the copy works there too.
+    pub fn command_with_key(&self, key: &str) -> io::Result<tokio::process::Command> {
+        let dir = config::wakatime_home()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no home directory"))?
+            .join(".wakatime");
+        self.command_with_key_in(key, config::config_path().as_deref(), &dir)
+    }
+
+    fn command_with_key_in(
+        &self,
+        key: &str,
+        config: Option<&Path>,
+        dir: &Path,
+    ) -> io::Result<tokio::process::Command> {
+        let path = key_config(config, dir, key)?;
+
+        let mut command = self.command();
+        command.arg("--config").arg(path);
+        Ok(command)
+    }
+
+    // When the binary was last written, such as by an update, or `None` once
+    // it is gone. A binary run on the host can't be looked at from inside the
+    // sandbox, nor one only named, to be found on `PATH`.
+    pub fn modified(&self) -> Option<SystemTime> {
+        fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    }
+
+    // The number of heartbeats in the offline queue, from
+    // `wakatime-cli --offline-count`, or `None` if it can't tell.
+    pub async fn offline_count(&self) -> Option<u64> {
+        let output = self
+            .command()
+            .arg("--offline-count")
+            .output()
+            .await
+            .ok()
+            .filter(|output| output.status.success())?;
+
+        String::from_utf8_lossy(&output.stdout).trim().parse().ok()
+    }
+
+    // How many bytes the offline queue takes as JSON, from printing its
+    // `count` heartbeats. Its file only ever grows, even once sent.
+    pub async fn offline_size(&self, count: u64) -> Option<u64> {
+        if count == 0 {
+            return Some(0);
+        }
+        let output = self
+            .command()
+            .arg("--print-offline-heartbeats")
+            .arg(count.to_string())
+            .output()
+            .await
+            .ok()
+            .filter(|output| output.status.success())?;
+
+        Some(output.stdout.len() as u64)
+    }
+
+    // The first `count` heartbeats of the offline queue, the oldest as
+    // wakatime-cli keeps them in order of time.
+    pub async fn offline_heartbeats(&self, count: u64) -> Option<Vec<Value>> {
+        if count == 0 {
+            return Some(Vec::new());
+        }
+        let output = self
+            .command()
+            .arg("--print-offline-heartbeats")
+            .arg(count.to_string())
+            .output()
+            .await
+            .ok()
+            .filter(|output| output.status.success())?;
+
+        serde_json::from_slice(&output.stdout).ok()
+    }
+
+    // Drop the `count` oldest heartbeats of the offline queue, returning how
+    // many were. wakatime-cli only takes heartbeats out of its queue once an
+    // API received them, so they are synced to one on localhost that throws
+    // them away, rather than the queue being rewritten behind its back.
+    pub async fn drop_offline(&self, count: u64, api_key: Option<&str>) -> io::Result<u64> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let address = listener.local_addr()?;
+        let received = Arc::new(AtomicU64::new(0));
+
+        let discard = tokio::spawn({
+            let received = received.clone();
+            async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    if let Ok(heartbeats) = discard(stream).await {
+                        received.fetch_add(heartbeats, Ordering::Relaxed);
+                    }
+                }
+            }
+        });
+
+        let mut command = match api_key {
+            Some(key) => self.command_with_key(key)?,
+            None => self.command(),
+        };
+        let output = command
+            .arg("--sync-offline-activity")
+            .arg(count.to_string())
+            .arg("--api-url")
+            .arg(format!("http://{address}/api/v1"))
+            .output()
+            .await;
+        discard.abort();
+
+        let output = output?;
+        if !output.status.success() {
+            return Err(io::Error::other(format!(
+                "wakatime-cli --sync-offline-activity failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(received.load(Ordering::Relaxed))
+    }
+
+    // The same binary on the host, when running in a Flatpak sandbox.
+    fn on_host(&self) -> Option<Self> {
+        (sandbox() == Some(Sandbox::Flatpak) && !self.on_host).then(|| Self {
+            path: self.path.clone(),
+            on_host: true,
+        })
+    }
+
+    // This binary, or else the same one run on the host, whichever works.
+    async fn runnable(self) -> Option<Self> {
+        if self.version().await.is_some() {
+            return Some(self);
+        }
+
+        let on_host = self.on_host()?;
+        on_host.version().await.map(|_| on_host)
+    }
+}
+
+impl fmt::Display for Cli {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.path.display())?;
+
+        if self.on_host {
+            write!(f, " (on the host)")?;
+        }
+
+        Ok(())
+    }
+}
+
+// Answer a batch of heartbeats synced by `drop_offline` as received, the way
+// the API does, and return how many it held.
+//
+// Example:
+// POST /api/v1/users/current/heartbeats.bulk [{...},{...}] -> 202 {"responses":[[{"data":{...}},201],[{"data":{...}},201]]}
+async fn discard(mut stream: TcpStream) -> io::Result<u64> {
+    let mut request = Vec::new();
+    let mut buffer = [0; 8192];
+    let header_end = loop {
+        if let Some(end) = request.windows(4).position(|window| window == b"\r\n\r\n") {
+            break end + 4;
+        }
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 || request.len() + read > MAX_SYNC_REQUEST {
+            return Ok(0);
+        }
+        request.extend_from_slice(&buffer[..read]);
+    };
+
+    let head = String::from_utf8_lossy(&request[..header_end]).into_owned();
+    let length = head
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+    if header_end + length > MAX_SYNC_REQUEST {
+        return Ok(0);
+    }
+    while request.len() < header_end + length {
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            return Ok(0);
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+
+    let heartbeats =
+        match serde_json::from_slice::<Value>(&request[header_end..header_end + length]) {
+            Ok(Value::Array(heartbeats)) if head.starts_with("POST ") => heartbeats,
+            _ => {
+                let response =
+                    "HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+                stream.write_all(response.as_bytes()).await?;
+                return Ok(0);
+            }
+        };
+    let responses: Vec<Value> = heartbeats
+        .iter()
+        .map(|heartbeat| json!([{ "data": heartbeat }, 201]))
+        .collect();
+    let body = json!({ "responses": responses }).to_string();
+
+    let response = format!(
+        "HTTP/1.1 202 Accepted\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(heartbeats.len() as u64)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sandbox {
+    Flatpak,
+    Snap,
+}
+
+// The sandbox Zed, and so the language server, runs in.
+pub fn sandbox() -> Option<Sandbox> {
+    static SANDBOX: OnceLock<Option<Sandbox>> = OnceLock::new();
+
+    *SANDBOX.get_or_init(|| {
+        if env::var_os("FLATPAK_ID").is_some() || Path::new("/.flatpak-info").is_file() {
+            Some(Sandbox::Flatpak)
+        } else if env::var_os("SNAP").is_some() {
+            Some(Sandbox::Snap)
+        } else {
+            None
+        }
+    })
+}
+
+// The wakatime-cli build for this machine, named like the release assets.
+//
+// Example:
+// linux x86_64    -> wakatime-cli-linux-amd64
+// macos aarch64   -> wakatime-cli-darwin-arm64
+// windows x86_64  -> wakatime-cli-windows-amd64 (wakatime-cli-windows-amd64.exe once extracted)
+fn build_name() -> Option<String> {
+    Some(format!("wakatime-cli-{}-{}", build_os()?, build_arch()?))
+}
+
+fn build_os() -> Option<&'static str> {
+    match env::consts::OS {
+        "macos" => Some("darwin"),
+        os @ ("linux" | "windows" | "freebsd" | "netbsd" | "openbsd") => Some(os),
+        _ => None,
+    }
+}
+
+fn build_arch() -> Option<&'static str> {
+    match env::consts::ARCH {
+        "x86_64" => Some("amd64"),
+        "aarch64" => Some("arm64"),
+        "x86" => Some("386"),
+        arch @ ("arm" | "riscv64") => Some(arch),
+        _ => None,
+    }
+}
+
+// Write the wakatime config at `config`, or an empty one, with `key` as its
+// `api_key` to a file in `dir` only its owner may read, returning where.
+// `~/.wakatime/` is shared by the Flatpak host and its sandbox. Each key gets
+// a file of its own, named by its hash, so heartbeats sent with different keys
+// at once don't overwrite each other's, and it is only written again once the
+// config changed, or it was removed by `remove_key_configs`.
+//
+// Example:
+// waka_xxx -> ~/.wakatime/zed-wakatime-key-c569cfd0f1b0eb46.cfg
+fn key_config(config: Option<&Path>, dir: &Path, key: &str) -> io::Result<PathBuf> {
+    let content = match config.map(fs::read_to_string) {
+        Some(Ok(content)) => content,
+        Some(Err(err)) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+        _ => String::new(),
+    };
+
+    let hash = key_hash(key);
+    let path = dir.join(format!("zed-wakatime-key-{hash:016x}.cfg"));
+    let content = config::set_entry(&content, "settings", "api_key", key);
+    if fs::read_to_string(&path).ok().as_deref() != Some(content.as_str()) {
+        fs::create_dir_all(dir)?;
+        paths::write_private(&path, content.as_bytes())?;
+    }
+    if let Ok(mut written) = KEY_CONFIGS.lock() {
+        if !written.iter().any(|(_, written)| *written == path) {
+            written.push((hash, path.clone()));
+        }
+    }
+
+    Ok(path)
+}
+
+fn key_hash(key: &str) -> u64 {
+    key.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+// Remove the configs written for keys other than `keep`, such as a key that
+// was rotated or refused, or all of them once the server shuts down, so copies
+// of old keys don't stay in `~/.wakatime/`. A config another server still
+// uses is written again before its next heartbeat.
+pub fn remove_key_configs(keep: &[&str]) {
+    if let Some(home) = config::wakatime_home() {
+        remove_key_configs_in(&home.join(".wakatime"), keep);
+    }
+}
+
+fn remove_key_configs_in(dir: &Path, keep: &[&str]) {
+    let keep: Vec<u64> = keep.iter().map(|key| key_hash(key)).collect();
+    if let Ok(mut written) = KEY_CONFIGS.lock() {
+        written.retain(|(hash, path)| {
+            if keep.contains(hash) || !path.starts_with(dir) {
+                return true;
+            }
+            fs::remove_file(path).ok();
+            false
+        });
+    }
+}
+
+// Where the other WakaTime plugins install wakatime-cli, so an existing
+// install is shared.
+pub fn install_path() -> Option<PathBuf> {
+    let name = build_name()?;
+    let name = if cfg!(windows) {
+        format!("{name}.exe")
+    } else {
+        name
+    };
+
+    Some(config::wakatime_home()?.join(".wakatime").join(name))
+}
+
+// An installed wakatime-cli that runs on this machine: one on the `PATH`, or
+// else one in the shared install folder, preferring the build for this
+// machine.
+//
+// Inside Flatpak, the sandbox has its own `PATH` and may not be allowed to
+// run binaries from the home directory, so the candidates are also tried on
+// the host, followed by the host's `PATH`.
+pub async fn find() -> Option<Cli> {
+    let candidates = find_in_path().into_iter().chain(find_installed());
+
+    for candidate in candidates {
+        if let Some(cli) = Cli::new(candidate).runnable().await {
+            return Some(cli);
+        }
+    }
+
+    let on_host = Cli::new(PathBuf::from("wakatime-cli")).on_host()?;
+    on_host.version().await.map(|_| on_host)
+}
+
+fn find_in_path() -> Option<PathBuf> {
+    let name = if cfg!(windows) {
+        "wakatime-cli.exe"
+    } else {
+        "wakatime-cli"
+    };
+
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(name))
+        .find(|path| path.is_file())
+}
+
+// Other plugins may have installed a differently named build, or a
+// `wakatime-cli` link to it. Builds for this OS are tried first, then the
+// rest in name order.
+fn find_installed() -> Vec<PathBuf> {
+    let Some(install_path) = install_path() else {
+        return Vec::new();
+    };
+    let Some(Ok(entries)) = install_path.parent().map(fs::read_dir) else {
+        return Vec::new();
+    };
+
+    let mut candidates: Vec<(u8, String, PathBuf)> = entries
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            let name = path.file_name()?.to_str()?.to_string();
+
+            // Skip the leftovers of an interrupted install or update.
+            let leftover = [".download", ".new", ".old", ".zip"]
+                .iter()
+                .any(|suffix| name.contains(suffix));
+            if !name.starts_with("wakatime-cli") || leftover || !path.is_file() {
+                return None;
+            }
+
+            let same_os = build_os().is_some_and(|os| name.contains(&format!("-{os}-")));
+            let rank = match (path == install_path, same_os) {
+                (true, _) => 0,
+                (false, true) => 1,
+                (false, false) => 2,
+            };
+
+            Some((rank, name, path))
+        })
+        .collect();
+
+    candidates.sort();
+    candidates.into_iter().map(|(_, _, path)| path).collect()
+}
+
+// Download the latest release to the shared install location.
+#[cfg(feature = "http")]
+pub async fn install() -> Result<Cli, String> {
+    let path = install_path().ok_or("could not find the home directory")?;
+    let client = http_client()?;
+
+    let tag = latest_release(&client, REPOSITORY).await?;
+    let archive = download(&client, &tag).await?;
+
+    let target = path.clone();
+    tokio::task::spawn_blocking(move || extract(&archive, &target))
+        .await
+        .map_err(|err| format!("failed to extract wakatime-cli: {err}"))??;
+
+    match Cli::new(path.clone()).runnable().await {
+        Some(cli) => Ok(cli),
+        None => {
+            fs::remove_file(&path).ok();
+            Err(format!(
+                "the downloaded wakatime-cli {tag} does not run on this machine"
+            ))
+        }
+    }
+}
+
+// Replace the given wakatime-cli with the latest release if it is older,
+// returning the new version. The new build is only swapped in once it runs,
+// and the old one is put back if the swap fails.
+#[cfg(feature = "http")]
+pub async fn update(cli: &Cli) -> Result<Option<String>, String> {
+    let client = http_client()?;
+    let tag = latest_release(&client, REPOSITORY).await?;
+
+    let path = cli.path.as_path();
+    let installed = cli.version().await;
+    if installed
+        .as_deref()
+        .is_some_and(|installed| !is_newer(&tag, installed))
+    {
+        return Ok(None);
+    }
+
+    let archive = download(&client, &tag).await?;
+
+    // Keeps the `.exe` extension, so it can be run on Windows.
+    let staged = match path.extension() {
+        Some(extension) => path.with_extension(format!("new.{}", extension.to_string_lossy())),
+        None => path.with_extension("new"),
+    };
+    let target = staged.clone();
+    tokio::task::spawn_blocking(move || extract(&archive, &target))
+        .await
+        .map_err(|err| format!("failed to extract wakatime-cli: {err}"))??;
+
+    let staged_cli = Cli {
+        path: staged.clone(),
+        on_host: cli.on_host,
+    };
+    if staged_cli.version().await.is_none() {
+        fs::remove_file(&staged).ok();
+        return Err(format!("the downloaded wakatime-cli {tag} does not run"));
+    }
+
+    let backup = path.with_extension("old");
+    fs::rename(path, &backup)
+        .map_err(|err| format!("failed to move {} aside: {err}", path.display()))?;
+
+    if let Err(err) = fs::rename(&staged, path) {
+        fs::rename(&backup, path).ok();
+        fs::remove_file(&staged).ok();
+        return Err(format!("failed to replace {}: {err}", path.display()));
+    }
+
+    // A running wakatime-cli can't be deleted on Windows; the backup is then
+    // overwritten by the next update.
+    fs::remove_file(&backup).ok();
+
+    Ok(Some(tag))
+}
+
+// Whether a version reported by `wakatime-cli --version` is at least
+// `MINIMUM_VERSION`. Development builds are assumed to be recent.
+pub fn is_supported(version: &str) -> bool {
+    !is_newer(MINIMUM_VERSION, version)
+}
+
+// Compare dotted version numbers, ignoring a leading `v` and trailing zeros.
+// Versions that don't parse, such as development builds, are never
+// considered older.
+//
+// Example:
+// v1.102.1 is newer than v1.98.0, 1.2 is as new as 1.2.0
+pub fn is_newer(latest: &str, installed: &str) -> bool {
+    let parse = |version: &str| -> Option<Vec<u64>> {
+        let mut parts = version
+            .trim()
+            .trim_start_matches('v')
+            .split('.')
+            .map(|part| part.parse().ok())
+            .collect::<Option<Vec<u64>>>()?;
+        while parts.last() == Some(&0) {
+            parts.pop();
+        }
+        Some(parts)
+    };
+
+    match (parse(latest), parse(installed)) {
+        (Some(latest), Some(installed)) => latest > installed,
+        _ => false,
+    }
+}
+
+#[cfg(feature = "http")]
+pub fn http_client() -> Result<reqwest::Client, String> {
+    build_client(reqwest::Client::builder())
+}
+
+// Like `http_client`, giving up on servers that don't accept a connection
+// within `timeout`.
+#[cfg(feature = "http")]
+pub fn http_client_connecting_within(timeout: Duration) -> Result<reqwest::Client, String> {
+    build_client(reqwest::Client::builder().connect_timeout(timeout))
+}
+
+#[cfg(feature = "http")]
+fn build_client(builder: reqwest::ClientBuilder) -> Result<reqwest::Client, String> {
+    builder
+        .user_agent(concat!("wakatime-ls/", env!("CARGO_PKG_VERSION")))
+        .build()
+        .map_err(|err| format!("failed to create HTTP client: {err}"))
+}
+
+// The tag of the latest release of a GitHub repository.
+#[cfg(feature = "http")]
+pub async fn latest_release(client: &reqwest::Client, repository: &str) -> Result<String, String> {
+    let release = get(
+        client,
+        &format!("https://api.github.com/repos/{repository}/releases/latest"),
+    )
+    .await?;
+    let release: Value = serde_json::from_slice(&release)
+        .map_err(|err| format!("failed to parse the latest release: {err}"))?;
+
+    release
+        .get("tag_name")
+        .and_then(Value::as_str)
+        .map(str::to_string)
+        .ok_or_else(|| "the latest release has no tag".to_string())
+}
+
+// Download the archive of a release for this machine. It is checked against
+// the SHA256 checksums published with the release before anything is
+// written.
+#[cfg(feature = "http")]
+async fn download(client: &reqwest::Client, tag: &str) -> Result<Vec<u8>, String> {
+    let name = build_name().ok_or_else(|| {
+        format!(
+            "no wakatime-cli build for {} {}",
+            env::consts::OS,
+            env::consts::ARCH
+        )
+    })?;
+
+    let base = format!("https://github.com/{REPOSITORY}/releases/download/{tag}");
+    let archive_name = format!("{name}.zip");
+
+    let checksums = get(client, &format!("{base}/checksums_sha256.txt")).await?;
+    let expected = checksum(&String::from_utf8_lossy(&checksums), &archive_name)
+        .ok_or_else(|| format!("no checksum published for {archive_name}"))?;
+
+    let archive = get(client, &format!("{base}/{archive_name}")).await?;
+    let actual: String = Sha256::digest(&archive)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+
+    if actual != expected {
+        return Err(format!(
+            "checksum mismatch for {archive_name}: expected {expected}, got {actual}"
+        ));
+    }
+
+    Ok(archive)
+}
+
+// The checksum of a file in the output of `sha256sum`, in text or binary
+// mode.
+//
+// Example:
+// "3f2a...  wakatime-cli-linux-amd64.zip" or "3F2A... *wakatime-cli-linux-amd64.zip"
+#[cfg(feature = "http")]
+fn checksum(checksums: &str, file_name: &str) -> Option<String> {
+    checksums.lines().find_map(|line| {
+        let (checksum, file) = line.split_once(char::is_whitespace)?;
+        (file.trim().trim_start_matches('*') == file_name).then(|| checksum.to_ascii_lowercase())
+    })
+}
+
+#[cfg(feature = "http")]
+pub async fn get(client: &reqwest::Client, url: &str) -> Result<Vec<u8>, String> {
+    let response = client
+        .get(url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|err| format!("failed to download {url}: {err}"))?;
+
+    let body = response
+        .bytes()
+        .await
+        .map_err(|err| format!("failed to download {url}: {err}"))?;
+
+    Ok(body.to_vec())
+}
+
+// The archive holds a single binary named like the install path. It is
+// written next to `path` and renamed into place, so a partial download is
+// never picked up.
+#[cfg(feature = "http")]
+fn extract(archive: &[u8], path: &Path) -> Result<(), String> {
+    let name = install_path()
+        .and_then(|path| Some(path.file_name()?.to_str()?.to_string()))
+        .ok_or("invalid install path")?;
+    let name = name.as_str();
+
+    let mut archive = zip::ZipArchive::new(io::Cursor::new(archive))
+        .map_err(|err| format!("failed to open the archive: {err}"))?;
+    let mut binary = archive
+        .by_name(name)
+        .map_err(|err| format!("failed to find {name} in the archive: {err}"))?;
+
+    let dir = path.parent().ok_or("invalid install path")?;
+    fs::create_dir_all(dir).map_err(|err| format!("failed to create {}: {err}", dir.display()))?;
+
+    let partial = path.with_extension("download");
+    let mut write = || -> io::Result<()> {
+        let mut file = fs::File::create(&partial)?;
+        io::copy(&mut binary, &mut file)?;
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(fs::Permissions::from_mode(0o755))?;
+        }
+
+        fs::rename(&partial, path)
+    };
+
+    write().map_err(|err| {
+        fs::remove_file(&partial).ok();
+        format!("failed to install {}: {err}", path.display())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions_are_compared_numerically() {
+        assert!(is_newer("v1.102.1", "v1.98.0"));
+        assert!(is_newer("1.10.0", "v1.9.9"));
+        assert!(!is_newer("v1.98.0", "1.98.0"));
+        assert!(!is_newer("1.2", "1.2.0"));
+        assert!(!is_newer("1.2.0", "1.2"));
+        assert!(is_newer("1.2.1", "1.2"));
+    }
+
+    #[test]
+    fn unparsable_versions_are_never_older() {
+        assert!(!is_newer("v1.98.0", "<local-build>"));
+        assert!(!is_newer("v1.98.0", "1.98.0-alpha.1"));
+        assert!(!is_newer("latest", "1.0.0"));
+        assert!(is_supported("<local-build>"));
+    }
+
+    // The config given to wakatime-cli with `--config` by a command.
+    fn given_config(command: &tokio::process::Command) -> String {
+        fs::read_to_string(given_config_path(command)).unwrap()
+    }
+
+    fn given_config_path(command: &tokio::process::Command) -> PathBuf {
+        let args: Vec<_> = command.as_std().get_args().collect();
+        let at = args.iter().position(|arg| *arg == "--config").unwrap();
+        PathBuf::from(args[at + 1])
+    }
+
+    #[test]
+    fn keys_from_the_server_override_the_one_in_the_config() {
+        let dir = env::temp_dir().join(format!("wakatime-ls-key-config-{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        let config = dir.join(".wakatime.cfg");
+        fs::write(
+            &config,
+            "[settings]\napi_key = waka_personal\n\n[projectmap]\nwork/ = work\n",
+        )
+        .unwrap();
+
+        let cli = Cli::new(PathBuf::from("wakatime-cli"));
+        let command = cli
+            .command_with_key_in("waka_profile", Some(&config), &dir)
+            .unwrap();
+
+        assert_eq!(
+            given_config(&command),
+            "[settings]\napi_key = waka_profile\n\n[projectmap]\nwork/ = work\n"
+        );
+        assert!(!format!("{:?}", command.as_std()).contains("waka_profile"));
+    }
+
+    #[test]
+    fn configs_of_keys_no_longer_used_are_removed() {
+        let dir = env::temp_dir().join(format!("wakatime-ls-key-removal-{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+
+        let cli = Cli::new(PathBuf::from("wakatime-cli"));
+        let refused =
+            given_config_path(&cli.command_with_key_in("waka_refused", None, &dir).unwrap());
+        let secondary = given_config_path(
+            &cli.command_with_key_in("waka_secondary", None, &dir)
+                .unwrap(),
+        );
+
+        remove_key_configs_in(&dir, &["waka_secondary"]);
+        assert!(!refused.exists());
+        assert!(secondary.exists());
+
+        remove_key_configs_in(&dir, &[]);
+        assert!(!secondary.exists());
+    }
+
+    #[cfg(feature = "http")]
+    #[test]
+    fn checksums_are_found_by_file_name() {
+        let checksums = "\
+0a1b  wakatime-cli-darwin-arm64.zip
+3F2A *wakatime-cli-linux-amd64.zip
+9c8d  wakatime-cli-linux-amd64.zip.sig
+";
+
+        assert_eq!(
+            checksum(checksums, "wakatime-cli-linux-amd64.zip").as_deref(),
+            Some("3f2a")
+        );
+        assert_eq!(
+            checksum(checksums, "wakatime-cli-darwin-arm64.zip").as_deref(),
+            Some("0a1b")
+        );
+        assert_eq!(checksum(checksums, "wakatime-cli-windows-amd64.zip"), None);
+        assert_eq!(checksum("", "wakatime-cli-linux-amd64.zip"), None);
+    }
+
+    #[test]
+    fn the_oldest_heartbeats_over_the_limits_are_in_excess() {
+        let queued: Vec<Value> = (0..5)
+            .map(|time| json!({ "entity": "/src/main.rs", "time": time }))
+            .collect();
+        let size = json_size(&queued[0]);
+
+        let by_count = QueueLimit {
+            heartbeats: Some(3),
+            ..QueueLimit::default()
+        };
+        assert_eq!(by_count.excess(&queued), 2);
+        assert_eq!(by_count.excess(&queued[..3]), 0);
+
+        let by_size = QueueLimit {
+            bytes: Some(size * 4),
+            ..QueueLimit::default()
+        };
+        assert_eq!(by_size.excess(&queued), 1);
+        assert_eq!(QueueLimit::default().excess(&queued), 0);
+    }
+
+    #[test]
+    fn synced_heartbeats_are_answered_as_received() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+
+        let response = runtime.block_on(async {
+            let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+            let address = listener.local_addr().unwrap();
+            let server = tokio::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                discard(stream).await.unwrap()
+            });
+
+            let body = r#"[{"entity":"/src/main.rs","time":1},{"entity":"/src/lib.rs","time":2}]"#;
+            let mut client = TcpStream::connect(address).await.unwrap();
+            let request = format!(
+                "POST /api/v1/users/current/heartbeats.bulk HTTP/1.1\r\nContent-Length: {}\r\n\r\n{body}",
+                body.len()
+            );
+            client.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+
+            assert_eq!(server.await.unwrap(), 2);
+            response
+        });
+
+        assert!(response.starts_with("HTTP/1.1 202 Accepted\r\n"));
+        let body: Value = serde_json::from_str(response.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+        assert_eq!(body["responses"][1][0]["data"]["entity"], "/src/lib.rs");
+        assert_eq!(body["responses"][1][1], 201);
+    }
+}
@@ -0,0 +1,79 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use chrono::{DateTime, Local, TimeDelta};
+
+// The time events are stamped with, and the monotonic one heartbeat
+// intervals are measured against so that DST transitions, NTP corrections
+// and timezone changes don't move them. Tests set both by hand instead of
+// waiting for them.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Local>;
+    fn instant(&self) -> Instant;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Local> {
+        Local::now()
+    }
+
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+// Stands still until it is moved.
+//
+// Example:
+// let clock = ManualClock::new(start);
+// clock.advance(TimeDelta::minutes(3)); // now() is start + 3 minutes, instant() 3 minutes later
+// clock.adjust(TimeDelta::hours(-1));   // now() is an hour earlier, instant() unchanged
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    times: Arc<Mutex<(DateTime<Local>, Instant)>>,
+}
+
+impl ManualClock {
+    pub fn new(now: DateTime<Local>) -> Self {
+        Self {
+            times: Arc::new(Mutex::new((now, Instant::now()))),
+        }
+    }
+
+    // Let time pass, which only moves the monotonic clock forward.
+    pub fn advance(&self, by: TimeDelta) {
+        if let Ok(mut times) = self.times.lock() {
+            times.0 += by;
+            times.1 += by.to_std().unwrap_or_default();
+        }
+    }
+
+    // Set the wall clock like an NTP correction or a DST transition would,
+    // without time passing.
+    pub fn adjust(&self, by: TimeDelta) {
+        if let Ok(mut times) = self.times.lock() {
+            times.0 += by;
+        }
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<Local> {
+        self.times
+            .lock()
+            .map(|times| times.0)
+            .unwrap_or_else(|_| Local::now())
+    }
+
+    fn instant(&self) -> Instant {
+        self.times
+            .lock()
+            .map(|times| times.1)
+            .unwrap_or_else(|_| Instant::now())
+    }
+}
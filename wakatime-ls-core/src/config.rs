@@ -0,0 +1,186 @@
+use std::{env, fs, io, path::PathBuf};
+
+// The user's `~/.wakatime.cfg`, shared with wakatime-cli and every other
+// WakaTime plugin.
+//
+// Example:
+// [settings]
+// api_key = waka_xxx
+//
+// [projectmap]
+// ~/work/monorepo/services/(.*)/ = service-{0}
+#[derive(Debug, Default)]
+pub struct WakatimeConfig {
+    sections: Vec<Section>,
+}
+
+#[derive(Debug)]
+struct Section {
+    name: String,
+    entries: Vec<(String, String)>,
+}
+
+impl WakatimeConfig {
+    pub fn load() -> io::Result<Self> {
+        let Some(path) = config_path() else {
+            return Ok(Self::default());
+        };
+
+        match fs::read_to_string(path) {
+            Ok(content) => Ok(Self::parse(&content)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err),
+        }
+    }
+
+    // Parse the INI dialect understood by wakatime-cli. Indented lines continue
+    // the previous value, which is how multi-line options are written.
+    pub fn parse(content: &str) -> Self {
+        let mut sections: Vec<Section> = Vec::new();
+
+        for line in content.lines() {
+            let trimmed = line.trim();
+            if trimmed.is_empty() || trimmed.starts_with('#') || trimmed.starts_with(';') {
+                continue;
+            }
+
+            if let Some(name) = trimmed
+                .strip_prefix('[')
+                .and_then(|rest| rest.strip_suffix(']'))
+            {
+                sections.push(Section {
+                    name: name.trim().to_string(),
+                    entries: Vec::new(),
+                });
+                continue;
+            }
+
+            let Some(section) = sections.last_mut() else {
+                continue;
+            };
+
+            if line.starts_with(char::is_whitespace) {
+                if let Some((_, value)) = section.entries.last_mut() {
+                    value.push('\n');
+                    value.push_str(trimmed);
+                    continue;
+                }
+            }
+
+            if let Some((key, value)) = trimmed.split_once('=') {
+                section
+                    .entries
+                    .push((key.trim().to_string(), value.trim().to_string()));
+            }
+        }
+
+        Self { sections }
+    }
+
+    // All entries of a section in file order.
+    pub fn section<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a (String, String)> {
+        self.sections
+            .iter()
+            .filter(move |section| section.name == name)
+            .flat_map(|section| section.entries.iter())
+    }
+
+    // The last value of a key in a section, like wakatime-cli reads it.
+    pub fn get<'a>(&'a self, section: &'a str, key: &str) -> Option<&'a str> {
+        self.section(section)
+            .filter(|(name, _)| name == key)
+            .last()
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+// Set a key of a section in the content of a config file, replacing its last
+// value or else adding it at the end of the section, which is added when
+// missing. Everything else, comments included, is kept as it was.
+//
+// Example, setting api_key in settings:
+// [settings]            [settings]
+// debug = true       -> debug = true
+//                       api_key = waka_xxx
+pub fn set_entry(content: &str, section: &str, key: &str, value: &str) -> String {
+    let mut lines: Vec<String> = content.lines().map(str::to_string).collect();
+    let entry = format!("{key} = {value}");
+
+    let mut in_section = false;
+    let mut found = None;
+    let mut end = None;
+    for (index, line) in lines.iter().enumerate() {
+        let trimmed = line.trim();
+        if let Some(name) = trimmed
+            .strip_prefix('[')
+            .and_then(|rest| rest.strip_suffix(']'))
+        {
+            in_section = name.trim() == section;
+            if in_section {
+                end = Some(index + 1);
+            }
+            continue;
+        }
+
+        if !in_section || trimmed.is_empty() || trimmed.starts_with('#') || trimmed.starts_with(';')
+        {
+            continue;
+        }
+        end = Some(index + 1);
+
+        let is_key = trimmed
+            .split_once('=')
+            .is_some_and(|(name, _)| name.trim() == key);
+        if is_key && !line.starts_with(char::is_whitespace) {
+            found = Some(index);
+        }
+    }
+
+    match (found, end) {
+        (Some(index), _) => {
+            // The lines the old value continued on.
+            let continued = lines[index + 1..]
+                .iter()
+                .take_while(|line| line.starts_with(char::is_whitespace) && !line.trim().is_empty())
+                .count();
+            lines.splice(index..=index + continued, [entry]);
+        }
+        (None, Some(end)) => lines.insert(end, entry),
+        (None, None) => {
+            if lines.last().is_some_and(|line| !line.trim().is_empty()) {
+                lines.push(String::new());
+            }
+            lines.push(format!("[{section}]"));
+            lines.push(entry);
+        }
+    }
+
+    lines.join("\n") + "\n"
+}
+
+// wakatime-cli looks for its config in `$WAKATIME_HOME` before the home
+// directory.
+pub fn config_path() -> Option<PathBuf> {
+    wakatime_home().map(|home| home.join(".wakatime.cfg"))
+}
+
+pub fn wakatime_home() -> Option<PathBuf> {
+    env::var_os("WAKATIME_HOME")
+        .filter(|home| !home.is_empty())
+        .map(PathBuf::from)
+        .or_else(home_dir)
+}
+
+// Snap points `$HOME` at a per-app folder and keeps the real one in
+// `$SNAP_REAL_HOME`.
+pub fn home_dir() -> Option<PathBuf> {
+    let home = if cfg!(windows) {
+        env::var_os("USERPROFILE")
+    } else {
+        env::var_os("SNAP_REAL_HOME")
+            .filter(|home| !home.is_empty())
+            .or_else(|| env::var_os("HOME"))
+    };
+
+    home.filter(|home| !home.is_empty()).map(PathBuf::from)
+}
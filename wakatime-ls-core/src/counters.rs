@@ -0,0 +1,72 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::Serialize;
+
+use crate::heartbeat::Outcome;
+
+// How many editor events ended which way since the server started, for the
+// `wakatime/status` request and the periodic summary in the log.
+#[derive(Debug, Default)]
+pub struct HeartbeatCounters {
+    sent: AtomicU64,
+    skipped: AtomicU64,
+    ignored: AtomicU64,
+    dropped: AtomicU64,
+    failed: AtomicU64,
+    queued: AtomicU64,
+    throttled: AtomicU64,
+    evicted: AtomicU64,
+}
+
+// Example:
+// {"sent":42,"skipped":1380,"ignored":3,"dropped":0,"failed":1,"queued":2,"throttled":0,"evicted":0}
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Counts {
+    pub sent: u64,
+    // Within the heartbeat interval of the previous heartbeat.
+    pub skipped: u64,
+    // Without a cursor position.
+    pub ignored: u64,
+    // Before a wakatime-cli was installed.
+    pub dropped: u64,
+    pub failed: u64,
+    // Kept by wakatime-cli in its offline queue, to be sent later.
+    pub queued: u64,
+    // Over the budget of API requests.
+    pub throttled: u64,
+    // Neither sent nor queued, over the limits of the offline queue.
+    pub evicted: u64,
+}
+
+impl HeartbeatCounters {
+    pub fn count(&self, outcome: Outcome) {
+        self.add(outcome, 1);
+    }
+
+    pub fn add(&self, outcome: Outcome, heartbeats: u64) {
+        let counter = match outcome {
+            Outcome::Sent => &self.sent,
+            Outcome::Skipped => &self.skipped,
+            Outcome::Ignored => &self.ignored,
+            Outcome::Dropped => &self.dropped,
+            Outcome::Failed => &self.failed,
+            Outcome::Queued => &self.queued,
+            Outcome::Throttled => &self.throttled,
+            Outcome::Evicted => &self.evicted,
+        };
+        counter.fetch_add(heartbeats, Ordering::Relaxed);
+    }
+
+    pub fn counts(&self) -> Counts {
+        Counts {
+            sent: self.sent.load(Ordering::Relaxed),
+            skipped: self.skipped.load(Ordering::Relaxed),
+            ignored: self.ignored.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            queued: self.queued.load(Ordering::Relaxed),
+            throttled: self.throttled.load(Ordering::Relaxed),
+            evicted: self.evicted.load(Ordering::Relaxed),
+        }
+    }
+}
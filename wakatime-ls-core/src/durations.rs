@@ -0,0 +1,83 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Local, NaiveDate};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::api::Api;
+
+// How long the durations of a day from the API are reused.
+const CACHE_TTL: Duration = Duration::from_secs(60);
+
+// The params of `wakatime/durations`, which may all be left out.
+//
+// Example:
+// {}                                -> today's blocks per project
+// {"date":"2026-10-13","by":"file"} -> yesterday's blocks per file
+#[derive(Debug, Clone, Deserialize)]
+pub struct DurationsParams {
+    pub date: Option<NaiveDate>,
+    #[serde(default)]
+    pub by: Slice,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Slice {
+    #[default]
+    Project,
+    File,
+}
+
+// A stretch of continuous coding on one project, or one file of it, as the
+// `durations` endpoint computes them from heartbeats.
+//
+// Example:
+// {"project":"zed-hackatime","file":null,"start":"2026-10-14T09:12:03+02:00","duration_seconds":1520}
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Block {
+    pub project: String,
+    pub file: Option<String>,
+    pub start: DateTime<Local>,
+    pub duration_seconds: u64,
+}
+
+impl Block {
+    fn parse(block: &Value) -> Option<Self> {
+        let start = block["time"].as_f64()?;
+
+        Some(Self {
+            project: block["project"]
+                .as_str()
+                .unwrap_or("Unknown Project")
+                .to_string(),
+            file: block["entity"].as_str().map(str::to_string),
+            start: DateTime::from_timestamp_millis((start * 1000.0) as i64)?.with_timezone(&Local),
+            duration_seconds: block["duration"].as_f64().unwrap_or(0.0).round() as u64,
+        })
+    }
+}
+
+// Needs an API key, as wakatime-cli has no way to ask for durations.
+pub async fn fetch(api: &Api, params: &DurationsParams) -> Result<Vec<Block>, String> {
+    if api.key.is_none() {
+        return Err("no API key to ask for durations".to_string());
+    }
+
+    let date = params.date.unwrap_or_else(|| Local::now().date_naive());
+    let mut path = format!("users/current/durations?date={date}");
+    if params.by == Slice::File {
+        path.push_str("&slice_by=entity");
+    }
+
+    let (status, body) = api.get_cached(&path, CACHE_TTL).await?;
+    if !(200..300).contains(&status) {
+        return Err(format!("{} answered HTTP {status}", api.url));
+    }
+
+    let blocks = body["data"]
+        .as_array()
+        .ok_or_else(|| format!("{} answered without durations", api.url))?;
+
+    Ok(blocks.iter().filter_map(Block::parse).collect())
+}
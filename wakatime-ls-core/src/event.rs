@@ -0,0 +1,199 @@
+use std::{
+    collections::VecDeque,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+// Unless the `heartbeat_interval` setting says otherwise.
+pub const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(2 * 60);
+
+// The bounds of the interval with `adaptive_interval`, unless the
+// `min_heartbeat_interval` and `max_heartbeat_interval` settings say
+// otherwise.
+pub const DEFAULT_MIN_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+pub const DEFAULT_MAX_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+// How far back `Activity` looks, and the events in that time that count as
+// sustained typing, about one a second.
+const ACTIVITY_WINDOW: Duration = Duration::from_secs(60);
+const BUSY: usize = 60;
+
+// An edit, save, open or cursor move in the editor, which may become a
+// heartbeat. Its entity is shared with the other events of the file, as
+// there is one for each keystroke.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    pub entity: Arc<Path>,
+    pub is_write: bool,
+    pub language: Option<String>,
+    pub lineno: Option<u64>,
+    pub cursor_pos: Option<u64>,
+    // Another file than the one of the previous event.
+    pub file_changed: bool,
+}
+
+impl Event {
+    // Events without one are never sent, as wakatime-cli would count time
+    // for files that are only open in the background.
+    pub fn has_position(&self) -> bool {
+        self.lineno.is_some() && self.cursor_pos.is_some()
+    }
+
+    // Writes and file switches are sent right away; any other event once
+    // `interval` passed since the last heartbeat that wasn't one of those. A
+    // branch switch is sent right away too, so time is split at the branch
+    // boundary instead of at the next interval. Both times come from a
+    // monotonic clock, so changes of the wall clock don't count.
+    //
+    // Example, with the default interval:
+    // a cursor move 90 seconds after the last heartbeat  -> skipped
+    // a cursor move 150 seconds after the last heartbeat -> sent
+    pub fn should_send(
+        &self,
+        branch_changed: bool,
+        last_sent: Instant,
+        now: Instant,
+        interval: Duration,
+    ) -> bool {
+        self.is_write
+            || self.file_changed
+            || branch_changed
+            || now.saturating_duration_since(last_sent) > interval
+    }
+
+    // Whether sending the event starts a new interval. Writes and file
+    // switches don't, so they don't delay the next regular heartbeat.
+    pub fn restarts_interval(&self) -> bool {
+        !self.is_write && !self.file_changed
+    }
+}
+
+// How busy the editor has been over the last `ACTIVITY_WINDOW`, for an
+// interval that is shorter while typing, so heartbeats split the time more
+// finely, and longer while only glancing at files now and then.
+#[derive(Debug, Default)]
+pub struct Activity {
+    // At most `BUSY` of them, as more don't shorten the interval further.
+    events: VecDeque<Instant>,
+}
+
+impl Activity {
+    pub fn record(&mut self, now: Instant) {
+        self.forget(now);
+        if self.events.len() == BUSY {
+            self.events.pop_front();
+        }
+        self.events.push_back(now);
+    }
+
+    // From `max` with one event or none in the window, down to `min` with
+    // `BUSY` of them.
+    //
+    // Example, with the default bounds:
+    // 1 event in the last minute   -> 300 seconds
+    // 30 events in the last minute -> 167 seconds
+    // 60 events in the last minute -> 30 seconds
+    pub fn interval(&mut self, now: Instant, min: Duration, max: Duration) -> Duration {
+        self.forget(now);
+        let max = max.max(min);
+
+        let busy = self.events.len().saturating_sub(1) as u32;
+        max - (max - min) * busy / (BUSY as u32 - 1)
+    }
+
+    fn forget(&mut self, now: Instant) {
+        while self
+            .events
+            .front()
+            .is_some_and(|&event| now.saturating_duration_since(event) > ACTIVITY_WINDOW)
+        {
+            self.events.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event() -> Event {
+        Event {
+            entity: Arc::from(Path::new("/home/alice/src/app/main.rs")),
+            is_write: false,
+            language: None,
+            lineno: Some(1),
+            cursor_pos: Some(1),
+            file_changed: false,
+        }
+    }
+
+    #[test]
+    fn skipped_within_the_interval() {
+        let last_sent = Instant::now();
+        let now = last_sent + Duration::from_secs(90);
+
+        assert!(!event().should_send(false, last_sent, now, DEFAULT_HEARTBEAT_INTERVAL));
+    }
+
+    #[test]
+    fn sent_after_the_interval() {
+        let last_sent = Instant::now();
+        let now = last_sent + Duration::from_secs(150);
+
+        assert!(event().should_send(false, last_sent, now, DEFAULT_HEARTBEAT_INTERVAL));
+    }
+
+    #[test]
+    fn writes_file_and_branch_switches_sent_right_away() {
+        let now = Instant::now();
+        let write = Event {
+            is_write: true,
+            ..event()
+        };
+        let switch = Event {
+            file_changed: true,
+            ..event()
+        };
+
+        assert!(write.should_send(false, now, now, DEFAULT_HEARTBEAT_INTERVAL));
+        assert!(switch.should_send(false, now, now, DEFAULT_HEARTBEAT_INTERVAL));
+        assert!(event().should_send(true, now, now, DEFAULT_HEARTBEAT_INTERVAL));
+        assert!(!write.restarts_interval());
+        assert!(!switch.restarts_interval());
+        assert!(event().restarts_interval());
+    }
+
+    #[test]
+    fn typing_shortens_the_interval() {
+        let (min, max) = (
+            DEFAULT_MIN_HEARTBEAT_INTERVAL,
+            DEFAULT_MAX_HEARTBEAT_INTERVAL,
+        );
+        let start = Instant::now();
+        let mut activity = Activity::default();
+        assert_eq!(activity.interval(start, min, max), max);
+
+        for second in 0..90 {
+            activity.record(start + Duration::from_secs(second));
+        }
+        let now = start + Duration::from_secs(90);
+        assert_eq!(activity.interval(now, min, max), min);
+
+        // Only the last minute counts.
+        let later = now + Duration::from_secs(45);
+        let interval = activity.interval(later, min, max);
+        assert!(min < interval && interval < max);
+        assert_eq!(activity.interval(later + ACTIVITY_WINDOW, min, max), max);
+    }
+
+    #[test]
+    fn needs_a_position() {
+        let event = Event {
+            cursor_pos: None,
+            ..event()
+        };
+
+        assert!(!event.has_position());
+    }
+}
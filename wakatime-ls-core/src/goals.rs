@@ -0,0 +1,81 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::api::Api;
+
+// How long goals from the API are reused, however often they are asked for.
+const CACHE_TTL: Duration = Duration::from_secs(60);
+
+// A goal set up on the dashboard, with its progress in the current day or
+// week, the last entry of its chart.
+//
+// Example:
+// Code 2 hrs per day in Rust: 1 hr 12 mins / 2 hrs
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Goal {
+    pub id: String,
+    pub title: String,
+    // `day` or `week`.
+    pub delta: String,
+    // The first day of the current period, as `YYYY-MM-DD`.
+    pub period: String,
+    pub actual_seconds: u64,
+    pub actual_text: String,
+    pub goal_seconds: u64,
+    pub goal_text: String,
+}
+
+impl Goal {
+    fn parse(goal: &Value) -> Option<Self> {
+        let current = goal["chart_data"].as_array()?.last()?;
+
+        Some(Self {
+            id: goal["id"].as_str()?.to_string(),
+            title: goal["title"].as_str()?.to_string(),
+            delta: goal["delta"].as_str().unwrap_or("day").to_string(),
+            period: current["range"]["date"]
+                .as_str()
+                .or_else(|| current["range"]["start"].as_str())
+                .unwrap_or_default()
+                .get(..10)
+                .unwrap_or_default()
+                .to_string(),
+            actual_seconds: current["actual_seconds"].as_f64()? as u64,
+            actual_text: current["actual_seconds_text"].as_str()?.to_string(),
+            goal_seconds: current["goal_seconds"].as_f64()? as u64,
+            goal_text: current["goal_seconds_text"].as_str()?.to_string(),
+        })
+    }
+
+    // Goals can also be about spending less time, such as on meetings, which
+    // are never "reached" this way.
+    pub fn is_reached(&self) -> bool {
+        self.goal_seconds > 0 && self.actual_seconds >= self.goal_seconds
+    }
+
+    pub fn progress(&self) -> String {
+        format!("{}: {} / {}", self.title, self.actual_text, self.goal_text)
+    }
+}
+
+// Needs an API key, as wakatime-cli has no way to ask for goals. Goals the
+// API describes without progress, such as ones it hasn't computed yet, are
+// left out.
+pub async fn fetch(api: &Api) -> Result<Vec<Goal>, String> {
+    if api.key.is_none() {
+        return Err("no API key to ask for goals".to_string());
+    }
+
+    let (status, body) = api.get_cached("users/current/goals", CACHE_TTL).await?;
+    if !(200..300).contains(&status) {
+        return Err(format!("{} answered HTTP {status}", api.url));
+    }
+
+    let goals = body["data"]
+        .as_array()
+        .ok_or_else(|| format!("{} answered without goals", api.url))?;
+
+    Ok(goals.iter().filter_map(Goal::parse).collect())
+}
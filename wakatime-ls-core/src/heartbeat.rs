@@ -0,0 +1,460 @@
+use std::{io, path::PathBuf, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use crate::{
+    cli::Cli,
+    project::{GitMessage, Project},
+};
+
+// A heartbeat as sent to wakatime-cli, by the language server and by the
+// `heartbeat` subcommand alike.
+#[derive(Debug, Clone, Default)]
+pub struct Heartbeat {
+    pub entity: PathBuf,
+    // The file wakatime-cli reads when the entity is reported under another
+    // path.
+    pub local_file: Option<PathBuf>,
+    // `file` unless set, e.g. `app` for git activity.
+    pub entity_type: Option<String>,
+    pub category: Option<String>,
+    pub time: f64,
+    pub is_write: bool,
+    pub plugin: Option<String>,
+    pub project: Option<String>,
+    pub branch: Option<String>,
+    // Used when wakatime-cli detects no project itself.
+    pub alternate_project: Option<String>,
+    pub project_folder: Option<PathBuf>,
+    // Guessed by wakatime-cli unless set.
+    pub language: Option<String>,
+    pub lineno: Option<u64>,
+    pub cursor_pos: Option<u64>,
+    pub lines_in_file: Option<u64>,
+}
+
+// Settings passed along with every heartbeat.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SendOptions<'a> {
+    pub api_key: Option<&'a str>,
+    pub api_url: Option<&'a str>,
+    pub metrics: bool,
+    pub verbose: bool,
+    // How long wakatime-cli waits for the API, instead of its own default.
+    pub timeout: Option<Duration>,
+    // Drop the heartbeat rather than queue it when the API can't be reached,
+    // while the offline queue is full.
+    pub disable_offline: bool,
+}
+
+// What became of an editor event, as logged on its `heartbeat` span.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    // Without a cursor position, so not sent.
+    Ignored,
+    // Within the heartbeat interval of the previous one.
+    Skipped,
+    // Before there was a wakatime-cli to send it with.
+    Dropped,
+    Sent,
+    // wakatime-cli failed or could not be run.
+    Failed,
+    // wakatime-cli could not reach the API and kept it in its offline queue.
+    Queued,
+    // Over the budget of API requests, so not sent until a later event.
+    Throttled,
+    // Not sent, and not queued either as the offline queue was full.
+    Evicted,
+}
+
+impl Outcome {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Ignored => "ignored",
+            Self::Skipped => "skipped",
+            Self::Dropped => "dropped",
+            Self::Sent => "sent",
+            Self::Failed => "failed",
+            Self::Queued => "queued",
+            Self::Throttled => "throttled",
+            Self::Evicted => "evicted",
+        }
+    }
+
+    // From the exit code of wakatime-cli, which tells an API error or a
+    // backoff, after which the heartbeat is queued, from other failures.
+    pub fn from_exit_code(code: Option<i32>) -> Self {
+        match code {
+            Some(0) => Self::Sent,
+            Some(102 | 112) => Self::Queued,
+            _ => Self::Failed,
+        }
+    }
+}
+
+impl Heartbeat {
+    // What wakatime-cli is given, with the names the API uses for it. The
+    // project and language are still left to wakatime-cli when not set.
+    //
+    // Example:
+    // {"entity":"/home/alice/src/app/main.rs","type":"file","category":"coding","time":1792335939.0,"is_write":true,"project":"app","branch":"main","language":"Rust","lineno":12,"cursorpos":4,"lines":120,"plugin":"Zed/0.207.3 Zed-hackatime/0.3.1 (linux x86_64) wakatime-cli/v1.102.1"}
+    pub fn to_json(&self) -> Value {
+        // JSON can't hold paths that aren't UTF-8.
+        json!({
+            "entity": self.entity.to_string_lossy(),
+            "type": self.entity_type.as_deref().unwrap_or("file"),
+            "category": self.category.as_deref().unwrap_or("coding"),
+            "time": self.time,
+            "is_write": self.is_write,
+            "project": self.project.as_ref().or(self.alternate_project.as_ref()),
+            "branch": self.branch,
+            "language": self.language,
+            "lineno": self.lineno,
+            "cursorpos": self.cursor_pos,
+            "lines": self.lines_in_file,
+            "plugin": self.plugin,
+        })
+    }
+
+    // The other way around, also reading heartbeats as wakatime-cli keeps them
+    // in its offline queue, where the plugin is the `user_agent`.
+    pub fn from_json(heartbeat: &Value) -> Option<Self> {
+        let string = |key: &str| heartbeat[key].as_str().map(str::to_string);
+
+        Some(Self {
+            entity: PathBuf::from(heartbeat["entity"].as_str()?),
+            entity_type: string("type").filter(|kind| kind != "file"),
+            category: string("category"),
+            time: heartbeat["time"].as_f64()?,
+            is_write: heartbeat["is_write"].as_bool().unwrap_or(false),
+            plugin: string("plugin").or_else(|| string("user_agent")),
+            project: string("project"),
+            branch: string("branch"),
+            language: string("language"),
+            lineno: heartbeat["lineno"].as_u64(),
+            cursor_pos: heartbeat["cursorpos"].as_u64(),
+            lines_in_file: heartbeat["lines"].as_u64(),
+            ..Default::default()
+        })
+    }
+
+    // A heartbeat for a file in the project it was detected in, or else in the
+    // fallback project. Commit messages and rebase todo lists are reported as
+    // git activity in their repository instead of as files inside `.git`.
+    pub fn new(
+        entity: PathBuf,
+        language: Option<String>,
+        project: Option<Project>,
+        fallback: Option<Project>,
+    ) -> Self {
+        let mut heartbeat = Self {
+            entity,
+            language,
+            ..Default::default()
+        };
+
+        if let Some((message, _)) = GitMessage::detect(&heartbeat.entity) {
+            heartbeat.entity = PathBuf::from(message.entity());
+            heartbeat.entity_type = Some("app".to_string());
+            heartbeat.category = Some(message.category().to_string());
+            heartbeat.language = Some("Git".to_string());
+        }
+
+        let root = match (project, fallback) {
+            (Some(project), _) => {
+                heartbeat.project = Some(project.name);
+                heartbeat.branch = project.branch;
+                project.root
+            }
+            (None, Some(fallback)) => {
+                heartbeat.alternate_project = Some(fallback.name);
+                fallback.root
+            }
+            (None, None) => None,
+        };
+
+        // Lets the backend report files relative to the project instead of by
+        // their absolute path.
+        heartbeat.project_folder = root;
+
+        heartbeat
+    }
+
+    // A heartbeat for the editor itself rather than one of its files, for time
+    // spent in it away from them, such as in its terminal or project panel. The
+    // project is only used when wakatime-cli detects none, which it can't for
+    // an app.
+    //
+    // Example:
+    // "zed", "app" -> --entity zed --entity-type app --alternate-project app
+    pub fn app(name: &str, project: Option<String>) -> Self {
+        Self {
+            entity: PathBuf::from(name),
+            entity_type: Some("app".to_string()),
+            alternate_project: project,
+            ..Default::default()
+        }
+    }
+
+    // What wakatime-cli reads from `--extra-heartbeats` for a heartbeat sent
+    // along with the one on its command line, with the names of the arguments
+    // it would otherwise be given, so it is sent the same way.
+    //
+    // Example:
+    // {"entity":"/home/alice/src/app/main.rs","entity_type":"file","time":1792335939.0,"is_write":false,"project":"app","alternate_branch":"main","project_folder":"/home/alice/src/app","language":"Rust","lineno":12,"cursorpos":4,"lines":120}
+    pub fn to_extra_json(&self) -> Value {
+        let mut extra = Map::new();
+        let mut set = |key: &str, value: Value| {
+            if !value.is_null() {
+                extra.insert(key.to_string(), value);
+            }
+        };
+
+        // Lossy as in `to_json`; only the command line takes any path.
+        let path = |path: &Option<PathBuf>| json!(path.as_ref().map(|path| path.to_string_lossy()));
+        set("entity", json!(self.entity.to_string_lossy()));
+        set(
+            "entity_type",
+            json!(self.entity_type.as_deref().unwrap_or("file")),
+        );
+        set("category", json!(self.category));
+        set("time", json!(self.time));
+        set("is_write", json!(self.is_write));
+        set("local_file", path(&self.local_file));
+        match self.project {
+            Some(ref project) => {
+                set("project", json!(project));
+                set("alternate_branch", json!(self.branch));
+            }
+            None => set("alternate_project", json!(self.alternate_project)),
+        }
+        set("project_folder", path(&self.project_folder));
+        set("language", json!(self.language));
+        set("lineno", json!(self.lineno));
+        set("cursorpos", json!(self.cursor_pos));
+        set(
+            "lines",
+            json!(self.lines_in_file.filter(|lines| *lines > 0)),
+        );
+
+        Value::Object(extra)
+    }
+
+    // Whether the entity is a file, which can be reported under another path.
+    pub fn is_file(&self) -> bool {
+        self.entity_type
+            .as_deref()
+            .is_none_or(|kind| kind == "file")
+    }
+
+    // The wakatime-cli invocation sending this heartbeat, which only fails
+    // when the API key can't be handed to it, see `Cli::command_with_key`.
+    pub fn command(&self, cli: &Cli, options: SendOptions) -> io::Result<tokio::process::Command> {
+        let mut command = match options.api_key {
+            Some(key) => cli.command_with_key(key)?,
+            None => cli.command(),
+        };
+
+        command
+            .arg("--time")
+            .arg(self.time.to_string())
+            .arg("--entity")
+            .arg(&self.entity);
+
+        if let Some(ref entity_type) = self.entity_type {
+            command.arg("--entity-type").arg(entity_type);
+        }
+
+        if let Some(ref category) = self.category {
+            command.arg("--category").arg(category);
+        }
+
+        if let Some(ref local_file) = self.local_file {
+            command.arg("--local-file").arg(local_file);
+        }
+
+        if let Some(ref plugin) = self.plugin {
+            command.arg("--plugin").arg(plugin);
+        }
+
+        if self.is_write {
+            command.arg("--write");
+        }
+
+        if options.metrics {
+            command.arg("--metrics");
+        }
+
+        if let Some(api_url) = options.api_url {
+            command.arg("--api-url").arg(api_url);
+        }
+
+        if let Some(ref project) = self.project {
+            command.arg("--project").arg(project);
+
+            if let Some(ref branch) = self.branch {
+                command.arg("--alternate-branch").arg(branch);
+            }
+        } else if let Some(ref alternate_project) = self.alternate_project {
+            // Still lets wakatime-cli's own detection take priority.
+            command.arg("--alternate-project").arg(alternate_project);
+        }
+
+        if let Some(ref project_folder) = self.project_folder {
+            command.arg("--project-folder").arg(project_folder);
+        }
+
+        match self.language {
+            Some(ref language) => command.arg("--language").arg(language),
+            None => command.arg("--guess-language"),
+        };
+
+        if options.verbose {
+            command.arg("--verbose");
+        }
+
+        if options.disable_offline {
+            command.arg("--disable-offline");
+        }
+
+        if let Some(timeout) = options.timeout {
+            command
+                .arg("--timeout")
+                .arg(timeout.as_secs().max(1).to_string());
+        }
+
+        if let Some(lineno) = self.lineno {
+            command.arg("--lineno").arg(lineno.to_string());
+        }
+
+        if let Some(cursor_pos) = self.cursor_pos {
+            command.arg("--cursorpos").arg(cursor_pos.to_string());
+        }
+
+        if let Some(lines_in_file) = self.lines_in_file.filter(|lines| *lines > 0) {
+            command
+                .arg("--lines-in-file")
+                .arg(lines_in_file.to_string());
+        }
+
+        Ok(command)
+    }
+}
+
+// Put heartbeats sent together in the order they happened, as the API works
+// out durations from each heartbeat to the next: sorted by time, without
+// repeats of an entity at the same time, and with those of other entities at
+// the same time a millisecond apart. Returns how many repeats were left out.
+//
+// Example:
+// [b.rs at 20, a.rs at 10, c.rs at 10, a.rs at 10] -> [a.rs at 10, c.rs at 10.001, b.rs at 20], 1
+pub fn order_batch(heartbeats: &mut Vec<Value>) -> usize {
+    let time = |heartbeat: &Value| heartbeat["time"].as_f64().unwrap_or(f64::INFINITY);
+    heartbeats.sort_by(|a, b| {
+        time(a)
+            .total_cmp(&time(b))
+            .then_with(|| a["entity"].as_str().cmp(&b["entity"].as_str()))
+    });
+
+    let count = heartbeats.len();
+    heartbeats.dedup_by(|heartbeat, previous| {
+        heartbeat["entity"] == previous["entity"] && time(heartbeat) == time(previous)
+    });
+
+    let mut previous = f64::NEG_INFINITY;
+    for heartbeat in heartbeats.iter_mut() {
+        let Some(mut time) = heartbeat["time"].as_f64() else {
+            continue;
+        };
+        if time <= previous {
+            time = ((previous * 1000.0).round() + 1.0) / 1000.0;
+            heartbeat["time"] = json!(time);
+        }
+        previous = time;
+    }
+
+    count - heartbeats.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extra_heartbeats_are_named_like_the_arguments() {
+        let heartbeat = Heartbeat {
+            entity: PathBuf::from("/home/alice/src/app/main.rs"),
+            time: 10.0,
+            project: Some("app".to_string()),
+            branch: Some("main".to_string()),
+            alternate_project: Some("src".to_string()),
+            lineno: Some(12),
+            lines_in_file: Some(0),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            heartbeat.to_extra_json(),
+            json!({
+                "entity": "/home/alice/src/app/main.rs",
+                "entity_type": "file",
+                "time": 10.0,
+                "is_write": false,
+                "project": "app",
+                "alternate_branch": "main",
+                "lineno": 12,
+            })
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn paths_that_are_not_utf8_are_written_lossily() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let heartbeat = Heartbeat {
+            entity: PathBuf::from(OsStr::from_bytes(b"/home/alice/\xff.rs")),
+            ..Default::default()
+        };
+
+        assert_eq!(heartbeat.to_json()["entity"], "/home/alice/\u{fffd}.rs");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn extra_heartbeats_of_paths_that_are_not_utf8_are_written_lossily() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let heartbeat = Heartbeat {
+            entity: PathBuf::from(OsStr::from_bytes(b"/home/alice/\xff.rs")),
+            project_folder: Some(PathBuf::from(OsStr::from_bytes(b"/home/\xff"))),
+            ..Default::default()
+        };
+
+        let extra = heartbeat.to_extra_json();
+        assert_eq!(extra["entity"], "/home/alice/\u{fffd}.rs");
+        assert_eq!(extra["project_folder"], "/home/\u{fffd}");
+    }
+
+    #[test]
+    fn batches_are_ordered_by_time_without_repeats() {
+        let mut heartbeats = vec![
+            json!({"entity": "b.rs", "time": 20.0}),
+            json!({"entity": "a.rs", "time": 10.0}),
+            json!({"entity": "c.rs", "time": 10.0}),
+            json!({"entity": "a.rs", "time": 10.0}),
+        ];
+
+        assert_eq!(order_batch(&mut heartbeats), 1);
+        assert_eq!(
+            heartbeats,
+            [
+                json!({"entity": "a.rs", "time": 10.0}),
+                json!({"entity": "c.rs", "time": 10.001}),
+                json!({"entity": "b.rs", "time": 20.0}),
+            ]
+        );
+    }
+}
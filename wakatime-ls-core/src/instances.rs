@@ -0,0 +1,152 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+
+// Heartbeats older than this are forgotten when the registry is written.
+const FORGET_AFTER: Duration = Duration::from_secs(60 * 60);
+
+static NEXT_INSTANCE: AtomicU64 = AtomicU64::new(0);
+
+// The last heartbeat of each file sent by any language server on this
+// machine, so that a file tracked by two Zed windows, each with its own
+// wakatime-ls, isn't sent by both within the heartbeat interval. Every
+// server reads and writes it under a lock on the file.
+//
+// Example:
+// {"entities":{"/home/alice/src/app/main.rs":{"instance":"4242-0","time":1792335939}}}
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct Registry {
+    entities: BTreeMap<String, Sent>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Sent {
+    instance: String,
+    // Seconds since the epoch.
+    time: i64,
+}
+
+pub fn path() -> Option<PathBuf> {
+    Some(
+        config::wakatime_home()?
+            .join(".wakatime")
+            .join("zed-wakatime-instances.json"),
+    )
+}
+
+// A name for a language server, unique on this machine while it runs, even
+// with several of them in one process.
+//
+// Example:
+// 4242-0
+pub fn instance() -> String {
+    format!(
+        "{}-{}",
+        process::id(),
+        NEXT_INSTANCE.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+// Record that `instance` sends a heartbeat for `entity` at `time`, unless
+// another one sent one less than `interval` before, in which case it returns
+// false and nothing changes. A zero interval always records it, for
+// heartbeats that are sent anyway. This blocks while another server holds
+// the lock.
+pub fn claim(
+    path: &Path,
+    instance: &str,
+    entity: &Path,
+    time: i64,
+    interval: Duration,
+) -> io::Result<bool> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    file.lock()?;
+
+    let mut content = String::new();
+    file.read_to_string(&mut content)?;
+    let mut registry: Registry = serde_json::from_str(&content).unwrap_or_default();
+
+    let entity = entity.to_string_lossy().into_owned();
+    // Either way, so a clock set back doesn't hold heartbeats back longer.
+    let within = |sent: &Sent, limit: Duration| (time - sent.time).unsigned_abs() < limit.as_secs();
+    if let Some(sent) = registry.entities.get(&entity) {
+        if sent.instance != instance && within(sent, interval) {
+            return Ok(false);
+        }
+    }
+
+    registry
+        .entities
+        .retain(|_, sent| within(sent, FORGET_AFTER));
+    registry.entities.insert(
+        entity,
+        Sent {
+            instance: instance.to_string(),
+            time,
+        },
+    );
+
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(serde_json::to_string(&registry)?.as_bytes())?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    const INTERVAL: Duration = Duration::from_secs(120);
+
+    fn registry(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!(
+            "wakatime-ls-instances-{}-{name}.json",
+            process::id()
+        ));
+        fs::remove_file(&path).ok();
+        path
+    }
+
+    #[test]
+    fn another_instance_waits_for_the_interval() {
+        let path = registry("interval");
+        let entity = Path::new("/home/alice/src/app/main.rs");
+
+        assert!(claim(&path, "1-0", entity, 1000, INTERVAL).unwrap());
+        assert!(!claim(&path, "2-0", entity, 1090, INTERVAL).unwrap());
+        assert!(claim(&path, "1-0", entity, 1090, INTERVAL).unwrap());
+        assert!(claim(&path, "2-0", entity, 1220, INTERVAL).unwrap());
+    }
+
+    #[test]
+    fn other_files_and_forced_heartbeats_are_not_held_back() {
+        let path = registry("forced");
+        let (main, lib) = (Path::new("/src/main.rs"), Path::new("/src/lib.rs"));
+
+        assert!(claim(&path, "1-0", main, 1000, INTERVAL).unwrap());
+        assert!(claim(&path, "2-0", lib, 1010, INTERVAL).unwrap());
+        assert!(claim(&path, "2-0", main, 1020, Duration::ZERO).unwrap());
+        assert!(!claim(&path, "1-0", main, 1030, INTERVAL).unwrap());
+    }
+}
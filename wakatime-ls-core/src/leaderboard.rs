@@ -0,0 +1,107 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::api::Api;
+
+// How long a page of the leaderboard is reused, as it changes slowly.
+const CACHE_TTL: Duration = Duration::from_secs(5 * 60);
+
+// How many ranks above and below the user's are returned.
+const NEARBY: u64 = 2;
+
+// The user's rank on the leaderboard of the last 7 days, with the
+// competitors right above and below it, from the `leaders` endpoint of
+// Hackatime and WakaTime.
+//
+// Example:
+// #41 of the last 7 days: #39 ada (21 hrs 3 mins), #40 grace, #41 you, #42 linus, #43 ken
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Leaderboard {
+    // None when the user isn't ranked, e.g. without coding time this week.
+    pub rank: Option<u64>,
+    pub range: Option<String>,
+    pub nearby: Vec<Leader>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Leader {
+    pub rank: u64,
+    pub name: String,
+    pub total_seconds: u64,
+    pub total_text: String,
+    pub is_current_user: bool,
+}
+
+impl Leader {
+    fn parse(leader: &Value, current_rank: u64) -> Option<Self> {
+        let rank = leader["rank"].as_u64()?;
+        let user = &leader["user"];
+        let running_total = &leader["running_total"];
+
+        Some(Self {
+            rank,
+            name: user["display_name"]
+                .as_str()
+                .or_else(|| user["username"].as_str())
+                .unwrap_or("anonymous")
+                .to_string(),
+            total_seconds: running_total["total_seconds"].as_f64().unwrap_or(0.0) as u64,
+            total_text: running_total["human_readable_total"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+            is_current_user: rank == current_rank,
+        })
+    }
+}
+
+// Needs an API key, both to be allowed to read the leaderboard and to know
+// who the user is. Nearby competitors come from the page the user is on, so
+// a rank at the edge of a page has fewer of them.
+pub async fn fetch(api: &Api) -> Result<Leaderboard, String> {
+    if api.key.is_none() {
+        return Err("no API key to ask for the leaderboard".to_string());
+    }
+
+    let body = get(api, "leaders").await?;
+    let current_user = &body["current_user"];
+    let range = body["range"]["text"].as_str().map(str::to_string);
+
+    let Some(rank) = current_user["rank"].as_u64() else {
+        return Ok(Leaderboard {
+            rank: None,
+            range,
+            nearby: Vec::new(),
+        });
+    };
+
+    let body = match current_user["page"].as_u64() {
+        Some(page) if page > 1 => get(api, &format!("leaders?page={page}")).await?,
+        _ => body,
+    };
+
+    let nearby = body["data"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|leader| Leader::parse(leader, rank))
+        .filter(|leader| leader.rank.abs_diff(rank) <= NEARBY)
+        .collect();
+
+    Ok(Leaderboard {
+        rank: Some(rank),
+        range,
+        nearby,
+    })
+}
+
+async fn get(api: &Api, path: &str) -> Result<Value, String> {
+    let (status, body) = api.get_cached(path, CACHE_TTL).await?;
+    if !(200..300).contains(&status) {
+        return Err(format!("{} answered HTTP {status}", api.url));
+    }
+
+    Ok(body)
+}
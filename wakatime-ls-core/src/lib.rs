@@ -0,0 +1,39 @@
+// What the language server is made of besides LSP: the editor events and when
+// they become heartbeats, sending them with wakatime-cli, project detection,
+// the WakaTime config and API, and the records kept on this machine. The
+// `wakatime-ls` binary wires it to tower-lsp; other editors' integrations can
+// use it the same way. Without the default `http` feature, nothing asks the
+// API or downloads releases, and stats come from wakatime-cli alone.
+pub mod activity;
+pub mod api;
+pub mod breakdown;
+pub mod budget;
+pub mod cli;
+pub mod clock;
+pub mod config;
+pub mod counters;
+#[cfg(feature = "http")]
+pub mod durations;
+pub mod event;
+#[cfg(feature = "http")]
+pub mod goals;
+pub mod heartbeat;
+pub mod instances;
+#[cfg(feature = "http")]
+pub mod leaderboard;
+pub mod lines;
+pub mod memory;
+pub mod metrics;
+pub mod paths;
+pub mod profile;
+pub mod project;
+pub mod queue;
+pub mod recent;
+pub mod redact;
+pub mod session;
+pub mod sink;
+pub mod skew;
+pub mod spool;
+pub mod streak;
+pub mod today;
+pub mod weekly;
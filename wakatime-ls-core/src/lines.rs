@@ -0,0 +1,191 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::memory;
+
+// Larger files aren't read just to count their lines; wakatime-cli is left to
+// decide about them.
+pub const MAX_SIZE: u64 = 2 * 1024 * 1024;
+
+// How much of a file is looked at for a NUL byte to tell it is binary, as
+// git does, such as an image or a compiled asset whose "lines" mean nothing.
+const BINARY_CHECK_SIZE: usize = 8000;
+
+// The line counts of files, kept until the size or modification time of the
+// file changes, so that the heartbeats of a file being read but not edited
+// don't read all of it again. Counting reads the file, so it must not run on
+// an async worker thread.
+#[derive(Debug)]
+pub struct LineCounts {
+    max_size: u64,
+    counts: Mutex<HashMap<PathBuf, Counted>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Counted {
+    modified: SystemTime,
+    size: u64,
+    // None for a binary file.
+    lines: Option<u64>,
+    // When the count was last asked for.
+    used: Instant,
+}
+
+impl Default for LineCounts {
+    fn default() -> Self {
+        Self::new(MAX_SIZE)
+    }
+}
+
+impl LineCounts {
+    pub fn new(max_size: u64) -> Self {
+        Self {
+            max_size,
+            counts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // None for a file that can't be read, is larger than the maximum size or is
+    // binary.
+    pub fn count(&self, path: &Path) -> Option<u64> {
+        let metadata = fs::metadata(path).ok()?;
+        let (modified, size) = (metadata.modified().ok()?, metadata.len());
+        if size > self.max_size {
+            return None;
+        }
+
+        if let Some(counted) = self
+            .counts
+            .lock()
+            .ok()?
+            .get_mut(path)
+            .filter(|counted| (counted.modified, counted.size) == (modified, size))
+        {
+            counted.used = Instant::now();
+            return counted.lines;
+        }
+
+        let content = fs::read(path).ok()?;
+        let lines = (!is_binary(&content)).then(|| count_lines(&content));
+        self.counts.lock().ok()?.insert(
+            path.to_path_buf(),
+            Counted {
+                modified,
+                size,
+                lines,
+                used: Instant::now(),
+            },
+        );
+        lines
+    }
+
+    pub fn approximate_size(&self) -> usize {
+        self.counts.lock().map_or(0, |counts| {
+            counts
+                .keys()
+                .map(|path| memory::entry_size::<Counted>(path))
+                .sum()
+        })
+    }
+
+    pub fn clear(&self) {
+        if let Ok(mut counts) = self.counts.lock() {
+            counts.clear();
+        }
+    }
+
+    // Forget the counts of files without a heartbeat for `max_age`,
+    // returning how many.
+    pub fn prune(&self, max_age: Duration) -> usize {
+        let Ok(mut counts) = self.counts.lock() else {
+            return 0;
+        };
+
+        let count = counts.len();
+        counts.retain(|_, counted| counted.used.elapsed() <= max_age);
+        count - counts.len()
+    }
+}
+
+// Like `str::lines`, a last line without a line break counts too.
+fn count_lines(content: &[u8]) -> u64 {
+    let breaks = content.iter().filter(|&&byte| byte == b'\n').count() as u64;
+    match content.last() {
+        Some(b'\n') | None => breaks,
+        Some(_) => breaks + 1,
+    }
+}
+
+fn is_binary(content: &[u8]) -> bool {
+    content[..content.len().min(BINARY_CHECK_SIZE)].contains(&0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    fn file(name: &str, content: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("wakatime-ls-lines-{}-{name}", std::process::id()));
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn counts_like_str_lines() {
+        for content in ["", "a", "a\n", "a\nb", "a\r\nb\r\n", "\n\n"] {
+            assert_eq!(
+                count_lines(content.as_bytes()),
+                content.lines().count() as u64,
+                "{content:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn counted_again_once_changed() {
+        let path = file("changed", "a\nb\n");
+        let counts = LineCounts::default();
+        assert_eq!(counts.count(&path), Some(2));
+
+        fs::write(&path, "a\nb\nc\n").unwrap();
+        assert_eq!(counts.count(&path), Some(3));
+    }
+
+    #[test]
+    fn large_and_missing_files_are_not_counted() {
+        let path = file("large", "a\nb\nc\n");
+        let counts = LineCounts::new(4);
+
+        assert_eq!(counts.count(&path), None);
+        assert_eq!(counts.count(&path.with_extension("missing")), None);
+    }
+
+    #[test]
+    fn binary_files_are_not_counted() {
+        let path = file("binary", "\u{89}PNG\r\n\u{1a}\n\0\0\0\rIHDR\n");
+        let counts = LineCounts::default();
+
+        assert_eq!(counts.count(&path), None);
+        assert_eq!(counts.count(&path), None);
+        assert_eq!(counts.count(&file("text", "a\nb\n")), Some(2));
+    }
+
+    #[test]
+    fn unused_counts_are_pruned() {
+        let path = file("pruned", "a\n");
+        let counts = LineCounts::default();
+        counts.count(&path);
+
+        assert_eq!(counts.prune(Duration::from_secs(60)), 0);
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(counts.prune(Duration::from_millis(1)), 1);
+        assert_eq!(counts.approximate_size(), 0);
+    }
+}
@@ -0,0 +1,38 @@
+use std::{mem, path::Path};
+
+use serde::Serialize;
+
+// Unless the `max_cache_memory` setting says otherwise.
+pub const DEFAULT_LIMIT: usize = 16 * 1024 * 1024;
+
+// Roughly how many bytes the server's caches and its queue of editor events
+// take, counting their entries and the paths and names in them but not what
+// the allocator adds. Only the caches count towards `limit`; the queue has a
+// length limit of its own.
+//
+// Example, in the `wakatime/status` request:
+// {"file_cache":18432,"line_counts":9216,"project_cache":40960,"events":0,"limit":16777216}
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct MemoryUsage {
+    pub file_cache: usize,
+    pub line_counts: usize,
+    pub project_cache: usize,
+    pub events: usize,
+    // None without a limit.
+    pub limit: Option<usize>,
+}
+
+impl MemoryUsage {
+    pub fn caches(&self) -> usize {
+        self.file_cache + self.line_counts + self.project_cache
+    }
+
+    pub fn is_over_limit(&self) -> bool {
+        self.limit.is_some_and(|limit| self.caches() > limit)
+    }
+}
+
+// An entry of a map keyed by `path`, holding a `T`.
+pub fn entry_size<T>(path: &Path) -> usize {
+    mem::size_of::<T>() + path.as_os_str().len()
+}
@@ -0,0 +1,350 @@
+#[cfg(feature = "metrics")]
+use std::io;
+use std::{
+    fmt::Write as _,
+    net::{SocketAddr, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        LazyLock, Mutex,
+    },
+    time::Duration,
+};
+
+#[cfg(feature = "metrics")]
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+};
+
+use crate::{
+    counters::{Counts, HeartbeatCounters},
+    heartbeat::Outcome,
+    queue::Pushed,
+};
+
+// Upper bounds of the latency histograms, in seconds. wakatime-cli takes
+// around a second to start, send and exit.
+const BUCKETS: [f64; 9] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+// Longer requests than this aren't for `/metrics`.
+#[cfg(feature = "metrics")]
+const MAX_REQUEST: usize = 8 * 1024;
+
+// How often the counters are sent with `--statsd`, as a StatsD agent
+// aggregates them over its own flush interval of 10 seconds by default.
+#[cfg(feature = "metrics")]
+const STATSD_INTERVAL: Duration = Duration::from_secs(10);
+
+// The metrics of every server in this process, served with `--metrics-listen`
+// for Prometheus to scrape or sent to a StatsD agent with `--statsd`. Without
+// the `metrics` feature they are only counted, and `render` is all there is
+// to read them.
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::default);
+
+#[derive(Default)]
+struct Metrics {
+    enabled: AtomicBool,
+    heartbeats: HeartbeatCounters,
+    events_coalesced: AtomicU64,
+    events_dropped: AtomicU64,
+    // Unknown until wakatime-cli was asked.
+    queue_depth: Mutex<Option<u64>>,
+    cli_duration: Histogram,
+    api_duration: Histogram,
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [AtomicU64; BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        for (bucket, &bound) in self.buckets.iter().zip(BUCKETS.iter()) {
+            if seconds <= bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        writeln!(out, "# HELP {name} {help}").ok();
+        writeln!(out, "# TYPE {name} histogram").ok();
+
+        for (bucket, bound) in self.buckets.iter().zip(BUCKETS.iter()) {
+            let count = bucket.load(Ordering::Relaxed);
+            writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {count}").ok();
+        }
+
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {count}").ok();
+        writeln!(out, "{name}_sum {sum}").ok();
+        writeln!(out, "{name}_count {count}").ok();
+    }
+}
+
+pub fn is_enabled() -> bool {
+    METRICS.enabled.load(Ordering::Relaxed)
+}
+
+pub fn count_heartbeat(outcome: Outcome) {
+    METRICS.heartbeats.count(outcome);
+}
+
+pub fn count_heartbeats(outcome: Outcome, heartbeats: u64) {
+    METRICS.heartbeats.add(outcome, heartbeats);
+}
+
+pub fn count_pushed(pushed: Pushed) {
+    let counter = match pushed {
+        Pushed::Queued => return,
+        Pushed::Coalesced => &METRICS.events_coalesced,
+        Pushed::DroppedOldest => &METRICS.events_dropped,
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn observe_cli(duration: Duration) {
+    METRICS.cli_duration.observe(duration);
+}
+
+pub fn observe_api(duration: Duration) {
+    METRICS.api_duration.observe(duration);
+}
+
+pub fn set_queue_depth(depth: u64) {
+    if let Ok(mut queue_depth) = METRICS.queue_depth.lock() {
+        *queue_depth = Some(depth);
+    }
+}
+
+// The value of `--metrics-listen` or `--statsd`. Host names such as `localhost` are
+// resolved once, at startup.
+//
+// Example:
+// 127.0.0.1:9464
+pub fn parse_address(value: &str) -> Result<SocketAddr, String> {
+    value
+        .to_socket_addrs()
+        .map_err(|err| format!("invalid address {value}: {err}"))?
+        .next()
+        .ok_or_else(|| format!("{value} resolves to no address"))
+}
+
+// Example:
+// # HELP wakatime_ls_heartbeats_total Editor events by what became of them.
+// # TYPE wakatime_ls_heartbeats_total counter
+// wakatime_ls_heartbeats_total{outcome="sent"} 42
+pub fn render() -> String {
+    let mut out = String::new();
+
+    out.push_str("# HELP wakatime_ls_heartbeats_total Editor events by what became of them.\n");
+    out.push_str("# TYPE wakatime_ls_heartbeats_total counter\n");
+    for (outcome, count) in by_outcome(METRICS.heartbeats.counts()) {
+        writeln!(
+            out,
+            "wakatime_ls_heartbeats_total{{outcome=\"{}\"}} {count}",
+            outcome.as_str()
+        )
+        .ok();
+    }
+
+    out.push_str("# HELP wakatime_ls_events_discarded_total Editor events discarded while waiting to be sent.\n");
+    out.push_str("# TYPE wakatime_ls_events_discarded_total counter\n");
+    for (reason, counter) in [
+        ("coalesced", &METRICS.events_coalesced),
+        ("dropped", &METRICS.events_dropped),
+    ] {
+        writeln!(
+            out,
+            "wakatime_ls_events_discarded_total{{reason=\"{reason}\"}} {}",
+            counter.load(Ordering::Relaxed)
+        )
+        .ok();
+    }
+
+    if let Some(depth) = queue_depth() {
+        out.push_str(
+            "# HELP wakatime_ls_queue_depth Heartbeats in the offline queue of wakatime-cli.\n",
+        );
+        out.push_str("# TYPE wakatime_ls_queue_depth gauge\n");
+        writeln!(out, "wakatime_ls_queue_depth {depth}").ok();
+    }
+
+    METRICS.cli_duration.render(
+        &mut out,
+        "wakatime_ls_cli_duration_seconds",
+        "How long wakatime-cli took to send a heartbeat.",
+    );
+    METRICS.api_duration.render(
+        &mut out,
+        "wakatime_ls_api_duration_seconds",
+        "How long requests to the WakaTime API took.",
+    );
+
+    out
+}
+
+fn by_outcome(counts: Counts) -> [(Outcome, u64); 8] {
+    [
+        (Outcome::Sent, counts.sent),
+        (Outcome::Skipped, counts.skipped),
+        (Outcome::Ignored, counts.ignored),
+        (Outcome::Dropped, counts.dropped),
+        (Outcome::Failed, counts.failed),
+        (Outcome::Queued, counts.queued),
+        (Outcome::Throttled, counts.throttled),
+        (Outcome::Evicted, counts.evicted),
+    ]
+}
+
+fn queue_depth() -> Option<u64> {
+    METRICS.queue_depth.lock().ok().and_then(|depth| *depth)
+}
+
+// Serve `/metrics` on `address` until the process is stopped. Only the
+// binding can fail; a connection that goes wrong is dropped.
+#[cfg(feature = "metrics")]
+pub async fn listen(address: SocketAddr) -> io::Result<()> {
+    let listener = TcpListener::bind(address).await?;
+    METRICS.enabled.store(true, Ordering::Relaxed);
+    eprintln!("wakatime-ls: serving metrics on http://{address}/metrics");
+
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                respond(stream).await.ok();
+            });
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(feature = "metrics")]
+async fn respond(mut stream: TcpStream) -> io::Result<()> {
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 || request.len() + read > MAX_REQUEST {
+            return Ok(());
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or_default().split(' ');
+    let (method, target) = (request_line.next(), request_line.next());
+    let path = target.map(|target| target.split('?').next().unwrap_or(target));
+
+    let (status, body) = match (method, path) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render()),
+        (Some("GET"), _) => ("404 Not Found", "not found\n".to_string()),
+        _ => ("405 Method Not Allowed", "method not allowed\n".to_string()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+// Send the metrics to a StatsD or DogStatsD agent on `address` every
+// `STATSD_INTERVAL` until the process is stopped. Only the binding can fail:
+// nothing is told about datagrams that no agent received.
+#[cfg(feature = "metrics")]
+pub async fn statsd(address: SocketAddr) -> io::Result<()> {
+    let local = if address.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(address).await?;
+    METRICS.enabled.store(true, Ordering::Relaxed);
+    eprintln!("wakatime-ls: sending metrics to statsd on {address}");
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(STATSD_INTERVAL);
+        let mut previous = Counts::default();
+
+        loop {
+            interval.tick().await;
+
+            let counts = METRICS.heartbeats.counts();
+            let payload = statsd_payload(previous, counts, queue_depth());
+            previous = counts;
+            if !payload.is_empty() {
+                socket.send(payload.as_bytes()).await.ok();
+            }
+        }
+    });
+
+    Ok(())
+}
+
+// The heartbeats of each outcome since the `previous` counts, leaving out
+// those without any, and the depth of the offline queue once known.
+//
+// Example:
+// wakatime_ls.heartbeats.sent:3|c
+// wakatime_ls.heartbeats.skipped:41|c
+// wakatime_ls.queue.depth:0|g
+#[cfg(feature = "metrics")]
+fn statsd_payload(previous: Counts, counts: Counts, queue_depth: Option<u64>) -> String {
+    let mut out = String::new();
+
+    for ((outcome, count), (_, before)) in by_outcome(counts).into_iter().zip(by_outcome(previous))
+    {
+        if count > before {
+            writeln!(
+                out,
+                "wakatime_ls.heartbeats.{}:{}|c",
+                outcome.as_str(),
+                count - before
+            )
+            .ok();
+        }
+    }
+    if let Some(depth) = queue_depth {
+        writeln!(out, "wakatime_ls.queue.depth:{depth}|g").ok();
+    }
+
+    out
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn statsd_counts_what_changed_since_the_last_time() {
+        let previous = Counts {
+            sent: 2,
+            skipped: 10,
+            ..Default::default()
+        };
+        let counts = Counts {
+            sent: 5,
+            skipped: 10,
+            failed: 1,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            statsd_payload(previous, counts, Some(4)),
+            "wakatime_ls.heartbeats.sent:3|c\nwakatime_ls.heartbeats.failed:1|c\nwakatime_ls.queue.depth:4|g\n"
+        );
+        assert_eq!(statsd_payload(counts, counts, None), "");
+    }
+}
@@ -0,0 +1,555 @@
+use std::{
+    borrow::Cow,
+    env,
+    ffi::{OsStr, OsString},
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Component, Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+};
+
+use regex::Regex;
+
+use crate::config;
+
+// Rewrites entities on a remote development host to a form that is the same
+// on every machine, so a project opened over SSH and locally isn't split into
+// host-specific file entries. Files reached across the WSL boundary are
+// reported at their native location. wakatime-cli still reads the real file,
+// which is passed as `--local-file`.
+#[derive(Debug, Default, Clone)]
+pub struct PathMapper {
+    // Remote path prefixes and their replacement, checked in order.
+    mappings: Vec<(String, String)>,
+    // Regexes replaced in the entity once mapped, in order, each in what the
+    // previous one left.
+    rewrites: Vec<(Regex, String)>,
+    // Report entities inside a workspace folder relative to it, prefixed with
+    // the folder name.
+    relative_to_folders: bool,
+}
+
+impl PathMapper {
+    // Without an explicit setting, folder-relative entities are used when the
+    // server runs in an SSH session, which is how Zed reaches remote projects.
+    pub fn new(mappings: Vec<(String, String)>, relative_to_folders: Option<bool>) -> Self {
+        Self {
+            mappings,
+            rewrites: Vec::new(),
+            relative_to_folders: relative_to_folders.unwrap_or_else(is_remote_session),
+        }
+    }
+
+    // The same, with regex rewrites of the `entity_rewrite` setting, along
+    // with the patterns that are not valid regular expressions. `$1`,
+    // `${name}`, ... in a replacement are its capture groups.
+    pub fn with_rewrites<'a>(
+        mut self,
+        rules: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> (Self, Vec<regex::Error>) {
+        let mut errors = Vec::new();
+
+        for (pattern, replacement) in rules {
+            match Regex::new(pattern) {
+                Ok(regex) => self.rewrites.push((regex, replacement.to_string())),
+                Err(err) => errors.push(err),
+            }
+        }
+
+        (self, errors)
+    }
+
+    // Returns the entity to report, or `None` when it is sent unchanged.
+    //
+    // Example, with `/home/alice/src` mapped to `~/src`:
+    // /home/alice/src/app/main.rs -> ~/src/app/main.rs
+    //
+    // Example, relative to the workspace folder `/srv/checkouts/app`:
+    // /srv/checkouts/app/src/main.rs -> app/src/main.rs
+    //
+    // Example, with `^/var/lib/docker/volumes/[^/]+/_data/` rewritten to `~/src/`:
+    // /var/lib/docker/volumes/app/_data/main.rs -> ~/src/main.rs
+    pub fn map(&self, entity: &Path, folders: &[&Path]) -> Option<PathBuf> {
+        let mapped = self.map_prefixes(entity, folders);
+        self.rewrite(mapped.as_deref().unwrap_or(entity)).or(mapped)
+    }
+
+    fn map_prefixes(&self, entity: &Path, folders: &[&Path]) -> Option<PathBuf> {
+        let native = entity.to_str().and_then(wsl_native_path).map(PathBuf::from);
+
+        for (prefix, replacement) in &self.mappings {
+            let rest = entity
+                .strip_prefix(prefix)
+                .ok()
+                .or_else(|| native.as_deref()?.strip_prefix(prefix).ok());
+
+            if let Some(rest) = rest {
+                // Keep the separator style of the replacement.
+                let separator = if replacement.contains('\\') && !replacement.contains('/') {
+                    "\\"
+                } else {
+                    "/"
+                };
+
+                return Some(join(replacement.as_ref(), rest, separator));
+            }
+        }
+
+        if !self.relative_to_folders {
+            return native;
+        }
+
+        let relative = folders
+            .iter()
+            .filter_map(|folder| Some((folder, entity.strip_prefix(folder).ok()?)))
+            .max_by_key(|(folder, _)| folder.components().count())
+            .and_then(|(folder, rest)| Some(join(folder.file_name()?, rest, "/")));
+
+        relative.or(native)
+    }
+
+    // Paths that aren't valid Unicode can't be matched, and a rewrite that
+    // leaves nothing is not used.
+    fn rewrite(&self, entity: &Path) -> Option<PathBuf> {
+        if self.rewrites.is_empty() {
+            return None;
+        }
+        let original = entity.to_str()?;
+
+        let mut rewritten = Cow::Borrowed(original);
+        for (regex, replacement) in &self.rewrites {
+            if let Cow::Owned(replaced) = regex.replace(&rewritten, replacement.as_str()) {
+                rewritten = Cow::Owned(replaced);
+            }
+        }
+
+        match rewritten {
+            Cow::Owned(rewritten) if !rewritten.is_empty() && rewritten != original => {
+                Some(PathBuf::from(rewritten))
+            }
+            _ => None,
+        }
+    }
+}
+
+// Join path components with a fixed separator without converting them to
+// UTF-8.
+fn join(base: &OsStr, rest: &Path, separator: &str) -> PathBuf {
+    let mut joined = OsString::from(base);
+
+    for component in rest.components() {
+        if !joined.is_empty() && !joined.to_string_lossy().ends_with(['/', '\\']) {
+            joined.push(separator);
+        }
+        joined.push(component.as_os_str());
+    }
+
+    PathBuf::from(joined)
+}
+
+// Normalize an entity path so the same file always produces the same path,
+// whichever form the editor or the filesystem handed us. Every entity goes
+// through this before it is cached, matched or reported.
+//
+// Paths that aren't valid Unicode can't be one of the Windows spellings below,
+// so they are kept as they are.
+pub fn normalize(path: PathBuf) -> PathBuf {
+    if !cfg!(windows) {
+        return path;
+    }
+
+    match path.to_str() {
+        Some(path) => PathBuf::from(normalize_windows(path)),
+        None => path,
+    }
+}
+
+// Write a file only its owner may read, such as one holding the API key or
+// the paths of every file worked on. It is written next to the file and
+// renamed over it, so a crash or another program reading it never sees it
+// half-written, and a symlink to it, such as a config kept with dotfiles,
+// still points at it. Windows files keep the permissions of their folder.
+pub fn write_private(path: &Path, content: &[u8]) -> io::Result<()> {
+    let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let temporary = write_temporary(&path, content)?;
+
+    let renamed = fs::rename(&temporary, &path);
+    if renamed.is_err() {
+        fs::remove_file(&temporary).ok();
+    }
+    renamed
+}
+
+// Like `write_private`, for a file that must not exist yet, such as an export
+// to a path chosen by a client, which fails rather than replace a file.
+pub fn create_private(path: &Path, content: &[u8]) -> io::Result<()> {
+    let temporary = write_temporary(path, content)?;
+
+    let linked = fs::hard_link(&temporary, path);
+    fs::remove_file(&temporary).ok();
+    linked
+}
+
+// Write `content` to a new file next to `path` that only its owner may read,
+// returning where.
+fn write_temporary(path: &Path, content: &[u8]) -> io::Result<PathBuf> {
+    static WRITES: AtomicU64 = AtomicU64::new(0);
+
+    let Some(name) = path.file_name() else {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "not a file"));
+    };
+    let mut temporary = OsString::from(".");
+    temporary.push(name);
+    temporary.push(format!(
+        ".{}-{}.tmp",
+        process::id(),
+        WRITES.fetch_add(1, Ordering::Relaxed)
+    ));
+    let temporary = path.with_file_name(temporary);
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let written = options.open(&temporary).and_then(|mut file| {
+        file.write_all(content)?;
+        file.sync_all()
+    });
+    match written {
+        Ok(()) => Ok(temporary),
+        Err(err) => {
+            fs::remove_file(&temporary).ok();
+            Err(err)
+        }
+    }
+}
+
+// Resolve symlinks and a leading `~`, so a project opened through a symlinked
+// path and through its real path is reported as the same files. Paths that
+// don't exist on disk are only expanded.
+pub fn canonicalize(path: &Path) -> PathBuf {
+    let expanded = expand_tilde(path);
+
+    match fs::canonicalize(&expanded) {
+        Ok(canonical) => normalize(canonical),
+        Err(_) => expanded,
+    }
+}
+
+fn expand_tilde(path: &Path) -> PathBuf {
+    let mut components = path.components();
+    if components.next() != Some(Component::Normal("~".as_ref())) {
+        return path.to_path_buf();
+    }
+
+    match config::home_dir() {
+        Some(home) => normalize(home.join(components.as_path())),
+        None => path.to_path_buf(),
+    }
+}
+
+// Windows paths come in many spellings of the same file: verbatim prefixes
+// from `canonicalize`, lowercase drive letters from some URIs, forward slashes
+// and doubled separators.
+//
+// Example:
+// \\?\C:\Users\alice\main.rs          -> C:\Users\alice\main.rs
+// \\?\UNC\server\share\main.rs        -> \\server\share\main.rs
+// /c:/Users/alice//main.rs            -> C:\Users\alice\main.rs
+// //server/share/main.rs              -> \\server\share\main.rs
+fn normalize_windows(path: &str) -> String {
+    let path = path.replace('/', "\\");
+
+    let (prefix, rest) = if let Some(rest) = strip_prefix_ignore_case(&path, "\\\\?\\UNC\\") {
+        ("\\\\".to_string(), rest)
+    } else if let Some(rest) = path.strip_prefix("\\\\?\\") {
+        split_drive(rest).unwrap_or_else(|| ("\\\\?\\".to_string(), rest))
+    } else if let Some(rest) = path.strip_prefix("\\\\") {
+        ("\\\\".to_string(), rest)
+    } else if let Some(split) = split_drive(path.trim_start_matches('\\')) {
+        split
+    } else {
+        (String::new(), path.as_str())
+    };
+
+    let mut normalized = prefix;
+    let mut previous_separator = normalized.ends_with('\\');
+
+    for c in rest.chars() {
+        if c == '\\' && previous_separator {
+            continue;
+        }
+
+        previous_separator = c == '\\';
+        normalized.push(c);
+    }
+
+    normalized
+}
+
+// Split `c:\rest` into an uppercase drive prefix and the rest.
+fn split_drive(path: &str) -> Option<(String, &str)> {
+    match path.as_bytes() {
+        [letter, b':', ..] if letter.is_ascii_alphabetic() => Some((
+            format!("{}:", letter.to_ascii_uppercase() as char),
+            &path[2..],
+        )),
+        _ => None,
+    }
+}
+
+// Translate a path that crosses the WSL boundary to where the file actually
+// lives, so the same file is reported and matched identically from Windows
+// and from inside WSL. Returns `None` for paths that don't cross it.
+//
+// Example:
+// \\wsl$\Ubuntu\home\alice\app\main.rs      -> /home/alice/app/main.rs
+// \\wsl.localhost\Ubuntu\home\alice\main.rs -> /home/alice/main.rs
+// /mnt/c/Users/alice/app/main.rs (in WSL)   -> C:/Users/alice/app/main.rs
+pub fn wsl_native_path(path: &str) -> Option<String> {
+    let slashed = path.replace('\\', "/");
+
+    for host in ["//wsl$/", "//wsl.localhost/"] {
+        let Some(rest) = strip_prefix_ignore_case(&slashed, host) else {
+            continue;
+        };
+
+        // Skip the distribution name.
+        let rest = rest.split_once('/').map_or("", |(_, rest)| rest);
+        return Some(format!("/{rest}"));
+    }
+
+    if !is_wsl() {
+        return None;
+    }
+
+    let rest = slashed.strip_prefix("/mnt/")?;
+    let (drive, rest) = rest.split_once('/').unwrap_or((rest, ""));
+
+    match drive.as_bytes() {
+        [letter] if letter.is_ascii_alphabetic() => {
+            Some(format!("{}:/{rest}", letter.to_ascii_uppercase() as char))
+        }
+        _ => None,
+    }
+}
+
+// Whether this process runs inside WSL, where `/mnt/<drive>` are the Windows
+// drives.
+fn is_wsl() -> bool {
+    static IS_WSL: OnceLock<bool> = OnceLock::new();
+
+    *IS_WSL.get_or_init(|| {
+        env::var_os("WSL_DISTRO_NAME").is_some()
+            || fs::read_to_string("/proc/sys/kernel/osrelease")
+                .is_ok_and(|release| release.to_lowercase().contains("microsoft"))
+    })
+}
+
+fn strip_prefix_ignore_case<'a>(value: &'a str, prefix: &str) -> Option<&'a str> {
+    let head = value.get(..prefix.len())?;
+    head.eq_ignore_ascii_case(prefix)
+        .then(|| &value[prefix.len()..])
+}
+
+fn is_remote_session() -> bool {
+    env::var_os("SSH_CONNECTION").is_some() || env::var_os("SSH_CLIENT").is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapper(mappings: &[(&str, &str)], relative_to_folders: bool) -> PathMapper {
+        PathMapper::new(
+            mappings
+                .iter()
+                .map(|(prefix, replacement)| (prefix.to_string(), replacement.to_string()))
+                .collect(),
+            Some(relative_to_folders),
+        )
+    }
+
+    #[test]
+    fn windows_spellings_are_normalized() {
+        for (path, normalized) in [
+            (r"\\?\C:\Users\alice\main.rs", r"C:\Users\alice\main.rs"),
+            (r"\\?\UNC\server\share\main.rs", r"\\server\share\main.rs"),
+            (r"\\?\unc\server\share\main.rs", r"\\server\share\main.rs"),
+            ("/c:/Users/alice//main.rs", r"C:\Users\alice\main.rs"),
+            ("//server/share/main.rs", r"\\server\share\main.rs"),
+            (r"d:\src\\app\main.rs", r"D:\src\app\main.rs"),
+            (
+                r"\\?\Volume{0b6f1c3e}\main.rs",
+                r"\\?\Volume{0b6f1c3e}\main.rs",
+            ),
+            (r"src\main.rs", r"src\main.rs"),
+        ] {
+            assert_eq!(normalize_windows(path), normalized, "{path}");
+        }
+    }
+
+    #[test]
+    fn wsl_paths_are_reported_at_their_native_location() {
+        assert_eq!(
+            wsl_native_path(r"\\wsl$\Ubuntu\home\alice\app\main.rs").as_deref(),
+            Some("/home/alice/app/main.rs")
+        );
+        assert_eq!(
+            wsl_native_path(r"\\wsl.localhost\Ubuntu\home\alice\main.rs").as_deref(),
+            Some("/home/alice/main.rs")
+        );
+        assert_eq!(
+            wsl_native_path("//WSL$/Debian/etc/hosts").as_deref(),
+            Some("/etc/hosts")
+        );
+        assert_eq!(wsl_native_path(r"C:\Users\alice\main.rs"), None);
+        assert_eq!(wsl_native_path("/home/alice/main.rs"), None);
+
+        // Windows drives are only under `/mnt` inside WSL.
+        assert_eq!(
+            wsl_native_path("/mnt/c/Users/alice/app/main.rs"),
+            is_wsl().then(|| "C:/Users/alice/app/main.rs".to_string())
+        );
+        assert_eq!(wsl_native_path("/mnt/data/main.rs"), None);
+    }
+
+    #[test]
+    fn prefixes_are_mapped_in_order() {
+        let mapper = mapper(
+            &[
+                ("/home/alice/src", "~/src"),
+                ("/home/alice", "~"),
+                ("/srv", r"D:\srv"),
+            ],
+            false,
+        );
+
+        assert_eq!(
+            mapper.map(Path::new("/home/alice/src/app/main.rs"), &[]),
+            Some(PathBuf::from("~/src/app/main.rs"))
+        );
+        assert_eq!(
+            mapper.map(Path::new("/home/alice/notes.md"), &[]),
+            Some(PathBuf::from("~/notes.md"))
+        );
+        assert_eq!(
+            mapper.map(Path::new("/srv/app/main.rs"), &[]),
+            Some(PathBuf::from(r"D:\srv\app\main.rs"))
+        );
+        assert_eq!(mapper.map(Path::new("/home/alicia/main.rs"), &[]), None);
+        assert_eq!(
+            mapper.map(Path::new(r"\\wsl$\Ubuntu\home\alice\src\main.rs"), &[]),
+            Some(PathBuf::from("~/src/main.rs"))
+        );
+    }
+
+    #[test]
+    fn entities_are_relative_to_the_innermost_folder() {
+        let folders = [Path::new("/srv/checkouts"), Path::new("/srv/checkouts/app")];
+
+        assert_eq!(
+            mapper(&[], true).map(Path::new("/srv/checkouts/app/src/main.rs"), &folders),
+            Some(PathBuf::from("app/src/main.rs"))
+        );
+        assert_eq!(
+            mapper(&[], true).map(Path::new("/srv/checkouts/lib/lib.rs"), &folders),
+            Some(PathBuf::from("checkouts/lib/lib.rs"))
+        );
+        assert_eq!(
+            mapper(&[], true).map(Path::new("/tmp/scratch.rs"), &folders),
+            None
+        );
+        assert_eq!(
+            mapper(&[], false).map(Path::new("/srv/checkouts/app/src/main.rs"), &folders),
+            None
+        );
+    }
+
+    #[test]
+    fn entities_are_rewritten_once_mapped() {
+        let (mapper, errors) = mapper(&[("/home/alice", "~")], false).with_rewrites([
+            ("^/var/lib/docker/volumes/[^/]+/_data/", "~/src/"),
+            (r"^~/(\w+)/", "~/work-$1/"),
+            (r"^/tmp/.*", ""),
+            ("(", "never"),
+        ]);
+        assert_eq!(errors.len(), 1);
+
+        assert_eq!(
+            mapper.map(Path::new("/var/lib/docker/volumes/app/_data/main.rs"), &[]),
+            Some(PathBuf::from("~/work-src/main.rs"))
+        );
+        assert_eq!(
+            mapper.map(Path::new("/home/alice/app/main.rs"), &[]),
+            Some(PathBuf::from("~/work-app/main.rs"))
+        );
+        assert_eq!(
+            mapper.map(Path::new("/home/alice/main.rs"), &[]),
+            Some(PathBuf::from("~/main.rs"))
+        );
+        // A rewrite that leaves nothing is not used.
+        assert_eq!(mapper.map(Path::new("/tmp/scratch.rs"), &[]), None);
+        assert_eq!(mapper.map(Path::new("/etc/hosts"), &[]), None);
+    }
+
+    #[test]
+    fn a_leading_tilde_is_expanded() {
+        let home = config::home_dir().unwrap();
+
+        assert_eq!(
+            expand_tilde(Path::new("~/src/main.rs")),
+            normalize(home.join("src/main.rs"))
+        );
+        assert_eq!(expand_tilde(Path::new("~")), normalize(home));
+        assert_eq!(
+            expand_tilde(Path::new("~alice/main.rs")),
+            Path::new("~alice/main.rs")
+        );
+        assert_eq!(
+            expand_tilde(Path::new("/srv/~/main.rs")),
+            Path::new("/srv/~/main.rs")
+        );
+    }
+
+    #[test]
+    fn private_files_are_replaced_at_once() {
+        let dir = env::temp_dir().join(format!("wakatime-ls-paths-{}", process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(".wakatime.cfg");
+
+        write_private(&path, b"[settings]\napi_key = old\n").unwrap();
+        write_private(&path, b"[settings]\napi_key = new\n").unwrap();
+
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "[settings]\napi_key = new\n"
+        );
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::{symlink, PermissionsExt};
+
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+
+            let link = dir.join("link.cfg");
+            symlink(&path, &link).unwrap();
+            write_private(&link, b"linked\n").unwrap();
+            assert!(fs::symlink_metadata(&link)
+                .unwrap()
+                .file_type()
+                .is_symlink());
+            assert_eq!(fs::read_to_string(&path).unwrap(), "linked\n");
+        }
+    }
+}
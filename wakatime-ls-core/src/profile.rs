@@ -0,0 +1,65 @@
+use std::{fs, path::Path};
+
+use serde_json::Value;
+
+use crate::api::{self, Backend};
+
+// The file at the root of a workspace folder that can pick its profile, so a
+// client's repository keeps its time on the client's account.
+//
+// Example:
+// profile = "acme"
+pub const FILE: &str = ".wakatime.toml";
+
+// The account heartbeats are sent to, one of the `profiles` setting. What's
+// left out falls back to `api-key` and `api-url`.
+//
+// Example:
+// "profiles": {
+//   "acme": {"api-key": "waka_xxx"},
+//   "personal": {"api-key": "hka_xxx", "backend": "hackatime"}
+// }
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Profile {
+    pub api_key: Option<String>,
+    pub api_url: Option<String>,
+}
+
+impl Profile {
+    fn from_options(options: &Value) -> Self {
+        let api_url = match options.get("api-url").and_then(Value::as_str) {
+            Some(api_url) => Some(
+                api::normalize_url(api_url).map_or_else(|_| api_url.to_string(), |(url, _)| url),
+            ),
+            None => options
+                .get("backend")
+                .and_then(Value::as_str)
+                .and_then(Backend::parse)
+                .map(|backend| backend.api_url().to_string()),
+        };
+
+        Self {
+            api_key: options
+                .get("api-key")
+                .and_then(Value::as_str)
+                .map(str::to_string),
+            api_url,
+        }
+    }
+}
+
+// The profiles of the `profiles` setting, in the order they are written.
+pub fn parse_profiles(profiles: &serde_json::Map<String, Value>) -> Vec<(String, Profile)> {
+    profiles
+        .iter()
+        .filter(|(_, options)| options.is_object())
+        .map(|(name, options)| (name.clone(), Profile::from_options(options)))
+        .collect()
+}
+
+// The profile a workspace folder's `.wakatime.toml` picks, if it has one.
+pub fn from_file(root: &Path) -> Option<String> {
+    let content = fs::read_to_string(root.join(FILE)).ok()?;
+    let table: toml::Table = content.parse().ok()?;
+    Some(table.get("profile")?.as_str()?.to_string())
+}
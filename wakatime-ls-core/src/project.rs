@@ -0,0 +1,1389 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    time::{Duration, Instant},
+};
+
+use regex::Regex;
+
+use crate::{config, memory, paths};
+
+// File placed at a repository root to override the detected project.
+const PROJECT_FILE: &str = ".wakatime-project";
+
+// How long a branch lookup is reused. Reading it can mean running `jj` or
+// `svn`, which is too slow to do on every keystroke.
+const BRANCH_TTL: Duration = Duration::from_secs(5);
+
+// Branches whose file the client watches are invalidated when it changes, and
+// only re-read this often in case a change notification is missed.
+const WATCHED_BRANCH_TTL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Project {
+    pub name: String,
+    pub branch: Option<String>,
+    // The folder the project starts at, which entity paths are reported
+    // relative to.
+    pub root: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DetectionOptions {
+    // Attribute files inside a git submodule to the enclosing repository
+    // instead of the submodule itself.
+    pub submodules_disabled: bool,
+    // Name projects in linked git worktrees after the worktree folder instead
+    // of the main repository.
+    pub worktree_project_names: bool,
+    // Split repositories into one project per package, named from the nearest
+    // `Cargo.toml`, `package.json` or `go.mod` below the repository root.
+    pub manifest_projects: bool,
+    // Fall back to the name of the enclosing workspace folder when nothing
+    // else names the project.
+    pub folder_fallback: bool,
+    pub project_map: ProjectMap,
+}
+
+// The `[projectmap]` rules: the first regex matching the entity path names
+// its project, with `{0}`, `{1}`, ... replaced by the capture groups.
+//
+// Example:
+// ~/work/monorepo/services/(.*?)/ = service-{0}
+#[derive(Debug, Clone, Default)]
+pub struct ProjectMap {
+    rules: Vec<(Regex, String)>,
+}
+
+impl ProjectMap {
+    // Compile the given rules, returning the patterns that are not valid
+    // regular expressions alongside the map.
+    pub fn new<'a>(
+        rules: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> (Self, Vec<regex::Error>) {
+        let mut map = Self::default();
+        let mut errors = Vec::new();
+
+        for (pattern, project) in rules {
+            match Regex::new(&expand_home(pattern)) {
+                Ok(regex) => map.rules.push((regex, project.to_string())),
+                Err(err) => errors.push(err),
+            }
+        }
+
+        (map, errors)
+    }
+
+    // Patterns are matched against the native location of files reached
+    // across the WSL boundary, so the same rules work from both sides.
+    fn resolve(&self, entity: &Path) -> Option<String> {
+        let entity = entity.to_string_lossy();
+        let native = paths::wsl_native_path(&entity);
+
+        self.rules.iter().find_map(|(regex, project)| {
+            let captures = native
+                .as_deref()
+                .and_then(|native| regex.captures(native))
+                .or_else(|| regex.captures(&entity))?;
+            let name = captures.iter().skip(1).enumerate().fold(
+                project.clone(),
+                |name, (index, group)| {
+                    let group = group.map_or("", |group| group.as_str());
+                    name.replace(&format!("{{{index}}}"), group)
+                },
+            );
+
+            Some(name.trim().to_string()).filter(|name| !name.is_empty())
+        })
+    }
+}
+
+// Patterns may start with `~` for the home directory, which is escaped since
+// it can contain regex syntax such as Windows path separators.
+fn expand_home(pattern: &str) -> String {
+    match (pattern.strip_prefix('~'), config::home_dir()) {
+        (Some(rest), Some(home)) => {
+            format!("{}{rest}", regex::escape(&home.to_string_lossy()))
+        }
+        _ => pattern.to_string(),
+    }
+}
+
+// The repository an entity belongs to. Branches change while editing, so they
+// are only cached for `BRANCH_TTL`, or until their file changes if the client
+// watches it.
+#[derive(Debug, Clone)]
+enum Repository {
+    Git { name: String, git_dir: PathBuf },
+    Jujutsu { name: String, repo: PathBuf },
+    Mercurial { name: String, hg_dir: PathBuf },
+    Subversion { name: String, working_copy: PathBuf },
+}
+
+impl Repository {
+    fn name(&self) -> &str {
+        match self {
+            Self::Git { name, .. }
+            | Self::Jujutsu { name, .. }
+            | Self::Mercurial { name, .. }
+            | Self::Subversion { name, .. } => name,
+        }
+    }
+
+    // The file recording the current branch, for the repositories that have
+    // one.
+    fn branch_file(&self) -> Option<PathBuf> {
+        match self {
+            Self::Git { git_dir, .. } => Some(git_dir.join("HEAD")),
+            Self::Mercurial { hg_dir, .. } => Some(hg_dir.join("branch")),
+            Self::Jujutsu { .. } | Self::Subversion { .. } => None,
+        }
+    }
+
+    fn branch(&self) -> Option<String> {
+        match self {
+            Self::Git { git_dir, .. } => read_git_branch(git_dir),
+            Self::Jujutsu { repo, .. } => read_jj_branch(repo),
+            Self::Mercurial { hg_dir, .. } => Some(read_hg_branch(hg_dir)),
+            Self::Subversion { working_copy, .. } => {
+                svn_info(working_copy).and_then(|info| info.branch)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    project_file: Option<Project>,
+    manifest: Option<Project>,
+    repository: Option<Repository>,
+    repository_root: Option<PathBuf>,
+    // The last branch read from the repository and when.
+    branch: Option<(Instant, Option<String>)>,
+    // When a heartbeat last looked the folder up.
+    used: Instant,
+}
+
+impl Entry {
+    fn approximate_size(&self, dir: &Path) -> usize {
+        let projects = [&self.project_file, &self.manifest]
+            .into_iter()
+            .flatten()
+            .map(|project| {
+                project.name.len()
+                    + project.branch.as_ref().map_or(0, String::len)
+                    + project
+                        .root
+                        .as_ref()
+                        .map_or(0, |root| root.as_os_str().len())
+            });
+        let branch = match &self.branch {
+            Some((_, Some(branch))) => branch.len(),
+            _ => 0,
+        };
+
+        memory::entry_size::<Entry>(dir)
+            + projects.sum::<usize>()
+            + self
+                .repository_root
+                .as_ref()
+                .map_or(0, |root| root.as_os_str().len())
+            + branch
+    }
+}
+
+// Project lookups keyed by the directory of the entity, so every file in the
+// same folder only walks the filesystem once.
+//
+// Each workspace folder can have its own detection options; entities outside
+// of every folder use the global ones.
+#[derive(Debug, Default)]
+pub struct ProjectCache {
+    options: DetectionOptions,
+    folders: Vec<(PathBuf, DetectionOptions)>,
+    entries: HashMap<PathBuf, Entry>,
+    // Bumped whenever lookups are forgotten, so the work of a `Pending` lookup
+    // started before isn't stored.
+    generation: u64,
+    // Whether the client reports changes to branch and project files inside
+    // the workspace folders, see `ProjectCache::invalidate`.
+    watching: bool,
+}
+
+impl ProjectCache {
+    pub fn configure(
+        &mut self,
+        options: DetectionOptions,
+        mut folders: Vec<(PathBuf, DetectionOptions)>,
+    ) {
+        // Deepest folders first, so nested workspace folders win.
+        folders.sort_by_key(|(root, _)| std::cmp::Reverse(root.components().count()));
+
+        self.options = options;
+        self.folders = folders;
+        self.forget();
+    }
+
+    pub fn approximate_size(&self) -> usize {
+        self.entries
+            .iter()
+            .map(|(dir, entry)| entry.approximate_size(dir))
+            .sum()
+    }
+
+    // Forget every lookup, to be made again for the next heartbeat of each
+    // folder.
+    pub fn forget(&mut self) {
+        self.entries.clear();
+        self.generation += 1;
+    }
+
+    // Forget the lookups of folders without a heartbeat for `max_age`,
+    // returning how many.
+    pub fn prune(&mut self, max_age: Duration) -> usize {
+        let count = self.entries.len();
+        self.entries
+            .retain(|_, entry| entry.used.elapsed() <= max_age);
+        count - self.entries.len()
+    }
+
+    pub fn set_watching(&mut self, watching: bool) {
+        self.watching = watching;
+    }
+
+    // The deepest workspace folder containing the entity, used as the project
+    // when nothing else names it.
+    pub fn fallback(&self, entity: &Path) -> Option<Project> {
+        let (root, options) = self
+            .folders
+            .iter()
+            .find(|(root, _)| entity.starts_with(root))?;
+
+        if !options.folder_fallback {
+            return None;
+        }
+
+        Some(Project {
+            name: root.file_name()?.to_string_lossy().to_string(),
+            branch: None,
+            root: Some(root.clone()),
+        })
+    }
+
+    // Forget the lookups a changed file affects: the branch of the repository
+    // it records, or every directory below a `.wakatime-project` file.
+    pub fn invalidate(&mut self, path: &Path) {
+        self.generation += 1;
+
+        if path.file_name() == Some(PROJECT_FILE.as_ref()) {
+            if let Some(dir) = path.parent() {
+                self.entries
+                    .retain(|entry_dir, _| !entry_dir.starts_with(dir));
+            }
+            return;
+        }
+
+        // Submodule and worktree git directories are spelled with `..`.
+        let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+
+        for entry in self.entries.values_mut() {
+            let branch_file = entry
+                .repository
+                .as_ref()
+                .and_then(Repository::branch_file)
+                .map(|file| fs::canonicalize(&file).unwrap_or(file));
+
+            if branch_file.as_deref() == Some(path.as_path()) {
+                entry.branch = None;
+            }
+        }
+    }
+
+    // Only files inside a workspace folder are reported by the client.
+    fn is_watched(&self, repository: &Repository) -> bool {
+        self.watching
+            && repository
+                .branch_file()
+                .is_some_and(|file| self.folders.iter().any(|(root, _)| file.starts_with(root)))
+    }
+
+    fn options_for(&self, path: &Path) -> &DetectionOptions {
+        self.folders
+            .iter()
+            .find(|(root, _)| path.starts_with(root))
+            .map_or(&self.options, |(_, options)| options)
+    }
+
+    // Resolve the project of an entity in the same order as wakatime-cli: a
+    // `.wakatime-project` file, then the project map, then version control.
+    // Package manifests, when enabled, are checked right before version
+    // control. Git messages belong to the working tree they are written for.
+    pub fn resolve(&mut self, entity: &Path) -> Option<Project> {
+        loop {
+            let pending = match self.lookup(entity) {
+                Lookup::Done(project) => return project,
+                Lookup::Pending(pending) => pending,
+            };
+            if let Some(project) = self.complete(entity, pending.run()) {
+                return project;
+            }
+        }
+    }
+
+    // Like `resolve`, but only with what is cached. Walking the filesystem or
+    // reading a branch, which can mean running `svn` or `jj`, is left to
+    // `Pending::run`, so it can be done without holding the cache, and its
+    // result handed back to `ProjectCache::complete`.
+    pub fn lookup(&mut self, entity: &Path) -> Lookup {
+        let Some((dir, _)) = lookup_target(entity) else {
+            return Lookup::Done(None);
+        };
+        let ttl = self.branch_ttl(&dir);
+        let generation = self.generation;
+
+        let Some(entry) = self.entries.get_mut(&dir) else {
+            let work = Work::Detect(self.options_for(&dir).clone());
+            return Lookup::Pending(Pending {
+                dir,
+                generation,
+                work,
+            });
+        };
+
+        entry.used = Instant::now();
+        match (&entry.branch, &entry.repository) {
+            (Some((read_at, _)), _) if read_at.elapsed() < ttl => {}
+            (_, Some(repository)) => {
+                let work = Work::Branch(repository.clone());
+                return Lookup::Pending(Pending {
+                    dir,
+                    generation,
+                    work,
+                });
+            }
+            (_, None) => entry.branch = Some((Instant::now(), None)),
+        }
+
+        Lookup::Done(self.project(entity))
+    }
+
+    // Store what a `Pending` lookup found, returning the project of the
+    // entity, or `None` when lookups were forgotten in the meantime and it
+    // has to be looked up again.
+    pub fn complete(&mut self, entity: &Path, fetched: Fetched) -> Option<Option<Project>> {
+        if fetched.generation != self.generation {
+            return None;
+        }
+
+        let entry = match fetched.entry {
+            Some(entry) => self.entries.entry(fetched.dir).or_insert(entry),
+            None => self.entries.get_mut(&fetched.dir)?,
+        };
+        entry.branch = Some(fetched.branch);
+        entry.used = Instant::now();
+
+        Some(self.project(entity))
+    }
+
+    // Whether the branch of the entity's folder was read recently enough to
+    // be reused, so looking it up again can't report a different branch.
+    pub fn is_fresh(&self, entity: &Path) -> bool {
+        let Some((dir, _)) = lookup_target(entity) else {
+            return true;
+        };
+
+        self.entries
+            .get(&dir)
+            .and_then(|entry| entry.branch.as_ref())
+            .is_some_and(|(read_at, _)| read_at.elapsed() < self.branch_ttl(&dir))
+    }
+
+    fn branch_ttl(&self, dir: &Path) -> Duration {
+        let watched = self
+            .entries
+            .get(dir)
+            .and_then(|entry| entry.repository.as_ref())
+            .is_some_and(|repository| self.is_watched(repository));
+
+        if watched {
+            WATCHED_BRANCH_TTL
+        } else {
+            BRANCH_TTL
+        }
+    }
+
+    // The project from the cached entry of the entity's folder.
+    fn project(&self, entity: &Path) -> Option<Project> {
+        let (dir, entity) = lookup_target(entity)?;
+        let entry = self.entries.get(&dir)?;
+        let branch = entry.branch.as_ref().and_then(|(_, branch)| branch.clone());
+
+        if let Some(mut project) = entry.project_file.clone() {
+            if project.branch.is_none() {
+                project.branch = branch;
+            }
+
+            return Some(project);
+        }
+
+        if let Some(name) = self.options_for(&dir).project_map.resolve(&entity) {
+            return Some(Project {
+                name,
+                branch,
+                root: entry.repository_root.clone(),
+            });
+        }
+
+        if let Some(project) = entry.manifest.clone() {
+            return Some(Project { branch, ..project });
+        }
+
+        Some(Project {
+            name: entry.repository.as_ref()?.name().to_string(),
+            branch,
+            root: entry.repository_root.clone(),
+        })
+    }
+}
+
+// The result of `ProjectCache::lookup`.
+#[derive(Debug)]
+pub enum Lookup {
+    Done(Option<Project>),
+    Pending(Pending),
+}
+
+// A lookup that needs the filesystem or version control.
+#[derive(Debug)]
+pub struct Pending {
+    dir: PathBuf,
+    generation: u64,
+    work: Work,
+}
+
+#[derive(Debug)]
+enum Work {
+    Detect(DetectionOptions),
+    Branch(Repository),
+}
+
+// What a `Pending` lookup found, for `ProjectCache::complete`.
+#[derive(Debug)]
+pub struct Fetched {
+    dir: PathBuf,
+    generation: u64,
+    entry: Option<Entry>,
+    branch: (Instant, Option<String>),
+}
+
+impl Pending {
+    // Blocks while `svn` or `jj` runs.
+    pub fn run(self) -> Fetched {
+        let (entry, branch) = match self.work {
+            Work::Detect(options) => {
+                let entry = detect(&options, &self.dir);
+                let branch = entry.repository.as_ref().and_then(Repository::branch);
+                (Some(entry), branch)
+            }
+            Work::Branch(repository) => (None, repository.branch()),
+        };
+
+        Fetched {
+            dir: self.dir,
+            generation: self.generation,
+            entry,
+            branch: (Instant::now(), branch),
+        }
+    }
+}
+
+// The folder an entity is looked up in, and the path matched against the
+// project map: its own folder, or the working tree of a git message.
+fn lookup_target(entity: &Path) -> Option<(PathBuf, PathBuf)> {
+    match GitMessage::detect(entity) {
+        Some((_, work_tree)) => Some((work_tree.clone(), work_tree)),
+        None => Some((entity.parent()?.to_path_buf(), entity.to_path_buf())),
+    }
+}
+
+fn detect(options: &DetectionOptions, dir: &Path) -> Entry {
+    let repository = detect_vcs(options, dir);
+
+    // Files at the repository root itself still belong to the repository.
+    let manifest = match repository {
+        Some((root, _)) if options.manifest_projects => dir
+            .ancestors()
+            .take_while(|dir| *dir != root)
+            .find_map(|dir| {
+                Some(Project {
+                    name: read_manifest_name(dir)?,
+                    branch: None,
+                    root: Some(dir.to_path_buf()),
+                })
+            }),
+        _ => None,
+    };
+
+    let (repository_root, repository) = repository
+        .map(|(root, repository)| (root.to_path_buf(), repository))
+        .unzip();
+
+    Entry {
+        project_file: dir.ancestors().find_map(read_project_file),
+        manifest,
+        repository,
+        repository_root,
+        branch: None,
+        used: Instant::now(),
+    }
+}
+
+// Walk up to the nearest repository, returning its root alongside it. A
+// colocated jj repository also has a `.git` directory, so `.jj` is checked
+// first.
+fn detect_vcs<'a>(options: &DetectionOptions, dir: &'a Path) -> Option<(&'a Path, Repository)> {
+    for dir in dir.ancestors() {
+        if dir.join(".jj").is_dir() {
+            let repository = Repository::Jujutsu {
+                name: dir.file_name()?.to_string_lossy().to_string(),
+                repo: dir.to_path_buf(),
+            };
+            return Some((dir, repository));
+        }
+
+        let hg_dir = dir.join(".hg");
+        if hg_dir.is_dir() {
+            let repository = Repository::Mercurial {
+                name: dir.file_name()?.to_string_lossy().to_string(),
+                hg_dir,
+            };
+            return Some((dir, repository));
+        }
+
+        if dir.join(".svn").is_dir() {
+            return detect_svn(dir);
+        }
+
+        let dot_git = dir.join(".git");
+
+        let git_dir = if dot_git.is_dir() {
+            dot_git
+        } else if dot_git.is_file() {
+            let Some(git_dir) = read_gitdir_file(&dot_git) else {
+                continue;
+            };
+
+            if options.submodules_disabled && is_submodule_git_dir(&git_dir) {
+                continue;
+            }
+
+            if !options.worktree_project_names {
+                if let Some(name) = worktree_repository_name(&git_dir) {
+                    return Some((dir, Repository::Git { name, git_dir }));
+                }
+            }
+
+            git_dir
+        } else {
+            continue;
+        };
+
+        let repository = Repository::Git {
+            name: dir.file_name()?.to_string_lossy().to_string(),
+            git_dir,
+        };
+        return Some((dir, repository));
+    }
+
+    None
+}
+
+// Parse a `.wakatime-project` file in `dir`.
+//
+// The first line is the project name and the optional second line is the
+// branch. An empty first line falls back to the name of `dir`, matching the
+// other WakaTime plugins.
+fn read_project_file(dir: &Path) -> Option<Project> {
+    let content = fs::read_to_string(dir.join(PROJECT_FILE)).ok()?;
+    let mut lines = content.lines().map(str::trim);
+
+    let name = match lines.next() {
+        Some(name) if !name.is_empty() => name.to_string(),
+        _ => dir.file_name()?.to_string_lossy().to_string(),
+    };
+
+    let branch = lines
+        .next()
+        .filter(|branch| !branch.is_empty())
+        .map(str::to_string);
+
+    Some(Project {
+        name,
+        branch,
+        root: Some(dir.to_path_buf()),
+    })
+}
+
+// Name of the package whose manifest is in `dir`. Manifests without a name,
+// such as a Cargo workspace root, are skipped.
+//
+// Example:
+// Cargo.toml:   [package] name = "auth"           -> auth
+// package.json: { "name": "@acme/web" }           -> @acme/web
+// go.mod:       module github.com/acme/mono/api/v2 -> api
+fn read_manifest_name(dir: &Path) -> Option<String> {
+    let read = |file| fs::read_to_string(dir.join(file)).ok();
+
+    let cargo = || {
+        let manifest: toml::Table = read("Cargo.toml")?.parse().ok()?;
+        Some(manifest.get("package")?.get("name")?.as_str()?.to_string())
+    };
+
+    let npm = || {
+        let manifest: serde_json::Value = serde_json::from_str(&read("package.json")?).ok()?;
+        Some(manifest.get("name")?.as_str()?.to_string())
+    };
+
+    let go = || {
+        let manifest = read("go.mod")?;
+        let module = manifest.lines().find_map(|line| {
+            line.trim()
+                .strip_prefix("module")
+                .filter(|rest| rest.starts_with(char::is_whitespace))
+        })?;
+
+        // Modules are named by their import path, which ends in a major
+        // version suffix from v2 onwards.
+        let mut segments = module.trim().trim_matches('"').rsplit('/');
+        let last = segments.next()?;
+        let is_major_version = last.strip_prefix('v').is_some_and(|version| {
+            !version.is_empty() && version.bytes().all(|b| b.is_ascii_digit())
+        });
+
+        let name = if is_major_version {
+            segments.next()?
+        } else {
+            last
+        };
+        Some(name.to_string())
+    };
+
+    cargo()
+        .or_else(npm)
+        .or_else(go)
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+}
+
+// Files git opens in the editor while committing, merging, tagging or
+// rebasing. They live in the git directory, so they are reported as git
+// activity in the working tree instead of as files inside `.git`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GitMessage {
+    Commit,
+    Merge,
+    Tag,
+    Rebase,
+}
+
+impl GitMessage {
+    // Returns the message kind and the working tree it belongs to.
+    //
+    // Example:
+    // /src/app/.git/COMMIT_EDITMSG               -> Commit in /src/app
+    // /src/app/.git/rebase-merge/git-rebase-todo -> Rebase in /src/app
+    // /src/app/.git/worktrees/fix/MERGE_MSG      -> Merge in /src/fix
+    pub fn detect(entity: &Path) -> Option<(Self, PathBuf)> {
+        let message = match entity.file_name()?.to_str()? {
+            "COMMIT_EDITMSG" => Self::Commit,
+            "MERGE_MSG" => Self::Merge,
+            "TAG_EDITMSG" => Self::Tag,
+            "git-rebase-todo" => Self::Rebase,
+            _ => return None,
+        };
+
+        let mut git_dir = entity.parent()?;
+        if message == Self::Rebase {
+            // Interactive rebases keep their state in `rebase-merge/`.
+            git_dir = git_dir.parent()?;
+        }
+
+        if !git_dir.join("HEAD").is_file() {
+            return None;
+        }
+
+        Some((message, work_tree(git_dir)))
+    }
+
+    // Reported as an app entity, since the file itself is short-lived.
+    pub fn entity(self) -> &'static str {
+        match self {
+            Self::Commit => "git commit",
+            Self::Merge => "git merge",
+            Self::Tag => "git tag",
+            Self::Rebase => "git rebase",
+        }
+    }
+
+    pub fn category(self) -> &'static str {
+        match self {
+            Self::Commit | Self::Merge | Self::Tag => "writing docs",
+            Self::Rebase => "code reviewing",
+        }
+    }
+}
+
+// The working tree a git directory belongs to, or the git directory itself
+// when it can't be found (e.g. a bare repository).
+//
+// Example:
+// /src/app/.git                                       -> /src/app
+// /src/app/.git/worktrees/fix (gitdir: /src/fix/.git) -> /src/fix
+// /src/app/.git/modules/lib (worktree = ../../../lib) -> /src/app/lib
+fn work_tree(git_dir: &Path) -> PathBuf {
+    if git_dir.file_name() == Some(".git".as_ref()) {
+        if let Some(parent) = git_dir.parent() {
+            return parent.to_path_buf();
+        }
+    }
+
+    // Linked worktrees point back at their `.git` file.
+    if let Ok(gitdir) = fs::read_to_string(git_dir.join("gitdir")) {
+        if let Some(work_tree) = git_dir.join(gitdir.trim()).parent() {
+            return work_tree.to_path_buf();
+        }
+    }
+
+    // Submodules record their working tree in `core.worktree`.
+    let config = fs::read_to_string(git_dir.join("config")).unwrap_or_default();
+    let work_tree = config.lines().find_map(|line| {
+        let (key, value) = line.split_once('=')?;
+        (key.trim() == "worktree").then(|| git_dir.join(value.trim()))
+    });
+
+    match work_tree {
+        Some(work_tree) => fs::canonicalize(&work_tree).unwrap_or(work_tree),
+        None => git_dir.to_path_buf(),
+    }
+}
+
+// Resolve a `.git` file (used by submodules and worktrees) to the git
+// directory it points at.
+//
+// Example:
+// gitdir: ../.git/modules/vendor/lib -> /repo/.git/modules/vendor/lib
+fn read_gitdir_file(dot_git: &Path) -> Option<PathBuf> {
+    let content = fs::read_to_string(dot_git).ok()?;
+    let git_dir = content.lines().next()?.strip_prefix("gitdir:")?.trim();
+
+    Some(dot_git.parent()?.join(git_dir))
+}
+
+// Submodule git directories live under the superproject's `.git/modules`,
+// whereas linked worktrees have a `commondir` file pointing back at the main
+// repository.
+fn is_submodule_git_dir(git_dir: &Path) -> bool {
+    !git_dir.join("commondir").is_file()
+        && git_dir
+            .components()
+            .any(|component| component.as_os_str() == "modules")
+}
+
+// Name of the main repository a linked worktree belongs to. The worktree's git
+// directory has a `commondir` file pointing at the main repository's `.git`.
+//
+// Example:
+// /src/myrepo/.git/worktrees/myrepo-fix-123/commondir: ../.. -> myrepo
+// /src/myrepo.git/worktrees/fix-123/commondir: ../..         -> myrepo
+fn worktree_repository_name(git_dir: &Path) -> Option<String> {
+    let commondir = fs::read_to_string(git_dir.join("commondir")).ok()?;
+    let common_dir = git_dir.join(commondir.trim());
+    let common_dir = fs::canonicalize(&common_dir).unwrap_or(common_dir);
+
+    let name = if common_dir.file_name()? == ".git" {
+        common_dir.parent()?.file_name()?
+    } else {
+        // Worktrees of a bare repository.
+        common_dir.file_name()?
+    };
+
+    let name = name.to_string_lossy();
+    Some(name.strip_suffix(".git").unwrap_or(&name).to_string())
+}
+
+fn read_git_branch(git_dir: &Path) -> Option<String> {
+    let head = fs::read_to_string(git_dir.join("HEAD")).ok()?;
+
+    head.trim()
+        .strip_prefix("ref: refs/heads/")
+        .map(str::to_string)
+}
+
+// Mercurial stores the current branch in `.hg/branch`, which is absent until
+// a named branch is created.
+fn read_hg_branch(hg_dir: &Path) -> String {
+    fs::read_to_string(hg_dir.join("branch"))
+        .ok()
+        .map(|branch| branch.trim().to_string())
+        .filter(|branch| !branch.is_empty())
+        .unwrap_or_else(|| "default".to_string())
+}
+
+// Subversion working copies created before 1.7 have a `.svn` directory in
+// every folder, so keep walking up to the topmost one.
+fn detect_svn(dir: &Path) -> Option<(&Path, Repository)> {
+    let working_copy = dir
+        .ancestors()
+        .take_while(|dir| dir.join(".svn").is_dir())
+        .last()?;
+
+    // Checkouts are often named after the branch (`trunk`), so prefer the
+    // name of the repository itself.
+    let name = svn_info(working_copy)
+        .and_then(|info| info.repository)
+        .or_else(|| Some(working_copy.file_name()?.to_string_lossy().to_string()))?;
+
+    let repository = Repository::Subversion {
+        name,
+        working_copy: working_copy.to_path_buf(),
+    };
+    Some((working_copy, repository))
+}
+
+#[derive(Debug, Default)]
+struct SvnInfo {
+    repository: Option<String>,
+    branch: Option<String>,
+}
+
+// Read the repository name and branch from `svn info`. The XML output is used
+// because the plain output is localized.
+fn svn_info(working_copy: &Path) -> Option<SvnInfo> {
+    let output = Command::new("svn")
+        .current_dir(working_copy)
+        .args(["info", "--xml", "--non-interactive"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+
+    Some(parse_svn_info(&String::from_utf8_lossy(&output.stdout)))
+}
+
+// Example:
+// <root>https://svn.example.com/repos/app</root>  -> repository: app
+// <relative-url>^/branches/fix-123</relative-url> -> branch: fix-123
+// <relative-url>^/trunk/src</relative-url>        -> branch: trunk
+fn parse_svn_info(xml: &str) -> SvnInfo {
+    let element = |tag: &str| {
+        let start = xml.find(&format!("<{tag}>"))? + tag.len() + 2;
+        let end = start + xml[start..].find(&format!("</{tag}>"))?;
+        Some(xml[start..end].trim().to_string())
+    };
+
+    let repository = element("root")
+        .and_then(|root| Some(root.trim_end_matches('/').rsplit('/').next()?.to_string()))
+        .filter(|name| !name.is_empty());
+
+    let branch = element("relative-url").and_then(|url| {
+        let mut segments = url.trim_start_matches("^/").split('/');
+        match segments.next()? {
+            "trunk" => Some("trunk".to_string()),
+            "branches" | "tags" => segments.next().map(str::to_string),
+            _ => None,
+        }
+    });
+
+    SvnInfo { repository, branch }
+}
+
+// Ask `jj` for the bookmark of the working-copy change, falling back to the
+// change id when no bookmark points at it. jj keeps its state in a binary
+// store, so unlike git there is no file to read directly.
+//
+// `--ignore-working-copy` keeps the lookup from snapshotting the working copy
+// on every heartbeat.
+fn read_jj_branch(repo: &Path) -> Option<String> {
+    // jj renamed branches to bookmarks in 0.22.
+    ["bookmarks", "branches"].iter().find_map(|keyword| {
+        let output = Command::new("jj")
+            .current_dir(repo)
+            .args([
+                "log",
+                "--ignore-working-copy",
+                "--no-graph",
+                "--color",
+                "never",
+            ])
+            .args(["-r", "@", "-T"])
+            .arg(format!(
+                r#"separate(" ", change_id.shortest(8), {keyword})"#
+            ))
+            .output()
+            .ok()
+            .filter(|output| output.status.success())?;
+
+        parse_jj_log(&String::from_utf8_lossy(&output.stdout))
+    })
+}
+
+// The first local bookmark in the output of `read_jj_branch`, or the change id.
+// Bookmarks render with markers for their sync state, e.g. `main*` when ahead
+// of the remote or `main??` when conflicted.
+//
+// Example:
+// kxqpwvzm main* main@origin -> main
+// kxqpwvzm                   -> kxqpwvzm
+fn parse_jj_log(output: &str) -> Option<String> {
+    let mut words = output.split_whitespace();
+    let change_id = words.next()?;
+
+    let bookmark = words
+        .map(|bookmark| bookmark.trim_end_matches(['*', '?']))
+        .find(|bookmark| !bookmark.is_empty() && !bookmark.contains('@'));
+
+    Some(bookmark.unwrap_or(change_id).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    // An empty folder of its own for each test.
+    fn folder(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("wakatime-ls-project-{}-{name}", process::id()));
+        fs::remove_dir_all(&path).ok();
+        fs::create_dir_all(&path).unwrap();
+        fs::canonicalize(path).unwrap()
+    }
+
+    fn write(path: &Path, content: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    // A git repository on `branch`, with only what detection reads.
+    fn git_repository(root: &Path, branch: &str) {
+        write(
+            &root.join(".git/HEAD"),
+            &format!("ref: refs/heads/{branch}\n"),
+        );
+    }
+
+    fn cache(options: DetectionOptions) -> ProjectCache {
+        let mut cache = ProjectCache::default();
+        cache.configure(options, Vec::new());
+        cache
+    }
+
+    fn resolve(cache: &mut ProjectCache, entity: &Path) -> Option<(String, Option<String>)> {
+        cache
+            .resolve(entity)
+            .map(|project| (project.name, project.branch))
+    }
+
+    fn named(name: &str, branch: Option<&str>) -> Option<(String, Option<String>)> {
+        Some((name.to_string(), branch.map(str::to_string)))
+    }
+
+    #[test]
+    fn git_repositories_are_named_after_their_folder() {
+        let root = folder("git").join("app");
+        git_repository(&root, "main");
+        let mut cache = cache(DetectionOptions::default());
+
+        let project = cache.resolve(&root.join("src/deep/main.rs")).unwrap();
+        assert_eq!(project.name, "app");
+        assert_eq!(project.branch.as_deref(), Some("main"));
+        assert_eq!(project.root, Some(root.clone()));
+
+        write(
+            &root.join(".git/HEAD"),
+            "0b6f1c3e5d2a4f7e9c8b1a2b3c4d5e6f01234567\n",
+        );
+        assert_eq!(
+            resolve(&mut cache, &root.join("README.md")),
+            named("app", None)
+        );
+        assert_eq!(
+            resolve(&mut cache, &folder("git-none").join("main.rs")),
+            None
+        );
+    }
+
+    #[test]
+    fn the_project_file_comes_first() {
+        let root = folder("order").join("app");
+        git_repository(&root, "main");
+        write(
+            &root.join("services/auth/Cargo.toml"),
+            "[package]\nname = \"auth\"\n",
+        );
+        write(
+            &root.join("services/auth/.wakatime-project"),
+            "auth-service\nrelease\n",
+        );
+        write(&root.join("services/web/.wakatime-project"), "\n");
+        let (project_map, errors) = ProjectMap::new([(".*/services/(.*?)/", "service-{0}")]);
+        assert!(errors.is_empty());
+        let mut cache = cache(DetectionOptions {
+            manifest_projects: true,
+            project_map,
+            ..DetectionOptions::default()
+        });
+
+        assert_eq!(
+            resolve(&mut cache, &root.join("services/auth/src/main.rs")),
+            named("auth-service", Some("release"))
+        );
+        // An empty name is the folder of the file, with the repository branch.
+        assert_eq!(
+            resolve(&mut cache, &root.join("services/web/index.ts")),
+            named("web", Some("main"))
+        );
+        assert_eq!(
+            resolve(&mut cache, &root.join("services/mail/main.go")),
+            named("service-mail", Some("main"))
+        );
+        assert_eq!(
+            resolve(&mut cache, &root.join("src/main.rs")),
+            named("app", Some("main"))
+        );
+    }
+
+    #[test]
+    fn project_map_groups_are_substituted() {
+        let (project_map, errors) = ProjectMap::new([
+            ("/work/(\\w+)/(\\w+)/", "{1}-of-{0}"),
+            ("/blank/", "  "),
+            ("/scratch/", "scratch"),
+            ("(", "never"),
+        ]);
+        assert_eq!(errors.len(), 1);
+
+        assert_eq!(
+            project_map
+                .resolve(Path::new("/work/acme/web/main.rs"))
+                .as_deref(),
+            Some("web-of-acme")
+        );
+        assert_eq!(project_map.resolve(Path::new("/blank/main.rs")), None);
+        assert_eq!(
+            project_map
+                .resolve(Path::new("/blank/scratch/main.rs"))
+                .as_deref(),
+            Some("scratch")
+        );
+        assert_eq!(project_map.resolve(Path::new("/home/main.rs")), None);
+    }
+
+    #[test]
+    fn manifests_name_packages_below_the_repository_root() {
+        let root = folder("manifests").join("mono");
+        git_repository(&root, "main");
+        write(
+            &root.join("Cargo.toml"),
+            "[workspace]\nmembers = [\"crates/*\"]\n",
+        );
+        write(
+            &root.join("crates/auth/Cargo.toml"),
+            "[package]\nname = \"auth\"\n",
+        );
+        write(&root.join("web/package.json"), r#"{ "name": "@acme/web" }"#);
+        write(
+            &root.join("api/go.mod"),
+            "module github.com/acme/mono/api/v2\n\ngo 1.22\n",
+        );
+
+        let mut packages = cache(DetectionOptions {
+            manifest_projects: true,
+            ..DetectionOptions::default()
+        });
+        let project = packages
+            .resolve(&root.join("crates/auth/src/lib.rs"))
+            .unwrap();
+        assert_eq!(project.name, "auth");
+        assert_eq!(project.root, Some(root.join("crates/auth")));
+        assert_eq!(
+            resolve(&mut packages, &root.join("web/src/index.ts")),
+            named("@acme/web", Some("main"))
+        );
+        assert_eq!(
+            resolve(&mut packages, &root.join("api/main.go")),
+            named("api", Some("main"))
+        );
+        assert_eq!(
+            resolve(&mut packages, &root.join("Cargo.toml")),
+            named("mono", Some("main"))
+        );
+
+        assert_eq!(
+            resolve(
+                &mut cache(DetectionOptions::default()),
+                &root.join("crates/auth/src/lib.rs")
+            ),
+            named("mono", Some("main"))
+        );
+    }
+
+    #[test]
+    fn submodules_belong_to_themselves_unless_disabled() {
+        let root = folder("submodules").join("app");
+        git_repository(&root, "main");
+        write(
+            &root.join(".git/modules/lib/HEAD"),
+            "ref: refs/heads/vendored\n",
+        );
+        write(&root.join("lib/.git"), "gitdir: ../.git/modules/lib\n");
+
+        assert_eq!(
+            resolve(
+                &mut cache(DetectionOptions::default()),
+                &root.join("lib/lib.rs")
+            ),
+            named("lib", Some("vendored"))
+        );
+        let mut disabled = cache(DetectionOptions {
+            submodules_disabled: true,
+            ..DetectionOptions::default()
+        });
+        assert_eq!(
+            resolve(&mut disabled, &root.join("lib/lib.rs")),
+            named("app", Some("main"))
+        );
+    }
+
+    #[test]
+    fn worktrees_are_named_after_their_repository() {
+        let parent = folder("worktrees");
+        let main = parent.join("myrepo");
+        let worktree = parent.join("myrepo-fix-123");
+        git_repository(&main, "main");
+        let git_dir = main.join(".git/worktrees/fix-123");
+        write(&git_dir.join("HEAD"), "ref: refs/heads/fix-123\n");
+        write(&git_dir.join("commondir"), "../..\n");
+        write(
+            &worktree.join(".git"),
+            &format!("gitdir: {}\n", git_dir.display()),
+        );
+
+        assert_eq!(
+            resolve(
+                &mut cache(DetectionOptions::default()),
+                &worktree.join("main.rs")
+            ),
+            named("myrepo", Some("fix-123"))
+        );
+        let mut worktree_names = cache(DetectionOptions {
+            worktree_project_names: true,
+            ..DetectionOptions::default()
+        });
+        assert_eq!(
+            resolve(&mut worktree_names, &worktree.join("main.rs")),
+            named("myrepo-fix-123", Some("fix-123"))
+        );
+    }
+
+    #[test]
+    fn other_version_control_systems_are_detected() {
+        let parent = folder("vcs");
+
+        let hg = parent.join("hg-app");
+        fs::create_dir_all(hg.join(".hg")).unwrap();
+        let mut cache = cache(DetectionOptions::default());
+        assert_eq!(
+            resolve(&mut cache, &hg.join("main.py")),
+            named("hg-app", Some("default"))
+        );
+        write(&hg.join(".hg/branch"), "stable\n");
+        cache.forget();
+        assert_eq!(
+            resolve(&mut cache, &hg.join("main.py")),
+            named("hg-app", Some("stable"))
+        );
+
+        // A colocated jj repository is a jj one.
+        let jj = parent.join("jj-app");
+        fs::create_dir_all(jj.join(".jj")).unwrap();
+        git_repository(&jj, "main");
+        let (name, _) = resolve(&mut cache, &jj.join("src/main.rs")).unwrap();
+        assert_eq!(name, "jj-app");
+        assert!(matches!(
+            cache.entries[&jj.join("src")].repository,
+            Some(Repository::Jujutsu { .. })
+        ));
+
+        // Older working copies have `.svn` in every folder.
+        let svn = parent.join("trunk");
+        fs::create_dir_all(svn.join(".svn")).unwrap();
+        fs::create_dir_all(svn.join("src/.svn")).unwrap();
+        cache.resolve(&svn.join("src/main.c")).unwrap();
+        assert!(matches!(
+            &cache.entries[&svn.join("src")].repository,
+            Some(Repository::Subversion { working_copy, .. }) if *working_copy == svn
+        ));
+    }
+
+    #[test]
+    fn svn_info_names_the_repository_and_branch() {
+        let info = |root: &str, relative_url: &str| {
+            let info = parse_svn_info(&format!(
+                "<?xml version=\"1.0\"?>\n<info>\n<entry kind=\"dir\" path=\".\">\n\
+                 <relative-url>{relative_url}</relative-url>\n\
+                 <repository>\n<root>{root}</root>\n</repository>\n</entry>\n</info>\n"
+            ));
+            (info.repository, info.branch)
+        };
+        let some = |value: &str| Some(value.to_string());
+
+        assert_eq!(
+            info("https://svn.example.com/repos/app", "^/branches/fix-123"),
+            (some("app"), some("fix-123"))
+        );
+        assert_eq!(
+            info("https://svn.example.com/repos/app/", "^/trunk/src"),
+            (some("app"), some("trunk"))
+        );
+        assert_eq!(
+            info("file:///srv/svn/lib", "^/tags/v1.0/include"),
+            (some("lib"), some("v1.0"))
+        );
+        assert_eq!(info("", "^/vendor/lib"), (None, None));
+    }
+
+    #[test]
+    fn jj_reports_the_first_local_bookmark() {
+        assert_eq!(
+            parse_jj_log("kxqpwvzm main* main@origin\n").as_deref(),
+            Some("main")
+        );
+        assert_eq!(
+            parse_jj_log("kxqpwvzm feature@origin fix??\n").as_deref(),
+            Some("fix")
+        );
+        assert_eq!(parse_jj_log("kxqpwvzm\n").as_deref(), Some("kxqpwvzm"));
+        assert_eq!(parse_jj_log(""), None);
+    }
+
+    #[test]
+    fn folders_are_the_fallback_when_enabled() {
+        let parent = folder("fallback");
+        let notes = parent.join("notes");
+        let scratch = parent.join("scratch");
+        let mut cache = ProjectCache::default();
+        cache.configure(
+            DetectionOptions::default(),
+            vec![
+                (
+                    notes.clone(),
+                    DetectionOptions {
+                        folder_fallback: true,
+                        ..DetectionOptions::default()
+                    },
+                ),
+                (scratch.clone(), DetectionOptions::default()),
+            ],
+        );
+
+        assert_eq!(cache.resolve(&notes.join("todo.md")), None);
+        assert_eq!(
+            cache.fallback(&notes.join("2026/todo.md")),
+            Some(Project {
+                name: "notes".to_string(),
+                branch: None,
+                root: Some(notes),
+            })
+        );
+        assert_eq!(cache.fallback(&scratch.join("main.rs")), None);
+        assert_eq!(cache.fallback(&parent.join("main.rs")), None);
+    }
+
+    #[test]
+    fn git_messages_belong_to_their_working_tree() {
+        let parent = folder("messages");
+        let root = parent.join("app");
+        git_repository(&root, "main");
+        let git_dir = root.join(".git");
+
+        assert_eq!(
+            GitMessage::detect(&git_dir.join("COMMIT_EDITMSG")),
+            Some((GitMessage::Commit, root.clone()))
+        );
+        assert_eq!(
+            GitMessage::detect(&git_dir.join("rebase-merge/git-rebase-todo")),
+            Some((GitMessage::Rebase, root.clone()))
+        );
+
+        let worktree_git_dir = git_dir.join("worktrees/fix");
+        write(&worktree_git_dir.join("HEAD"), "ref: refs/heads/fix\n");
+        write(
+            &worktree_git_dir.join("gitdir"),
+            &format!("{}\n", parent.join("fix/.git").display()),
+        );
+        assert_eq!(
+            GitMessage::detect(&worktree_git_dir.join("MERGE_MSG")),
+            Some((GitMessage::Merge, parent.join("fix")))
+        );
+
+        assert_eq!(GitMessage::detect(&root.join("COMMIT_EDITMSG")), None);
+        assert_eq!(GitMessage::detect(&git_dir.join("config")), None);
+
+        let mut cache = cache(DetectionOptions::default());
+        assert_eq!(
+            resolve(&mut cache, &git_dir.join("COMMIT_EDITMSG")),
+            named("app", Some("main"))
+        );
+    }
+
+    #[test]
+    fn branches_are_reused_until_they_expire_or_change() {
+        let root = folder("branches").join("app");
+        git_repository(&root, "main");
+        let mut cache = cache(DetectionOptions::default());
+        let entity = root.join("main.rs");
+
+        assert_eq!(resolve(&mut cache, &entity), named("app", Some("main")));
+        git_repository(&root, "feature");
+        assert_eq!(resolve(&mut cache, &entity), named("app", Some("main")));
+
+        assert!(cache.is_fresh(&entity));
+        let entry = cache.entries.get_mut(&root).unwrap();
+        entry.branch = entry
+            .branch
+            .take()
+            .map(|(read_at, branch)| (read_at - BRANCH_TTL, branch));
+        assert!(!cache.is_fresh(&entity));
+        assert!(matches!(cache.lookup(&entity), Lookup::Pending(_)));
+        assert_eq!(resolve(&mut cache, &entity), named("app", Some("feature")));
+
+        git_repository(&root, "release");
+        cache.invalidate(&root.join(".git/HEAD"));
+        assert_eq!(resolve(&mut cache, &entity), named("app", Some("release")));
+    }
+
+    #[test]
+    fn lookups_forgotten_while_pending_are_not_stored() {
+        let root = folder("pending").join("app");
+        git_repository(&root, "main");
+        let mut cache = cache(DetectionOptions::default());
+        let entity = root.join("main.rs");
+
+        let Lookup::Pending(pending) = cache.lookup(&entity) else {
+            panic!("nothing is cached yet");
+        };
+        let fetched = pending.run();
+        cache.forget();
+        assert_eq!(cache.complete(&entity, fetched), None);
+        assert!(cache.entries.is_empty());
+
+        let Lookup::Pending(pending) = cache.lookup(&entity) else {
+            panic!("nothing is cached yet");
+        };
+        let project = cache.complete(&entity, pending.run()).unwrap().unwrap();
+        assert_eq!(project.branch.as_deref(), Some("main"));
+        assert!(matches!(cache.lookup(&entity), Lookup::Done(Some(_))));
+    }
+}
@@ -0,0 +1,227 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use serde::Serialize;
+
+use crate::{event::Event, memory};
+
+// Editor events waiting for the one before them to be sent. Sending one
+// mostly waits on wakatime-cli, so this is how far behind the editor can get
+// before the oldest events are dropped.
+pub const CAPACITY: usize = 64;
+
+// The editor events not handled yet, sent one at a time in the order they
+// came so that a storm of edits can't run wakatime-cli for each of them at
+// once, or grow memory without bound:
+// - an edit replaces the last queued event when that is an edit of the same
+//   file too, as only the latest position matters within an interval;
+// - once `CAPACITY` events are queued, the oldest is dropped for a new one.
+//
+// Whoever pushes an event and finds nobody sending the queue sends it, until
+// it is empty, so no task of its own is needed.
+#[derive(Debug)]
+pub struct EventQueue {
+    capacity: usize,
+    events: Mutex<VecDeque<Event>>,
+    sending: AtomicBool,
+    coalesced: AtomicU64,
+    dropped: AtomicU64,
+}
+
+// Example, in the `wakatime/status` request:
+// {"queued":0,"coalesced":211,"dropped":0}
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct QueueCounts {
+    pub queued: u64,
+    // Replaced by a later edit of the same file.
+    pub coalesced: u64,
+    // Over `CAPACITY`.
+    pub dropped: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pushed {
+    Queued,
+    Coalesced,
+    // The oldest event was dropped to make room.
+    DroppedOldest,
+}
+
+impl Default for EventQueue {
+    fn default() -> Self {
+        Self::new(CAPACITY)
+    }
+}
+
+impl EventQueue {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            events: Mutex::new(VecDeque::with_capacity(capacity)),
+            sending: AtomicBool::new(false),
+            coalesced: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    pub fn push(&self, event: Event) -> Pushed {
+        let Ok(mut events) = self.events.lock() else {
+            return Pushed::Queued;
+        };
+
+        if let Some(last) = events.back_mut() {
+            if !last.is_write && !event.is_write && last.entity == event.entity {
+                let file_changed = last.file_changed || event.file_changed;
+                *last = Event {
+                    file_changed,
+                    ..event
+                };
+                self.coalesced.fetch_add(1, Ordering::Relaxed);
+                return Pushed::Coalesced;
+            }
+        }
+
+        let pushed = if events.len() >= self.capacity {
+            events.pop_front();
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            Pushed::DroppedOldest
+        } else {
+            Pushed::Queued
+        };
+        events.push_back(event);
+        pushed
+    }
+
+    pub fn pop(&self) -> Option<Event> {
+        self.events.lock().ok()?.pop_front()
+    }
+
+    // Start sending the queue, unless it is empty or already being sent.
+    // Whoever gets true pops events until there are none and then calls
+    // `release`, and claims again to catch those pushed in between.
+    //
+    // Example:
+    // while queue.claim() {
+    //     while let Some(event) = queue.pop() { send(event).await }
+    //     queue.release();
+    // }
+    pub fn claim(&self) -> bool {
+        !self.is_empty() && !self.sending.swap(true, Ordering::AcqRel)
+    }
+
+    pub fn release(&self) {
+        self.sending.store(false, Ordering::Release);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.lock().map_or(true, |events| events.is_empty())
+    }
+
+    pub fn approximate_size(&self) -> usize {
+        self.events.lock().map_or(0, |events| {
+            events
+                .iter()
+                .map(|event| memory::entry_size::<Event>(&event.entity))
+                .sum()
+        })
+    }
+
+    pub fn counts(&self) -> QueueCounts {
+        QueueCounts {
+            queued: self.events.lock().map_or(0, |events| events.len() as u64),
+            coalesced: self.coalesced.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, sync::Arc};
+
+    use super::*;
+
+    fn edit(file: &str, lineno: u64) -> Event {
+        Event {
+            entity: Arc::from(Path::new(file)),
+            is_write: false,
+            language: None,
+            lineno: Some(lineno),
+            cursor_pos: Some(0),
+            file_changed: false,
+        }
+    }
+
+    fn linenos(queue: &EventQueue) -> Vec<(String, Option<u64>)> {
+        std::iter::from_fn(|| queue.pop())
+            .map(|event| (event.entity.display().to_string(), event.lineno))
+            .collect()
+    }
+
+    #[test]
+    fn edits_of_the_same_file_are_coalesced() {
+        let queue = EventQueue::default();
+        let switch = Event {
+            file_changed: true,
+            ..edit("main.rs", 1)
+        };
+
+        assert_eq!(queue.push(switch), Pushed::Queued);
+        assert_eq!(queue.push(edit("main.rs", 2)), Pushed::Coalesced);
+        assert_eq!(queue.push(edit("lib.rs", 1)), Pushed::Queued);
+        assert_eq!(queue.counts().coalesced, 1);
+
+        let first = queue.pop().unwrap();
+        assert_eq!(first.lineno, Some(2));
+        assert!(first.file_changed);
+        assert_eq!(linenos(&queue), [("lib.rs".to_string(), Some(1))]);
+    }
+
+    #[test]
+    fn writes_are_kept() {
+        let queue = EventQueue::default();
+        let write = Event {
+            is_write: true,
+            ..edit("main.rs", 1)
+        };
+
+        queue.push(edit("main.rs", 1));
+        assert_eq!(queue.push(write.clone()), Pushed::Queued);
+        assert_eq!(queue.push(edit("main.rs", 2)), Pushed::Queued);
+        assert_eq!(queue.counts().queued, 3);
+        assert_eq!(queue.pop().map(|event| event.is_write), Some(false));
+        assert_eq!(queue.pop(), Some(write));
+    }
+
+    #[test]
+    fn the_oldest_are_dropped_once_full() {
+        let queue = EventQueue::new(2);
+
+        queue.push(edit("a.rs", 1));
+        queue.push(edit("b.rs", 1));
+        assert_eq!(queue.push(edit("c.rs", 1)), Pushed::DroppedOldest);
+        assert_eq!(queue.counts().dropped, 1);
+        assert_eq!(
+            linenos(&queue),
+            [("b.rs".to_string(), Some(1)), ("c.rs".to_string(), Some(1))]
+        );
+    }
+
+    #[test]
+    fn claimed_once_until_released() {
+        let queue = EventQueue::default();
+        assert!(!queue.claim());
+
+        queue.push(edit("main.rs", 1));
+        assert!(queue.claim());
+        assert!(!queue.claim());
+
+        queue.release();
+        assert!(queue.claim());
+    }
+}
@@ -0,0 +1,50 @@
+use std::{collections::VecDeque, path::PathBuf};
+
+use chrono::{DateTime, Local};
+use serde::Serialize;
+
+use crate::heartbeat::Outcome;
+
+// How many heartbeat attempts are remembered.
+pub const CAPACITY: usize = 100;
+
+// The last heartbeats the server tried to send, in the order they finished,
+// to answer "did it send anything in the last hour?" from the editor.
+//
+// Example, as returned by `wakatime/dumpRecent`:
+// [{"entity":"/home/alice/src/app/main.rs","time":"2026-10-14T15:05:39.202+02:00","outcome":"sent","latency_ms":212,"is_write":false}]
+#[derive(Debug)]
+pub struct RecentHeartbeats {
+    attempts: VecDeque<Attempt>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Attempt {
+    pub entity: PathBuf,
+    pub time: DateTime<Local>,
+    pub outcome: Outcome,
+    // How long the attempt took, mostly running wakatime-cli.
+    pub latency_ms: u64,
+    pub is_write: bool,
+}
+
+impl Default for RecentHeartbeats {
+    fn default() -> Self {
+        Self {
+            attempts: VecDeque::with_capacity(CAPACITY),
+        }
+    }
+}
+
+impl RecentHeartbeats {
+    pub fn push(&mut self, attempt: Attempt) {
+        if self.attempts.len() == CAPACITY {
+            self.attempts.pop_front();
+        }
+        self.attempts.push_back(attempt);
+    }
+
+    pub fn to_vec(&self) -> Vec<Attempt> {
+        self.attempts.iter().cloned().collect()
+    }
+}
@@ -0,0 +1,129 @@
+use std::time::{Duration, Instant};
+
+// A pause this long between editor events is a break, which ends the coding
+// session.
+pub const IDLE: Duration = Duration::from_secs(5 * 60);
+
+// How long `wakatime.snoozeBreakReminder` holds reminders back when it isn't
+// given a number of minutes.
+pub const SNOOZE: Duration = Duration::from_secs(30 * 60);
+
+// The current coding session, from the editor events, and when the user is
+// due a reminder to take a break with the `break_reminder` setting.
+//
+// Example, with a 90 minute threshold:
+// events from 9:00 to 10:30, none more than 5 minutes apart -> reminder at 10:30
+// the same with a 10 minute pause at 10:00                  -> no reminder yet
+#[derive(Debug, Default)]
+pub struct Session {
+    started: Option<Instant>,
+    last_activity: Option<Instant>,
+    // When the time until the next reminder started, which is also reset
+    // after each reminder.
+    remind_from: Option<Instant>,
+    snoozed_until: Option<Instant>,
+}
+
+impl Session {
+    // Record activity at `now`, returning how long the user has been coding
+    // when a reminder is due after `threshold`. The next one is due after as
+    // long again.
+    pub fn activity(&mut self, now: Instant, threshold: Option<Duration>) -> Option<Duration> {
+        if self.length(now).is_none() {
+            self.started = Some(now);
+            self.remind_from = Some(now);
+        }
+        self.last_activity = Some(now);
+
+        let threshold = threshold?;
+        let coding = now.saturating_duration_since(self.remind_from?);
+        if coding < threshold || self.snoozed_until.is_some_and(|until| now < until) {
+            return None;
+        }
+
+        self.remind_from = Some(now);
+        Some(now.saturating_duration_since(self.started?))
+    }
+
+    // How long the current session has lasted, or None after a break.
+    pub fn length(&self, now: Instant) -> Option<Duration> {
+        let last_activity = self.last_activity?;
+        if now.saturating_duration_since(last_activity) >= IDLE {
+            return None;
+        }
+
+        Some(now.saturating_duration_since(self.started?))
+    }
+
+    pub fn snooze(&mut self, now: Instant, duration: Duration) {
+        self.snoozed_until = Some(now + duration);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn a_pause_of_idle_ends_the_session() {
+        let mut session = Session::default();
+        let start = Instant::now();
+
+        session.activity(start, None);
+        session.activity(start + 4 * MINUTE, None);
+        assert_eq!(session.length(start + 8 * MINUTE), Some(8 * MINUTE));
+        assert_eq!(session.length(start + 9 * MINUTE), None);
+
+        session.activity(start + 9 * MINUTE, None);
+        assert_eq!(session.length(start + 10 * MINUTE), Some(MINUTE));
+    }
+
+    #[test]
+    fn reminders_are_due_after_the_threshold_of_coding() {
+        let mut session = Session::default();
+        let start = Instant::now();
+        let threshold = Some(10 * MINUTE);
+        let mut coding = |minutes: u32| session.activity(start + minutes * MINUTE, threshold);
+
+        assert_eq!((0..10).find_map(&mut coding), None);
+        assert_eq!(coding(10), Some(10 * MINUTE));
+        assert_eq!(coding(14), None);
+        assert_eq!(coding(18), None);
+        assert_eq!(coding(20), Some(20 * MINUTE));
+    }
+
+    #[test]
+    fn a_break_restarts_the_time_until_a_reminder() {
+        let mut session = Session::default();
+        let start = Instant::now();
+        let threshold = Some(10 * MINUTE);
+
+        session.activity(start, threshold);
+        assert_eq!(session.activity(start + 6 * MINUTE, threshold), None);
+        assert_eq!(session.activity(start + 12 * MINUTE, threshold), None);
+        assert_eq!(
+            session.activity(start + 22 * MINUTE, threshold),
+            None,
+            "more than {IDLE:?} since the last activity"
+        );
+    }
+
+    #[test]
+    fn snoozed_reminders_wait() {
+        let mut session = Session::default();
+        let start = Instant::now();
+        let threshold = Some(10 * MINUTE);
+
+        session.activity(start, threshold);
+        session.snooze(start, 15 * MINUTE);
+        assert_eq!(session.activity(start + 4 * MINUTE, threshold), None);
+        assert_eq!(session.activity(start + 8 * MINUTE, threshold), None);
+        assert_eq!(session.activity(start + 12 * MINUTE, threshold), None);
+        assert_eq!(
+            session.activity(start + 15 * MINUTE, threshold),
+            Some(15 * MINUTE)
+        );
+    }
+}
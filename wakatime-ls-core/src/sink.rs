@@ -0,0 +1,25 @@
+use std::sync::{Arc, Mutex};
+
+use crate::heartbeat::Heartbeat;
+
+// Keeps heartbeats in memory instead of sending them with wakatime-cli, for
+// tests to check what would have been sent. Clones share the heartbeats.
+#[derive(Debug, Clone, Default)]
+pub struct RecordingSink {
+    heartbeats: Arc<Mutex<Vec<Heartbeat>>>,
+}
+
+impl RecordingSink {
+    pub fn record(&self, heartbeat: &Heartbeat) {
+        if let Ok(mut heartbeats) = self.heartbeats.lock() {
+            heartbeats.push(heartbeat.clone());
+        }
+    }
+
+    pub fn heartbeats(&self) -> Vec<Heartbeat> {
+        self.heartbeats
+            .lock()
+            .map(|heartbeats| heartbeats.clone())
+            .unwrap_or_default()
+    }
+}
@@ -0,0 +1,86 @@
+use std::sync::Mutex;
+
+use chrono::{DateTime, TimeDelta, Utc};
+
+// How far this machine's clock may be from the API's before heartbeats are
+// at risk: the API files them under the wrong minutes, or drops those that
+// seem to come from the future. The `Date` header only has whole seconds.
+pub const THRESHOLD: TimeDelta = TimeDelta::seconds(60);
+
+// The API's time minus this machine's, from the last response of the API
+// with a `Date` header, shared by every server in the process.
+static SKEW: Mutex<Option<TimeDelta>> = Mutex::new(None);
+
+// Compare the `Date` header of a response with the local time it was
+// received at.
+//
+// Example:
+// "Wed, 14 Oct 2026 13:05:39 GMT" received at 13:00:39 UTC -> the API is 5 minutes ahead
+pub fn record(date: &str, received: DateTime<Utc>) {
+    let Some(measured) = measure(date, received) else {
+        return;
+    };
+    if let Ok(mut skew) = SKEW.lock() {
+        *skew = Some(measured);
+    }
+}
+
+fn measure(date: &str, received: DateTime<Utc>) -> Option<TimeDelta> {
+    let date = DateTime::parse_from_rfc2822(date).ok()?;
+    Some(date.with_timezone(&Utc) - received)
+}
+
+// How much to add to the local time to get the API's, when it is more than
+// `THRESHOLD` away.
+pub fn offset() -> Option<TimeDelta> {
+    let skew = (*SKEW.lock().ok()?)?;
+    (skew.abs() > THRESHOLD).then_some(skew)
+}
+
+// Example:
+// TimeDelta::minutes(-5) -> "this machine's clock is 5 minutes ahead of the API's"
+pub fn describe(offset: TimeDelta) -> String {
+    let seconds = offset.num_seconds().abs();
+    let amount = match seconds {
+        0..120 => format!("{seconds} seconds"),
+        120..7200 => format!("{} minutes", (seconds + 30) / 60),
+        _ => format!("{} hours", (seconds + 1800) / 3600),
+    };
+    let direction = if offset < TimeDelta::zero() {
+        "ahead of"
+    } else {
+        "behind"
+    };
+
+    format!("this machine's clock is {amount} {direction} the API's")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn measured_from_the_date_header() {
+        let received = DateTime::parse_from_rfc3339("2026-10-14T13:00:39Z")
+            .unwrap()
+            .with_timezone(&Utc);
+
+        assert_eq!(
+            measure("Wed, 14 Oct 2026 13:05:39 GMT", received),
+            Some(TimeDelta::minutes(5))
+        );
+        assert_eq!(measure("yesterday", received), None);
+    }
+
+    #[test]
+    fn described_from_this_machine() {
+        assert_eq!(
+            describe(TimeDelta::minutes(-5)),
+            "this machine's clock is 5 minutes ahead of the API's"
+        );
+        assert_eq!(
+            describe(TimeDelta::seconds(90)),
+            "this machine's clock is 90 seconds behind the API's"
+        );
+    }
+}
@@ -0,0 +1,37 @@
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use chrono::{DateTime, Local};
+
+use crate::heartbeat::Heartbeat;
+
+// Tells apart heartbeats written in the same millisecond.
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+// Write a heartbeat about to be sent to the `heartbeat_spool` directory, one
+// file each in a folder per day, to compare with the dashboard when time seems
+// to be missing from it. Nothing is ever deleted from there.
+//
+// Example:
+// ~/wakatime-spool/2026-10-14/150539.482-7.json
+pub fn write(dir: &Path, heartbeat: &Heartbeat, now: DateTime<Local>) -> io::Result<PathBuf> {
+    let day = dir.join(now.format("%Y-%m-%d").to_string());
+    fs::create_dir_all(&day)?;
+
+    let sequence = SEQUENCE.fetch_add(1, Ordering::Relaxed);
+    let path = day.join(format!("{}-{sequence}.json", now.format("%H%M%S%.3f")));
+
+    let mut content = serde_json::to_string_pretty(&heartbeat.to_json())?;
+    content.push('\n');
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path)?
+        .write_all(content.as_bytes())?;
+
+    Ok(path)
+}
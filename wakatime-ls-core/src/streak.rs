@@ -0,0 +1,134 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Days, Local, NaiveDate, NaiveTime};
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+
+// Consecutive days with at least one heartbeat, kept on this machine across
+// restarts. Only the last day with activity and the number of days in a row
+// up to it are needed.
+//
+// Example:
+// {"last_day":"2026-10-13","days":5} -> a 5 day streak, which ends tonight
+//                                       without coding on 2026-10-14
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Streak {
+    pub last_day: Option<NaiveDate>,
+    pub days: u32,
+}
+
+pub fn path() -> Option<PathBuf> {
+    Some(
+        config::wakatime_home()?
+            .join(".wakatime")
+            .join("zed-wakatime-streak.json"),
+    )
+}
+
+impl Streak {
+    // A missing or unreadable file is no streak yet.
+    pub fn load(path: &Path) -> Self {
+        fs::read_to_string(path)
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, serde_json::to_string(self)?)
+    }
+
+    // Count activity on `today`, returning whether that changed the streak,
+    // which is only the first time on each day.
+    pub fn record(&mut self, today: NaiveDate) -> bool {
+        if self.last_day == Some(today) {
+            return false;
+        }
+
+        self.days = if self.last_day == today.pred_opt() {
+            self.days + 1
+        } else {
+            1
+        };
+        self.last_day = Some(today);
+        true
+    }
+
+    // The streak as of `today`: still going while yesterday was the last
+    // day with activity, since today isn't over.
+    pub fn current(&self, today: NaiveDate) -> u32 {
+        match self.last_day {
+            Some(day) if day == today || Some(day) == today.pred_opt() => self.days,
+            _ => 0,
+        }
+    }
+
+    pub fn is_active(&self, today: NaiveDate) -> bool {
+        self.last_day == Some(today)
+    }
+}
+
+// The first time after `now` the clock shows `time`.
+pub fn next_at(time: NaiveTime, now: DateTime<Local>) -> DateTime<Local> {
+    (0..=2)
+        .filter_map(|days| now.date_naive().checked_add_days(Days::new(days)))
+        .filter_map(|date| date.and_time(time).and_local_timezone(Local).earliest())
+        .find(|at| *at > now)
+        .unwrap_or(now + Days::new(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn day(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2026, 10, day).unwrap()
+    }
+
+    #[test]
+    fn consecutive_days_extend_the_streak() {
+        let mut streak = Streak::default();
+
+        assert!(streak.record(day(12)));
+        assert!(!streak.record(day(12)));
+        assert!(streak.record(day(13)));
+        assert_eq!(streak.days, 2);
+
+        // A day without coding ends it.
+        assert!(streak.record(day(15)));
+        assert_eq!(streak.days, 1);
+    }
+
+    #[test]
+    fn a_streak_lasts_until_the_end_of_the_next_day() {
+        let streak = Streak {
+            last_day: Some(day(13)),
+            days: 5,
+        };
+
+        assert_eq!(streak.current(day(13)), 5);
+        assert!(streak.is_active(day(13)));
+        assert_eq!(streak.current(day(14)), 5);
+        assert!(!streak.is_active(day(14)));
+        assert_eq!(streak.current(day(15)), 0);
+    }
+
+    #[test]
+    fn the_warning_is_due_today_until_its_time_passed() {
+        let time = NaiveTime::from_hms_opt(20, 0, 0).unwrap();
+        let at = |day, hour| Local.with_ymd_and_hms(2026, 10, day, hour, 0, 0).unwrap();
+
+        assert_eq!(next_at(time, at(14, 9)), at(14, 20));
+        assert_eq!(next_at(time, at(14, 20)), at(15, 20));
+        assert_eq!(next_at(time, at(14, 23)), at(15, 20));
+    }
+}
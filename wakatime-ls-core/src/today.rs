@@ -0,0 +1,152 @@
+#[cfg(feature = "http")]
+use std::time::Duration;
+
+use serde_json::Value;
+
+use crate::{
+    api::{self, Api},
+    budget,
+    cli::Cli,
+};
+
+// How long today's coding time from the API is reused, whatever the status bar
+// refresh interval.
+#[cfg(feature = "http")]
+const CACHE_TTL: Duration = Duration::from_secs(60);
+
+// Today's coding time, as `wakatime-cli --today` and the WakaTime status bar
+// show it, in total and for each project and language.
+//
+// Example:
+// total = 1 hr 23 mins (4980 seconds), projects = [("zed-hackatime", 52 mins), ...],
+// languages = [("Rust", 1 hr 10 mins), ("YAML", 13 mins)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Today {
+    pub total: Total,
+    pub projects: Vec<(String, Total)>,
+    pub languages: Vec<(String, Total)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Total {
+    pub text: String,
+    pub total_seconds: u64,
+}
+
+impl Total {
+    // A `text` and its `total_seconds`, as found in most of the API's
+    // responses.
+    pub fn parse(total: &Value) -> Option<Self> {
+        Some(Self {
+            text: total["text"].as_str()?.to_string(),
+            total_seconds: total["total_seconds"].as_f64()? as u64,
+        })
+    }
+
+    // Hours and minutes on a clock, such as `1:23`.
+    pub fn digital(&self) -> String {
+        let minutes = self.total_seconds / 60;
+        format!("{}:{:02}", minutes / 60, minutes % 60)
+    }
+}
+
+impl Today {
+    // From a `statusbar/today` response, which wakatime-cli prints as is with
+    // `--output raw-json`.
+    fn parse(body: &Value) -> Option<Self> {
+        let data = &body["data"];
+
+        Some(Self {
+            total: Total::parse(&data["grand_total"])?,
+            projects: named_totals(&data["projects"]),
+            languages: named_totals(&data["languages"]),
+        })
+    }
+
+    // Fill in a status bar template. `{project}` and `{project_digital}` are
+    // the time spent today on `project`, 0 when there is none.
+    //
+    // Example, with project = "zed-hackatime":
+    // "{total} ({project} here)" -> "1 hr 23 mins (52 mins here)"
+    // "{digital}"                -> "1:23"
+    pub fn render(&self, template: &str, project: Option<&str>) -> String {
+        let nothing = Total {
+            text: "0 mins".to_string(),
+            total_seconds: 0,
+        };
+        let project = project
+            .and_then(|project| self.projects.iter().find(|(name, _)| name == project))
+            .map_or(&nothing, |(_, total)| total);
+
+        template
+            .replace("{total}", &self.total.text)
+            .replace("{digital}", &self.total.digital())
+            .replace("{project}", &project.text)
+            .replace("{project_digital}", &project.digital())
+    }
+}
+
+// The `name` and total of each entry of a list such as `projects` or
+// `languages`, none when it is missing.
+pub fn named_totals(list: &Value) -> Vec<(String, Total)> {
+    list.as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| Some((entry["name"].as_str()?.to_string(), Total::parse(entry)?)))
+        .collect()
+}
+
+// Asks the API when it has a key, or else wakatime-cli, which can also get
+// keys from a command such as a password manager. Builds without the `http`
+// feature always ask wakatime-cli.
+pub async fn fetch(api: &Api, wakatime_cli: Option<&Cli>) -> Result<Today, String> {
+    match (&api.key, wakatime_cli) {
+        #[cfg(feature = "http")]
+        (Some(_), _) => from_api(api).await,
+        (_, Some(cli)) => from_cli(cli).await,
+        (None, None) => Err("no API key and no wakatime-cli to ask".to_string()),
+        #[cfg(not(feature = "http"))]
+        (Some(_), None) => Err("no wakatime-cli to ask".to_string()),
+    }
+}
+
+#[cfg(feature = "http")]
+async fn from_api(api: &Api) -> Result<Today, String> {
+    let (status, body) = api
+        .get_cached("users/current/statusbar/today", CACHE_TTL)
+        .await?;
+    if !(200..300).contains(&status) {
+        return Err(format!("{} answered HTTP {status}", api.url));
+    }
+
+    Today::parse(&body).ok_or_else(|| format!("{} answered without today's total", api.url))
+}
+
+async fn from_cli(wakatime_cli: &Cli) -> Result<Today, String> {
+    if !budget::try_acquire() {
+        return Err(api::over_budget());
+    }
+
+    let mut command = wakatime_cli.command();
+    command.args(["--today", "--output", "raw-json"]);
+
+    let output = command
+        .output()
+        .await
+        .map_err(|err| format!("could not run wakatime-cli {wakatime_cli}: {err}"))?;
+    let stdout = String::from_utf8_lossy(&output.stdout).trim().to_string();
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!(
+            "wakatime-cli --today failed: {}",
+            [stdout.as_str(), stderr.trim()].join(" ").trim()
+        ));
+    }
+
+    serde_json::from_str(&stdout)
+        .ok()
+        .as_ref()
+        .and_then(Today::parse)
+        .ok_or_else(|| "wakatime-cli --today printed no total".to_string())
+}
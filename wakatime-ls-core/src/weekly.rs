@@ -0,0 +1,108 @@
+use chrono::{DateTime, Datelike, Days, Local, NaiveTime, Weekday};
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "http")]
+use serde_json::Value;
+
+#[cfg(feature = "http")]
+use crate::api::Api;
+
+// When the weekly summary is delivered, every week on the same day at the same
+// local time.
+//
+// Example:
+// ("friday", "17:30") -> every Friday at 17:30
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Schedule {
+    pub weekday: Weekday,
+    pub time: NaiveTime,
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Self {
+            weekday: Weekday::Mon,
+            time: NaiveTime::from_hms_opt(9, 0, 0).unwrap(),
+        }
+    }
+}
+
+impl Schedule {
+    // `mon` or `monday`, in any case.
+    pub fn parse_weekday(value: &str) -> Option<Weekday> {
+        value.trim().parse().ok()
+    }
+
+    // `HH:MM` on a 24-hour clock.
+    pub fn parse_time(value: &str) -> Option<NaiveTime> {
+        NaiveTime::parse_from_str(value.trim(), "%H:%M").ok()
+    }
+
+    // The first time after `now` the summary is due. A time skipped by a
+    // daylight saving change falls on the next week.
+    pub fn next_after(&self, now: DateTime<Local>) -> DateTime<Local> {
+        (0..=14)
+            .filter_map(|days| now.date_naive().checked_add_days(Days::new(days)))
+            .filter(|date| date.weekday() == self.weekday)
+            .filter_map(|date| {
+                date.and_time(self.time)
+                    .and_local_timezone(Local)
+                    .earliest()
+            })
+            .find(|at| *at > now)
+            .unwrap_or(now + Days::new(7))
+    }
+}
+
+// The last 7 days of coding, from the `stats/last_7_days` endpoint.
+//
+// Example:
+// "You coded 14 hrs 3 mins this week, top language Rust"
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WeeklySummary {
+    pub text: String,
+    pub total_seconds: u64,
+    pub top_language: Option<String>,
+}
+
+impl WeeklySummary {
+    #[cfg(feature = "http")]
+    fn parse(body: &Value) -> Option<Self> {
+        let data = &body["data"];
+
+        // Languages come sorted by time, the most used first.
+        let top_language = data["languages"]
+            .as_array()
+            .and_then(|languages| languages.first())
+            .and_then(|language| language["name"].as_str())
+            .map(str::to_string);
+
+        Some(Self {
+            text: data["human_readable_total"].as_str()?.to_string(),
+            total_seconds: data["total_seconds"].as_f64()? as u64,
+            top_language,
+        })
+    }
+
+    pub fn message(&self) -> String {
+        match &self.top_language {
+            Some(language) => format!("You coded {} this week, top language {language}", self.text),
+            None => format!("You coded {} this week", self.text),
+        }
+    }
+}
+
+// Needs an API key, as wakatime-cli has no way to ask for stats.
+#[cfg(feature = "http")]
+pub async fn fetch(api: &Api) -> Result<WeeklySummary, String> {
+    if api.key.is_none() {
+        return Err("no API key to ask for the last 7 days".to_string());
+    }
+
+    let (status, body) = api.get("users/current/stats/last_7_days").await?;
+    if !(200..300).contains(&status) {
+        return Err(format!("{} answered HTTP {status}", api.url));
+    }
+
+    WeeklySummary::parse(&body)
+        .ok_or_else(|| format!("{} answered without the last 7 days' total", api.url))
+}
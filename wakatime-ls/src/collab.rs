@@ -0,0 +1,29 @@
+use std::path::Path;
+
+// Zed's collaboration buffers, such as the notes of a channel, have `zed://`
+// URIs rather than files, which wakatime-cli would skip as missing. They are
+// reported as app activity in a project named after their channel.
+pub const SCHEME: &str = "zed";
+
+// The project of a channel's buffers, unless `collab_project` names another.
+pub const DEFAULT_PROJECT: &str = "{channel}";
+
+// The channel of a collaboration buffer, from its entity.
+//
+// Example:
+// zed://channel/design review/notes -> design review
+// /home/alice/src/app/main.rs       -> None
+pub fn channel(entity: &Path) -> Option<&str> {
+    entity
+        .to_str()?
+        .strip_prefix("zed://channel/")?
+        .split('/')
+        .next()
+        .filter(|channel| !channel.is_empty())
+}
+
+// Example, with `collab_project` set to "collab-{channel}":
+// design review -> collab-design review
+pub fn project(template: &str, channel: &str) -> String {
+    template.replace("{channel}", channel)
+}
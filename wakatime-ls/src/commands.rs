@@ -0,0 +1,697 @@
+use std::{
+    io,
+    path::{self, Path, PathBuf},
+    process::ExitCode,
+};
+
+use chrono::{Local, NaiveDate};
+use clap::{value_parser, Arg, ArgAction, ArgMatches, Command};
+use tokio::io::AsyncWriteExt;
+
+#[cfg(feature = "http")]
+use wakatime_ls_core::skew;
+use wakatime_ls_core::{
+    activity::{self, ExportParams, Format},
+    api::{self, Api},
+    cli::{self, Cli},
+    config::{self, WakatimeConfig},
+    heartbeat::{self, Heartbeat, SendOptions},
+    paths,
+    project::{Project, ProjectCache},
+    today,
+};
+
+use crate::{detection_options, plugin, Settings};
+
+// `wakatime-ls heartbeat`: send a single heartbeat, for scripts and for events
+// the editor doesn't report over LSP.
+//
+// Example:
+// wakatime-ls heartbeat --entity src/main.rs --write --lineno 12
+pub fn heartbeat_command() -> Command {
+    Command::new("heartbeat")
+        .about("Send a single heartbeat and exit with wakatime-cli's exit code")
+        .arg(
+            Arg::new("entity")
+                .long("entity")
+                .required(true)
+                .help("File the heartbeat is for, or an app or domain name with --entity-type"),
+        )
+        .arg(
+            Arg::new("entity-type")
+                .long("entity-type")
+                .value_parser(["file", "app", "domain"])
+                .help("Kind of entity (default: file)"),
+        )
+        .arg(
+            Arg::new("category")
+                .long("category")
+                .help("Activity category, e.g. debugging (default: coding)"),
+        )
+        .arg(
+            Arg::new("write")
+                .long("write")
+                .action(ArgAction::SetTrue)
+                .help("The file was saved"),
+        )
+        .arg(
+            Arg::new("language")
+                .long("language")
+                .help("Language of the file (default: guessed by wakatime-cli)"),
+        )
+        .arg(
+            Arg::new("project")
+                .long("project")
+                .help("Project name (default: detected like the language server does)"),
+        )
+        .arg(
+            Arg::new("lineno")
+                .long("lineno")
+                .value_parser(value_parser!(u64))
+                .help("Line number of the cursor"),
+        )
+        .arg(
+            Arg::new("cursorpos")
+                .long("cursorpos")
+                .value_parser(value_parser!(u64))
+                .help("Column of the cursor"),
+        )
+        .arg(
+            Arg::new("plugin")
+                .long("plugin")
+                .help("Editor and plugin sending the heartbeat"),
+        )
+}
+
+// Files are attributed to projects with the rules of `~/.wakatime.cfg`, as the
+// language server does without LSP settings. The API key and URL are left for
+// wakatime-cli to read from there or from `WAKATIME_API_KEY`.
+pub async fn heartbeat(matches: &ArgMatches, wakatime_cli: Option<Cli>) -> ExitCode {
+    let wakatime_cli = match wakatime_cli {
+        Some(cli) => cli,
+        None => match find_or_install().await {
+            Ok(cli) => cli,
+            Err(err) => {
+                eprintln!("wakatime-ls: {err}");
+                return ExitCode::FAILURE;
+            }
+        },
+    };
+
+    let entity_type = matches.get_one::<String>("entity-type").cloned();
+    let is_file = entity_type.as_deref().is_none_or(|kind| kind == "file");
+
+    let mut entity = PathBuf::from(matches.get_one::<String>("entity").unwrap());
+    if is_file {
+        entity = paths::normalize(path::absolute(&entity).unwrap_or(entity));
+    }
+
+    let project = if is_file {
+        let config = WakatimeConfig::load().unwrap_or_else(|err| {
+            eprintln!("wakatime-ls: could not read wakatime config: {err}");
+            WakatimeConfig::default()
+        });
+
+        let (options, errors) = detection_options(&Settings::default(), &config);
+        for err in errors {
+            eprintln!("wakatime-ls: ignoring invalid projectmap pattern: {err}");
+        }
+
+        let mut project_cache = ProjectCache::default();
+        project_cache.configure(options, Vec::new());
+        project_cache.resolve(&entity)
+    } else {
+        None
+    };
+
+    // An explicit name keeps the detected branch and folder.
+    let project = match (matches.get_one::<String>("project"), project) {
+        (Some(name), Some(project)) => Some(Project {
+            name: name.clone(),
+            ..project
+        }),
+        (Some(name), None) => Some(Project {
+            name: name.clone(),
+            branch: None,
+            root: None,
+        }),
+        (None, project) => project,
+    };
+
+    let language = matches.get_one::<String>("language").cloned();
+    let mut heartbeat = Heartbeat::new(entity, language, project, None);
+    if !is_file {
+        heartbeat.entity_type = entity_type;
+    }
+    if let Some(category) = matches.get_one::<String>("category") {
+        heartbeat.category = Some(category.clone());
+    }
+    heartbeat.time = Local::now().timestamp() as f64;
+    heartbeat.is_write = matches.get_flag("write");
+    heartbeat.lineno = matches.get_one::<u64>("lineno").copied();
+    heartbeat.cursor_pos = matches.get_one::<u64>("cursorpos").copied();
+    heartbeat.lines_in_file = is_file
+        .then(|| std::fs::read_to_string(&heartbeat.entity).ok())
+        .flatten()
+        .map(|content| content.lines().count() as u64);
+    heartbeat.plugin = Some(match matches.get_one::<String>("plugin") {
+        Some(plugin) => plugin.clone(),
+        None => plugin(
+            &format!("Zed Zed-hackatime/{}", env!("CARGO_PKG_VERSION")),
+            None,
+        ),
+    });
+
+    // wakatime-cli explains failures itself on stdout and stderr.
+    let status = match heartbeat.command(&wakatime_cli, SendOptions::default()) {
+        Ok(mut command) => command.status().await,
+        Err(err) => Err(err),
+    };
+    match status {
+        Ok(status) => match status.code() {
+            Some(code) => ExitCode::from(u8::try_from(code).unwrap_or(1)),
+            None => ExitCode::FAILURE,
+        },
+        Err(err) => {
+            eprintln!("wakatime-ls: could not run wakatime-cli {wakatime_cli}: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+// The wakatime-cli the language server would use without `-p`, downloading it
+// when none is installed.
+async fn find_or_install() -> Result<Cli, String> {
+    if let Some(cli) = cli::find().await {
+        return Ok(cli);
+    }
+
+    install().await
+}
+
+#[cfg(feature = "http")]
+async fn install() -> Result<Cli, String> {
+    eprintln!("wakatime-ls: wakatime-cli not found, downloading it");
+    cli::install()
+        .await
+        .map_err(|err| format!("could not install wakatime-cli: {err}"))
+}
+
+#[cfg(not(feature = "http"))]
+async fn install() -> Result<Cli, String> {
+    Err("wakatime-cli not found, and this build of wakatime-ls can't download it".to_string())
+}
+
+// `wakatime-ls doctor`: check what most often keeps heartbeats from arriving.
+pub fn doctor_command() -> Command {
+    Command::new("doctor")
+        .about("Check wakatime-cli, the wakatime config, the API key and the connection to the API")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Ok,
+    Warning,
+    Error,
+}
+
+struct Report {
+    failed: bool,
+}
+
+impl Report {
+    fn check(&mut self, status: Status, name: &str, detail: impl std::fmt::Display) {
+        let label = match status {
+            Status::Ok => "ok",
+            Status::Warning => "warning",
+            Status::Error => {
+                self.failed = true;
+                "error"
+            }
+        };
+
+        println!("{label:<8} {name:<13} {detail}");
+    }
+}
+
+// Only `~/.wakatime.cfg` and the environment are known here, not the settings
+// Zed passes to the language server.
+pub async fn doctor(wakatime_cli: Option<Cli>) -> ExitCode {
+    let mut report = Report { failed: false };
+
+    let wakatime_cli = match wakatime_cli {
+        Some(cli) => Some(cli),
+        None => cli::find().await,
+    };
+    match wakatime_cli {
+        Some(cli) => match cli.version().await {
+            Some(version) if cli::is_supported(&version) => {
+                report.check(Status::Ok, "wakatime-cli", format!("{cli}, {version}"));
+            }
+            Some(version) => report.check(
+                Status::Warning,
+                "wakatime-cli",
+                format!(
+                    "{cli}, {version} is older than {}, the minimum supported release",
+                    cli::MINIMUM_VERSION
+                ),
+            ),
+            None => report.check(
+                Status::Error,
+                "wakatime-cli",
+                format!("{cli} could not be run"),
+            ),
+        },
+        None => {
+            let install_path = cli::install_path()
+                .map_or("~/.wakatime".to_string(), |path| path.display().to_string());
+            report.check(
+                Status::Warning,
+                "wakatime-cli",
+                format!("not found; the language server downloads it to {install_path}"),
+            );
+        }
+    }
+
+    let config_path = config::config_path();
+    let config = match config_path.as_deref().map(std::fs::read_to_string) {
+        Some(Ok(content)) => {
+            report.check(Status::Ok, "config", config_path.unwrap().display());
+            WakatimeConfig::parse(&content)
+        }
+        Some(Err(err)) if err.kind() == io::ErrorKind::NotFound => {
+            report.check(
+                Status::Warning,
+                "config",
+                format!("{} does not exist", config_path.unwrap().display()),
+            );
+            WakatimeConfig::default()
+        }
+        Some(Err(err)) => {
+            report.check(
+                Status::Error,
+                "config",
+                format!(
+                    "{} could not be read: {err}",
+                    config_path.unwrap().display()
+                ),
+            );
+            WakatimeConfig::default()
+        }
+        None => {
+            report.check(Status::Error, "config", "could not find the home directory");
+            WakatimeConfig::default()
+        }
+    };
+
+    let (_, errors) = detection_options(&Settings::default(), &config);
+    for err in errors {
+        report.check(
+            Status::Warning,
+            "projectmap",
+            format!(
+                "invalid pattern: {}",
+                err.to_string()
+                    .split_whitespace()
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            ),
+        );
+    }
+
+    let api = Api::resolve(None, None, &config);
+    match api.key {
+        Some(ref key) if api::is_uuid_key(key) => report.check(Status::Ok, "api key", "set"),
+        Some(_) => report.check(
+            Status::Warning,
+            "api key",
+            "set, but not a UUID like WakaTime's, which some wakatime-cli releases refuse as an invalid format",
+        ),
+        None => report.check(
+            Status::Error,
+            "api key",
+            "not set in the config or WAKATIME_API_KEY (the api-key setting in Zed isn't checked here)",
+        ),
+    }
+
+    #[cfg(not(feature = "http"))]
+    report.check(
+        Status::Warning,
+        "network",
+        "not checked, as this build of wakatime-ls can't ask the API",
+    );
+    #[cfg(feature = "http")]
+    match api.get("users/current/statusbar/today").await {
+        Ok((status, _)) => {
+            report.check(Status::Ok, "network", format!("{} is reachable", api.url));
+            if let Some(offset) = skew::offset() {
+                report.check(Status::Warning, "clock", skew::describe(offset));
+            }
+
+            match status {
+                _ if api.key.is_none() => {}
+                200..=299 => report.check(Status::Ok, "api key", "accepted by the API"),
+                401 | 403 => report.check(Status::Error, "api key", "rejected by the API"),
+                status => report.check(
+                    Status::Warning,
+                    "api key",
+                    format!("could not be checked, the API answered HTTP {status}"),
+                ),
+            }
+        }
+        Err(err) => report.check(Status::Error, "network", err),
+    }
+
+    // wakatime-cli keeps heartbeats it couldn't send there.
+    match config::wakatime_home().map(|home| home.join(".wakatime")) {
+        Some(dir) => match check_writable(&dir) {
+            Ok(()) => report.check(Status::Ok, "queue", dir.display()),
+            Err(err) => report.check(
+                Status::Error,
+                "queue",
+                format!("{} is not writable: {err}", dir.display()),
+            ),
+        },
+        None => report.check(Status::Error, "queue", "could not find the home directory"),
+    }
+
+    if report.failed {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+// A folder that doesn't exist yet is created by wakatime-cli, so the closest
+// existing parent is checked instead.
+fn check_writable(dir: &Path) -> io::Result<()> {
+    let dir = dir
+        .ancestors()
+        .find(|dir| dir.exists())
+        .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+
+    let probe = dir.join(format!(".wakatime-ls-doctor-{}", std::process::id()));
+    std::fs::write(&probe, b"")?;
+    std::fs::remove_file(&probe)
+}
+
+// `wakatime-ls queue`: move the heartbeats wakatime-cli couldn't send yet to
+// another machine, or keep them aside before reinstalling.
+//
+// Example:
+// $ wakatime-ls queue export -o queue.json
+// $ wakatime-ls queue import queue.json
+pub fn queue_command() -> Command {
+    Command::new("queue")
+        .about("Export or import wakatime-cli's queue of heartbeats waiting to be sent")
+        .subcommand_required(true)
+        .subcommand(
+            Command::new("export")
+                .about("Print or write the queued heartbeats as a JSON array")
+                .arg(
+                    Arg::new("output")
+                        .long("output")
+                        .short('o')
+                        .value_name("PATH")
+                        .help("Write the heartbeats to this file instead of printing them"),
+                ),
+        )
+        .subcommand(
+            Command::new("import")
+                .about("Send the heartbeats of a JSON array, queueing them again while offline")
+                .arg(
+                    Arg::new("path")
+                        .required(true)
+                        .help("File written by queue export, or - for stdin"),
+                ),
+        )
+}
+
+pub async fn queue(matches: &ArgMatches, wakatime_cli: Option<Cli>) -> ExitCode {
+    let wakatime_cli = match wakatime_cli {
+        Some(cli) => cli,
+        None => match find_or_install().await {
+            Ok(cli) => cli,
+            Err(err) => {
+                eprintln!("wakatime-ls: {err}");
+                return ExitCode::FAILURE;
+            }
+        },
+    };
+
+    let result = match matches.subcommand() {
+        Some(("export", matches)) => {
+            export_queue(&wakatime_cli, matches.get_one::<String>("output")).await
+        }
+        Some(("import", matches)) => {
+            import_queue(&wakatime_cli, matches.get_one::<String>("path").unwrap()).await
+        }
+        _ => unreachable!("a queue subcommand is required"),
+    };
+
+    match result {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("wakatime-ls: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+// wakatime-cli prints its queue without removing anything from it.
+async fn export_queue(wakatime_cli: &Cli, output: Option<&String>) -> Result<ExitCode, String> {
+    let run = |args: Vec<String>| async move {
+        let output = wakatime_cli
+            .command()
+            .args(&args)
+            .output()
+            .await
+            .map_err(|err| format!("could not run wakatime-cli {wakatime_cli}: {err}"))?;
+        if !output.status.success() {
+            return Err(format!(
+                "wakatime-cli {} failed: {}",
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    };
+
+    let count = run(vec!["--offline-count".to_string()]).await?;
+    let count: u64 = count.parse().map_err(|_| {
+        format!("wakatime-cli printed {count:?} as the number of queued heartbeats")
+    })?;
+
+    let heartbeats: Vec<serde_json::Value> = if count == 0 {
+        Vec::new()
+    } else {
+        let printed = run(vec![
+            "--print-offline-heartbeats".to_string(),
+            count.to_string(),
+        ])
+        .await?;
+        serde_json::from_str(&printed)
+            .map_err(|err| format!("wakatime-cli printed a queue that isn't a JSON array: {err}"))?
+    };
+    let export = serde_json::to_string_pretty(&heartbeats).unwrap_or_default() + "\n";
+
+    match output {
+        Some(output) => {
+            // The queue tells every file worked on while offline.
+            let output = paths::canonicalize(Path::new(output));
+            paths::write_private(&output, export.as_bytes())
+                .map_err(|err| format!("could not write {}: {err}", output.display()))?;
+            eprintln!(
+                "wakatime-ls: wrote {} heartbeats to {}",
+                heartbeats.len(),
+                output.display()
+            );
+        }
+        None => print!("{export}"),
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+// Sent like any heartbeat, the first one on wakatime-cli's command line and
+// the others as `--extra-heartbeats` on its stdin, so those that still can't
+// be sent are queued again. They are marked unsaved, as the files they are for
+// may not exist on this machine.
+async fn import_queue(wakatime_cli: &Cli, path: &str) -> Result<ExitCode, String> {
+    let content = if path == "-" {
+        io::read_to_string(io::stdin()).map_err(|err| format!("could not read stdin: {err}"))?
+    } else {
+        std::fs::read_to_string(path).map_err(|err| format!("could not read {path}: {err}"))?
+    };
+    let mut heartbeats: Vec<serde_json::Value> = serde_json::from_str(&content)
+        .map_err(|err| format!("{path} is not a JSON array of heartbeats: {err}"))?;
+
+    if heartbeats.is_empty() {
+        eprintln!("wakatime-ls: no heartbeats to import");
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    // Exports of several machines, or of a queue that was also sent live,
+    // are neither in order nor free of repeats.
+    let repeats = heartbeat::order_batch(&mut heartbeats);
+    if repeats > 0 {
+        eprintln!("wakatime-ls: left out {repeats} repeated heartbeats");
+    }
+
+    let first = heartbeats.remove(0);
+    let heartbeat =
+        Heartbeat::from_json(&first).ok_or("the first heartbeat has no entity or time")?;
+    for extra in &mut heartbeats {
+        if let Some(extra) = extra.as_object_mut() {
+            extra.insert("is_unsaved_entity".to_string(), true.into());
+        }
+    }
+
+    let mut command = heartbeat
+        .command(wakatime_cli, SendOptions::default())
+        .map_err(|err| format!("could not run wakatime-cli {wakatime_cli}: {err}"))?;
+    command.arg("--is-unsaved-entity");
+    if !heartbeats.is_empty() {
+        command
+            .arg("--extra-heartbeats")
+            .stdin(std::process::Stdio::piped());
+    }
+
+    let mut child = command
+        .spawn()
+        .map_err(|err| format!("could not run wakatime-cli {wakatime_cli}: {err}"))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        let extra = serde_json::to_vec(&heartbeats).unwrap_or_default();
+        stdin
+            .write_all(&extra)
+            .await
+            .map_err(|err| format!("could not give wakatime-cli the heartbeats: {err}"))?;
+    }
+
+    // wakatime-cli explains failures itself on stdout and stderr.
+    let status = child
+        .wait()
+        .await
+        .map_err(|err| format!("could not run wakatime-cli {wakatime_cli}: {err}"))?;
+    Ok(match status.code() {
+        Some(code) => ExitCode::from(u8::try_from(code).unwrap_or(1)),
+        None => ExitCode::FAILURE,
+    })
+}
+
+// `wakatime-ls today`: print today's coding time, for status bar scripts.
+//
+// Example:
+// $ wakatime-ls today
+// 1 hr 23 mins
+pub fn today_command() -> Command {
+    Command::new("today")
+        .about("Print today's total coding time")
+        .arg(
+            Arg::new("seconds")
+                .long("seconds")
+                .action(ArgAction::SetTrue)
+                .help("Print the total in seconds"),
+        )
+}
+
+// Asks the API with the key and URL wakatime-cli would use, or wakatime-cli
+// itself when no key is configured here.
+pub async fn today(matches: &ArgMatches, wakatime_cli: Option<Cli>) -> ExitCode {
+    let config = WakatimeConfig::load().unwrap_or_else(|err| {
+        eprintln!("wakatime-ls: could not read wakatime config: {err}");
+        WakatimeConfig::default()
+    });
+    let api = Api::resolve(None, None, &config);
+
+    let wakatime_cli = match wakatime_cli {
+        Some(cli) => Some(cli),
+        None if api.key.is_none() || !cfg!(feature = "http") => match find_or_install().await {
+            Ok(cli) => Some(cli),
+            Err(err) => {
+                eprintln!("wakatime-ls: {err}");
+                return ExitCode::FAILURE;
+            }
+        },
+        None => None,
+    };
+
+    match today::fetch(&api, wakatime_cli.as_ref()).await {
+        Ok(today) if matches.get_flag("seconds") => {
+            println!("{}", today.total.total_seconds);
+            ExitCode::SUCCESS
+        }
+        Ok(today) => {
+            println!("{}", today.total.text);
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("wakatime-ls: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+// `wakatime-ls export`: the heartbeats recorded with the `activity_log`
+// setting over a range of days, for invoicing or your own analysis.
+//
+// Example:
+// $ wakatime-ls export --from 2026-10-01 --to 2026-10-31 --format csv > october.csv
+pub fn export_command() -> Command {
+    Command::new("export")
+        .about("Print or write the heartbeats recorded with the activity_log setting")
+        .arg(
+            Arg::new("from")
+                .long("from")
+                .value_name("YYYY-MM-DD")
+                .value_parser(value_parser!(NaiveDate))
+                .help("First day to export (default: the --to day)"),
+        )
+        .arg(
+            Arg::new("to")
+                .long("to")
+                .value_name("YYYY-MM-DD")
+                .value_parser(value_parser!(NaiveDate))
+                .help("Last day to export (default: today)"),
+        )
+        .arg(
+            Arg::new("format")
+                .long("format")
+                .value_parser(["csv", "json"])
+                .help("Export as CSV or as a JSON array (default: json)"),
+        )
+        .arg(
+            Arg::new("output")
+                .long("output")
+                .short('o')
+                .value_name("PATH")
+                .help("Write the export to this file instead of printing it"),
+        )
+}
+
+pub fn export(matches: &ArgMatches) -> ExitCode {
+    let params = ExportParams {
+        from: matches.get_one::<NaiveDate>("from").copied(),
+        to: matches.get_one::<NaiveDate>("to").copied(),
+        format: matches
+            .get_one::<String>("format")
+            .and_then(|format| Format::parse(format))
+            .unwrap_or_default(),
+        path: matches.get_one::<String>("output").cloned(),
+    };
+
+    match activity::export_range(&params) {
+        Ok(output) if params.path.is_some() => {
+            eprintln!("wakatime-ls: wrote {output}");
+            ExitCode::SUCCESS
+        }
+        Ok(export) => {
+            print!("{export}");
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("wakatime-ls: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,101 @@
+use std::{
+    backtrace::Backtrace,
+    fmt::Write,
+    panic,
+    sync::{Arc, Mutex, Weak},
+};
+
+use wakatime_ls_core::recent::RecentHeartbeats;
+
+use crate::{
+    log::{entity_hash, Logger},
+    reports,
+};
+
+// The servers of this process, whose logs a panic goes to. A server is left
+// out once it is dropped, such as when its client disconnects from
+// `--listen`.
+static SERVERS: Mutex<Vec<Witness>> = Mutex::new(Vec::new());
+
+struct Witness {
+    logger: Logger,
+    recent: Weak<tokio::sync::Mutex<RecentHeartbeats>>,
+}
+
+pub fn watch(logger: Logger, recent: &Arc<tokio::sync::Mutex<RecentHeartbeats>>) {
+    if let Ok(mut servers) = SERVERS.lock() {
+        servers.retain(|server| server.recent.strong_count() > 0);
+        servers.push(Witness {
+            logger,
+            recent: Arc::downgrade(recent),
+        });
+    }
+}
+
+// Write panics of any thread to the log file and the client's log of every
+// server, with a backtrace and the last heartbeats it tried to send, after
+// the usual message on stderr. Zed only shows stderr as "server exited", so
+// this is what's left to look at in a bug report.
+pub fn install_panic_hook() {
+    let previous = panic::take_hook();
+
+    panic::set_hook(Box::new(move |info| {
+        previous(info);
+
+        // Locks are only tried, as the panic may have happened while this
+        // thread held one of them.
+        let Ok(servers) = SERVERS.try_lock() else {
+            return;
+        };
+        if servers.is_empty() {
+            return;
+        }
+
+        let backtrace = Backtrace::force_capture();
+        for server in servers.iter() {
+            let Some(recent) = server.recent.upgrade() else {
+                continue;
+            };
+            let report = describe(&reports::panic_message(info), &backtrace, &recent);
+            server.logger.write_panic(&report);
+        }
+    }));
+}
+
+// Example:
+// panicked: index out of bounds at wakatime-ls/src/main.rs:123
+// backtrace:
+//    0: wakatime_ls::WakatimeLanguageServer::prepare_event
+// ...
+// last heartbeats:
+// 2026-10-14T15:05:39.202+02:00 051f86b06e9229de sent in 212 ms
+fn describe(
+    message: &str,
+    backtrace: &Backtrace,
+    recent: &tokio::sync::Mutex<RecentHeartbeats>,
+) -> String {
+    let mut report = format!("panicked: {message}\nbacktrace:\n{backtrace}");
+
+    let Ok(recent) = recent.try_lock() else {
+        return report;
+    };
+    let attempts = recent.to_vec();
+    if attempts.is_empty() {
+        report.push_str("no heartbeats yet");
+        return report;
+    }
+
+    report.push_str("last heartbeats:");
+    for attempt in attempts {
+        write!(
+            report,
+            "\n{} {} {} in {} ms",
+            attempt.time.format("%Y-%m-%dT%H:%M:%S%.3f%:z"),
+            entity_hash(&attempt.entity),
+            attempt.outcome.as_str(),
+            attempt.latency_ms,
+        )
+        .ok();
+    }
+    report
+}
@@ -0,0 +1,79 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tower_lsp::lsp_types::notification::Notification;
+
+use crate::notification::{Focus, StatusBarText};
+#[cfg(feature = "http")]
+use crate::notification::{GoalProgress, WeeklySummaryNotification};
+
+// The custom requests this server answers, so an extension can tell which
+// ones it may send before sending them. Those asking the API are left out of
+// builds without the `http` feature.
+pub const REQUESTS: &[&str] = &[
+    "wakatime/status",
+    "wakatime/dumpRecent",
+    #[cfg(feature = "http")]
+    "wakatime/goals",
+    #[cfg(feature = "http")]
+    "wakatime/leaderboard",
+    #[cfg(feature = "http")]
+    "wakatime/durations",
+    "wakatime/projects",
+    "wakatime/languages",
+    "wakatime/session",
+];
+
+pub const NOTIFICATIONS: &[&str] = &[
+    StatusBarText::METHOD,
+    #[cfg(feature = "http")]
+    WeeklySummaryNotification::METHOD,
+    #[cfg(feature = "http")]
+    GoalProgress::METHOD,
+];
+
+// The custom notifications this server takes from the client.
+pub const CLIENT_NOTIFICATIONS: &[&str] = &[Focus::METHOD];
+
+// What the Zed extension that started this server says about itself in the
+// `extension` initialization option. Its features are the custom notifications
+// it handles; those it turns off are not sent, and every one is sent when it
+// leaves them out, as extensions that predate the option don't say either.
+//
+// Example:
+// "extension": {"version": "0.3.1", "features": {"wakatime/goalProgress": false}}
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct Extension {
+    pub version: Option<String>,
+    #[serde(default)]
+    pub features: HashMap<String, bool>,
+}
+
+impl Extension {
+    pub fn from_options(options: &Value) -> Self {
+        options
+            .get("extension")
+            .and_then(|extension| serde_json::from_value(extension.clone()).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn supports(&self, method: &str) -> bool {
+        self.features.get(method).copied().unwrap_or(true)
+    }
+}
+
+// Returned under `experimental` in the capabilities of the server.
+//
+// Example:
+// {"wakatime": {"version": "0.3.1", "requests": ["wakatime/status", ...], "notifications": ["wakatime/statusBarText", ...], "client_notifications": ["wakatime/focus"]}}
+pub fn capabilities() -> Value {
+    json!({
+        "wakatime": {
+            "version": env!("CARGO_PKG_VERSION"),
+            "requests": REQUESTS,
+            "notifications": NOTIFICATIONS,
+            "client_notifications": CLIENT_NOTIFICATIONS,
+        }
+    })
+}
@@ -0,0 +1,808 @@
+// Drives a language server in-process the way Zed does, with a clock set by
+// hand and the heartbeats it would send recorded instead of run with
+// wakatime-cli, so the decisions about what is sent can be checked.
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    sync::{atomic::Ordering, Arc, Once},
+    time::Duration,
+};
+
+use chrono::{Local, TimeDelta};
+use futures_util::StreamExt;
+use serde_json::{json, Value};
+use tower_lsp::{
+    lsp_types::{
+        DidChangeConfigurationParams, DidChangeTextDocumentParams, DidOpenTextDocumentParams,
+        DidSaveTextDocumentParams, ExecuteCommandParams, InitializeParams, InitializedParams,
+        Position, Range, TextDocumentContentChangeEvent, TextDocumentIdentifier, TextDocumentItem,
+        Url, VersionedTextDocumentIdentifier, WorkspaceFolder,
+    },
+    LanguageServer, LspService,
+};
+use wakatime_ls_core::{
+    cli::Cli,
+    clock::ManualClock,
+    heartbeat::{Heartbeat, Outcome},
+    sink::RecordingSink,
+};
+
+use crate::{notification::FocusParams, service_with, WakatimeLanguageServer};
+
+// Everything the servers write under `WAKATIME_HOME`, such as the streak,
+// stays in here rather than in the home folder.
+fn home() -> PathBuf {
+    static HOME: Once = Once::new();
+    let home = env::temp_dir().join(format!("wakatime-ls-harness-{}", std::process::id()));
+
+    HOME.call_once(|| {
+        fs::create_dir_all(&home).unwrap();
+        env::set_var("WAKATIME_HOME", &home);
+    });
+    home
+}
+
+struct Harness {
+    service: LspService<WakatimeLanguageServer>,
+    clock: ManualClock,
+    sink: RecordingSink,
+    root: PathBuf,
+}
+
+impl Harness {
+    // An initialized server for a workspace folder of its own, named after
+    // the test, with its background tasks that would reach the network off.
+    async fn new(name: &str) -> Self {
+        Self::with_options(name, json!({})).await
+    }
+
+    // The same, with settings of the test's own.
+    async fn with_options(name: &str, options: Value) -> Self {
+        Self::build(name, options, None, false).await
+    }
+
+    // A server for a client over `--listen` without `--trust-clients`.
+    async fn untrusted(name: &str, options: Value) -> Self {
+        Self::build(name, options, None, true).await
+    }
+
+    // A server sending its heartbeats with a wakatime-cli standing in for the
+    // real one, a shell script written to the test's folder, rather than
+    // recording them.
+    #[cfg(unix)]
+    async fn with_script(name: &str, options: Value, script: &str) -> Self {
+        use std::os::unix::fs::PermissionsExt;
+
+        let root = home().join(name);
+        fs::create_dir_all(&root).unwrap();
+        let cli = root.join("wakatime-cli");
+        fs::write(&cli, script).unwrap();
+        fs::set_permissions(&cli, fs::Permissions::from_mode(0o755)).unwrap();
+
+        Self::build(name, options, Some(cli), false).await
+    }
+
+    async fn build(name: &str, options: Value, script: Option<PathBuf>, untrusted: bool) -> Self {
+        let root = home().join(name);
+        fs::create_dir_all(&root).unwrap();
+
+        let clock = ManualClock::new(Local::now());
+        let sink = RecordingSink::default();
+        let cli = Cli::new(root.join("wakatime-cli"));
+        let (service, socket, _) = service_with(
+            Some(Arc::new(cli)),
+            None,
+            untrusted,
+            Arc::new(clock.clone()),
+            script.is_none().then(|| sink.clone()),
+        );
+        tokio::spawn(socket.for_each(|_| async {}));
+
+        let mut initialization_options = json!({
+            "status_bar_interval": 0,
+            "update_notifications": false,
+            "api_requests_per_minute": 0,
+        });
+        if let (Some(defaults), Value::Object(options)) =
+            (initialization_options.as_object_mut(), options)
+        {
+            defaults.extend(options);
+        }
+
+        let server = service.inner();
+        server
+            .initialize(InitializeParams {
+                initialization_options: Some(initialization_options),
+                workspace_folders: Some(vec![WorkspaceFolder {
+                    uri: Url::from_directory_path(&root).unwrap(),
+                    name: name.to_string(),
+                }]),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        server.initialized(InitializedParams {}).await;
+
+        Self {
+            service,
+            clock,
+            sink,
+            root,
+        }
+    }
+
+    fn server(&self) -> &WakatimeLanguageServer {
+        self.service.inner()
+    }
+
+    fn uri(&self, file: &str) -> Url {
+        let path = self.root.join(file);
+        if !path.exists() {
+            fs::write(&path, "fn main() {}\n").unwrap();
+        }
+        Url::from_file_path(path).unwrap()
+    }
+
+    async fn open(&self, file: &str) {
+        self.server()
+            .did_open(DidOpenTextDocumentParams {
+                text_document: TextDocumentItem {
+                    uri: self.uri(file),
+                    language_id: "rust".to_string(),
+                    version: 1,
+                    text: String::new(),
+                },
+            })
+            .await;
+    }
+
+    // Typing at a position, as Zed reports it with incremental sync.
+    async fn change(&self, file: &str, line: u32) {
+        let position = Position { line, character: 4 };
+        self.change_with(
+            file,
+            TextDocumentContentChangeEvent {
+                range: Some(Range {
+                    start: position,
+                    end: position,
+                }),
+                range_length: None,
+                text: "x".to_string(),
+            },
+        )
+        .await;
+    }
+
+    async fn change_with(&self, file: &str, change: TextDocumentContentChangeEvent) {
+        self.server()
+            .did_change(DidChangeTextDocumentParams {
+                text_document: VersionedTextDocumentIdentifier {
+                    uri: self.uri(file),
+                    version: 2,
+                },
+                content_changes: vec![change],
+            })
+            .await;
+    }
+
+    async fn save(&self, file: &str) {
+        self.server()
+            .did_save(DidSaveTextDocumentParams {
+                text_document: TextDocumentIdentifier {
+                    uri: self.uri(file),
+                },
+                text: None,
+            })
+            .await;
+    }
+
+    fn heartbeats(&self) -> Vec<Heartbeat> {
+        self.sink.heartbeats()
+    }
+
+    fn path(&self, file: &str) -> PathBuf {
+        self.uri(file).to_file_path().unwrap()
+    }
+}
+
+// Whether `condition` holds within a few seconds, for what background tasks
+// do on their own time.
+async fn eventually(condition: impl Fn() -> bool) -> bool {
+    for _ in 0..500 {
+        if condition() {
+            return true;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    false
+}
+
+fn entities(heartbeats: &[Heartbeat]) -> Vec<&Path> {
+    heartbeats
+        .iter()
+        .map(|heartbeat| heartbeat.entity.as_path())
+        .collect()
+}
+
+#[tokio::test]
+async fn changes_within_the_interval_are_skipped() {
+    let harness = Harness::new("interval").await;
+
+    harness.change("main.rs", 1).await;
+    harness.clock.advance(TimeDelta::seconds(30));
+    harness.change("main.rs", 2).await;
+    harness.clock.advance(TimeDelta::seconds(60));
+    harness.change("main.rs", 3).await;
+    assert_eq!(harness.heartbeats().len(), 1);
+
+    harness.clock.advance(TimeDelta::minutes(2));
+    harness.change("main.rs", 4).await;
+    let heartbeats = harness.heartbeats();
+    assert_eq!(heartbeats.len(), 2);
+    assert_eq!(heartbeats[1].lineno, Some(4));
+
+    // The interval starts again from the last heartbeat.
+    harness.clock.advance(TimeDelta::seconds(90));
+    harness.change("main.rs", 5).await;
+    assert_eq!(harness.heartbeats().len(), 2);
+}
+
+#[tokio::test]
+async fn wall_clock_changes_dont_move_the_interval() {
+    let harness = Harness::new("wall-clock").await;
+
+    // Turned back an hour, as at the end of DST: the next heartbeat is still
+    // due two minutes after the last one, not an hour and two minutes.
+    harness.change("main.rs", 1).await;
+    harness.clock.adjust(TimeDelta::hours(-1));
+    harness.clock.advance(TimeDelta::seconds(150));
+    harness.change("main.rs", 2).await;
+    assert_eq!(harness.heartbeats().len(), 2);
+
+    // Set forward, as by an NTP correction: time that didn't pass doesn't
+    // end the interval.
+    harness.clock.adjust(TimeDelta::hours(1));
+    harness.clock.advance(TimeDelta::seconds(30));
+    harness.change("main.rs", 3).await;
+    assert_eq!(harness.heartbeats().len(), 2);
+}
+
+#[tokio::test]
+async fn the_streak_is_of_the_days_of_the_clock() {
+    let harness = Harness::new("streak clock").await;
+    let today = || async { harness.server().status().await.unwrap()["streak"]["today"].as_bool() };
+
+    harness.change("main.rs", 1).await;
+    assert_eq!(today().await, Some(true));
+
+    harness.clock.advance(TimeDelta::days(2));
+    assert_eq!(today().await, Some(false));
+}
+
+#[tokio::test]
+async fn typing_shortens_the_adaptive_interval() {
+    let harness = Harness::with_options("adaptive", json!({"adaptive_interval": true})).await;
+
+    // A minute of typing, about once a second.
+    for line in 0..60 {
+        harness.change("main.rs", line).await;
+        harness.clock.advance(TimeDelta::seconds(1));
+    }
+    assert_eq!(harness.heartbeats().len(), 2);
+
+    // After a few quiet minutes, the interval is back to five minutes.
+    harness.clock.advance(TimeDelta::minutes(3));
+    harness.change("main.rs", 1).await;
+    assert_eq!(harness.heartbeats().len(), 2);
+}
+
+#[tokio::test]
+async fn saves_are_sent_right_away() {
+    let harness = Harness::new("saves").await;
+
+    harness.change("main.rs", 1).await;
+    harness.clock.advance(TimeDelta::seconds(10));
+    harness.save("main.rs").await;
+    harness.clock.advance(TimeDelta::seconds(10));
+    harness.save("main.rs").await;
+
+    let heartbeats = harness.heartbeats();
+    let writes: Vec<_> = heartbeats
+        .iter()
+        .map(|heartbeat| heartbeat.is_write)
+        .collect();
+    assert_eq!(writes, [false, true, true]);
+
+    // Saves don't delay the next regular heartbeat.
+    harness.clock.advance(TimeDelta::seconds(110));
+    harness.change("main.rs", 2).await;
+    assert_eq!(harness.heartbeats().len(), 4);
+}
+
+#[tokio::test]
+async fn saves_within_the_write_interval_are_edits() {
+    let harness = Harness::with_options("write-interval", json!({"write_interval": 60})).await;
+
+    harness.change("main.rs", 1).await;
+    harness.clock.advance(TimeDelta::seconds(10));
+    harness.save("main.rs").await;
+    harness.clock.advance(TimeDelta::seconds(10));
+    harness.save("main.rs").await;
+    assert_eq!(harness.heartbeats().len(), 2);
+
+    // A minute after the last write heartbeat, saves are sent again.
+    harness.clock.advance(TimeDelta::seconds(55));
+    harness.save("main.rs").await;
+
+    let writes: Vec<_> = harness
+        .heartbeats()
+        .iter()
+        .map(|heartbeat| heartbeat.is_write)
+        .collect();
+    assert_eq!(writes, [false, true, true]);
+}
+
+// The script fails while a `fail` file is next to it, and logs its arguments.
+#[cfg(unix)]
+#[tokio::test]
+async fn saves_that_failed_dont_start_the_write_interval() {
+    let script = "#!/bin/sh\ncase \"$*\" in *--version*) echo v1.102.1; exit 0;; esac\ncd \"$(dirname \"$0\")\"\necho \"$*\" >> calls\n[ -e fail ] && exit 1\nexit 0\n";
+    let harness = Harness::with_script("failed write", json!({"write_interval": 60}), script).await;
+    fs::write(harness.root.join("fail"), "").unwrap();
+
+    harness.change("main.rs", 1).await;
+    harness.clock.advance(TimeDelta::seconds(10));
+    harness.save("main.rs").await;
+    fs::remove_file(harness.root.join("fail")).unwrap();
+    harness.clock.advance(TimeDelta::seconds(10));
+    harness.save("main.rs").await;
+
+    let calls = fs::read_to_string(harness.root.join("calls")).unwrap();
+    let writes: Vec<bool> = calls.lines().map(|call| call.contains("--write")).collect();
+    assert_eq!(writes, [false, true, true]);
+}
+
+#[tokio::test]
+async fn switching_files_is_sent_right_away() {
+    let harness = Harness::new("switching").await;
+
+    harness.change("main.rs", 1).await;
+    harness.clock.advance(TimeDelta::seconds(5));
+    harness.change("lib.rs", 1).await;
+    harness.clock.advance(TimeDelta::seconds(5));
+    harness.change("lib.rs", 2).await;
+    harness.clock.advance(TimeDelta::seconds(5));
+    harness.change("main.rs", 2).await;
+
+    let heartbeats = harness.heartbeats();
+    assert_eq!(
+        entities(&heartbeats),
+        [
+            harness.path("main.rs"),
+            harness.path("lib.rs"),
+            harness.path("main.rs")
+        ]
+    );
+}
+
+#[tokio::test]
+async fn events_without_a_position_are_ignored() {
+    let harness = Harness::new("positions").await;
+
+    // Opening a file, replacing all of it, or saving one that was never
+    // edited doesn't tell where the cursor is.
+    harness.open("main.rs").await;
+    harness
+        .change_with(
+            "main.rs",
+            TextDocumentContentChangeEvent {
+                range: None,
+                range_length: None,
+                text: "fn main() {}\n".to_string(),
+            },
+        )
+        .await;
+    harness.save("lib.rs").await;
+
+    assert!(harness.heartbeats().is_empty());
+}
+
+#[tokio::test]
+async fn another_server_waits_for_the_interval() {
+    // Two Zed windows on the same project, each with its own server.
+    let first = Harness::new("instances").await;
+    let second = Harness::new("instances").await;
+
+    first.change("main.rs", 1).await;
+    second.change("main.rs", 1).await;
+    first.clock.advance(TimeDelta::seconds(150));
+    first.change("main.rs", 2).await;
+    assert_eq!(first.heartbeats().len(), 2);
+
+    // The switch to the file is sent anyway, not the next heartbeat.
+    second.clock.advance(TimeDelta::seconds(150));
+    second.change("main.rs", 2).await;
+    assert_eq!(second.heartbeats().len(), 1);
+}
+
+#[tokio::test]
+async fn entities_are_rewritten() {
+    let root = home().join("rewrite");
+    let harness = Harness::with_options(
+        "rewrite",
+        json!({"entity_rewrite": {
+            format!("^{}/", regex::escape(&root.to_string_lossy())): "/src/app/",
+            "\\.rs$": ".rust",
+            "[": "ignored",
+        }}),
+    )
+    .await;
+
+    harness.change("main.rs", 1).await;
+
+    let heartbeats = harness.heartbeats();
+    assert_eq!(entities(&heartbeats), [Path::new("/src/app/main.rust")]);
+    assert_eq!(heartbeats[0].local_file, Some(harness.path("main.rs")));
+}
+
+#[tokio::test]
+async fn channel_buffers_are_reported_in_their_project() {
+    let harness =
+        Harness::with_options("collab", json!({"collab_project": "collab-{channel}"})).await;
+    let position = Position {
+        line: 3,
+        character: 1,
+    };
+
+    harness
+        .server()
+        .did_change(DidChangeTextDocumentParams {
+            text_document: VersionedTextDocumentIdentifier {
+                uri: Url::parse("zed://channel/design%20review/notes").unwrap(),
+                version: 2,
+            },
+            content_changes: vec![TextDocumentContentChangeEvent {
+                range: Some(Range {
+                    start: position,
+                    end: position,
+                }),
+                range_length: None,
+                text: "x".to_string(),
+            }],
+        })
+        .await;
+
+    let heartbeats = harness.heartbeats();
+    assert_eq!(
+        entities(&heartbeats),
+        [Path::new("zed://channel/design review/notes")]
+    );
+    assert_eq!(heartbeats[0].entity_type.as_deref(), Some("app"));
+    assert_eq!(
+        heartbeats[0].project.as_deref(),
+        Some("collab-design review")
+    );
+    assert_eq!(heartbeats[0].project_folder, None);
+}
+
+#[tokio::test]
+async fn changed_settings_are_applied() {
+    let harness =
+        Harness::with_options("reconfigure", json!({"entity_rewrite": {"\\.rs$": ".a"}})).await;
+
+    harness.change("main.rs", 1).await;
+    harness
+        .server()
+        .did_change_configuration(DidChangeConfigurationParams {
+            settings: json!({"entity_rewrite": {"\\.rs$": ".b"}}),
+        })
+        .await;
+    harness.change("lib.rs", 1).await;
+
+    let entities = entities(&harness.heartbeats())
+        .into_iter()
+        .map(|entity| entity.extension().unwrap().to_owned())
+        .collect::<Vec<_>>();
+    assert_eq!(entities, ["a", "b"]);
+}
+
+#[tokio::test]
+async fn tasks_start_again_only_once_their_settings_change() {
+    let harness = Harness::new("reconfigure tasks").await;
+    let status_bar = || {
+        let tasks = harness.server().configured_tasks.lock().unwrap();
+        tasks
+            .get("status bar")
+            .filter(|task| !task.handle.is_finished())
+            .map(|task| task.handle.id())
+    };
+    let configure = |settings: Value| {
+        harness
+            .server()
+            .did_change_configuration(DidChangeConfigurationParams { settings })
+    };
+
+    configure(json!({"status_bar_interval": 60})).await;
+    let started = status_bar();
+    assert!(started.is_some());
+
+    // Saving other settings leaves it running as it is.
+    configure(json!({"status_bar_interval": 60, "debug": true})).await;
+    assert_eq!(status_bar(), started);
+
+    configure(json!({"status_bar_interval": 120})).await;
+    assert!(status_bar().is_some_and(|id| Some(id) != started));
+
+    configure(json!({"status_bar_interval": 0})).await;
+    assert_eq!(status_bar(), None);
+}
+
+#[tokio::test]
+async fn caches_are_pruned_for_the_last_cache_max_age() {
+    let harness = Harness::new("reconfigure pruning").await;
+    let pruning = || {
+        let tasks = harness.server().configured_tasks.lock().unwrap();
+        tasks
+            .get("cache pruning")
+            .filter(|task| !task.handle.is_finished())
+            .map(|task| task.handle.id())
+    };
+    let configure = |settings: Value| {
+        harness
+            .server()
+            .did_change_configuration(DidChangeConfigurationParams { settings })
+    };
+    let started = pruning();
+    assert!(started.is_some());
+
+    configure(json!({"cache_max_age": 30})).await;
+    assert!(pruning().is_some_and(|id| Some(id) != started));
+
+    configure(json!({"cache_max_age": 0})).await;
+    assert_eq!(pruning(), None);
+}
+
+// A wakatime-cli with 10 heartbeats in its offline queue for good, which
+// writes the key it syncs them with to `synced`.
+#[cfg(unix)]
+const FULL_QUEUE_SCRIPT: &str = r#"#!/bin/sh
+dir="$(dirname "$0")"
+case "$*" in
+*--version*) echo v1.102.1 ;;
+*--offline-count*) echo 10 ;;
+*--print-offline-heartbeats*)
+    echo '[{"entity":"1"},{"entity":"2"},{"entity":"3"},{"entity":"4"},{"entity":"5"},{"entity":"6"},{"entity":"7"},{"entity":"8"},{"entity":"9"},{"entity":"10"}]' ;;
+*--sync-offline-activity*)
+    config=""; previous=""
+    for arg in "$@"; do
+        [ "$previous" = "--config" ] && config="$arg"
+        previous="$arg"
+    done
+    grep "^api_key" "$config" > "$dir/synced.tmp"
+    mv "$dir/synced.tmp" "$dir/synced" ;;
+esac
+"#;
+
+#[cfg(unix)]
+#[tokio::test]
+async fn full_queues_are_lifted_once_their_limits_change() {
+    let harness = Harness::with_script(
+        "queue limits",
+        json!({"max_queued_heartbeats": 5}),
+        FULL_QUEUE_SCRIPT,
+    )
+    .await;
+    let full = || harness.server().queue_full.load(Ordering::Relaxed);
+    let configure = |settings: Value| {
+        harness
+            .server()
+            .did_change_configuration(DidChangeConfigurationParams { settings })
+    };
+    assert!(eventually(full).await);
+
+    configure(json!({"max_queued_heartbeats": 0})).await;
+    assert!(!full());
+
+    configure(json!({"max_queued_heartbeats": 5})).await;
+    assert!(eventually(full).await);
+
+    // Dropping the oldest makes room instead.
+    configure(json!({"max_queued_heartbeats": 5, "queue_eviction": "drop_oldest"})).await;
+    assert!(eventually(|| !full()).await);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn the_oldest_queued_heartbeats_are_dropped_with_the_profiles_key() {
+    let harness = Harness::with_script(
+        "queue eviction",
+        json!({
+            "max_queued_heartbeats": 5,
+            "queue_eviction": "drop_oldest",
+            "profiles": {"work": {"api-key": "waka_work"}},
+            "profile": "work",
+        }),
+        FULL_QUEUE_SCRIPT,
+    )
+    .await;
+    let synced = harness.root.join("synced");
+
+    assert!(eventually(|| synced.exists()).await);
+    assert_eq!(
+        fs::read_to_string(&synced).unwrap(),
+        "api_key = waka_work\n"
+    );
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn app_heartbeats_go_to_the_profile_of_the_current_folder() {
+    // Writes the key of every run to `sent`, or an empty line without one.
+    let script = r#"#!/bin/sh
+case "$*" in *--version*) echo v1.102.1; exit 0;; esac
+config=""; previous=""
+for arg in "$@"; do
+    [ "$previous" = "--config" ] && config="$arg"
+    previous="$arg"
+done
+echo "$(grep "^api_key" "$config" 2>/dev/null)" >> "$(dirname "$0")/sent"
+"#;
+    let harness = Harness::with_script(
+        "app profile",
+        json!({
+            "profiles": {"work": {"api-key": "waka_work"}},
+            "coordinate_instances": false,
+        }),
+        script,
+    )
+    .await;
+    let configure = |settings: Value| {
+        harness
+            .server()
+            .did_change_configuration(DidChangeConfigurationParams { settings })
+    };
+    fs::write(harness.root.join(".wakatime.toml"), "profile = \"work\"\n").unwrap();
+    configure(json!({})).await;
+
+    harness.change("main.rs", 1).await;
+    harness.server().focus(FocusParams { focused: true }).await;
+    harness.clock.advance(TimeDelta::minutes(3));
+    configure(json!({"app_heartbeats": true})).await;
+
+    let sent = harness.root.join("sent");
+    let lines = || {
+        fs::read_to_string(&sent)
+            .unwrap_or_default()
+            .lines()
+            .count()
+    };
+    assert!(eventually(|| lines() == 2).await);
+    assert_eq!(
+        fs::read_to_string(&sent).unwrap(),
+        "api_key = waka_work\napi_key = waka_work\n"
+    );
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn refused_keys_fail_over_despite_a_key_in_the_config() {
+    // Only the secondary key is accepted, and a personal key sits in the
+    // config wakatime-cli would otherwise read its key from.
+    let script = r#"#!/bin/sh
+case "$*" in *--version*) echo v1.102.1; exit 0;; esac
+config=""; previous=""
+for arg in "$@"; do
+    [ "$previous" = "--config" ] && config="$arg"
+    previous="$arg"
+done
+grep -q "^api_key = waka_secondary$" "$config" || exit 104
+echo sent >> "$(dirname "$0")/sent"
+"#;
+    let config = home().join(".wakatime.cfg");
+    if !config.exists() {
+        fs::write(&config, "[settings]\napi_key = waka_personal\n").unwrap();
+    }
+    let harness = Harness::with_script(
+        "fail-over",
+        json!({"api-key": "waka_primary", "secondary-api-key": "waka_secondary"}),
+        script,
+    )
+    .await;
+
+    harness.change("main.rs", 1).await;
+
+    let sent = fs::read_to_string(harness.root.join("sent")).unwrap_or_default();
+    assert_eq!(sent, "sent\n");
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn failed_over_keys_are_kept_once_the_settings_change() {
+    // Refuses every key but the replacement, writing which it was given for
+    // each heartbeat to `sent`.
+    let script = r#"#!/bin/sh
+case "$*" in *--version*) echo v1.102.1; exit 0;; esac
+config=""; previous=""
+for arg in "$@"; do
+    [ "$previous" = "--config" ] && config="$arg"
+    previous="$arg"
+done
+if grep -q "^api_key = waka_replacement$" "$config"; then
+    echo replacement >> "$(dirname "$0")/sent"
+else
+    echo refused >> "$(dirname "$0")/sent"
+    exit 104
+fi
+"#;
+    let harness = Harness::with_script(
+        "fail-over kept",
+        json!({"api-key": "waka_rotated", "secondary-api-key": "waka_replacement"}),
+        script,
+    )
+    .await;
+
+    harness.change("main.rs", 1).await;
+    harness
+        .server()
+        .did_change_configuration(DidChangeConfigurationParams {
+            settings: json!({"debug": true}),
+        })
+        .await;
+    harness.clock.advance(TimeDelta::minutes(3));
+    harness.change("main.rs", 2).await;
+
+    let sent = fs::read_to_string(harness.root.join("sent")).unwrap_or_default();
+    assert_eq!(sent, "refused\nreplacement\nreplacement\n");
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn a_failed_batch_counts_as_one_failure() {
+    let script = "#!/bin/sh\ncase \"$*\" in *--version*) echo v1.102.1; exit 0;; esac\nexit 1\n";
+    let harness = Harness::with_script("failed batch", json!({}), script).await;
+    let heartbeats: Vec<Heartbeat> = ["a.rs", "b.rs", "c.rs"]
+        .iter()
+        .map(|file| Heartbeat {
+            entity: harness.root.join(file),
+            ..Default::default()
+        })
+        .collect();
+    let cli = Cli::new(harness.root.join("wakatime-cli"));
+
+    let delivery = &harness.server().delivery;
+    let outcome = delivery.send(&heartbeats, &cli, None, Local::now()).await;
+
+    assert_eq!(outcome, Outcome::Failed);
+    assert_eq!(delivery.send_failures.load(Ordering::Relaxed), 1);
+}
+
+#[tokio::test]
+async fn clients_over_the_network_cant_redirect_the_key_or_write_files() {
+    let spool = home().join("untrusted spool");
+    let harness = Harness::untrusted(
+        "untrusted client",
+        json!({
+            "api-url": "https://attacker.example/api",
+            "heartbeat_spool": spool,
+            "profiles": {"work": {"api-key": "waka_work", "api-url": "https://attacker.example/api"}},
+        }),
+    )
+    .await;
+
+    let settings = harness.server().settings.load();
+    assert_eq!(settings.api_url, None);
+    assert_eq!(settings.heartbeat_spool, None);
+    let (_, work) = &settings.profiles.as_ref().unwrap()[0];
+    assert_eq!(work.api_url, None);
+
+    let export = harness
+        .server()
+        .execute_command(ExecuteCommandParams {
+            command: "wakatime.exportActivity".to_string(),
+            arguments: vec![json!({"path": harness.root.join("export.json")})],
+            ..Default::default()
+        })
+        .await;
+    assert!(export.is_err());
+    assert!(!harness.root.join("export.json").exists());
+}
@@ -0,0 +1,503 @@
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+use arc_swap::{ArcSwap, ArcSwapOption};
+use chrono::Local;
+use tokio::sync::mpsc;
+use tower_lsp::{lsp_types::MessageType, Client};
+use tracing::{Dispatch, Level, Metadata};
+use tracing_subscriber::{
+    field::RecordFields,
+    filter::dynamic_filter_fn,
+    fmt::{
+        format::{DefaultFields, FmtSpan, Writer},
+        time::FormatTime,
+        FormatFields, MakeWriter,
+    },
+    layer::{Filter, SubscriberExt},
+    Layer, Registry,
+};
+
+use wakatime_ls_core::redact::Secrets;
+
+// Where the `tracing` events of one language server go: the client's log, and
+// once a log file is set, that file as well, with secrets masked in both.
+// Zed's log panel only keeps the current session, so the file is what's left
+// to look at when time wasn't tracked the day before. Each server has its own,
+// so clients sharing a process with `--listen` don't see each other's
+// messages.
+//
+// Example line:
+// 2026-10-14T15:05:39.202+00:00  WARN heartbeat{entity="051f86b06e9229de"}: wakatime-cli exited with code 104
+#[derive(Clone)]
+pub struct Logger {
+    level: Arc<ArcSwap<Level>>,
+    json: Arc<AtomicBool>,
+    file: Arc<ArcSwapOption<LogFile>>,
+    secrets: Arc<ArcSwap<Secrets>>,
+    messages: mpsc::UnboundedSender<(MessageType, String)>,
+}
+
+// How the log file is written.
+//
+// Example:
+// text -> 2026-10-14T15:05:39.202+00:00  INFO wakatime-cli version v1.102.1
+// json -> {"timestamp":"2026-10-14T15:05:39.202+00:00","level":"INFO","fields":{"message":"wakatime-cli version v1.102.1"}}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl LogFormat {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "text" => Some(Self::Text),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+}
+
+// When the log file is moved aside for a new one. Rotated files get a number,
+// the most recent being `.1`, and those past `keep` are deleted.
+//
+// Example, with keep = 2:
+// zed-wakatime-ls.log -> zed-wakatime-ls.log.1 -> zed-wakatime-ls.log.2 -> deleted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rotation {
+    pub max_size: Option<u64>,
+    pub max_age: Option<Duration>,
+    pub keep: usize,
+}
+
+impl Default for Rotation {
+    fn default() -> Self {
+        Self {
+            max_size: Some(10 * 1024 * 1024),
+            max_age: None,
+            keep: 5,
+        }
+    }
+}
+
+struct LogFile {
+    path: PathBuf,
+    rotation: Rotation,
+    file: Mutex<OpenLogFile>,
+}
+
+struct OpenLogFile {
+    file: File,
+    size: u64,
+    // When the first entry was written, for `max_age`.
+    created: SystemTime,
+}
+
+impl OpenLogFile {
+    fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let metadata = file.metadata()?;
+
+        Ok(Self {
+            size: metadata.len(),
+            created: metadata
+                .created()
+                .or_else(|_| metadata.modified())
+                .unwrap_or_else(|_| SystemTime::now()),
+            file,
+        })
+    }
+}
+
+impl LogFile {
+    fn write(&self, entry: &[u8]) -> io::Result<()> {
+        let Ok(mut file) = self.file.lock() else {
+            return Ok(());
+        };
+
+        if file.size > 0 && self.must_rotate(&file, entry.len() as u64) {
+            // The new file replaces the old one even when the old one can't
+            // be moved aside, so an oversized log doesn't keep growing.
+            self.rotate().ok();
+            *file = OpenLogFile::open(&self.path)?;
+        }
+
+        file.file.write_all(entry)?;
+        file.size += entry.len() as u64;
+        Ok(())
+    }
+
+    // For the panic hook, which may run while this thread is writing, and
+    // mustn't wait for itself.
+    fn try_write(&self, entry: &[u8]) -> io::Result<()> {
+        let Ok(mut file) = self.file.try_lock() else {
+            return Ok(());
+        };
+
+        file.file.write_all(entry)?;
+        file.size += entry.len() as u64;
+        Ok(())
+    }
+
+    fn must_rotate(&self, file: &OpenLogFile, entry_len: u64) -> bool {
+        let too_big = self
+            .rotation
+            .max_size
+            .is_some_and(|max_size| file.size + entry_len > max_size);
+        let too_old = self
+            .rotation
+            .max_age
+            .is_some_and(|max_age| file.created.elapsed().is_ok_and(|age| age > max_age));
+
+        too_big || too_old
+    }
+
+    fn rotate(&self) -> io::Result<()> {
+        let rotated = |index: usize| {
+            let mut path = self.path.clone().into_os_string();
+            path.push(format!(".{index}"));
+            PathBuf::from(path)
+        };
+
+        if self.rotation.keep == 0 {
+            return fs::remove_file(&self.path);
+        }
+
+        // Dropped off the end, including any left from a larger `keep`.
+        let mut index = self.rotation.keep;
+        while rotated(index).exists() {
+            fs::remove_file(rotated(index))?;
+            index += 1;
+        }
+
+        for index in (1..self.rotation.keep).rev() {
+            let from = rotated(index);
+            if from.exists() {
+                fs::rename(from, rotated(index + 1))?;
+            }
+        }
+
+        fs::rename(&self.path, rotated(1))
+    }
+}
+
+impl Logger {
+    // Must be called within the Tokio runtime, which forwards messages to the
+    // client in the order they were logged.
+    pub fn new(client: Client) -> Self {
+        let (messages, mut receiver) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            while let Some((typ, message)) = receiver.recv().await {
+                client.log_message(typ, message).await;
+            }
+        });
+
+        Self {
+            level: Arc::new(ArcSwap::from_pointee(Level::INFO)),
+            json: Arc::new(AtomicBool::new(false)),
+            file: Arc::new(ArcSwapOption::empty()),
+            secrets: Arc::new(ArcSwap::from_pointee(Secrets::default())),
+            messages,
+        }
+    }
+
+    // The subscriber to run the server with. Heartbeat spans are logged when
+    // they close, with their outcome and how long they took.
+    pub fn dispatch(&self) -> Dispatch {
+        let client = tracing_subscriber::fmt::layer()
+            .with_ansi(false)
+            .without_time()
+            .with_level(false)
+            .with_target(false)
+            // Span fields are formatted once per field formatter type, and
+            // would show up twice if this layer shared the text file's.
+            .fmt_fields(ClientFields(DefaultFields::new()))
+            .with_span_events(FmtSpan::CLOSE)
+            .with_writer(self.clone())
+            .with_filter(self.filter(|_| true));
+
+        let text = tracing_subscriber::fmt::layer()
+            .with_ansi(false)
+            .with_target(false)
+            .with_timer(Timestamp)
+            .with_span_events(FmtSpan::CLOSE)
+            .with_writer(LogFileWriter {
+                file: self.file.clone(),
+                secrets: self.secrets.clone(),
+                indent: true,
+            })
+            .with_filter(self.filter(|logger| !logger.json.load(Ordering::Relaxed)));
+
+        let json = tracing_subscriber::fmt::layer()
+            .json()
+            .with_target(false)
+            .with_current_span(true)
+            .with_span_list(false)
+            .with_timer(Timestamp)
+            .with_span_events(FmtSpan::CLOSE)
+            .with_writer(LogFileWriter {
+                file: self.file.clone(),
+                secrets: self.secrets.clone(),
+                indent: false,
+            })
+            .with_filter(self.filter(|logger| logger.json.load(Ordering::Relaxed)));
+
+        let registry = Registry::default().with(client).with(text).with(json);
+        #[cfg(feature = "otel")]
+        let registry = registry.with(crate::otel::layer());
+
+        Dispatch::new(registry)
+    }
+
+    // Checked on every event rather than once per call site, since the level
+    // and the format change with the settings. Events of other crates are left
+    // out: tower-lsp traces every message it sends, including these logs.
+    fn filter<S>(&self, enabled: fn(&Logger) -> bool) -> impl Filter<S> {
+        let logger = self.clone();
+        dynamic_filter_fn(move |metadata: &Metadata<'_>, _| {
+            metadata.target().starts_with(env!("CARGO_CRATE_NAME"))
+                && metadata.level() <= &**logger.level.load()
+                && enabled(&logger)
+        })
+    }
+
+    pub fn set_level(&self, level: Level) {
+        self.level.store(Arc::new(level));
+    }
+
+    pub fn set_secrets(&self, secrets: Secrets) {
+        self.secrets.store(Arc::new(secrets));
+    }
+
+    pub fn set_format(&self, format: LogFormat) {
+        self.json
+            .store(format == LogFormat::Json, Ordering::Relaxed);
+    }
+
+    // Start appending to `path`, creating it and its folder when missing, or
+    // stop writing to a file with `None`.
+    pub fn set_file(&self, path: Option<&Path>, rotation: Rotation) -> io::Result<()> {
+        let Some(path) = path else {
+            self.file.store(None);
+            return Ok(());
+        };
+
+        if self
+            .file
+            .load()
+            .as_ref()
+            .is_some_and(|file| file.path == path && file.rotation == rotation)
+        {
+            return Ok(());
+        }
+
+        if let Some(parent) = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenLogFile::open(path)?;
+
+        self.file.store(Some(Arc::new(LogFile {
+            path: path.to_path_buf(),
+            rotation,
+            file: Mutex::new(file),
+        })));
+        Ok(())
+    }
+
+    // Write a panic to the log file, outside of `tracing`, whose subscriber
+    // may be what panicked, and try to tell the client. The message only
+    // reaches it if the process lives long enough to forward it.
+    pub fn write_panic(&self, report: &str) {
+        let secrets = self.secrets.load();
+        let report = secrets.redact(report.trim_end());
+
+        if let Some(file) = self.file.load().as_ref() {
+            let entry = if self.json.load(Ordering::Relaxed) {
+                let entry = serde_json::json!({
+                    "timestamp": Local::now().format("%Y-%m-%dT%H:%M:%S%.3f%:z").to_string(),
+                    "level": "ERROR",
+                    "fields": {"message": report},
+                });
+                format!("{entry}\n")
+            } else {
+                format!(
+                    "{} ERROR {}\n",
+                    Local::now().format("%Y-%m-%dT%H:%M:%S%.3f%:z"),
+                    report.replace('\n', "\n    "),
+                )
+            };
+            file.try_write(entry.as_bytes()).ok();
+        }
+
+        self.messages
+            .send((MessageType::ERROR, report.into_owned()))
+            .ok();
+    }
+
+    fn client_message(&self, typ: MessageType) -> ClientMessage {
+        ClientMessage {
+            typ,
+            buffer: Vec::new(),
+            secrets: self.secrets.load_full(),
+            messages: self.messages.clone(),
+        }
+    }
+}
+
+// A short, stable stand-in for a file path, so the heartbeats of one file can
+// be followed through the log without the path on every line. It is the
+// 64-bit FNV-1a hash of the path, which needs no hashing crate.
+//
+// Example:
+// /home/alice/src/app/main.rs -> 051f86b06e9229de
+pub fn entity_hash(entity: &Path) -> String {
+    let hash = entity
+        .as_os_str()
+        .as_encoded_bytes()
+        .iter()
+        .fold(0xcbf2_9ce4_8422_2325_u64, |hash, &byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        });
+    format!("{hash:016x}")
+}
+
+struct ClientFields(DefaultFields);
+
+impl<'writer> FormatFields<'writer> for ClientFields {
+    fn format_fields<R: RecordFields>(&self, writer: Writer<'writer>, fields: R) -> fmt::Result {
+        self.0.format_fields(writer, fields)
+    }
+}
+
+struct Timestamp;
+
+impl FormatTime for Timestamp {
+    fn format_time(&self, w: &mut Writer<'_>) -> fmt::Result {
+        write!(w, "{}", Local::now().format("%Y-%m-%dT%H:%M:%S%.3f%:z"))
+    }
+}
+
+// `tracing_subscriber::fmt` writes each event at once, so the writers below
+// collect one event and pass it on when dropped.
+pub struct ClientMessage {
+    typ: MessageType,
+    buffer: Vec<u8>,
+    secrets: Arc<Secrets>,
+    messages: mpsc::UnboundedSender<(MessageType, String)>,
+}
+
+impl<'a> MakeWriter<'a> for Logger {
+    type Writer = ClientMessage;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.client_message(MessageType::LOG)
+    }
+
+    fn make_writer_for(&'a self, metadata: &Metadata<'_>) -> Self::Writer {
+        self.client_message(match *metadata.level() {
+            Level::ERROR => MessageType::ERROR,
+            Level::WARN => MessageType::WARNING,
+            Level::INFO => MessageType::INFO,
+            // LSP has no trace messages, both debug and trace ones are logs.
+            _ => MessageType::LOG,
+        })
+    }
+}
+
+impl Write for ClientMessage {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for ClientMessage {
+    fn drop(&mut self) {
+        let message = String::from_utf8_lossy(&self.buffer);
+        let message = self.secrets.redact(message.trim_end());
+        if !message.is_empty() {
+            // Sending only fails once the client is gone.
+            self.messages.send((self.typ, message.into_owned())).ok();
+        }
+    }
+}
+
+struct LogFileWriter {
+    file: Arc<ArcSwapOption<LogFile>>,
+    secrets: Arc<ArcSwap<Secrets>>,
+    // Indent continuation lines, so every text entry starts with its
+    // timestamp.
+    indent: bool,
+}
+
+pub struct LogFileEntry {
+    file: Option<Arc<LogFile>>,
+    secrets: Arc<Secrets>,
+    indent: bool,
+    buffer: Vec<u8>,
+}
+
+impl<'a> MakeWriter<'a> for LogFileWriter {
+    type Writer = LogFileEntry;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        LogFileEntry {
+            file: self.file.load_full(),
+            secrets: self.secrets.load_full(),
+            indent: self.indent,
+            buffer: Vec::new(),
+        }
+    }
+}
+
+impl Write for LogFileEntry {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.file.is_some() {
+            self.buffer.extend_from_slice(buf);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for LogFileEntry {
+    fn drop(&mut self) {
+        let Some(ref log_file) = self.file else {
+            return;
+        };
+
+        let entry = String::from_utf8_lossy(&self.buffer);
+        let mut entry = self.secrets.redact(entry.trim_end()).into_owned();
+        if entry.is_empty() {
+            return;
+        }
+        if self.indent {
+            entry = entry.replace('\n', "\n    ");
+        }
+        entry.push('\n');
+
+        // Logging must never fail a request, so write errors are dropped.
+        log_file.write(entry.as_bytes()).ok();
+    }
+}
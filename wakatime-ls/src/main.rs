@@ -1,233 +1,3428 @@
-use std::{collections::HashMap, fs, sync::Arc};
+mod collab;
+mod commands;
+mod crash;
+mod extension;
+#[cfg(test)]
+mod harness;
+mod log;
+mod notification;
+#[cfg(feature = "otel")]
+mod otel;
+mod replay;
+mod reports;
+#[cfg(feature = "http")]
+mod self_update;
+mod supervisor;
+mod transport;
+#[cfg(feature = "http")]
+mod updates;
+mod uri;
 
-use arc_swap::ArcSwap;
-use chrono::{DateTime, Local, TimeDelta};
-use clap::{Arg, Command};
+use std::{
+    any::Any,
+    collections::HashMap,
+    future::Future,
+    panic::AssertUnwindSafe,
+    path::{Path, PathBuf},
+    process::{ExitCode, Stdio},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use arc_swap::{ArcSwap, ArcSwapOption};
+use chrono::{DateTime, Local, SubsecRound, TimeDelta};
+use clap::{Arg, ArgAction, Command};
+use dashmap::{mapref::entry::Entry, DashMap};
+use futures_util::FutureExt;
 use serde::Deserialize;
-use serde_json::Value;
-use tokio::{process::Command as TokioCommand, sync::Mutex};
-use tower_lsp::{jsonrpc::Result, lsp_types::*, Client, LanguageServer, LspService, Server};
+use serde_json::{json, Value};
+use tokio::{
+    io::AsyncWriteExt,
+    sync::{mpsc, Mutex},
+    task::AbortHandle,
+};
+use tower_lsp::{
+    jsonrpc::Result,
+    lsp_types::{notification::Notification, *},
+    Client, ClientSocket, LanguageServer, LspService, Server,
+};
+use tracing::{
+    debug, debug_span, error, field, info, instrument::WithSubscriber, trace, trace_span, warn,
+    Dispatch, Instrument, Level, Span,
+};
+
+use wakatime_ls_core::{
+    activity::{self, ExportParams, Record},
+    api::{self, Api, Backend},
+    breakdown::{self, BreakdownParams, Group, TimeSpent},
+    budget,
+    cli::{self, Cli, Eviction, QueueLimit},
+    clock::{Clock, SystemClock},
+    config::{self, WakatimeConfig},
+    counters::{Counts, HeartbeatCounters},
+    event::{self, Activity, Event},
+    heartbeat::{Heartbeat, Outcome, SendOptions},
+    instances,
+    lines::LineCounts,
+    memory::{self, MemoryUsage},
+    metrics,
+    paths::{self, PathMapper},
+    profile::{self, Profile},
+    project::{DetectionOptions, Lookup, Project, ProjectCache, ProjectMap},
+    queue::{EventQueue, Pushed},
+    recent::{Attempt, RecentHeartbeats},
+    redact::Secrets,
+    session::{self, Session},
+    sink::RecordingSink,
+    skew, spool,
+    streak::{self, Streak},
+    today,
+    weekly::Schedule,
+};
+#[cfg(feature = "http")]
+use wakatime_ls_core::{
+    durations::{self, Block, DurationsParams},
+    goals::{self, Goal},
+    leaderboard::{self, Leaderboard},
+    weekly,
+};
+
+#[cfg(feature = "http")]
+use crate::notification::{GoalProgress, GoalProgressParams, WeeklySummaryNotification};
+use crate::{
+    extension::Extension,
+    log::{LogFormat, Logger, Rotation},
+    notification::{Focus, FocusParams, StatusBarText, StatusBarTextParams},
+    replay::Recorder,
+    reports::Kind,
+    transport::Listen,
+};
+
+// The `api-key` settings the API refused once `secondary-api-key` took over
+// from them, `None` for the key of the wakatime config, shared by every server
+// in the process. Settings rebuilt with one of them, as when they change, use
+// the secondary key again rather than the refused one.
+static REFUSED_API_KEYS: std::sync::Mutex<Vec<Option<String>>> = std::sync::Mutex::new(Vec::new());
 
-#[derive(Deserialize, Default)]
+#[derive(Deserialize, Default, Clone)]
 struct Settings {
     api_key: Option<String>,
+    // Switched to once the API refuses `api_key`.
+    secondary_api_key: Option<String>,
+    #[serde(skip)]
+    profiles: Option<Vec<(String, Profile)>>,
+    // One of `profiles`, for workspace folders that don't pick their own.
+    profile: Option<String>,
     api_url: Option<String>,
+    // In seconds, for requests to the API of this server and wakatime-cli.
+    connect_timeout: Option<u64>,
+    request_timeout: Option<u64>,
     metrics: Option<bool>,
     debug: Option<bool>,
-    heartbeat_interval: Option<i64>,
+    heartbeat_interval: Option<u64>,
+    adaptive_interval: Option<bool>,
+    // In seconds, the bounds of `adaptive_interval`.
+    min_heartbeat_interval: Option<u64>,
+    max_heartbeat_interval: Option<u64>,
+    // In seconds between write heartbeats of a file, 0 for no limit.
+    write_interval: Option<u64>,
+    submodules_disabled: Option<bool>,
+    worktree_project_names: Option<bool>,
+    manifest_projects: Option<bool>,
+    folder_project_fallback: Option<bool>,
+    project_map: Option<Vec<(String, String)>>,
+    path_mappings: Option<Vec<(String, String)>>,
+    // Where `{channel}` is the channel's name.
+    collab_project: Option<String>,
+    entity_rewrite: Option<Vec<(String, String)>>,
+    remote_relative_paths: Option<bool>,
+    canonicalize_paths: Option<bool>,
+    cli_auto_update: Option<bool>,
+    self_update: Option<bool>,
+    update_notifications: Option<bool>,
+    coordinate_instances: Option<bool>,
+    correct_clock_skew: Option<bool>,
+    log_file: Option<String>,
+    activity_log: Option<bool>,
+    heartbeat_spool: Option<String>,
+    error_reports: Option<bool>,
+    error_report_url: Option<String>,
+    #[serde(skip)]
+    log_level: Option<Level>,
+    #[serde(skip)]
+    log_format: Option<LogFormat>,
+    // In megabytes, 0 to never rotate because of the size.
+    log_max_size: Option<u64>,
+    // In days, 0 to never rotate because of the age.
+    log_max_age: Option<u64>,
+    max_cache_memory: Option<u64>,
+    // In minutes, 0 to keep what is cached until it is evicted.
+    cache_max_age: Option<u64>,
+    // 0 for no limit on the offline queue of wakatime-cli.
+    max_queued_heartbeats: Option<u64>,
+    // In megabytes, 0 for no limit.
+    max_queue_size: Option<u64>,
+    #[serde(skip)]
+    queue_eviction: Option<Eviction>,
+    log_keep: Option<usize>,
+    // In seconds, 0 to never fetch today's coding time.
+    status_bar_interval: Option<u64>,
+    status_bar_format: Option<String>,
+    // In seconds, today's coding time below which the status text is empty.
+    status_bar_hide_below: Option<u64>,
+    goal_notifications: Option<bool>,
+    app_heartbeats: Option<bool>,
+    // 0 for no limit.
+    api_requests_per_minute: Option<u32>,
+    // In minutes of coding without a break, 0 for no reminders.
+    break_reminder: Option<u64>,
+    // In minutes of coding a day, 0 for none.
+    daily_target: Option<u64>,
+    #[serde(skip)]
+    streak_warning: Option<chrono::NaiveTime>,
+    weekly_summary: Option<bool>,
+    #[serde(skip)]
+    weekly_summary_day: Option<chrono::Weekday>,
+    #[serde(skip)]
+    weekly_summary_time: Option<chrono::NaiveTime>,
+}
+
+impl Settings {
+    fn from_options(options: &Value) -> Self {
+        let mut settings = Settings::default();
+
+        if let Some(api_url) = options.get("api-url").and_then(Value::as_str) {
+            settings.api_url = Some(
+                api::normalize_url(api_url).map_or_else(|_| api_url.to_string(), |(url, _)| url),
+            );
+        } else if let Some(backend) = options
+            .get("backend")
+            .and_then(Value::as_str)
+            .and_then(Backend::parse)
+        {
+            settings.api_url = Some(backend.api_url().to_string());
+        }
+
+        if let Some(api_key) = options.get("api-key").and_then(Value::as_str) {
+            settings.api_key = Some(api_key.to_string());
+        }
+
+        if let Some(secondary_api_key) = options.get("secondary-api-key").and_then(Value::as_str) {
+            settings.secondary_api_key = Some(secondary_api_key.to_string());
+        }
+
+        if REFUSED_API_KEYS
+            .lock()
+            .is_ok_and(|refused| refused.contains(&settings.api_key))
+        {
+            if let Some(secondary_api_key) = settings.secondary_api_key.take() {
+                settings.api_key = Some(secondary_api_key);
+            }
+        }
+
+        if let Some(metrics) = options.get("metrics").and_then(Value::as_bool) {
+            settings.metrics = Some(metrics);
+        }
+
+        if let Some(debug) = options.get("debug").and_then(Value::as_bool) {
+            settings.debug = Some(debug);
+        }
+
+        if let Some(submodules_disabled) =
+            options.get("submodules_disabled").and_then(Value::as_bool)
+        {
+            settings.submodules_disabled = Some(submodules_disabled);
+        }
+
+        if let Some(worktree_project_names) = options
+            .get("worktree_project_names")
+            .and_then(Value::as_bool)
+        {
+            settings.worktree_project_names = Some(worktree_project_names);
+        }
+
+        if let Some(manifest_projects) = options.get("manifest_projects").and_then(Value::as_bool) {
+            settings.manifest_projects = Some(manifest_projects);
+        }
+
+        if let Some(folder_project_fallback) = options
+            .get("folder_project_fallback")
+            .and_then(Value::as_bool)
+        {
+            settings.folder_project_fallback = Some(folder_project_fallback);
+        }
+
+        if let Some(profiles) = options.get("profiles").and_then(Value::as_object) {
+            settings.profiles = Some(profile::parse_profiles(profiles));
+        }
+
+        if let Some(profile) = options.get("profile").and_then(Value::as_str) {
+            settings.profile = Some(profile.to_string());
+        }
+
+        if let Some(project_map) = options.get("projectmap").and_then(Value::as_object) {
+            settings.project_map = Some(
+                project_map
+                    .iter()
+                    .filter_map(|(pattern, project)| {
+                        Some((pattern.clone(), project.as_str()?.to_string()))
+                    })
+                    .collect(),
+            );
+        }
+
+        if let Some(path_mappings) = options.get("path_mappings").and_then(Value::as_object) {
+            settings.path_mappings = Some(
+                path_mappings
+                    .iter()
+                    .filter_map(|(remote, local)| {
+                        Some((remote.clone(), local.as_str()?.to_string()))
+                    })
+                    .collect(),
+            );
+        }
+
+        if let Some(collab_project) = options.get("collab_project").and_then(Value::as_str) {
+            settings.collab_project = Some(collab_project.to_string());
+        }
+
+        if let Some(entity_rewrite) = options.get("entity_rewrite").and_then(Value::as_object) {
+            settings.entity_rewrite = Some(
+                entity_rewrite
+                    .iter()
+                    .filter_map(|(pattern, replacement)| {
+                        Some((pattern.clone(), replacement.as_str()?.to_string()))
+                    })
+                    .collect(),
+            );
+        }
+
+        if let Some(remote_relative_paths) = options
+            .get("remote_relative_paths")
+            .and_then(Value::as_bool)
+        {
+            settings.remote_relative_paths = Some(remote_relative_paths);
+        }
+
+        if let Some(canonicalize_paths) = options.get("canonicalize_paths").and_then(Value::as_bool)
+        {
+            settings.canonicalize_paths = Some(canonicalize_paths);
+        }
+
+        if let Some(cli_auto_update) = options.get("cli_auto_update").and_then(Value::as_bool) {
+            settings.cli_auto_update = Some(cli_auto_update);
+        }
+
+        if let Some(self_update) = options.get("self_update").and_then(Value::as_bool) {
+            settings.self_update = Some(self_update);
+        }
+
+        if let Some(update_notifications) =
+            options.get("update_notifications").and_then(Value::as_bool)
+        {
+            settings.update_notifications = Some(update_notifications);
+        }
+
+        if let Some(correct_clock_skew) = options.get("correct_clock_skew").and_then(Value::as_bool)
+        {
+            settings.correct_clock_skew = Some(correct_clock_skew);
+        }
+
+        if let Some(coordinate_instances) =
+            options.get("coordinate_instances").and_then(Value::as_bool)
+        {
+            settings.coordinate_instances = Some(coordinate_instances);
+        }
+
+        if let Some(log_file) = options.get("log_file").and_then(Value::as_str) {
+            settings.log_file = Some(log_file.to_string());
+        }
+
+        if let Some(activity_log) = options.get("activity_log").and_then(Value::as_bool) {
+            settings.activity_log = Some(activity_log);
+        }
+
+        if let Some(heartbeat_spool) = options.get("heartbeat_spool").and_then(Value::as_str) {
+            settings.heartbeat_spool = Some(heartbeat_spool.to_string());
+        }
+
+        if let Some(error_reports) = options.get("error_reports").and_then(Value::as_bool) {
+            settings.error_reports = Some(error_reports);
+        }
+
+        if let Some(error_report_url) = options.get("error_report_url").and_then(Value::as_str) {
+            settings.error_report_url = Some(error_report_url.to_string());
+        }
+
+        if let Some(log_level) = options
+            .get("log_level")
+            .and_then(Value::as_str)
+            .and_then(|log_level| log_level.parse().ok())
+        {
+            settings.log_level = Some(log_level);
+        }
+
+        if let Some(log_format) = options
+            .get("log_format")
+            .and_then(Value::as_str)
+            .and_then(LogFormat::parse)
+        {
+            settings.log_format = Some(log_format);
+        }
+
+        if let Some(log_max_size) = options.get("log_max_size").and_then(Value::as_u64) {
+            settings.log_max_size = Some(log_max_size);
+        }
+
+        if let Some(cache_max_age) = options.get("cache_max_age").and_then(Value::as_u64) {
+            settings.cache_max_age = Some(cache_max_age);
+        }
+
+        if let Some(max_queued_heartbeats) =
+            options.get("max_queued_heartbeats").and_then(Value::as_u64)
+        {
+            settings.max_queued_heartbeats = Some(max_queued_heartbeats);
+        }
+
+        if let Some(max_queue_size) = options.get("max_queue_size").and_then(Value::as_u64) {
+            settings.max_queue_size = Some(max_queue_size);
+        }
+
+        if let Some(queue_eviction) = options
+            .get("queue_eviction")
+            .and_then(Value::as_str)
+            .and_then(Eviction::parse)
+        {
+            settings.queue_eviction = Some(queue_eviction);
+        }
+
+        if let Some(log_max_age) = options.get("log_max_age").and_then(Value::as_u64) {
+            settings.log_max_age = Some(log_max_age);
+        }
+
+        if let Some(max_cache_memory) = options.get("max_cache_memory").and_then(Value::as_u64) {
+            settings.max_cache_memory = Some(max_cache_memory);
+        }
+
+        if let Some(log_keep) = options.get("log_keep").and_then(Value::as_u64) {
+            settings.log_keep = Some(log_keep as usize);
+        }
+
+        if let Some(heartbeat_interval) = options.get("heartbeat_interval").and_then(Value::as_u64)
+        {
+            settings.heartbeat_interval = Some(heartbeat_interval);
+        }
+
+        if let Some(adaptive_interval) = options.get("adaptive_interval").and_then(Value::as_bool) {
+            settings.adaptive_interval = Some(adaptive_interval);
+        }
+
+        if let Some(min_heartbeat_interval) = options
+            .get("min_heartbeat_interval")
+            .and_then(Value::as_u64)
+        {
+            settings.min_heartbeat_interval = Some(min_heartbeat_interval);
+        }
+
+        if let Some(max_heartbeat_interval) = options
+            .get("max_heartbeat_interval")
+            .and_then(Value::as_u64)
+        {
+            settings.max_heartbeat_interval = Some(max_heartbeat_interval);
+        }
+
+        if let Some(write_interval) = options.get("write_interval").and_then(Value::as_u64) {
+            settings.write_interval = Some(write_interval);
+        }
+
+        if let Some(connect_timeout) = options
+            .get("connect_timeout")
+            .and_then(Value::as_u64)
+            .filter(|&timeout| timeout > 0)
+        {
+            settings.connect_timeout = Some(connect_timeout);
+        }
+
+        if let Some(request_timeout) = options
+            .get("request_timeout")
+            .and_then(Value::as_u64)
+            .filter(|&timeout| timeout > 0)
+        {
+            settings.request_timeout = Some(request_timeout);
+        }
+
+        if let Some(status_bar_interval) =
+            options.get("status_bar_interval").and_then(Value::as_u64)
+        {
+            settings.status_bar_interval = Some(status_bar_interval);
+        }
+
+        if let Some(status_bar_format) = options.get("status_bar_format").and_then(Value::as_str) {
+            settings.status_bar_format = Some(status_bar_format.to_string());
+        }
+
+        if let Some(status_bar_hide_below) =
+            options.get("status_bar_hide_below").and_then(Value::as_u64)
+        {
+            settings.status_bar_hide_below = Some(status_bar_hide_below);
+        }
+
+        if let Some(api_requests_per_minute) = options
+            .get("api_requests_per_minute")
+            .and_then(Value::as_u64)
+        {
+            settings.api_requests_per_minute = Some(api_requests_per_minute as u32);
+        }
+
+        if let Some(break_reminder) = options.get("break_reminder").and_then(Value::as_u64) {
+            settings.break_reminder = Some(break_reminder);
+        }
+
+        if let Some(daily_target) = options.get("daily_target").and_then(Value::as_u64) {
+            settings.daily_target = Some(daily_target);
+        }
+
+        if let Some(goal_notifications) = options.get("goal_notifications").and_then(Value::as_bool)
+        {
+            settings.goal_notifications = Some(goal_notifications);
+        }
+
+        if let Some(app_heartbeats) = options.get("app_heartbeats").and_then(Value::as_bool) {
+            settings.app_heartbeats = Some(app_heartbeats);
+        }
+
+        if let Some(streak_warning) = options
+            .get("streak_warning")
+            .and_then(Value::as_str)
+            .and_then(Schedule::parse_time)
+        {
+            settings.streak_warning = Some(streak_warning);
+        }
+
+        if let Some(weekly_summary) = options.get("weekly_summary").and_then(Value::as_bool) {
+            settings.weekly_summary = Some(weekly_summary);
+        }
+
+        if let Some(weekly_summary_day) = options
+            .get("weekly_summary_day")
+            .and_then(Value::as_str)
+            .and_then(Schedule::parse_weekday)
+        {
+            settings.weekly_summary_day = Some(weekly_summary_day);
+        }
+
+        if let Some(weekly_summary_time) = options
+            .get("weekly_summary_time")
+            .and_then(Value::as_str)
+            .and_then(Schedule::parse_time)
+        {
+            settings.weekly_summary_time = Some(weekly_summary_time);
+        }
+
+        settings
+    }
 }
 
+const DUMP_RECENT_COMMAND: &str = "wakatime.dumpRecent";
+#[cfg(feature = "http")]
+const LIST_GOALS_COMMAND: &str = "wakatime.listGoals";
+#[cfg(feature = "http")]
+const LEADERBOARD_COMMAND: &str = "wakatime.leaderboard";
+const OPEN_DASHBOARD_COMMAND: &str = "wakatime.openDashboard";
+const EXPORT_ACTIVITY_COMMAND: &str = "wakatime.exportActivity";
+const SNOOZE_BREAK_REMINDER_COMMAND: &str = "wakatime.snoozeBreakReminder";
+
+// What `workspace/executeCommand` takes. Listing goals and the leaderboard
+// asks the API, so builds without it leave them out.
+const COMMANDS: &[&str] = &[
+    DUMP_RECENT_COMMAND,
+    #[cfg(feature = "http")]
+    LIST_GOALS_COMMAND,
+    #[cfg(feature = "http")]
+    LEADERBOARD_COMMAND,
+    OPEN_DASHBOARD_COMMAND,
+    EXPORT_ACTIVITY_COMMAND,
+    SNOOZE_BREAK_REMINDER_COMMAND,
+];
+
+// How long the caches of each file and folder are kept without a heartbeat,
+// unless the `cache_max_age` setting says otherwise, and how often they are
+// pruned at most.
+const CACHE_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+// How long changes to `~/.wakatime.cfg` are waited for to settle, as saving
+// it can take several writes.
+const CONFIG_SETTLE: Duration = Duration::from_millis(200);
+
+// How often today's coding time is fetched for the status bar, unless the
+// `status_bar_interval` setting says otherwise.
+const STATUS_BAR_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+const STATUS_BAR_FORMAT: &str = "{total}";
+
+// How often `app_heartbeats` checks whether Zed went a heartbeat interval
+// without one.
+const APP_HEARTBEAT_CHECK: Duration = Duration::from_secs(30);
+
+// How often goal progress is fetched with `goal_notifications`.
+#[cfg(feature = "http")]
+const GOALS_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+// How often the clock is checked while waiting for a time of day. Sleeps
+// don't count the time the machine is suspended, so one long sleep until the
+// weekly summary is due would be late after a night in standby.
+const CLOCK_CHECK: Duration = Duration::from_secs(60);
+
+// How often the heartbeat counters are logged.
+const SUMMARY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// How often the wakatime-cli installed by the language server is checked for
+// updates.
+#[cfg(feature = "http")]
+const CLI_UPDATE_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+// Runs of wakatime-cli failed in a row before it is reported with
+// `error_reports`, as one failure is usually a file wakatime-cli couldn't
+// read. A batch of heartbeats sent with one run counts once.
+const FAILURE_REPORT_THRESHOLD: u32 = 5;
+
+// How often the offline queue of wakatime-cli is counted for the metrics
+// served with `--metrics-listen` or sent with `--statsd`, and checked against
+// its limits.
+const QUEUE_DEPTH_INTERVAL: Duration = Duration::from_secs(60);
+
 #[derive(Debug, Clone)]
 struct FileCacheEntry {
     lineno: u64,
     cursor_pos: u64,
+    // Which change of any file this was, so the oldest can be evicted.
+    changed: u64,
+    // When the last write heartbeat of the file was sent.
+    written: Option<Instant>,
+    // When the file last changed, to forget it after `cache_max_age`.
+    touched: Instant,
+}
+
+// The file of the last event, and when the last heartbeat that restarts the
+// interval was sent, in nanoseconds of the monotonic clock since `origin`.
+// Every keystroke reads and writes them, so they aren't behind a lock.
+#[derive(Debug)]
+struct CurrentFile {
+    entity: ArcSwap<Arc<Path>>,
+    origin: Instant,
+    timestamp: AtomicU64,
+}
+
+impl CurrentFile {
+    fn new(origin: Instant) -> Self {
+        Self {
+            entity: ArcSwap::from_pointee(Arc::from(Path::new(""))),
+            origin,
+            timestamp: AtomicU64::new(0),
+        }
+    }
+
+    // Make `entity` the current file, returning whether it is another one.
+    // Staying in the same file, as most events do, writes nothing.
+    fn switch(&self, entity: &Arc<Path>) -> bool {
+        if **self.entity.load() == *entity {
+            return false;
+        }
+        self.entity.store(Arc::new(entity.clone()));
+        true
+    }
+
+    fn timestamp(&self) -> Instant {
+        self.origin + Duration::from_nanos(self.timestamp.load(Ordering::Relaxed))
+    }
+
+    fn set_timestamp(&self, timestamp: Instant) {
+        let nanos = timestamp.saturating_duration_since(self.origin).as_nanos();
+        self.timestamp.store(nanos as u64, Ordering::Relaxed);
+    }
+}
+
+struct WakatimeLanguageServer {
+    client: Client,
+    logger: Logger,
+    // Given with `--log-file`, which takes precedence over the `log_file`
+    // setting.
+    log_file: Option<PathBuf>,
+    // A client over `--listen` without `--trust-clients`, whose options are
+    // taken through `restrict_options`.
+    untrusted: bool,
+    settings: Arc<ArcSwap<Settings>>,
+    initialization_options: ArcSwap<Value>,
+    // Sent with `workspace/didChangeConfiguration`, such as Zed's `settings`
+    // of the language server, over the initialization options.
+    client_settings: ArcSwap<Value>,
+    config: Arc<ArcSwap<WakatimeConfig>>,
+    workspace_folders: Mutex<Vec<WorkspaceFolder>>,
+    supports_configuration: AtomicBool,
+    supports_watched_files: AtomicBool,
+    supports_show_document: AtomicBool,
+    // Unset until a wakatime-cli has been found or installed.
+    wakatime_path: Arc<ArcSwapOption<Cli>>,
+    // The version `wakatime_path` reported, added to the plugin string.
+    wakatime_version: Arc<ArcSwapOption<String>>,
+    // Whether `wakatime_path` was given with `--wakatime-cli` rather than
+    // found, so it is kept even once it is gone.
+    wakatime_cli_given: bool,
+    // The binary of `wakatime_path` and when it was last written, as of its
+    // last check, to check it again once an update replaced or removed it.
+    wakatime_modified: std::sync::Mutex<Option<(PathBuf, Option<SystemTime>)>>,
+    // Set once the wakatime-cli in `~/.wakatime/` is checked for updates, so
+    // finding it again doesn't start another check.
+    #[cfg(feature = "http")]
+    updating_wakatime_cli: Arc<AtomicBool>,
+    current_file: Arc<CurrentFile>,
+    platform: Arc<ArcSwap<String>>,
+    // Whether the client last reported the editor focused with
+    // `wakatime/focus`.
+    focused: Arc<AtomicBool>,
+    extension: ArcSwap<Extension>,
+    // The cursor of each file at its last change, for its saves.
+    file_cache: Arc<DashMap<Arc<Path>, FileCacheEntry>>,
+    line_counts: Arc<LineCounts>,
+    changes: AtomicU64,
+    events: Arc<EventQueue>,
+    // For `adaptive_interval`.
+    activity: std::sync::Mutex<Activity>,
+    project_cache: Arc<Mutex<ProjectCache>>,
+    // The branch of each project at its last heartbeat.
+    project_branches: Mutex<HashMap<String, Option<String>>>,
+    path_mapper: ArcSwap<PathMapper>,
+    // The profile picked for each workspace folder, by its settings or its
+    // `.wakatime.toml`.
+    folder_profiles: Arc<ArcSwap<Vec<(PathBuf, String)>>>,
+    // The project of the last heartbeat, whose time the status text can show.
+    current_project: Arc<ArcSwapOption<String>>,
+    // What was last pushed to the status bar, which a status bar task started
+    // again for new settings doesn't push again.
+    status_bar_text: Arc<ArcSwapOption<StatusBarTextParams>>,
+    // The goals as of their last fetch with `goal_notifications`, which a
+    // goal progress task started again doesn't push or celebrate again.
+    #[cfg(feature = "http")]
+    goals: Arc<ArcSwapOption<Vec<Goal>>>,
+    // The last day `daily_target` was seen not reached yet, kept for a daily
+    // target task started again for a new target.
+    below_target_on: Arc<std::sync::Mutex<Option<chrono::NaiveDate>>>,
+    session: Arc<std::sync::Mutex<Session>>,
+    // Also shared through a file with the other servers on this machine.
+    streak: std::sync::Mutex<Streak>,
+    recent_heartbeats: Arc<Mutex<RecentHeartbeats>>,
+    heartbeat_counters: Arc<HeartbeatCounters>,
+    last_failure: Arc<std::sync::Mutex<Option<String>>>,
+    // Whether the offline queue of wakatime-cli is over its limits, so that
+    // heartbeats that can't be sent are dropped.
+    queue_full: Arc<AtomicBool>,
+    // Set by SIGHUP, for the next editor event to reload the settings.
+    reload_requested: Arc<AtomicBool>,
+    // Stopped when the server is dropped, as its client is gone.
+    tasks: Arc<std::sync::Mutex<Vec<AbortHandle>>>,
+    // Those of `tasks` started for some of the settings, by name, stopped
+    // when those settings change.
+    configured_tasks: std::sync::Mutex<HashMap<&'static str, ConfiguredTask>>,
+    // What heartbeats are stamped with and intervals measured against.
+    clock: Arc<dyn Clock>,
+    delivery: Delivery,
+}
+
+impl Drop for WakatimeLanguageServer {
+    fn drop(&mut self) {
+        if let Ok(tasks) = self.tasks.lock() {
+            tasks.iter().for_each(AbortHandle::abort);
+        }
+    }
+}
+
+// A task started for some of the settings, and the values of them it was
+// started for.
+struct ConfiguredTask {
+    settings: Box<dyn Any + Send>,
+    handle: AbortHandle,
 }
 
-#[derive(Debug, Default)]
-struct FileCache {
-    entries: HashMap<String, FileCacheEntry>,
+// What sending a heartbeat takes, shared with the tasks that send heartbeats
+// of their own, such as for the app, which outlive a borrow of the server.
+#[derive(Clone)]
+struct Delivery {
+    client: Client,
+    settings: Arc<ArcSwap<Settings>>,
+    config: Arc<ArcSwap<WakatimeConfig>>,
+    platform: Arc<ArcSwap<String>>,
+    wakatime_version: Arc<ArcSwapOption<String>>,
+    // This server's name in the registry of `instances`.
+    instance: Arc<str>,
+    // Heartbeats failed since the last one that didn't, and the error of the
+    // last, for `error_reports`.
+    send_failures: Arc<AtomicU32>,
+    last_failure: Arc<std::sync::Mutex<Option<String>>>,
+    // Whether the user was told that the clock is off from the API's.
+    clock_skew_warned: Arc<AtomicBool>,
+    queue_full: Arc<AtomicBool>,
+    tasks: Arc<std::sync::Mutex<Vec<AbortHandle>>>,
+    clock: Arc<dyn Clock>,
+    // Set by tests, which get the heartbeats instead of wakatime-cli.
+    sink: Option<RecordingSink>,
 }
 
-type SharedFileCache = Arc<Mutex<FileCache>>;
+impl Delivery {
+    // The `--plugin` of heartbeats, once the client said which editor it is.
+    fn plugin(&self) -> Option<String> {
+        let platform = self.platform.load();
+        (!platform.is_empty()).then(|| {
+            plugin(
+                &platform,
+                self.wakatime_version.load().as_deref().map(String::as_str),
+            )
+        })
+    }
+
+    // Whether this server may send a heartbeat for `entity` now, or another
+    // one on this machine sent one within `interval`. When the registry can't
+    // be used, heartbeats are sent as if there were no other server.
+    async fn claim(&self, entity: &Arc<Path>, interval: Duration) -> bool {
+        if self.settings.load().coordinate_instances == Some(false) {
+            return true;
+        }
+        let Some(path) = instances::path() else {
+            return true;
+        };
+
+        let (instance, entity) = (self.instance.clone(), entity.clone());
+        let time = self.clock.now().timestamp();
+        let claim = tokio::task::spawn_blocking(move || {
+            instances::claim(&path, &instance, &entity, time, interval)
+                .map_err(|err| format!("could not update {}: {err}", path.display()))
+        });
+
+        match claim.await {
+            Ok(Ok(claimed)) => claimed,
+            Ok(Err(err)) => {
+                warn!("{err}");
+                true
+            }
+            Err(_) => true,
+        }
+    }
+
+    // The time of a heartbeat sent `now`, moved to the API's clock when
+    // `correct_clock_skew` is on and this machine's is off from it, as the
+    // user is told once.
+    async fn heartbeat_time(&self, now: DateTime<Local>) -> DateTime<Local> {
+        let Some(offset) = skew::offset() else {
+            return now;
+        };
+        let correct = self.settings.load().correct_clock_skew == Some(true);
+
+        if !self.clock_skew_warned.swap(true, Ordering::Relaxed) {
+            let message = if correct {
+                format!(
+                    "{}, heartbeats are sent with the API's time",
+                    skew::describe(offset)
+                )
+            } else {
+                format!(
+                    "{}, so coding time may show up at the wrong time or not at all on the dashboard. Set the time of this machine automatically, or turn on correct_clock_skew",
+                    skew::describe(offset)
+                )
+            };
+            warn!("{message}");
+            self.client
+                .show_message(MessageType::WARNING, format!("WakaTime: {message}"))
+                .await;
+        }
+
+        if correct {
+            now + offset
+        } else {
+            now
+        }
+    }
+
+    // Send heartbeats with one wakatime-cli, or to the sink of tests, after
+    // writing them to the `heartbeat_spool`, returning what became of them
+    // all. Heartbeats refused for their key are sent again with the secondary
+    // one, so switching to it loses nothing; a profile's key has none.
+    async fn send(
+        &self,
+        heartbeats: &[Heartbeat],
+        wakatime_cli: &Cli,
+        profile: Option<&Profile>,
+        now: DateTime<Local>,
+    ) -> Outcome {
+        if let Some(ref dir) = self.settings.load().heartbeat_spool {
+            let dir = paths::canonicalize(Path::new(dir));
+            for heartbeat in heartbeats {
+                if let Err(err) = spool::write(&dir, heartbeat, now) {
+                    warn!("could not spool heartbeat to {}: {err}", dir.display());
+                }
+            }
+        }
+
+        let send = || async {
+            match self.sink {
+                Some(ref sink) => {
+                    heartbeats
+                        .iter()
+                        .for_each(|heartbeat| sink.record(heartbeat));
+                    (Outcome::Sent, Some(0))
+                }
+                None => {
+                    run_wakatime_cli(
+                        &self.settings,
+                        &self.config,
+                        &self.queue_full,
+                        &self.last_failure,
+                        wakatime_cli,
+                        heartbeats,
+                        profile,
+                    )
+                    .await
+                }
+            }
+        };
+        let (mut outcome, code) = send().await;
+        let default_key = profile.is_none_or(|profile| profile.api_key.is_none());
+        if code == Some(104)
+            && default_key
+            && self.fail_over_api_key().await
+            && budget::try_acquire()
+        {
+            (outcome, _) = send().await;
+        }
+
+        self.report_failures(outcome);
+
+        if self.settings.load().activity_log != Some(true) {
+            return outcome;
+        }
+        for heartbeat in heartbeats {
+            let record = Record {
+                time: now.trunc_subsecs(0),
+                entity: heartbeat.entity.to_string_lossy().into_owned(),
+                project: heartbeat
+                    .project
+                    .clone()
+                    .or_else(|| heartbeat.alternate_project.clone()),
+                branch: heartbeat.branch.clone(),
+                language: heartbeat.language.clone(),
+                category: heartbeat.category.clone(),
+                is_write: heartbeat.is_write,
+                outcome,
+            };
+            if let Some(Err(err)) = activity::path().map(|path| activity::append(&path, &record)) {
+                warn!("could not record activity: {err}");
+            }
+        }
+
+        outcome
+    }
+
+    // Report sends failing `FAILURE_REPORT_THRESHOLD` times in a row with the
+    // last error, once until one doesn't.
+    fn report_failures(&self, outcome: Outcome) {
+        if outcome != Outcome::Failed {
+            self.send_failures.store(0, Ordering::Relaxed);
+            return;
+        }
+        let failures = self.send_failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures != FAILURE_REPORT_THRESHOLD {
+            return;
+        }
+
+        let error = self
+            .last_failure
+            .lock()
+            .ok()
+            .and_then(|last_failure| last_failure.clone())
+            .unwrap_or_default();
+        let message = format!("{failures} sends in a row failed: {error}");
+        if reports::record(Kind::SendFailures, &message).is_some() {
+            #[cfg(feature = "http")]
+            self.upload_error_reports();
+        }
+    }
+
+    // Upload the reports written so far, including those of panics in earlier
+    // runs, when `error_report_url` is set.
+    #[cfg(feature = "http")]
+    fn upload_error_reports(&self) {
+        let settings = self.settings.load();
+        if settings.error_reports != Some(true) {
+            return;
+        }
+        let Some(url) = settings.error_report_url.clone() else {
+            return;
+        };
+
+        let task = tokio::spawn(
+            async move {
+                match reports::upload(&url).await {
+                    Ok(0) => {}
+                    Ok(uploaded) => debug!("uploaded {uploaded} error reports to {url}"),
+                    Err(err) => warn!("could not upload error reports: {err}"),
+                }
+            }
+            .with_current_subscriber(),
+        );
+        if let Ok(mut tasks) = self.tasks.lock() {
+            tasks.push(task.abort_handle());
+        }
+    }
+
+    // Switch to the `secondary-api-key` setting once wakatime-cli says the key
+    // in use was refused, and tell the user, so an expiring key doesn't stop
+    // tracking without anyone noticing. The refused key is remembered in
+    // `REFUSED_API_KEYS`, for the settings to keep the secondary key once
+    // they are rebuilt. Returns whether it switched.
+    async fn fail_over_api_key(&self) -> bool {
+        let mut settings = Settings::clone(&self.settings.load());
+        let Some(secondary) = settings.secondary_api_key.take() else {
+            return false;
+        };
+        if let Ok(mut refused) = REFUSED_API_KEYS.lock() {
+            if !refused.contains(&settings.api_key) {
+                refused.push(settings.api_key.clone());
+            }
+        }
+        settings.api_key = Some(secondary);
+        remove_unused_key_configs(&settings, &self.config.load());
+        self.settings.store(Arc::new(settings));
+
+        warn!("the API key was refused, switching to secondary-api-key");
+        self.client
+            .show_message(
+                MessageType::WARNING,
+                "WakaTime refused the API key, heartbeats are now sent with the secondary API key",
+            )
+            .await;
+        true
+    }
+}
+
+// An editor event made into a heartbeat, waiting to be sent along with the
+// others of its flush.
+struct Prepared {
+    heartbeat: Heartbeat,
+    wakatime_cli: Arc<Cli>,
+    profile: Option<Profile>,
+}
+
+// An editor event of a flush, with what its span and the counters take once
+// it has an outcome: right away when it isn't sent, such as `skipped`.
+struct Pending {
+    span: Span,
+    start: Instant,
+    time: DateTime<Local>,
+    entity: Arc<Path>,
+    is_write: bool,
+    heartbeat: std::result::Result<Prepared, Outcome>,
+}
+
+async fn sleep_until(clock: &dyn Clock, due: DateTime<Local>) {
+    while clock.now() < due {
+        let left = (due - clock.now()).to_std().unwrap_or_default();
+        tokio::time::sleep(left.min(CLOCK_CHECK)).await;
+    }
+}
+
+// The API the settings and the wakatime config point at, for tasks that
+// outlive a borrow of the server.
+fn resolve_api(settings: &ArcSwap<Settings>, config: &ArcSwap<WakatimeConfig>) -> Api {
+    let settings = settings.load();
+    let mut api = Api::resolve(
+        settings.api_key.as_deref(),
+        settings.api_url.as_deref(),
+        &config.load(),
+    );
+
+    if let Some(connect_timeout) = settings.connect_timeout {
+        api.timeouts.connect = Duration::from_secs(connect_timeout);
+    }
+    if let Some(request_timeout) = settings.request_timeout {
+        api.timeouts.request = Duration::from_secs(request_timeout);
+    }
+    api
+}
+
+// Remove the copies of the wakatime config written for keys that neither the
+// settings nor the config have any more, such as one replaced in the settings
+// or refused.
+fn remove_unused_key_configs(settings: &Settings, config: &WakatimeConfig) {
+    let keys: Vec<&str> = [
+        settings.api_key.as_deref(),
+        settings.secondary_api_key.as_deref(),
+        config.get("settings", "api_key"),
+    ]
+    .into_iter()
+    .chain(
+        settings
+            .profiles
+            .iter()
+            .flatten()
+            .map(|(_, profile)| profile.api_key.as_deref()),
+    )
+    .flatten()
+    .collect();
+    cli::remove_key_configs(&keys);
+}
+
+// The profile of the workspace folder `entity` is in, or else of the `profile`
+// setting, for tasks that outlive a borrow of the server.
+fn resolve_profile(
+    settings: &Settings,
+    folder_profiles: &[(PathBuf, String)],
+    entity: Option<&Path>,
+) -> Option<Profile> {
+    let name = folder_profiles
+        .iter()
+        .find(|(root, _)| entity.is_some_and(|entity| entity.starts_with(root)))
+        .map(|(_, name)| name)
+        .or(settings.profile.as_ref())?;
+
+    settings
+        .profiles
+        .iter()
+        .flatten()
+        .find(|(profile_name, _)| profile_name == name)
+        .map(|(_, profile)| profile.clone())
+}
+
+// What the caches of a server take, for tasks that outlive a borrow of it.
+async fn memory_usage(
+    settings: &Settings,
+    file_cache: &DashMap<Arc<Path>, FileCacheEntry>,
+    line_counts: &LineCounts,
+    project_cache: &Mutex<ProjectCache>,
+    events: &EventQueue,
+) -> MemoryUsage {
+    let limit = match settings.max_cache_memory {
+        Some(0) => None,
+        Some(megabytes) => Some(megabytes as usize * 1024 * 1024),
+        None => Some(memory::DEFAULT_LIMIT),
+    };
+
+    MemoryUsage {
+        file_cache: file_cache
+            .iter()
+            .map(|entry| memory::entry_size::<FileCacheEntry>(entry.key()))
+            .sum(),
+        line_counts: line_counts.approximate_size(),
+        project_cache: project_cache.lock().await.approximate_size(),
+        events: events.approximate_size(),
+        limit,
+    }
+}
+
+// Warn about an API URL that had to be fixed up, or can't work at all, which
+// is easy to get wrong when pasting the URL of a self-hosted server.
+fn check_api_url(source: &str, url: &str) {
+    match api::normalize_url(url) {
+        Ok((_, problems)) => {
+            for problem in problems {
+                warn!("{source} {url} {problem}");
+            }
+        }
+        Err(err) => warn!("{source} {url} is not a valid API URL: {err}"),
+    }
+}
+
+// For requests that fail on the server's side, such as an API that can't
+// be reached.
+fn internal_error(message: String) -> tower_lsp::jsonrpc::Error {
+    tower_lsp::jsonrpc::Error {
+        code: tower_lsp::jsonrpc::ErrorCode::InternalError,
+        message: message.into(),
+        data: None,
+    }
+}
+
+// Run `wakatime-cli --version` before sending heartbeats with it, so a path
+// that can't run or a release that is too old is reported once instead of
+// failing silently on every heartbeat. Returns the version it reported.
+async fn check_wakatime_cli(cli: &Cli) -> Option<String> {
+    let Some(version) = cli.version().await else {
+        warn!("wakatime-cli {cli} could not be run, heartbeats will fail");
+        return None;
+    };
+
+    info!("wakatime-cli version {version}");
+
+    if !cli::is_supported(&version) {
+        warn!(
+            "wakatime-cli {version} is older than {}, the minimum supported release; please update it",
+            cli::MINIMUM_VERSION
+        );
+    }
+
+    Some(version)
+}
+
+// Check for a newer wakatime-cli every `CLI_UPDATE_INTERVAL`, starting now.
+#[cfg(feature = "http")]
+async fn update_wakatime_cli(
+    settings: &ArcSwap<Settings>,
+    cli: &Cli,
+    cli_version: &ArcSwapOption<String>,
+) {
+    let mut interval = tokio::time::interval(CLI_UPDATE_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        if settings.load().cli_auto_update == Some(false) {
+            continue;
+        }
+
+        match cli::update(cli).await {
+            Ok(Some(version)) => {
+                info!("updated wakatime-cli to {version}");
+                cli_version.store(Some(Arc::new(version)));
+            }
+            Ok(None) => {}
+            Err(err) => {
+                warn!("could not update wakatime-cli: {err}");
+            }
+        }
+    }
+}
+
+// Check for a newer wakatime-ls every `CLI_UPDATE_INTERVAL`, starting now,
+// swapping it in for the next start of the server.
+#[cfg(feature = "http")]
+async fn update_self() {
+    let mut interval = tokio::time::interval(CLI_UPDATE_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        match self_update::update().await {
+            Ok(Some(version)) => {
+                info!("updated wakatime-ls to {version}, which runs once the language server restarts");
+            }
+            Ok(None) => {}
+            Err(err) => {
+                warn!("could not update wakatime-ls: {err}");
+            }
+        }
+    }
+}
+
+// The `--plugin` of heartbeats: the editor and this server, the system they
+// run on, as dashboards break coding time down by it, and the wakatime-cli
+// sending them once its version is known.
+//
+// Example:
+// "Zed/0.200.0 Zed-hackatime/0.3.1", v1.102.1 -> "Zed/0.200.0 Zed-hackatime/0.3.1 (linux x86_64) wakatime-cli/v1.102.1"
+fn plugin(platform: &str, wakatime_version: Option<&str>) -> String {
+    let plugin = format!(
+        "{platform} ({} {})",
+        std::env::consts::OS,
+        std::env::consts::ARCH
+    );
+
+    match wakatime_version {
+        Some(version) => format!("{plugin} wakatime-cli/{version}"),
+        None => plugin,
+    }
+}
+
+// Send heartbeats with wakatime-cli, with the key and URL of a profile over
+// the settings', returning its exit code along with the outcome. The first is
+// given on its command line and the others as `--extra-heartbeats` on its
+// stdin, so a burst of events costs one wakatime-cli and one API request.
+//
+// wakatime-cli has no mode that keeps running: it reads its stdin until it is
+// closed, sends every heartbeat and exits, so heartbeats are only sent
+// together when they are waiting at the same time.
+async fn run_wakatime_cli(
+    settings: &ArcSwap<Settings>,
+    config: &ArcSwap<WakatimeConfig>,
+    queue_full: &AtomicBool,
+    last_failure: &std::sync::Mutex<Option<String>>,
+    wakatime_cli: &Cli,
+    heartbeats: &[Heartbeat],
+    profile: Option<&Profile>,
+) -> (Outcome, Option<i32>) {
+    let Some((heartbeat, extra)) = heartbeats.split_first() else {
+        return (Outcome::Sent, Some(0));
+    };
+    let set_last_failure = |message| {
+        if let Ok(mut last_failure) = last_failure.lock() {
+            *last_failure = Some(message);
+        }
+    };
+    let resolved_key = || resolve_api(settings, config).key;
+    let settings = settings.load();
+    let disable_offline = queue_full.load(Ordering::Relaxed);
+    let api_key = profile
+        .and_then(|profile| profile.api_key.as_deref())
+        .or(settings.api_key.as_deref());
+    let api_url = profile
+        .and_then(|profile| profile.api_url.as_deref())
+        .or(settings.api_url.as_deref());
+    let command = heartbeat.command(
+        wakatime_cli,
+        SendOptions {
+            api_key,
+            api_url,
+            metrics: settings.metrics == Some(true),
+            verbose: settings.debug == Some(true),
+            timeout: settings.request_timeout.map(Duration::from_secs),
+            disable_offline,
+        },
+    );
+    let mut command = match command {
+        Ok(command) => command,
+        Err(err) => {
+            let message = format!("could not pass the API key to wakatime-cli: {err}");
+            error!("{message}");
+            set_last_failure(message);
+            return (Outcome::Failed, None);
+        }
+    };
+
+    if !extra.is_empty() {
+        command
+            .arg("--extra-heartbeats")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+    }
+
+    debug!("wakatime command: {}", command_line(command.as_std()));
+
+    // Children of the `heartbeat` span, only exported as traces, which
+    // would crowd the log.
+    let span = trace_span!(
+        "wakatime_cli",
+        heartbeats = heartbeats.len(),
+        exit_code = field::Empty
+    );
+    let start = Instant::now();
+    let output = async {
+        if extra.is_empty() {
+            return command.output().await;
+        }
+        let mut child = command.spawn()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            let extra: Vec<Value> = extra.iter().map(Heartbeat::to_extra_json).collect();
+            stdin
+                .write_all(&serde_json::to_vec(&extra).unwrap_or_default())
+                .await?;
+        }
+        child.wait_with_output().await
+    }
+    .instrument(span.clone())
+    .await;
+    metrics::observe_cli(start.elapsed());
+    if let Some(code) = output.as_ref().ok().and_then(|output| output.status.code()) {
+        span.record("exit_code", code);
+    }
+
+    match output {
+        // wakatime-cli prints nothing on success unless asked to, and
+        // explains failures on stdout or stderr.
+        Ok(output) if !output.status.success() || settings.debug == Some(true) => {
+            let status = match output.status.code() {
+                Some(code) => format!("exited with code {code}"),
+                None => "was terminated by a signal".to_string(),
+            };
+            let mut message = format!("wakatime-cli {status}");
+
+            for stream in [&output.stdout, &output.stderr] {
+                let stream = String::from_utf8_lossy(stream);
+                if !stream.trim().is_empty() {
+                    message.push_str(&format!("\n{}", stream.trim_end()));
+                }
+            }
+
+            // wakatime-cli checks the format of the key itself, exiting
+            // with 104 as for a key the API refused, and some releases only
+            // take UUIDs even from servers that issue others.
+            let key_refused = output.status.code() == Some(104)
+                && api_key
+                    .map(str::to_string)
+                    .or_else(resolved_key)
+                    .is_some_and(|key| !api::is_uuid_key(&key));
+            if key_refused {
+                message.push_str(
+                    "\nthe API key is not a UUID, which this wakatime-cli release may refuse as an invalid format; updating wakatime-cli can fix it",
+                );
+            }
+
+            if output.status.success() {
+                debug!("{message}");
+            } else {
+                warn!("{message}");
+            }
+
+            let outcome = match Outcome::from_exit_code(output.status.code()) {
+                Outcome::Queued if disable_offline => Outcome::Evicted,
+                outcome => outcome,
+            };
+            if outcome == Outcome::Failed {
+                set_last_failure(message);
+            }
+            (outcome, output.status.code())
+        }
+        Ok(_) => (Outcome::Sent, Some(0)),
+        Err(e) => {
+            error!(
+                "could not run wakatime-cli: {e}, command: {}",
+                command_line(command.as_std())
+            );
+            // Without the command, which names the project.
+            set_last_failure(format!("could not run wakatime-cli: {e}"));
+            (Outcome::Failed, None)
+        }
+    }
+}
+
+// The program and arguments of a command for logging, without its environment,
+// which holds the API key.
+//
+// Example:
+// "flatpak-spawn" "--host" "wakatime-cli" "--config" "/home/alice/.wakatime/zed-wakatime-key-c569cfd0f1b0eb46.cfg" "--time" ...
+fn command_line(command: &std::process::Command) -> String {
+    std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|arg| format!("{arg:?}"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// Project detection options from the LSP settings and the wakatime config,
+// along with the projectmap patterns that are not valid regular expressions.
+fn detection_options(
+    settings: &Settings,
+    config: &WakatimeConfig,
+) -> (DetectionOptions, Vec<regex::Error>) {
+    // Rules from the LSP settings take precedence over the ones shared with
+    // other editors in the wakatime config.
+    let rules = settings
+        .project_map
+        .iter()
+        .flatten()
+        .map(|(pattern, project)| (pattern.as_str(), project.as_str()))
+        .chain(
+            config
+                .section("projectmap")
+                .map(|(pattern, project)| (pattern.as_str(), project.as_str())),
+        );
+
+    let (project_map, errors) = ProjectMap::new(rules);
+
+    let options = DetectionOptions {
+        submodules_disabled: settings.submodules_disabled.unwrap_or(false),
+        worktree_project_names: settings.worktree_project_names.unwrap_or(false),
+        manifest_projects: settings.manifest_projects.unwrap_or(false),
+        folder_fallback: settings.folder_project_fallback.unwrap_or(false),
+        project_map,
+    };
+
+    (options, errors)
+}
+
+// The options a client over the network may not set without
+// `--trust-clients`, as they tell where the API key and error reports are sent
+// or which files are written. The `api-url` of profiles is left out too.
+const UNTRUSTED_OPTIONS: [&str; 4] = ["api-url", "error_report_url", "heartbeat_spool", "log_file"];
+
+fn restrict_options(options: Value) -> Value {
+    let Value::Object(mut options) = options else {
+        return options;
+    };
+
+    for name in UNTRUSTED_OPTIONS {
+        if options.remove(name).is_some() {
+            warn!("ignoring the {name} setting of a client over the network, see --trust-clients");
+        }
+    }
+    if let Some(Value::Object(profiles)) = options.get_mut("profiles") {
+        for profile in profiles.values_mut().filter_map(Value::as_object_mut) {
+            profile.remove("api-url");
+        }
+    }
+
+    Value::Object(options)
+}
+
+// Overlay settings scoped to a workspace folder on the initialization options.
+fn merge_options(base: &Value, overrides: &Value) -> Value {
+    let mut merged = base.as_object().cloned().unwrap_or_default();
+
+    if let Some(overrides) = overrides.as_object() {
+        for (key, value) in overrides {
+            merged.insert(key.clone(), value.clone());
+        }
+    }
+
+    Value::Object(merged)
+}
+
+impl WakatimeLanguageServer {
+    fn entity(&self, uri: &url::Url) -> PathBuf {
+        let entity = uri::to_entity(uri);
+
+        if self.settings.load().canonicalize_paths == Some(true) {
+            paths::canonicalize(&entity)
+        } else {
+            entity
+        }
+    }
+
+    // The entity of a file shared by its events and its cache entry once it
+    // has one, rather than a copy of its path for each keystroke.
+    fn intern(&self, entity: PathBuf) -> Arc<Path> {
+        match self.file_cache.get(entity.as_path()) {
+            Some(entry) => entry.key().clone(),
+            None => Arc::from(entity),
+        }
+    }
+
+    async fn memory_usage(&self) -> MemoryUsage {
+        memory_usage(
+            &self.settings.load(),
+            &self.file_cache,
+            &self.line_counts,
+            &self.project_cache,
+            &self.events,
+        )
+        .await
+    }
+
+    // Bring the caches under `max_cache_memory`: the line counts and project
+    // lookups are forgotten first, as they are made again for the next
+    // heartbeat, then the cursors of the files changed longest ago, down to
+    // half the limit so this doesn't run again for every new file. Saves of
+    // those files are ignored until they change again.
+    async fn limit_memory(&self) {
+        let usage = self.memory_usage().await;
+        if !usage.is_over_limit() {
+            return;
+        }
+        let limit = usage.limit.unwrap_or_default();
+        debug!(
+            "caches take {} bytes, over the limit of {limit}, clearing them",
+            usage.caches()
+        );
+
+        self.line_counts.clear();
+        self.project_cache.lock().await.forget();
+        if usage.file_cache <= limit {
+            return;
+        }
+
+        let mut entries: Vec<_> = self
+            .file_cache
+            .iter()
+            .map(|entry| (entry.changed, entry.key().clone()))
+            .collect();
+        entries.sort_unstable_by_key(|(changed, _)| *changed);
+
+        let mut size = usage.file_cache;
+        for (_, entity) in entries {
+            if size <= limit / 2 {
+                break;
+            }
+            size -= memory::entry_size::<FileCacheEntry>(&entity);
+            self.file_cache.remove(&entity);
+        }
+    }
+
+    // Queue the event, and send the queued ones unless an earlier event is
+    // being sent already, in which case that one sends this too.
+    async fn enqueue(&self, event: Event) {
+        if self.reload_requested.swap(false, Ordering::Relaxed) {
+            self.settings
+                .store(Arc::new(Settings::from_options(&self.options())));
+            self.apply_settings();
+            self.start_configured_tasks();
+            self.configure_project_detection(true).await;
+            info!("reloaded the settings");
+        }
+
+        if let Ok(mut activity) = self.activity.lock() {
+            activity.record(self.clock.instant());
+        }
+
+        let pushed = self.events.push(event);
+        if pushed == Pushed::DroppedOldest {
+            debug!("too many events waiting to be sent, dropped the oldest");
+        }
+        metrics::count_pushed(pushed);
+
+        // The events waiting are made into heartbeats and sent together, so
+        // those that came while wakatime-cli was sending the last ones take
+        // one more wakatime-cli rather than one each.
+        while self.events.claim() {
+            loop {
+                let mut flush = Vec::new();
+                let last_timestamp = self.current_file.timestamp();
+                while let Some(event) = self.events.pop() {
+                    // Or the queue would stay claimed, and no event sent again.
+                    let entity = event.entity.clone();
+                    match AssertUnwindSafe(self.prepare(event)).catch_unwind().await {
+                        Ok(pending) => flush.push(pending),
+                        Err(_) => error!(
+                            "sending a heartbeat for file: {} panicked, skipping it",
+                            entity.display()
+                        ),
+                    }
+                }
+                if flush.is_empty() {
+                    break;
+                }
+
+                let heartbeats = flush.len();
+                if AssertUnwindSafe(self.flush(flush, last_timestamp))
+                    .catch_unwind()
+                    .await
+                    .is_err()
+                {
+                    error!("sending {heartbeats} heartbeats panicked, skipping them");
+                }
+            }
+            self.events.release();
+        }
+    }
+
+    // Every event gets a `heartbeat` span, logged at the debug level when it
+    // closes with the file's hash, the outcome and how long it took.
+    async fn prepare(&self, event: Event) -> Pending {
+        let span = debug_span!(
+            "heartbeat",
+            entity = log::entity_hash(&event.entity),
+            outcome = field::Empty,
+            duration_ms = field::Empty,
+        );
+        let start = Instant::now();
+        let time = self.clock.now();
+        let (entity, is_write) = (event.entity.clone(), event.is_write);
+
+        let heartbeat = self.prepare_event(event).instrument(span.clone()).await;
+        Pending {
+            span,
+            start,
+            time,
+            entity,
+            is_write,
+            heartbeat,
+        }
+    }
+
+    // Send the heartbeats of a flush, those in a row for the same wakatime-cli
+    // and profile with one wakatime-cli, then count what became of each event.
+    // Each wakatime-cli takes one request from the budget, however many
+    // heartbeats it sends.
+    async fn flush(&self, flush: Vec<Pending>, last_timestamp: Instant) {
+        // Overwritten for the events made into heartbeats once they are sent.
+        let mut outcomes: Vec<Outcome> = flush
+            .iter()
+            .map(|pending| {
+                pending
+                    .heartbeat
+                    .as_ref()
+                    .err()
+                    .copied()
+                    .unwrap_or(Outcome::Failed)
+            })
+            .collect();
+        let ready: Vec<(usize, &Prepared)> = flush
+            .iter()
+            .enumerate()
+            .filter_map(|(index, pending)| Some((index, pending.heartbeat.as_ref().ok()?)))
+            .collect();
+
+        for batch in ready.chunk_by(|(_, a), (_, b)| {
+            Arc::ptr_eq(&a.wakatime_cli, &b.wakatime_cli) && a.profile == b.profile
+        }) {
+            let (index, first) = batch[0];
+            // The file's timestamp goes back to before the flush, so its next
+            // event tries again.
+            if !budget::try_acquire() {
+                debug!(
+                    "over the budget of {} API requests a minute, not sending {} heartbeats",
+                    budget::per_minute(),
+                    batch.len()
+                );
+                self.current_file.set_timestamp(last_timestamp);
+                for (index, _) in batch {
+                    outcomes[*index] = Outcome::Throttled;
+                }
+                continue;
+            }
+            let heartbeats: Vec<Heartbeat> = batch
+                .iter()
+                .map(|(_, prepared)| prepared.heartbeat.clone())
+                .collect();
+            let outcome = self
+                .delivery
+                .send(
+                    &heartbeats,
+                    &first.wakatime_cli,
+                    first.profile.as_ref(),
+                    self.clock.now(),
+                )
+                .instrument(flush[index].span.clone())
+                .await;
+            for (index, _) in batch {
+                outcomes[*index] = outcome;
+            }
+        }
+
+        for (pending, outcome) in flush.into_iter().zip(outcomes) {
+            self.finish(pending, outcome).await;
+        }
+    }
+
+    // Count what became of an event and close its span.
+    async fn finish(&self, pending: Pending, outcome: Outcome) {
+        let wrote = pending
+            .heartbeat
+            .as_ref()
+            .is_ok_and(|prepared| prepared.heartbeat.is_write);
+        let Pending {
+            span,
+            start,
+            time,
+            entity,
+            is_write,
+            ..
+        } = pending;
+        let latency_ms = start.elapsed().as_millis() as u64;
+
+        span.record("outcome", outcome.as_str());
+        span.record("duration_ms", latency_ms);
+        self.heartbeat_counters.count(outcome);
+        metrics::count_heartbeat(outcome);
+
+        if outcome != Outcome::Ignored {
+            self.track_session();
+        }
+        if matches!(outcome, Outcome::Sent | Outcome::Queued) {
+            self.extend_streak();
+            // A save that failed or was throttled isn't the last write of
+            // `write_interval`.
+            if wrote {
+                if let Some(mut entry) = self.file_cache.get_mut(&entity) {
+                    entry.written = Some(self.clock.instant());
+                }
+            }
+        }
+
+        // Events that never became a heartbeat would crowd out the ones that
+        // did, one per keystroke.
+        if !matches!(outcome, Outcome::Ignored | Outcome::Skipped) {
+            self.recent_heartbeats.lock().await.push(Attempt {
+                entity: entity.to_path_buf(),
+                time,
+                outcome,
+                latency_ms,
+                is_write,
+            });
+        }
+    }
+
+    // Count today in the coding streak, the first time there is a heartbeat
+    // for it.
+    fn extend_streak(&self) {
+        let today = self.clock.now().date_naive();
+        let (Ok(mut streak), Some(path)) = (self.streak.lock(), streak::path()) else {
+            return;
+        };
+        if streak.is_active(today) {
+            return;
+        }
+
+        // Another server may have counted today already.
+        let mut stored = Streak::load(&path);
+        if stored.record(today) {
+            if let Err(err) = stored.save(&path) {
+                warn!(
+                    "could not save the coding streak to {}: {err}",
+                    path.display()
+                );
+            }
+        }
+        *streak = stored;
+    }
+
+    // Warn the user every day at `streak_warning` when there was no coding
+    // yet that day, while there's a streak to lose.
+    fn warn_streak(&self) {
+        let (Some(time), Some(path)) = (self.settings.load().streak_warning, streak::path()) else {
+            self.stop_configured("streak warning");
+            return;
+        };
+        let client = self.client.clone();
+        let clock = self.clock.clone();
+
+        self.spawn_configured("streak warning", time, async move {
+            loop {
+                sleep_until(clock.as_ref(), streak::next_at(time, clock.now())).await;
+
+                let today = clock.now().date_naive();
+                let streak = Streak::load(&path);
+                let days = streak.current(today);
+
+                if days > 0 && !streak.is_active(today) {
+                    client
+                        .show_message(
+                            MessageType::WARNING,
+                            format!(
+                                "No coding time today yet, code before midnight to keep your {days} day streak"
+                            ),
+                        )
+                        .await;
+                }
+            }
+        });
+    }
+
+    // Forget the cursors of files that didn't change for `cache_max_age`, and
+    // the line counts and project lookups of those without a heartbeat for as
+    // long, so a server left running for weeks doesn't keep every file it
+    // ever saw until `max_cache_memory` is reached.
+    fn prune_caches(&self) {
+        let max_age = match self.settings.load().cache_max_age {
+            Some(0) => {
+                self.stop_configured("cache pruning");
+                return;
+            }
+            Some(minutes) => Duration::from_secs(minutes * 60),
+            None => CACHE_MAX_AGE,
+        };
+        let file_cache = self.file_cache.clone();
+        let line_counts = self.line_counts.clone();
+        let project_cache = self.project_cache.clone();
+        let clock = self.clock.clone();
+
+        let task = supervisor::supervise("cache pruning", move || {
+            let file_cache = file_cache.clone();
+            let line_counts = line_counts.clone();
+            let project_cache = project_cache.clone();
+            let clock = clock.clone();
+
+            async move {
+                let mut interval = tokio::time::interval(max_age.min(PRUNE_INTERVAL));
+                interval.tick().await;
+
+                loop {
+                    interval.tick().await;
+
+                    let now = clock.instant();
+                    let files = file_cache.len();
+                    file_cache
+                        .retain(|_, entry| now.saturating_duration_since(entry.touched) <= max_age);
+                    let files = files - file_cache.len();
+                    let lines = line_counts.prune(max_age);
+                    let folders = project_cache.lock().await.prune(max_age);
+
+                    if files + lines + folders > 0 {
+                        debug!(
+                            "forgot {files} cursors, {lines} line counts and {folders} project lookups unused for {} minutes",
+                            max_age.as_secs() / 60
+                        );
+                    }
+                }
+            }
+        });
+        self.spawn_configured("cache pruning", max_age, task);
+    }
+
+    // Read `~/.wakatime.cfg` again when it changes, such as when the user
+    // edits it or another WakaTime plugin saves a new key, and the settings at
+    // the next editor event, as after SIGHUP. Its folder is watched rather
+    // than the file, which editors and plugins often replace instead of
+    // writing to.
+    fn watch_config(&self) {
+        let Some(path) = config::config_path() else {
+            return;
+        };
+        let Some(folder) = path.parent().map(Path::to_path_buf) else {
+            return;
+        };
+        let config = self.config.clone();
+        let reload_requested = self.reload_requested.clone();
+
+        self.spawn(supervisor::supervise("config watcher", move || {
+            let (path, folder) = (path.clone(), folder.clone());
+            let config = config.clone();
+            let reload_requested = reload_requested.clone();
+
+            async move {
+                let (changes, mut changed) = mpsc::unbounded_channel();
+                let watched = path.clone();
+                let watcher =
+                    notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                        let Ok(event) = event else {
+                            return;
+                        };
+                        if !event.kind.is_access() && event.paths.contains(&watched) {
+                            changes.send(()).ok();
+                        }
+                    });
+                let mut watcher = match watcher {
+                    Ok(watcher) => watcher,
+                    Err(err) => {
+                        warn!("could not watch {}: {err}", path.display());
+                        return;
+                    }
+                };
+                if let Err(err) = notify::Watcher::watch(
+                    &mut watcher,
+                    &folder,
+                    notify::RecursiveMode::NonRecursive,
+                ) {
+                    warn!("could not watch {}: {err}", folder.display());
+                    return;
+                }
+
+                while changed.recv().await.is_some() {
+                    tokio::time::sleep(CONFIG_SETTLE).await;
+                    while changed.try_recv().is_ok() {}
+
+                    match WakatimeConfig::load() {
+                        Ok(loaded) => {
+                            config.store(Arc::new(loaded));
+                            info!("{} changed, read it again", path.display());
+                        }
+                        Err(err) => warn!("could not read wakatime config: {err}"),
+                    }
+                    reload_requested.store(true, Ordering::Relaxed);
+                }
+            }
+        }));
+    }
+
+    // On SIGHUP, read `~/.wakatime.cfg` again, and at the next editor event
+    // rebuild the settings from the options the client last sent and ask it
+    // for those of the workspace folders. On SIGUSR1, log what the server is
+    // up to, to look into a server that seems stuck without restarting the
+    // editor.
+    #[cfg(unix)]
+    fn handle_signals(&self) {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let (mut hangup, mut user_defined) = match (
+            signal(SignalKind::hangup()),
+            signal(SignalKind::user_defined1()),
+        ) {
+            (Ok(hangup), Ok(user_defined)) => (hangup, user_defined),
+            (Err(err), _) | (_, Err(err)) => {
+                warn!("could not handle signals: {err}");
+                return;
+            }
+        };
+        let settings = self.settings.clone();
+        let config = self.config.clone();
+        let reload_requested = self.reload_requested.clone();
+        let wakatime_path = self.wakatime_path.clone();
+        let heartbeat_counters = self.heartbeat_counters.clone();
+        let events = self.events.clone();
+        let file_cache = self.file_cache.clone();
+        let line_counts = self.line_counts.clone();
+        let project_cache = self.project_cache.clone();
+        let last_failure = self.last_failure.clone();
+
+        self.spawn(async move {
+            loop {
+                tokio::select! {
+                    Some(()) = hangup.recv() => {
+                        match WakatimeConfig::load() {
+                            Ok(loaded) => {
+                                config.store(Arc::new(loaded));
+                                info!("got SIGHUP, read the wakatime config again");
+                            }
+                            Err(err) => warn!("got SIGHUP, could not read wakatime config: {err}"),
+                        }
+                        reload_requested.store(true, Ordering::Relaxed);
+                    }
+                    Some(()) = user_defined.recv() => {
+                        let cli = wakatime_path.load_full();
+                        let queue_depth = match cli {
+                            Some(ref cli) => cli.offline_count().await,
+                            None => None,
+                        };
+                        let memory = memory_usage(
+                            &settings.load(),
+                            &file_cache,
+                            &line_counts,
+                            &project_cache,
+                            &events,
+                        )
+                        .await;
+                        let state = json!({
+                            "version": env!("CARGO_PKG_VERSION"),
+                            "wakatime_cli": cli.map(|cli| cli.to_string()),
+                            "queue_depth": queue_depth,
+                            "heartbeats": heartbeat_counters.counts(),
+                            "events": events.counts(),
+                            "memory": memory,
+                            "last_failure": last_failure.lock().ok().and_then(|failure| failure.clone()),
+                        });
+                        info!("got SIGUSR1, state: {state}");
+                    }
+                    else => return,
+                }
+            }
+        });
+    }
+
+    // With `app_heartbeats`, send a heartbeat for Zed itself once a heartbeat
+    // interval passed without one while the client reports it focused, so
+    // time in its terminal or project panel isn't lost. They go to the profile
+    // of the folder of the last file heartbeat, like its heartbeats. Only
+    // clients that send `wakatime/focus` get them.
+    fn send_app_heartbeats(&self) {
+        if self.settings.load().app_heartbeats != Some(true) {
+            self.stop_configured("app heartbeats");
+            return;
+        }
+        let delivery = self.delivery.clone();
+        let settings = self.settings.clone();
+        let wakatime_path = self.wakatime_path.clone();
+        let focused = self.focused.clone();
+        let current_file = self.current_file.clone();
+        let current_project = self.current_project.clone();
+        let folder_profiles = self.folder_profiles.clone();
+        let heartbeat_counters = self.heartbeat_counters.clone();
+        let recent_heartbeats = self.recent_heartbeats.clone();
+        let clock = self.clock.clone();
+
+        let task = supervisor::supervise("app heartbeats", move || {
+            let delivery = delivery.clone();
+            let settings = settings.clone();
+            let wakatime_path = wakatime_path.clone();
+            let focused = focused.clone();
+            let current_file = current_file.clone();
+            let current_project = current_project.clone();
+            let folder_profiles = folder_profiles.clone();
+            let heartbeat_counters = heartbeat_counters.clone();
+            let recent_heartbeats = recent_heartbeats.clone();
+            let clock = clock.clone();
+
+            async move {
+                let entity: Arc<Path> = Arc::from(Path::new("zed"));
+                let mut interval = tokio::time::interval(APP_HEARTBEAT_CHECK);
+                let mut last_sent: Option<Instant> = None;
+
+                loop {
+                    interval.tick().await;
+
+                    if !focused.load(Ordering::Relaxed) {
+                        continue;
+                    }
+                    let heartbeat_interval = settings
+                        .load()
+                        .heartbeat_interval
+                        .map_or(event::DEFAULT_HEARTBEAT_INTERVAL, Duration::from_secs);
+                    let now = clock.instant();
+                    let due =
+                        |since: Instant| now.saturating_duration_since(since) >= heartbeat_interval;
+                    if !due(current_file.timestamp()) || last_sent.is_some_and(|sent| !due(sent)) {
+                        continue;
+                    }
+                    let Some(cli) = wakatime_path.load_full() else {
+                        continue;
+                    };
+                    // A throttled heartbeat doesn't keep the other Zed
+                    // windows from sending theirs.
+                    if !budget::try_acquire() {
+                        continue;
+                    }
+                    last_sent = Some(now);
+                    // Another Zed window counts the time already.
+                    if !delivery.claim(&entity, heartbeat_interval).await {
+                        continue;
+                    }
+
+                    let span = debug_span!(
+                        "heartbeat",
+                        entity = log::entity_hash(&entity),
+                        outcome = field::Empty,
+                        duration_ms = field::Empty,
+                    );
+                    let start = Instant::now();
+                    let time = clock.now();
+                    let mut heartbeat =
+                        Heartbeat::app("zed", current_project.load().as_deref().cloned());
+                    heartbeat.time = delivery.heartbeat_time(time).await.timestamp() as f64;
+                    heartbeat.plugin = delivery.plugin();
+
+                    let file = current_file.entity.load_full();
+                    let profile =
+                        resolve_profile(&settings.load(), &folder_profiles.load(), Some(&**file));
+                    let outcome = delivery
+                        .send(
+                            std::slice::from_ref(&heartbeat),
+                            &cli,
+                            profile.as_ref(),
+                            time,
+                        )
+                        .instrument(span.clone())
+                        .await;
+                    let latency_ms = start.elapsed().as_millis() as u64;
+
+                    span.record("outcome", outcome.as_str());
+                    span.record("duration_ms", latency_ms);
+                    heartbeat_counters.count(outcome);
+                    metrics::count_heartbeat(outcome);
+                    recent_heartbeats.lock().await.push(Attempt {
+                        entity: heartbeat.entity,
+                        time,
+                        outcome,
+                        latency_ms,
+                        is_write: false,
+                    });
+                }
+            }
+        });
+        self.spawn_configured("app heartbeats", (), task);
+    }
+
+    // Count the offline queue of wakatime-cli every `QUEUE_DEPTH_INTERVAL`
+    // while metrics are served or sent, or the queue has limits. A queue found
+    // full stops being so once its limits are removed or changed, rather than
+    // heartbeats being dropped until the server restarts.
+    fn track_queue_depth(&self) {
+        let limit = self.queue_limit();
+        if !limit.is_set() {
+            self.queue_full.store(false, Ordering::Relaxed);
+        }
+        if !metrics::is_enabled() && !limit.is_set() {
+            self.stop_configured("queue depth");
+            return;
+        }
+        let settings = self.settings.clone();
+        let config = self.config.clone();
+        let wakatime_path = self.wakatime_path.clone();
+        let queue_full = self.queue_full.clone();
+        let heartbeat_counters = self.heartbeat_counters.clone();
+
+        let task = supervisor::supervise("queue depth", move || {
+            let settings = settings.clone();
+            let config = config.clone();
+            let wakatime_path = wakatime_path.clone();
+            let queue_full = queue_full.clone();
+            let heartbeat_counters = heartbeat_counters.clone();
+
+            async move {
+                // Until the queue is measured against these limits.
+                queue_full.store(false, Ordering::Relaxed);
+                let mut interval = tokio::time::interval(QUEUE_DEPTH_INTERVAL);
+                // Printing the queue to measure it is only worth it when
+                // something was queued or sent since.
+                let mut measured = None;
+
+                loop {
+                    interval.tick().await;
+
+                    let Some(cli) = wakatime_path.load_full() else {
+                        continue;
+                    };
+                    let Some(depth) = cli.offline_count().await else {
+                        continue;
+                    };
+                    if metrics::is_enabled() {
+                        metrics::set_queue_depth(depth);
+                    }
+                    if !limit.is_set() {
+                        continue;
+                    }
+
+                    let size = match measured {
+                        Some((count, size)) if count == depth => Some(size),
+                        _ if limit.bytes.is_some() => cli.offline_size(depth).await,
+                        _ => None,
+                    };
+                    if let Some(size) = size {
+                        measured = Some((depth, size));
+                    }
+
+                    let full = limit.is_full(depth, size);
+                    if full && limit.eviction == Eviction::DropOldest {
+                        let Some(queued) = cli.offline_heartbeats(depth).await else {
+                            continue;
+                        };
+                        let excess = limit.excess(&queued) as u64;
+                        if excess == 0 {
+                            continue;
+                        }
+                        // The key heartbeats without a folder are sent with.
+                        let api_key = resolve_profile(&settings.load(), &[], None)
+                            .and_then(|profile| profile.api_key)
+                            .or_else(|| resolve_api(&settings, &config).key);
+                        match cli.drop_offline(excess, api_key.as_deref()).await {
+                            Ok(dropped) => {
+                                heartbeat_counters.add(Outcome::Evicted, dropped);
+                                metrics::count_heartbeats(Outcome::Evicted, dropped);
+                                measured = None;
+                                warn!(
+                                    "wakatime-cli had {depth} heartbeats queued, over max_queued_heartbeats or max_queue_size: dropped the {dropped} oldest"
+                                );
+                            }
+                            Err(err) => {
+                                warn!("could not drop the oldest queued heartbeats: {err}")
+                            }
+                        }
+                    } else if full && !queue_full.swap(true, Ordering::Relaxed) {
+                        warn!(
+                            "wakatime-cli has {depth} heartbeats queued, over max_queued_heartbeats or max_queue_size: heartbeats that can't be sent are dropped until the API can be reached again"
+                        );
+                    } else if !full && queue_full.swap(false, Ordering::Relaxed) {
+                        info!(
+                            "wakatime-cli has {depth} heartbeats queued, queueing heartbeats again"
+                        );
+                    }
+                }
+            }
+        });
+        self.spawn_configured("queue depth", limit, task);
+    }
+
+    fn queue_limit(&self) -> QueueLimit {
+        let settings = self.settings.load();
+
+        QueueLimit {
+            heartbeats: settings
+                .max_queued_heartbeats
+                .filter(|&heartbeats| heartbeats > 0),
+            bytes: settings
+                .max_queue_size
+                .filter(|&megabytes| megabytes > 0)
+                .map(|megabytes| megabytes * 1024 * 1024),
+            eviction: settings.queue_eviction.unwrap_or_default(),
+        }
+    }
+
+    // Tell the user once a day about a newer wakatime-ls or wakatime-cli that
+    // isn't installed for them by `self_update` or `cli_auto_update`.
+    #[cfg(feature = "http")]
+    fn notify_updates(&self) {
+        if self.settings.load().update_notifications == Some(false) {
+            return;
+        }
+        let Some(path) = updates::path() else {
+            return;
+        };
+
+        let client = self.client.clone();
+        let settings = self.settings.clone();
+        let wakatime_path = self.wakatime_path.clone();
+        let wakatime_version = self.wakatime_version.clone();
+        let clock = self.clock.clone();
+
+        self.spawn(async move {
+            let mut interval = tokio::time::interval(CLI_UPDATE_INTERVAL);
+
+            loop {
+                interval.tick().await;
+
+                let settings = settings.load();
+                let updates_cli = settings.cli_auto_update != Some(false)
+                    && wakatime_path.load().as_ref().is_some_and(|cli| {
+                        cli::install_path().is_some_and(|install_path| install_path == cli.path)
+                    });
+                let cli_version = wakatime_version.load_full().filter(|_| !updates_cli);
+
+                let releases = match updates::check(
+                    settings.self_update != Some(true),
+                    cli_version.as_deref().map(String::as_str),
+                )
+                .await
+                {
+                    Ok(releases) => releases,
+                    Err(err) => {
+                        debug!("could not check for updates: {err}");
+                        continue;
+                    }
+                };
+                if releases.is_empty() {
+                    continue;
+                }
+
+                match updates::claim(&path, clock.now().date_naive()) {
+                    Ok(true) => {}
+                    Ok(false) => continue,
+                    Err(err) => {
+                        warn!("could not write {}: {err}", path.display());
+                        continue;
+                    }
+                }
+
+                for release in releases {
+                    client
+                        .show_message(MessageType::INFO, release.message())
+                        .await;
+                }
+            }
+        });
+    }
+
+    // Extend the coding session, and remind the user to take a break after
+    // `break_reminder` minutes of it, with a button to snooze the reminders.
+    fn track_session(&self) {
+        let threshold = self
+            .settings
+            .load()
+            .break_reminder
+            .filter(|&minutes| minutes > 0)
+            .map(|minutes| Duration::from_secs(minutes * 60));
+
+        let Some(coding) = self
+            .session
+            .lock()
+            .ok()
+            .and_then(|mut session| session.activity(self.clock.instant(), threshold))
+        else {
+            return;
+        };
+
+        let client = self.client.clone();
+        let session = self.session.clone();
+        let clock = self.clock.clone();
+
+        // The reminder waits for an answer, which there may never be.
+        self.spawn(async move {
+            let snooze = MessageActionItem {
+                title: format!("Snooze for {} minutes", session::SNOOZE.as_secs() / 60),
+                properties: HashMap::new(),
+            };
+            let message = format!(
+                "You've been coding for {} minutes \u{2014} take a break",
+                coding.as_secs() / 60
+            );
+
+            let action = client
+                .show_message_request(MessageType::INFO, message, Some(vec![snooze.clone()]))
+                .await;
+            if matches!(action, Ok(Some(action)) if action == snooze) {
+                if let Ok(mut session) = session.lock() {
+                    session.snooze(clock.instant(), session::SNOOZE);
+                }
+            }
+        });
+    }
+
+    // `wakatime/session`: how long the user has been coding without a break,
+    // from the editor events this server got.
+    //
+    // Example:
+    // {"seconds":2820,"started":"2026-10-14T14:18:02.512+02:00"}
+    // {"seconds":0,"started":null} -> after a break
+    async fn session(&self) -> Result<Value> {
+        let length = self
+            .session
+            .lock()
+            .ok()
+            .and_then(|session| session.length(self.clock.instant()));
+
+        Ok(json!({
+            "seconds": length.map_or(0, |length| length.as_secs()),
+            "started": length.and_then(|length| {
+                self.clock.now().checked_sub_signed(TimeDelta::from_std(length).ok()?)
+            }),
+        }))
+    }
+
+    // `wakatime/focus`, a notification from the client.
+    async fn focus(&self, params: FocusParams) {
+        trace!(focused = params.focused, "focus changed");
+        self.focused.store(params.focused, Ordering::Relaxed);
+    }
+
+    // `wakatime/status`: the server and wakatime-cli in use, what became of
+    // the editor events since the server started, the memory its caches
+    // take, and the coding streak.
+    //
+    // Example:
+    // {"version":"0.3.1","wakatime_cli":"/home/alice/.wakatime/wakatime-cli","wakatime_cli_version":"v1.102.1","heartbeats":{"sent":42,...},"events":{"queued":0,...},"memory":{"file_cache":18432,...},"streak":{"days":5,"today":true}}
+    async fn status(&self) -> Result<Value> {
+        Ok(json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "wakatime_cli": self.wakatime_path.load().as_ref().map(|cli| cli.to_string()),
+            "wakatime_cli_version": self.wakatime_version.load().as_deref(),
+            "heartbeats": self.heartbeat_counters.counts(),
+            "events": self.events.counts(),
+            "memory": self.memory_usage().await,
+            "streak": self.streak.lock().ok().map(|streak| {
+                let today = self.clock.now().date_naive();
+                json!({ "days": streak.current(today), "today": streak.is_active(today) })
+            }),
+        }))
+    }
+
+    // Run a task for as long as the server, logging to its client.
+    fn spawn(&self, task: impl Future<Output = ()> + Send + 'static) {
+        let task = tokio::spawn(task.with_current_subscriber());
+        if let Ok(mut tasks) = self.tasks.lock() {
+            tasks.push(task.abort_handle());
+        }
+    }
+
+    // Run the task of `name` for the values of the settings it depends on,
+    // stopping the one started for other values. One already running for the
+    // same values is kept as it is, with what it remembers, so saving other
+    // settings doesn't start it over.
+    fn spawn_configured<S>(
+        &self,
+        name: &'static str,
+        settings: S,
+        task: impl Future<Output = ()> + Send + 'static,
+    ) where
+        S: PartialEq + Send + 'static,
+    {
+        let Ok(mut configured) = self.configured_tasks.lock() else {
+            return;
+        };
+        if let Some(running) = configured.get(name) {
+            if !running.handle.is_finished()
+                && running.settings.downcast_ref::<S>() == Some(&settings)
+            {
+                return;
+            }
+            running.handle.abort();
+        }
+
+        let task = tokio::spawn(task.with_current_subscriber());
+        if let Ok(mut tasks) = self.tasks.lock() {
+            tasks.push(task.abort_handle());
+        }
+        configured.insert(
+            name,
+            ConfiguredTask {
+                settings: Box::new(settings),
+                handle: task.abort_handle(),
+            },
+        );
+    }
+
+    // Stop the task of `name`, once the settings turned it off.
+    fn stop_configured(&self, name: &'static str) {
+        let stopped = self
+            .configured_tasks
+            .lock()
+            .ok()
+            .and_then(|mut tasks| tasks.remove(name));
+        if let Some(task) = stopped {
+            task.handle.abort();
+        }
+    }
+
+    // Start the tasks the settings turn on, and start again or stop those
+    // whose settings changed, so that turning one on or changing how often it
+    // runs takes effect without restarting the editor.
+    fn start_configured_tasks(&self) {
+        if let Ok(mut tasks) = self.tasks.lock() {
+            tasks.retain(|task| !task.is_finished());
+        }
+
+        self.push_status_bar_text();
+        #[cfg(feature = "http")]
+        self.deliver_weekly_summary();
+        #[cfg(feature = "http")]
+        self.push_goal_progress();
+        self.congratulate_daily_target();
+        self.warn_streak();
+        self.track_queue_depth();
+        self.send_app_heartbeats();
+        self.prune_caches();
+    }
+
+    // Log the counters every `SUMMARY_INTERVAL` while any changed.
+    fn summarize_heartbeats(&self) {
+        let counters = self.heartbeat_counters.clone();
+
+        self.spawn(async move {
+            let mut interval = tokio::time::interval(SUMMARY_INTERVAL);
+            interval.tick().await;
+            let mut last = Counts::default();
+
+            loop {
+                interval.tick().await;
+
+                let counts = counters.counts();
+                if counts != last {
+                    info!(
+                        "heartbeats since start: {} sent, {} queued, {} failed, {} skipped, {} ignored, {} dropped, {} throttled, {} evicted",
+                        counts.sent,
+                        counts.queued,
+                        counts.failed,
+                        counts.skipped,
+                        counts.ignored,
+                        counts.dropped,
+                        counts.throttled,
+                        counts.evicted
+                    );
+                    last = counts;
+                }
+            }
+        });
+    }
+
+    // Congratulate the user once a day when today's coding time crosses the
+    // `daily_target` setting. It is checked as often as the status bar's
+    // default, which shares cached responses with it.
+    fn congratulate_daily_target(&self) {
+        let Some(minutes) = self
+            .settings
+            .load()
+            .daily_target
+            .filter(|&minutes| minutes > 0)
+        else {
+            self.stop_configured("daily target");
+            return;
+        };
+
+        let client = self.client.clone();
+        let settings = self.settings.clone();
+        let config = self.config.clone();
+        let wakatime_path = self.wakatime_path.clone();
+        // A target already reached when the server started was crossed
+        // before.
+        let below_on = self.below_target_on.clone();
+        let clock = self.clock.clone();
+
+        self.spawn_configured("daily target", minutes, async move {
+            let mut interval = tokio::time::interval(STATUS_BAR_INTERVAL);
+
+            loop {
+                interval.tick().await;
+
+                let api = resolve_api(&settings, &config);
+
+                let today = match today::fetch(&api, wakatime_path.load().as_deref()).await {
+                    Ok(today) => today,
+                    Err(err) => {
+                        debug!("could not fetch today's coding time: {err}");
+                        continue;
+                    }
+                };
+
+                let date = clock.now().date_naive();
+                let crossed = {
+                    let Ok(mut below_on) = below_on.lock() else {
+                        continue;
+                    };
+                    if today.total.total_seconds < minutes * 60 {
+                        *below_on = Some(date);
+                        false
+                    } else {
+                        below_on.take_if(|day| *day == date).is_some()
+                    }
+                };
+                if crossed {
+                    client
+                        .show_message(
+                            MessageType::INFO,
+                            format!(
+                                "Daily target reached: {} of coding today, well done!",
+                                today.total.text
+                            ),
+                        )
+                        .await;
+                }
+            }
+        });
+    }
+
+    // Deliver the last 7 days' coding time once a week, when the
+    // `weekly_summary` setting opts in.
+    #[cfg(feature = "http")]
+    fn deliver_weekly_summary(&self) {
+        let settings = self.settings.load();
+        if settings.weekly_summary != Some(true) {
+            self.stop_configured("weekly summary");
+            return;
+        }
+
+        let default = Schedule::default();
+        let schedule = Schedule {
+            weekday: settings.weekly_summary_day.unwrap_or(default.weekday),
+            time: settings.weekly_summary_time.unwrap_or(default.time),
+        };
+
+        let client = self.client.clone();
+        let settings = self.settings.clone();
+        let config = self.config.clone();
+        let notify = self
+            .extension
+            .load()
+            .supports(WeeklySummaryNotification::METHOD);
+        let clock = self.clock.clone();
+
+        self.spawn_configured("weekly summary", schedule, async move {
+            loop {
+                let due = schedule.next_after(clock.now());
+                debug!("next weekly summary at {due}");
+
+                sleep_until(clock.as_ref(), due).await;
+
+                let api = resolve_api(&settings, &config);
+
+                match weekly::fetch(&api).await {
+                    Ok(summary) => {
+                        client
+                            .show_message(MessageType::INFO, summary.message())
+                            .await;
+                        if notify {
+                            client
+                                .send_notification::<WeeklySummaryNotification>(summary)
+                                .await;
+                        }
+                    }
+                    Err(err) => warn!("could not fetch the weekly summary: {err}"),
+                }
+            }
+        });
+    }
+
+    // Push today's coding time every `status_bar_interval`, whenever the
+    // status text changed, for the client's status bar.
+    fn push_status_bar_text(&self) {
+        let settings = self.settings.load();
+        let period = match settings.status_bar_interval {
+            Some(0) => None,
+            Some(seconds) => Some(Duration::from_secs(seconds)),
+            None => Some(STATUS_BAR_INTERVAL),
+        };
+        let supported = self.extension.load().supports(StatusBarText::METHOD);
+        let Some(period) = period.filter(|_| supported) else {
+            self.stop_configured("status bar");
+            return;
+        };
+        let configured = (
+            period,
+            settings.status_bar_format.clone(),
+            settings.status_bar_hide_below,
+        );
+
+        let client = self.client.clone();
+        let settings = self.settings.clone();
+        let config = self.config.clone();
+        let wakatime_path = self.wakatime_path.clone();
+        let current_project = self.current_project.clone();
+        let last = self.status_bar_text.clone();
+
+        let task = supervisor::supervise("status bar", move || {
+            let client = client.clone();
+            let settings = settings.clone();
+            let config = config.clone();
+            let wakatime_path = wakatime_path.clone();
+            let current_project = current_project.clone();
+            let last = last.clone();
+
+            async move {
+                let mut interval = tokio::time::interval(period);
+
+                loop {
+                    interval.tick().await;
+
+                    let api = resolve_api(&settings, &config);
+                    let settings = settings.load();
+
+                    // Before wakatime-cli is installed, and while offline, the
+                    // next attempt is as good as a retry.
+                    let today = match today::fetch(&api, wakatime_path.load().as_deref()).await {
+                        Ok(today) => today,
+                        Err(err) => {
+                            debug!("could not fetch today's coding time: {err}");
+                            continue;
+                        }
+                    };
+
+                    let total_seconds = today.total.total_seconds;
+                    let text = if total_seconds < settings.status_bar_hide_below.unwrap_or(0) {
+                        String::new()
+                    } else {
+                        today.render(
+                            settings
+                                .status_bar_format
+                                .as_deref()
+                                .unwrap_or(STATUS_BAR_FORMAT),
+                            current_project.load().as_deref().map(String::as_str),
+                        )
+                    };
+
+                    let params = StatusBarTextParams {
+                        text,
+                        total_seconds,
+                    };
+                    if last.load().as_deref() != Some(&params) {
+                        client
+                            .send_notification::<StatusBarText>(params.clone())
+                            .await;
+                        last.store(Some(Arc::new(params)));
+                    }
+                }
+            }
+        });
+        self.spawn_configured("status bar", configured, task);
+    }
+
+    // `wakatime/goals`, also run as the `wakatime.listGoals` command.
+    #[cfg(feature = "http")]
+    async fn goals(&self) -> Result<Vec<Goal>> {
+        goals::fetch(&self.api()).await.map_err(internal_error)
+    }
+
+    // `wakatime/leaderboard`, also run as the `wakatime.leaderboard` command.
+    #[cfg(feature = "http")]
+    async fn leaderboard(&self) -> Result<Leaderboard> {
+        leaderboard::fetch(&self.api())
+            .await
+            .map_err(internal_error)
+    }
+
+    // `wakatime/durations`: the day's blocks of coding, for a timeline.
+    #[cfg(feature = "http")]
+    async fn durations(&self, params: DurationsParams) -> Result<Vec<Block>> {
+        durations::fetch(&self.api(), &params)
+            .await
+            .map_err(internal_error)
+    }
+
+    // `wakatime.openDashboard`: open the dashboard of the API in use in the
+    // browser of the client, which isn't on this machine for remote projects.
+    // Clients that can't open links are shown it instead. Returns the URL.
+    async fn open_dashboard(&self) -> String {
+        let url = self.api().dashboard_url();
+
+        let opened = match Url::parse(&url) {
+            Ok(uri) if self.supports_show_document.load(Ordering::Relaxed) => self
+                .client
+                .show_document(ShowDocumentParams {
+                    uri,
+                    external: Some(true),
+                    take_focus: Some(true),
+                    selection: None,
+                })
+                .await
+                .unwrap_or(false),
+            _ => false,
+        };
+
+        if !opened {
+            self.client
+                .show_message(MessageType::INFO, format!("WakaTime dashboard: {url}"))
+                .await;
+        }
+
+        url
+    }
+
+    // `wakatime/projects`: today's or the week's time per project, e.g. for a
+    // project switcher.
+    async fn projects(&self, params: BreakdownParams) -> Result<Vec<TimeSpent>> {
+        breakdown::fetch(
+            &self.api(),
+            self.wakatime_path.load().as_deref(),
+            Group::Project,
+            params.range,
+        )
+        .await
+        .map_err(internal_error)
+    }
+
+    // `wakatime/languages`: today's or the week's time per language, to see
+    // how much of the day went to Rust and how much to YAML.
+    async fn languages(&self, params: BreakdownParams) -> Result<Vec<TimeSpent>> {
+        breakdown::fetch(
+            &self.api(),
+            self.wakatime_path.load().as_deref(),
+            Group::Language,
+            params.range,
+        )
+        .await
+        .map_err(internal_error)
+    }
+
+    fn api(&self) -> Api {
+        resolve_api(&self.settings, &self.config)
+    }
+
+    // Push goal progress every `GOALS_INTERVAL` whenever it changed, when the
+    // `goal_notifications` setting opts in, and show a message for every goal
+    // reached while the server runs.
+    #[cfg(feature = "http")]
+    fn push_goal_progress(&self) {
+        if self.settings.load().goal_notifications != Some(true) {
+            self.stop_configured("goal progress");
+            return;
+        }
+
+        let client = self.client.clone();
+        let settings = self.settings.clone();
+        let config = self.config.clone();
+        let notify = self.extension.load().supports(GoalProgress::METHOD);
+        let last = self.goals.clone();
+
+        self.spawn_configured("goal progress", (), async move {
+            let mut interval = tokio::time::interval(GOALS_INTERVAL);
+
+            loop {
+                interval.tick().await;
+
+                let api = resolve_api(&settings, &config);
+
+                let goals = match goals::fetch(&api).await {
+                    Ok(goals) => goals,
+                    Err(err) => {
+                        debug!("could not fetch goals: {err}");
+                        continue;
+                    }
+                };
+                let last_goals = last.load_full();
+                if last_goals.as_deref() == Some(&goals) {
+                    continue;
+                }
+
+                // Goals already reached when the server started were
+                // celebrated by an earlier one.
+                if let Some(last_goals) = last_goals {
+                    let was_reached = |goal: &Goal| {
+                        last_goals.iter().any(|previous| {
+                            previous.id == goal.id
+                                && previous.period == goal.period
+                                && previous.is_reached()
+                        })
+                    };
+
+                    for goal in goals.iter().filter(|goal| goal.is_reached()) {
+                        if !was_reached(goal) {
+                            client
+                                .show_message(
+                                    MessageType::INFO,
+                                    format!("Goal reached! {}", goal.progress()),
+                                )
+                                .await;
+                        }
+                    }
+                }
+
+                if notify {
+                    client
+                        .send_notification::<GoalProgress>(GoalProgressParams {
+                            goals: goals.clone(),
+                        })
+                        .await;
+                }
+                last.store(Some(Arc::new(goals)));
+            }
+        });
+    }
+
+    // `wakatime/dumpRecent`, also run as the `wakatime.dumpRecent` command.
+    async fn dump_recent(&self) -> Result<Vec<Attempt>> {
+        Ok(self.recent_heartbeats.lock().await.to_vec())
+    }
+
+    async fn prepare_event(&self, mut event: Event) -> std::result::Result<Prepared, Outcome> {
+        if !event.has_position() {
+            debug!(
+                "no cursor position or line number info for file: {}, ignoring event",
+                event.entity.display()
+            );
+            return Err(Outcome::Ignored);
+        }
+
+        trace!(?event, "send called");
+
+        let now = self.clock.instant();
+        let interval = self.heartbeat_interval(now);
+        let last_timestamp = self.current_file.timestamp();
+
+        if event.is_write && !self.may_write(&event.entity, now) {
+            debug!(
+                "file: {} was saved within write_interval of its last write heartbeat, sending it as an edit",
+                event.entity.display()
+            );
+            event.is_write = false;
+        }
+
+        // An event within the interval is only sent for a branch switch, which
+        // can't be seen while the branch of its folder is reused, so most
+        // events are skipped without looking up their project.
+        let channel = collab::channel(&event.entity);
+        if !event.should_send(false, last_timestamp, now, interval)
+            && (channel.is_some() || self.project_cache.lock().await.is_fresh(&event.entity))
+        {
+            trace!(
+                since_last_sent = ?now.saturating_duration_since(last_timestamp),
+                "skipping heartbeat for file: {}, interval not reached",
+                event.entity.display()
+            );
+            return Err(Outcome::Skipped);
+        }
+
+        let project = match channel {
+            Some(channel) => Some(Project {
+                name: collab::project(
+                    self.settings
+                        .load()
+                        .collab_project
+                        .as_deref()
+                        .unwrap_or(collab::DEFAULT_PROJECT),
+                    channel,
+                ),
+                branch: None,
+                root: None,
+            }),
+            None => {
+                self.project(&event.entity)
+                    .instrument(trace_span!("project"))
+                    .await
+            }
+        };
+        let branch_changed = match &project {
+            Some(project) => self
+                .project_branches
+                .lock()
+                .await
+                .insert(project.name.clone(), project.branch.clone())
+                .is_some_and(|previous| previous != project.branch),
+            None => false,
+        };
+
+        if event.should_send(branch_changed, last_timestamp, now, interval) {
+            debug!(
+                since_last_sent = ?now.saturating_duration_since(last_timestamp),
+                "sending heartbeat for file: {}",
+                event.entity.display()
+            );
+            let should_update_timestamp = event.restarts_interval();
+            let interval = if should_update_timestamp && !branch_changed {
+                interval
+            } else {
+                Duration::ZERO
+            };
+            if !self.delivery.claim(&event.entity, interval).await {
+                debug!(
+                    "another wakatime-ls sent a heartbeat for file: {} within the interval, skipping it",
+                    event.entity.display()
+                );
+                // Like a heartbeat sent here, so the next events don't ask again.
+                self.current_file.set_timestamp(now);
+                return Err(Outcome::Skipped);
+            }
+            self.build_heartbeat(event, project, should_update_timestamp)
+                .await
+        } else {
+            trace!(
+                since_last_sent = ?now.saturating_duration_since(last_timestamp),
+                "skipping heartbeat for file: {}, interval not reached",
+                event.entity.display()
+            );
+            Err(Outcome::Skipped)
+        }
+    }
 
-#[derive(Default, Debug)]
-struct Event {
-    uri: String,
-    is_write: bool,
-    language: Option<String>,
-    lineno: Option<u64>,
-    cursor_pos: Option<u64>,
-    file_changed: bool,
-}
+    // The interval between heartbeats of a file, set by `heartbeat_interval`,
+    // or with `adaptive_interval` by how busy the editor has been lately.
+    fn heartbeat_interval(&self, now: Instant) -> Duration {
+        let settings = self.settings.load();
+        if settings.adaptive_interval != Some(true) {
+            return settings
+                .heartbeat_interval
+                .map_or(event::DEFAULT_HEARTBEAT_INTERVAL, Duration::from_secs);
+        }
 
-#[derive(Debug)]
-struct CurrentFile {
-    uri: String,
-    timestamp: DateTime<Local>,
-}
+        let min = settings
+            .min_heartbeat_interval
+            .map_or(event::DEFAULT_MIN_HEARTBEAT_INTERVAL, Duration::from_secs);
+        let max = settings
+            .max_heartbeat_interval
+            .map_or(event::DEFAULT_MAX_HEARTBEAT_INTERVAL, Duration::from_secs);
+        match self.activity.lock() {
+            Ok(mut activity) => activity.interval(now, min, max),
+            Err(_) => max,
+        }
+    }
 
-struct WakatimeLanguageServer {
-    client: Client,
-    settings: ArcSwap<Settings>,
-    wakatime_path: String,
-    current_file: Mutex<CurrentFile>,
-    platform: ArcSwap<String>,
-    file_cache: SharedFileCache,
-}
+    // Whether a save of `entity` may be sent as a write heartbeat `now`,
+    // rather than as an edit that waits for the interval, as it may not within
+    // `write_interval` of the last one sent or queued. Editors that save on
+    // every keystroke or change of focus would otherwise send a heartbeat for
+    // each save.
+    //
+    // Example, with a write_interval of 30:
+    // a save 10 seconds after the last write heartbeat -> an edit
+    // a save 40 seconds after the last write heartbeat -> a write
+    fn may_write(&self, entity: &Path, now: Instant) -> bool {
+        let interval = match self.settings.load().write_interval {
+            Some(write_interval) if write_interval > 0 => Duration::from_secs(write_interval),
+            _ => return true,
+        };
+        self.file_cache.get(entity).is_none_or(|entry| {
+            entry
+                .written
+                .is_none_or(|written| now.saturating_duration_since(written) >= interval)
+        })
+    }
 
-// Extract filepath string from 'file://' URI.
-//
-// Example:
-// file:///var/log/test.txt    -> /var/log/test.txt
-// file:///C:/path/to/file.txt -> C:\path\to\file.txt
-fn extract_uri_string(uri: &url::Url) -> String {
-    uri.to_file_path()
-        .map(|path: std::path::PathBuf| path.to_string_lossy().to_string())
-        .unwrap_or_else(|()| uri[url::Position::BeforeUsername..].to_string())
-}
+    // Rebuild the project detection options, including the settings scoped to
+    // each workspace folder. `fetch_folder_settings` asks the client for them,
+    // which is only allowed once the server is initialized.
+    async fn configure_project_detection(&self, fetch_folder_settings: bool) {
+        let options = self.detection_options(&self.settings.load()).await;
 
-impl WakatimeLanguageServer {
-    async fn send(&self, event: Event) {
-        if event.lineno.is_none() || event.cursor_pos.is_none() {
-            // log message
-            self.client
-                .log_message(
-                    MessageType::INFO,
-                    format!("Wakatime language server: no cursor position or line number info for file: {}, ignoring event", event.uri),
-                )
-                .await;
-            return;
-        }
+        let folders = self.workspace_folders.lock().await.clone();
+        let folder_settings = if fetch_folder_settings {
+            self.folder_settings(&folders).await
+        } else {
+            Vec::new()
+        };
 
-        #[cfg(debug_assertions)]
-        self.client
-            .log_message(
-                MessageType::LOG,
-                format!("Wakatime language server send called, event: {event:?}",),
-            )
-            .await;
+        let mut folder_options = Vec::new();
+        let mut folder_profiles = Vec::new();
+        for (index, folder) in folders.iter().enumerate() {
+            let Ok(root) = folder.uri.to_file_path() else {
+                continue;
+            };
 
-        // is_write -> send immediately ( don't update the timestamp for the interval check )
-        // file_changed -> send immediately ( same )
-        // else -> check interval, if now - last_sent > interval, send it and update timestamp
+            let profile_name = folder_settings
+                .get(index)
+                .and_then(|scoped| scoped.get("profile"))
+                .and_then(Value::as_str)
+                .map(str::to_string)
+                .or_else(|| profile::from_file(&root));
+            if let Some(profile_name) = profile_name {
+                let known = self
+                    .settings
+                    .load()
+                    .profiles
+                    .iter()
+                    .flatten()
+                    .any(|(name, _)| *name == profile_name);
+                if !known {
+                    warn!(
+                        "profile {profile_name} of {} is not in the profiles setting",
+                        root.display()
+                    );
+                }
+                folder_profiles.push((root.clone(), profile_name));
+            }
 
-        let (last_timestamp, interval) = {
-            let settings = self.settings.load();
-            let interval = if let Some(heartbeat_interval) = settings.heartbeat_interval {
-                TimeDelta::seconds(heartbeat_interval as i64)
-            } else {
-                TimeDelta::minutes(2)
+            let options = match folder_settings.get(index) {
+                Some(scoped) if scoped.is_object() => {
+                    let merged = merge_options(&self.options(), scoped);
+                    self.detection_options(&Settings::from_options(&merged))
+                        .await
+                }
+                _ => options.clone(),
             };
 
-            let cf = self.current_file.lock().await;
-            (cf.timestamp, interval)
-        };
+            folder_options.push((root, options));
+        }
+        self.folder_profiles.store(Arc::new(folder_profiles));
 
-        let now = Local::now();
+        self.project_cache
+            .lock()
+            .await
+            .configure(options, folder_options);
+    }
 
-        #[cfg(debug_assertions)]
-        self.client
-            .log_message(
-                MessageType::LOG,
-                format!("Wakatime language server send called, event: {event:?}"),
-            )
-            .await;
+    async fn detection_options(&self, settings: &Settings) -> DetectionOptions {
+        let (options, errors) = detection_options(settings, &self.config.load());
 
-        let should_send = event.is_write || event.file_changed || now - last_timestamp > interval;
+        for err in errors {
+            warn!("ignoring invalid projectmap pattern: {err}");
+        }
 
-        if should_send {
-            #[cfg(debug_assertions)]
-            self.client
-                .log_message(
-                    MessageType::LOG,
-                    format!(
-                        "Wakatime language server: sending heartbeat for file: {}, last sent at {}, interval reached",
-                        event.uri, last_timestamp
-                    ),
-                )
-                .await;
-            let should_update_timestamp = !event.is_write && !event.file_changed;
-            self.push_heartbeat(event, should_update_timestamp).await;
-        } else {
-            #[cfg(debug_assertions)]
-            self.client
-                .log_message(
-                    MessageType::LOG,
-                    format!(
-                        "Wakatime language server: skipping heartbeat for file: {}, last sent at {}, interval not reached",
-                        event.uri, last_timestamp
-                    ),
-                )
-                .await;
+        options
+    }
+
+    // Without `-p`, use a wakatime-cli on the `PATH` or the one shared with the
+    // other WakaTime plugins, downloading it in the background when neither is
+    // installed. The shared install is then kept up to date, unless
+    // `cli_auto_update` is disabled. A wakatime-cli given with `-p` or found on
+    // the `PATH` is left alone, as is every one without the `http` feature.
+    async fn ensure_wakatime_cli(&self) {
+        if let Some(cli) = self.wakatime_path.load_full() {
+            info!("using wakatime-cli {cli}");
+            let version = check_wakatime_cli(&cli).await;
+            self.wakatime_version.store(version.map(Arc::new));
             return;
         }
+
+        let found = cli::find().await;
+        if let Some(ref cli) = found {
+            info!("found wakatime-cli {cli}");
+            let version = check_wakatime_cli(cli).await;
+            self.wakatime_version.store(version.map(Arc::new));
+            self.wakatime_path.store(Some(Arc::new(cli.clone())));
+        }
+
+        self.keep_wakatime_cli(found);
     }
 
-    async fn push_heartbeat(&self, event: Event, update_timestamp: bool) {
-        let now = Local::now();
+    // Download wakatime-cli when none was found, then keep the shared install
+    // up to date.
+    #[cfg(feature = "http")]
+    fn keep_wakatime_cli(&self, found: Option<Cli>) {
+        let wakatime_path = self.wakatime_path.clone();
+        let wakatime_version = self.wakatime_version.clone();
+        let updating_wakatime_cli = self.updating_wakatime_cli.clone();
+        let settings = self.settings.clone();
+
+        // Stopped with the server, like its other tasks, rather than updating
+        // wakatime-cli once its client is gone.
+        self.spawn(async move {
+            let cli = match found {
+                Some(cli) => cli,
+                None => {
+                    info!("wakatime-cli not found, downloading it");
 
-        // get the line count of the file
-        let line_count = fs::read_to_string(&event.uri)
-            .map(|content| content.lines().count() as u64)
-            .unwrap_or(0);
+                    match cli::install().await {
+                        Ok(cli) => {
+                            info!("installed wakatime-cli {cli}");
+                            let version = check_wakatime_cli(&cli).await;
+                            wakatime_version.store(version.map(Arc::new));
+                            wakatime_path.store(Some(Arc::new(cli.clone())));
+                            cli
+                        }
+                        Err(err) => {
+                            error!("could not install wakatime-cli: {err}");
+                            return;
+                        }
+                    }
+                }
+            };
+
+            if cli::install_path().is_some_and(|install_path| install_path == cli.path)
+                && !updating_wakatime_cli.swap(true, Ordering::Relaxed)
+            {
+                update_wakatime_cli(&settings, &cli, &wakatime_version).await;
+            }
+        });
+    }
 
-        let mut command = TokioCommand::new(self.wakatime_path.as_str());
+    // Builds without the `http` feature can't download wakatime-cli.
+    #[cfg(not(feature = "http"))]
+    fn keep_wakatime_cli(&self, found: Option<Cli>) {
+        if found.is_none() {
+            error!("wakatime-cli not found: install it, or give its path with -p, as this build of wakatime-ls can't download it");
+        }
+    }
 
-        command
-            .arg("--time")
-            .arg((now.timestamp() as f64).to_string())
-            .arg("--entity")
-            .arg(event.uri.as_str());
+    // The wakatime-cli to send a heartbeat with, checked again when its binary
+    // changed since the last heartbeat, as when another WakaTime plugin
+    // updated it, so its version stays right. One that is gone is looked for
+    // again, or downloaded again, rather than failing every heartbeat until
+    // the server restarts; heartbeats are dropped while it is downloaded.
+    async fn resolve_wakatime_cli(&self, cli: Arc<Cli>) -> Option<Arc<Cli>> {
+        if cli.on_host {
+            return Some(cli);
+        }
 
-        if !self.platform.load().is_empty() {
-            command.arg("--plugin").arg(self.platform.load().as_str());
+        let modified = cli.modified();
+        {
+            let Ok(mut checked) = self.wakatime_modified.lock() else {
+                return Some(cli);
+            };
+            let first_check = checked.as_ref().is_none_or(|(path, _)| *path != cli.path);
+            let unchanged = checked
+                .as_ref()
+                .is_some_and(|(_, checked)| *checked == modified);
+            *checked = Some((cli.path.clone(), modified));
+            if first_check || unchanged {
+                return Some(cli);
+            }
         }
 
-        if event.is_write {
-            command.arg("--write");
+        if modified.is_some() || self.wakatime_cli_given {
+            info!("wakatime-cli {cli} changed, checking it again");
+            let version = check_wakatime_cli(&cli).await;
+            self.wakatime_version.store(version.map(Arc::new));
+            return Some(cli);
         }
 
-        let settings = self.settings.load();
+        warn!("wakatime-cli {cli} is gone, looking for it again");
+        self.wakatime_path.store(None);
+        self.wakatime_version.store(None);
+        self.ensure_wakatime_cli().await;
+        self.wakatime_path.load_full()
+    }
 
-        if settings.metrics == Some(true) {
-            command.arg("--metrics");
+    // Ask the client to report changes to the files project lookups depend on,
+    // so they can be cached until one changes.
+    async fn watch_project_files(&self) {
+        if !self.supports_watched_files.load(Ordering::Relaxed) {
+            return;
         }
 
-        if let Some(ref key) = settings.api_key {
-            command.arg("--key").arg(key);
+        let watchers = ["**/.git/**/HEAD", "**/.hg/branch", "**/.wakatime-project"]
+            .into_iter()
+            .map(|glob| FileSystemWatcher {
+                glob_pattern: GlobPattern::String(glob.to_string()),
+                kind: None,
+            })
+            .collect();
+
+        let registration = Registration {
+            id: "wakatime-project-files".to_string(),
+            method: "workspace/didChangeWatchedFiles".to_string(),
+            register_options: serde_json::to_value(DidChangeWatchedFilesRegistrationOptions {
+                watchers,
+            })
+            .ok(),
+        };
+
+        match self.client.register_capability(vec![registration]).await {
+            Ok(()) => self.project_cache.lock().await.set_watching(true),
+            Err(err) => {
+                warn!("could not watch project files: {err}");
+            }
         }
+    }
 
-        if let Some(ref api_url) = settings.api_url {
-            command.arg("--api-url").arg(api_url);
+    // Settings scoped to each workspace folder, e.g. from a project's
+    // `.zed/settings.json`, in the same order as `folders`.
+    async fn folder_settings(&self, folders: &[WorkspaceFolder]) -> Vec<Value> {
+        if folders.is_empty() || !self.supports_configuration.load(Ordering::Relaxed) {
+            return Vec::new();
         }
 
-        if let Some(ref language) = event.language {
-            command.arg("--language").arg(language);
-        } else {
-            command.arg("--guess-language");
+        let items = folders
+            .iter()
+            .map(|folder| ConfigurationItem {
+                scope_uri: Some(folder.uri.clone()),
+                section: None,
+            })
+            .collect();
+
+        match self.client.configuration(items).await {
+            Ok(settings) => settings,
+            Err(err) => {
+                warn!("could not fetch workspace folder settings: {err}");
+                Vec::new()
+            }
         }
+    }
 
-        if let Some(ref debug) = settings.debug {
-            if *debug {
-                command.arg("--verbose");
+    // The profile of the workspace folder an entity is in, or else of the
+    // `profile` setting.
+    fn profile(&self, entity: &Path) -> Option<Profile> {
+        resolve_profile(
+            &self.settings.load(),
+            &self.folder_profiles.load(),
+            Some(entity),
+        )
+    }
+
+    // Walking the filesystem and running `svn` or `jj` for a branch happen on
+    // a blocking thread, without holding the cache for other events.
+    async fn project(&self, entity: &Path) -> Option<Project> {
+        loop {
+            let pending = match self.project_cache.lock().await.lookup(entity) {
+                Lookup::Done(project) => return project,
+                Lookup::Pending(pending) => pending,
+            };
+            let fetched = tokio::task::spawn_blocking(move || pending.run())
+                .await
+                .ok()?;
+            if let Some(project) = self.project_cache.lock().await.complete(entity, fetched) {
+                return project;
             }
         }
+    }
+
+    async fn build_heartbeat(
+        &self,
+        event: Event,
+        project: Option<Project>,
+        update_timestamp: bool,
+    ) -> std::result::Result<Prepared, Outcome> {
+        let Some(wakatime_cli) = self.wakatime_path.load_full() else {
+            debug!(
+                "wakatime-cli is not installed yet, dropping heartbeat for file: {}",
+                event.entity.display()
+            );
+            return Err(Outcome::Dropped);
+        };
+        let Some(wakatime_cli) = self.resolve_wakatime_cli(wakatime_cli).await else {
+            debug!(
+                "wakatime-cli is being installed again, dropping heartbeat for file: {}",
+                event.entity.display()
+            );
+            return Err(Outcome::Dropped);
+        };
+
+        let now = self.clock.now();
+        let instant = self.clock.instant();
+
+        // get the line count of the file, reading it on a blocking thread
+        let (line_counts, entity) = (self.line_counts.clone(), event.entity.clone());
+        let line_count = tokio::task::spawn_blocking(move || line_counts.count(&entity))
+            .await
+            .ok()
+            .flatten();
 
-        if let Some(lineno) = event.lineno {
-            command.arg("--lineno").arg(lineno.to_string());
+        let fallback = match project {
+            Some(_) => None,
+            None => self.project_cache.lock().await.fallback(&event.entity),
+        };
+
+        let mut heartbeat = Heartbeat::new(
+            event.entity.to_path_buf(),
+            event.language,
+            project,
+            fallback,
+        );
+        if collab::channel(&event.entity).is_some() {
+            heartbeat.entity_type = Some("app".to_string());
         }
+        self.current_project.store(
+            heartbeat
+                .project
+                .clone()
+                .or_else(|| heartbeat.alternate_project.clone())
+                .map(Arc::new),
+        );
+        heartbeat.time = self.delivery.heartbeat_time(now).await.timestamp() as f64;
+        heartbeat.is_write = event.is_write;
+        heartbeat.lineno = event.lineno;
+        heartbeat.cursor_pos = event.cursor_pos;
+        heartbeat.lines_in_file = line_count;
+
+        heartbeat.plugin = self.delivery.plugin();
+
+        let roots: Vec<_> = self
+            .workspace_folders
+            .lock()
+            .await
+            .iter()
+            .filter_map(|folder| folder.uri.to_file_path().ok())
+            .collect();
+        let roots: Vec<&Path> = roots.iter().map(PathBuf::as_path).collect();
 
-        if let Some(cursor_pos) = event.cursor_pos {
-            command.arg("--cursorpos").arg(cursor_pos.to_string());
+        // Mapped entities get a mapped project folder to match.
+        let path_mapper = self.path_mapper.load();
+        if let Some(entity) = path_mapper.map(&event.entity, &roots) {
+            if heartbeat.is_file() {
+                heartbeat.local_file = Some(std::mem::replace(&mut heartbeat.entity, entity));
+            }
+            heartbeat.project_folder = heartbeat
+                .project_folder
+                .map(|root| path_mapper.map(&root, &roots).unwrap_or(root));
         }
 
-        if line_count > 0 {
-            command.arg("--lines-in-file").arg(line_count.to_string());
+        if update_timestamp {
+            self.current_file.set_timestamp(instant);
         }
 
-        self.client
-            .log_message(
-                MessageType::LOG,
-                format!("Wakatime command: {:?}", command.as_std()),
-            )
-            .await;
+        Ok(Prepared {
+            heartbeat,
+            wakatime_cli,
+            profile: self.profile(&event.entity),
+        })
+    }
 
-        if let Err(e) = command.output().await {
-            self.client
-                .log_message(
-                    MessageType::LOG,
-                    format!(
-                        "Wakatime language server send msg failed: {e:?}, command: {:?}",
-                        command.as_std()
-                    ),
-                )
-                .await;
+    // Put the settings in effect beyond those read when they are used: the
+    // keys to mask, the log, the API request budget and the path mappings.
+    fn apply_settings(&self) {
+        let settings = self.settings.load();
+        self.configure_secrets();
+        remove_unused_key_configs(&settings, &self.config.load());
+
+        // `debug` predates `log_level` and still turns on debug messages.
+        self.logger
+            .set_level(settings.log_level.unwrap_or(match settings.debug {
+                Some(true) => Level::DEBUG,
+                _ => Level::INFO,
+            }));
+        self.logger
+            .set_format(settings.log_format.unwrap_or(LogFormat::Text));
+        budget::set_per_minute(
+            settings
+                .api_requests_per_minute
+                .unwrap_or(budget::DEFAULT_PER_MINUTE),
+        );
+
+        let log_file = self.log_file.clone().or_else(|| {
+            settings
+                .log_file
+                .as_deref()
+                .map(|path| paths::canonicalize(Path::new(path)))
+        });
+        let default = Rotation::default();
+        let rotation = Rotation {
+            max_size: match settings.log_max_size {
+                Some(0) => None,
+                Some(megabytes) => Some(megabytes * 1024 * 1024),
+                None => default.max_size,
+            },
+            max_age: match settings.log_max_age {
+                Some(0) => None,
+                Some(days) => Some(Duration::from_secs(days * 24 * 60 * 60)),
+                None => default.max_age,
+            },
+            keep: settings.log_keep.unwrap_or(default.keep),
         };
+        if let Err(err) = self.logger.set_file(log_file.as_deref(), rotation) {
+            warn!("could not open log file: {err}");
+        }
 
-        if update_timestamp {
-            let mut cf = self.current_file.lock().await;
-            cf.timestamp = now;
+        let (path_mapper, errors) = PathMapper::new(
+            settings.path_mappings.clone().unwrap_or_default(),
+            settings.remote_relative_paths,
+        )
+        .with_rewrites(
+            settings
+                .entity_rewrite
+                .iter()
+                .flatten()
+                .map(|(pattern, replacement)| (pattern.as_str(), replacement.as_str())),
+        );
+        for err in errors {
+            warn!("ignoring invalid entity_rewrite pattern: {err}");
         }
+        self.path_mapper.store(Arc::new(path_mapper));
+    }
+
+    // The initialization options with the `settings` of the last
+    // `workspace/didChangeConfiguration` over them.
+    fn options(&self) -> Value {
+        merge_options(
+            &self.initialization_options.load(),
+            &self.client_settings.load(),
+        )
+    }
+
+    // Mask every key wakatime-cli might be given, whichever one it picks, in
+    // the log and in error reports.
+    fn configure_secrets(&self) {
+        let settings = self.settings.load();
+        let config = self.config.load();
+        let env_key = std::env::var("WAKATIME_API_KEY").ok();
+        let secrets = Secrets::new(
+            [
+                settings.api_key.as_deref(),
+                settings.secondary_api_key.as_deref(),
+                config.get("settings", "api_key"),
+                env_key.as_deref(),
+            ]
+            .into_iter()
+            .chain(
+                settings
+                    .profiles
+                    .iter()
+                    .flatten()
+                    .map(|(_, profile)| profile.api_key.as_deref()),
+            )
+            .flatten(),
+        );
+        self.logger.set_secrets(secrets.clone());
+        reports::configure(settings.error_reports == Some(true), secrets);
     }
 }
 
@@ -250,42 +3445,79 @@ impl LanguageServer for WakatimeLanguageServer {
         }
 
         if let Some(initialization_options) = params.initialization_options {
-            let initialization_options: Value = serde_json::from_value(initialization_options)
+            let mut initialization_options: Value = serde_json::from_value(initialization_options)
                 .map_err(|_| "Could not parse settings (this should never happen)".to_string())
                 .unwrap();
+            if self.untrusted {
+                initialization_options = restrict_options(initialization_options);
+            }
 
-            let mut settings = Settings::default();
+            let settings = Settings::from_options(&initialization_options);
+            let extension = Extension::from_options(&initialization_options);
+            if let Some(ref version) = extension.version {
+                debug!("started by version {version} of the Zed extension");
+            }
 
-            // check if the plugin is disabled
+            self.settings.swap(Arc::from(settings));
+            self.extension.store(Arc::new(extension));
+            self.initialization_options
+                .store(Arc::new(initialization_options));
+        }
 
-            if let Some(api_url) = initialization_options
-                .get("api-url")
-                .and_then(Value::as_str)
-            {
-                settings.api_url = Some(api_url.to_string());
-            }
+        let supports_configuration = params
+            .capabilities
+            .workspace
+            .as_ref()
+            .and_then(|workspace| workspace.configuration)
+            .unwrap_or(false);
+        self.supports_configuration
+            .store(supports_configuration, Ordering::Relaxed);
 
-            if let Some(api_key) = initialization_options
-                .get("api-key")
-                .and_then(Value::as_str)
-            {
-                settings.api_key = Some(api_key.to_string());
-            }
+        let supports_watched_files = params
+            .capabilities
+            .workspace
+            .as_ref()
+            .and_then(|workspace| workspace.did_change_watched_files)
+            .and_then(|watched_files| watched_files.dynamic_registration)
+            .unwrap_or(false);
+        self.supports_watched_files
+            .store(supports_watched_files, Ordering::Relaxed);
 
-            if let Some(metrics) = initialization_options
-                .get("metrics")
-                .and_then(Value::as_bool)
-            {
-                settings.metrics = Some(metrics);
-            }
+        let supports_show_document = params
+            .capabilities
+            .window
+            .as_ref()
+            .and_then(|window| window.show_document.as_ref())
+            .is_some_and(|show_document| show_document.support);
+        self.supports_show_document
+            .store(supports_show_document, Ordering::Relaxed);
+
+        #[allow(deprecated)]
+        let folders = params.workspace_folders.or_else(|| {
+            let uri = params.root_uri?;
+            let name = uri.path_segments()?.next_back()?.to_string();
+            Some(vec![WorkspaceFolder { uri, name }])
+        });
+        *self.workspace_folders.lock().await = folders.unwrap_or_default();
 
-            if let Some(debug) = initialization_options.get("debug").and_then(Value::as_bool) {
-                settings.debug = Some(debug);
+        match WakatimeConfig::load() {
+            Ok(config) => self.config.store(Arc::new(config)),
+            Err(err) => {
+                warn!("could not read wakatime config: {err}");
             }
+        }
 
-            self.settings.swap(Arc::from(settings));
+        self.apply_settings();
+
+        if let Some(api_url) = self.options().get("api-url").and_then(Value::as_str) {
+            check_api_url("api-url", api_url);
+        }
+        if let Some(api_url) = self.config.load().get("settings", "api_url") {
+            check_api_url("api_url in the wakatime config", api_url);
         }
 
+        self.configure_project_detection(false).await;
+
         Ok(InitializeResult {
             server_info: Some(ServerInfo {
                 name: env!("CARGO_PKG_NAME").to_string(),
@@ -300,36 +3532,148 @@ impl LanguageServer for WakatimeLanguageServer {
                         ..Default::default()
                     },
                 )),
+                workspace: Some(WorkspaceServerCapabilities {
+                    workspace_folders: Some(WorkspaceFoldersServerCapabilities {
+                        supported: Some(true),
+                        change_notifications: Some(OneOf::Left(true)),
+                    }),
+                    file_operations: None,
+                }),
+                execute_command_provider: Some(ExecuteCommandOptions {
+                    commands: COMMANDS.iter().map(|command| command.to_string()).collect(),
+                    ..Default::default()
+                }),
+                experimental: Some(extension::capabilities()),
                 ..Default::default()
             },
         })
     }
 
     async fn initialized(&self, _params: InitializedParams) {
-        self.client
-            .log_message(MessageType::INFO, "Hackatime language server initialized")
-            .await;
-        self.client
-            .log_message(
-                MessageType::INFO,
-                "Hackatime version; only tracking events with line and cursor position will be sent.",
-            )
-            .await;
+        info!("Hackatime language server initialized");
+        debug!(
+            "Hackatime version; only tracking events with line and cursor position will be sent."
+        );
+
+        self.ensure_wakatime_cli().await;
+        reports::set_context(reports::Context {
+            editor: Some(self.platform.load().to_string()).filter(|editor| !editor.is_empty()),
+            wakatime_cli: self
+                .wakatime_version
+                .load_full()
+                .map(|version| version.to_string()),
+        });
+        #[cfg(feature = "http")]
+        self.delivery.upload_error_reports();
+        self.configure_project_detection(true).await;
+        self.watch_project_files().await;
+        self.summarize_heartbeats();
+        self.start_configured_tasks();
+        #[cfg(feature = "http")]
+        self.notify_updates();
+        #[cfg(unix)]
+        self.handle_signals();
+        self.watch_config();
+        #[cfg(feature = "otel")]
+        otel::start();
+
+        #[cfg(feature = "http")]
+        if self.settings.load().self_update == Some(true) {
+            self.spawn(update_self());
+        }
     }
 
     async fn shutdown(&self) -> Result<()> {
+        cli::remove_key_configs(&[]);
         Ok(())
     }
 
+    async fn execute_command(&self, params: ExecuteCommandParams) -> Result<Option<Value>> {
+        match params.command.as_str() {
+            DUMP_RECENT_COMMAND => Ok(Some(json!(self.dump_recent().await?))),
+            #[cfg(feature = "http")]
+            LIST_GOALS_COMMAND => Ok(Some(json!(self.goals().await?))),
+            #[cfg(feature = "http")]
+            LEADERBOARD_COMMAND => Ok(Some(json!(self.leaderboard().await?))),
+            OPEN_DASHBOARD_COMMAND => Ok(Some(json!(self.open_dashboard().await))),
+            EXPORT_ACTIVITY_COMMAND => {
+                let export = match params.arguments.into_iter().next() {
+                    Some(argument) => serde_json::from_value(argument).map_err(|err| {
+                        tower_lsp::jsonrpc::Error::invalid_params(err.to_string())
+                    })?,
+                    None => ExportParams::default(),
+                };
+                if self.untrusted && export.path.is_some() {
+                    return Err(tower_lsp::jsonrpc::Error::invalid_params(
+                        "clients over the network can't write exports, see --trust-clients",
+                    ));
+                }
+                Ok(Some(json!(
+                    activity::export_range(&export).map_err(internal_error)?
+                )))
+            }
+            SNOOZE_BREAK_REMINDER_COMMAND => {
+                // The number of minutes, if given.
+                let snooze = params
+                    .arguments
+                    .first()
+                    .and_then(Value::as_u64)
+                    .map_or(session::SNOOZE, |minutes| Duration::from_secs(minutes * 60));
+                if let Ok(mut session) = self.session.lock() {
+                    session.snooze(self.clock.instant(), snooze);
+                }
+                Ok(None)
+            }
+            command => Err(tower_lsp::jsonrpc::Error::invalid_params(format!(
+                "unknown command {command}"
+            ))),
+        }
+    }
+
+    async fn did_change_workspace_folders(&self, params: DidChangeWorkspaceFoldersParams) {
+        {
+            let mut folders = self.workspace_folders.lock().await;
+            folders.retain(|folder| !params.event.removed.contains(folder));
+            folders.extend(params.event.added);
+        }
+
+        self.configure_project_detection(true).await;
+    }
+
+    async fn did_change_configuration(&self, params: DidChangeConfigurationParams) {
+        // Clients that only announce a change, with null settings, keep the
+        // last ones.
+        if params.settings.is_object() {
+            let settings = match self.untrusted {
+                true => restrict_options(params.settings),
+                false => params.settings,
+            };
+            self.client_settings.store(Arc::new(settings));
+            self.settings
+                .store(Arc::new(Settings::from_options(&self.options())));
+            self.apply_settings();
+            self.start_configured_tasks();
+            info!("reloaded the settings");
+        }
+        self.configure_project_detection(true).await;
+    }
+
+    async fn did_change_watched_files(&self, params: DidChangeWatchedFilesParams) {
+        let mut project_cache = self.project_cache.lock().await;
+
+        for change in params.changes {
+            if let Ok(path) = change.uri.to_file_path() {
+                project_cache.invalidate(&paths::normalize(path));
+            }
+        }
+    }
+
     async fn did_change(&self, params: DidChangeTextDocumentParams) {
-        let file_uri = extract_uri_string(&params.text_document.uri);
-        let file_changed = {
-            let cf = self.current_file.lock().await;
-            file_uri != cf.uri
-        };
+        let entity = self.intern(self.entity(&params.text_document.uri));
+        let file_changed = self.current_file.switch(&entity);
 
         let event = Event {
-            uri: file_uri.clone(),
+            entity: entity.clone(),
             is_write: false,
             lineno: params
                 .content_changes
@@ -347,60 +3691,58 @@ impl LanguageServer for WakatimeLanguageServer {
 
         // add it to the cache
 
-        {
-            let mut cache = self.file_cache.lock().await;
-            cache.entries.insert(
-                file_uri.clone(),
-                FileCacheEntry {
-                    lineno: event.lineno.unwrap_or(0),
-                    cursor_pos: event.cursor_pos.unwrap_or(0),
-                },
-            );
-        }
-
-        {
-            let mut cf = self.current_file.lock().await;
-            cf.uri = file_uri.clone();
+        let (lineno, cursor_pos) = (event.lineno.unwrap_or(0), event.cursor_pos.unwrap_or(0));
+        let changed = self.changes.fetch_add(1, Ordering::Relaxed);
+        let touched = self.clock.instant();
+        let new_file = match self.file_cache.entry(entity) {
+            Entry::Occupied(mut entry) => {
+                let entry = entry.get_mut();
+                (entry.lineno, entry.cursor_pos) = (lineno, cursor_pos);
+                (entry.changed, entry.touched) = (changed, touched);
+                false
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(FileCacheEntry {
+                    lineno,
+                    cursor_pos,
+                    changed,
+                    written: None,
+                    touched,
+                });
+                true
+            }
+        };
+        // The caches only grow much with new files.
+        if new_file {
+            self.limit_memory().await;
         }
 
-        self.send(event).await;
+        self.enqueue(event).await;
     }
 
     async fn did_save(&self, params: DidSaveTextDocumentParams) {
-        self.client
-            .log_message(
-                MessageType::INFO,
-                format!(
-                    "Wakatime language server: file saved: {}",
-                    params.text_document.uri
-                ),
-            )
-            .await;
+        debug!("file saved: {}", params.text_document.uri);
 
-        let file_uri = extract_uri_string(&params.text_document.uri);
+        let entity = self.entity(&params.text_document.uri);
 
         // check if the file is in the cache
 
-        let cache = self.file_cache.lock().await;
-        let (lineno, cursor_pos) = if let Some(entry) = cache.entries.get(&file_uri) {
-            (Some(entry.lineno), Some(entry.cursor_pos))
-        } else {
-            (None, None)
+        let (entity, lineno, cursor_pos) = match self.file_cache.get(entity.as_path()) {
+            Some(entry) => (
+                entry.key().clone(),
+                Some(entry.lineno),
+                Some(entry.cursor_pos),
+            ),
+            None => (Arc::from(entity), None, None),
         };
 
         if lineno.is_none() || cursor_pos.is_none() {
-            // log message
-            self.client
-                .log_message(
-                    MessageType::INFO,
-                    format!("Wakatime language server: no cursor position or line number info for saved file: {}, probably not in the cache, so we're ignoring it", file_uri),
-                )
-                .await;
+            debug!("no cursor position or line number info for saved file: {}, probably not in the cache, so we're ignoring it", entity.display());
             return;
         }
 
         let event = Event {
-            uri: file_uri.clone(),
+            entity: entity.clone(),
             is_write: true,
             lineno,
             language: None,
@@ -408,18 +3750,135 @@ impl LanguageServer for WakatimeLanguageServer {
             file_changed: false,
         };
 
-        {
-            let mut cf = self.current_file.lock().await;
-            cf.uri = file_uri.clone();
-        }
+        self.current_file.switch(&entity);
 
-        self.send(event).await;
+        self.enqueue(event).await;
     }
 }
 
+// A language server, with its own state, for one client. It must be run with
+// the returned dispatcher for its messages to reach the client.
+fn service(
+    wakatime_cli: Option<Arc<Cli>>,
+    log_file: Option<PathBuf>,
+    untrusted: bool,
+) -> (LspService<WakatimeLanguageServer>, ClientSocket, Dispatch) {
+    service_with(
+        wakatime_cli,
+        log_file,
+        untrusted,
+        Arc::new(SystemClock),
+        None,
+    )
+}
+
+// Like `service`, with the clock and the sink of heartbeats given, for tests.
+fn service_with(
+    wakatime_cli: Option<Arc<Cli>>,
+    log_file: Option<PathBuf>,
+    untrusted: bool,
+    clock: Arc<dyn Clock>,
+    sink: Option<RecordingSink>,
+) -> (LspService<WakatimeLanguageServer>, ClientSocket, Dispatch) {
+    let mut dispatch = Dispatch::none();
+
+    let service = LspService::build(|client| {
+        let logger = Logger::new(client.clone());
+        dispatch = logger.dispatch();
+        let recent_heartbeats = Arc::new(Mutex::new(RecentHeartbeats::default()));
+        crash::watch(logger.clone(), &recent_heartbeats);
+        let delivery = Delivery {
+            client: client.clone(),
+            settings: Arc::new(ArcSwap::from_pointee(Settings::default())),
+            config: Arc::new(ArcSwap::from_pointee(WakatimeConfig::default())),
+            platform: Arc::new(ArcSwap::from_pointee(String::new())),
+            wakatime_version: Arc::new(ArcSwapOption::empty()),
+            instance: instances::instance().into(),
+            send_failures: Arc::new(AtomicU32::new(0)),
+            last_failure: Arc::new(std::sync::Mutex::new(None)),
+            clock_skew_warned: Arc::new(AtomicBool::new(false)),
+            queue_full: Arc::new(AtomicBool::new(false)),
+            tasks: Arc::new(std::sync::Mutex::new(Vec::new())),
+            clock: clock.clone(),
+            sink,
+        };
+
+        WakatimeLanguageServer {
+            logger,
+            client,
+            log_file,
+            untrusted,
+            settings: delivery.settings.clone(),
+            initialization_options: ArcSwap::from_pointee(Value::Null),
+            client_settings: ArcSwap::from_pointee(Value::Null),
+            config: delivery.config.clone(),
+            workspace_folders: Mutex::new(Vec::new()),
+            supports_configuration: AtomicBool::new(false),
+            supports_watched_files: AtomicBool::new(false),
+            supports_show_document: AtomicBool::new(false),
+            wakatime_cli_given: wakatime_cli.is_some(),
+            wakatime_path: Arc::new(ArcSwapOption::new(wakatime_cli)),
+            wakatime_version: delivery.wakatime_version.clone(),
+            wakatime_modified: std::sync::Mutex::new(None),
+            #[cfg(feature = "http")]
+            updating_wakatime_cli: Arc::new(AtomicBool::new(false)),
+            platform: delivery.platform.clone(),
+            focused: Arc::new(AtomicBool::new(false)),
+            extension: ArcSwap::from_pointee(Extension::default()),
+            current_file: Arc::new(CurrentFile::new(clock.instant())),
+            file_cache: Arc::new(DashMap::new()),
+            line_counts: Arc::new(LineCounts::default()),
+            changes: AtomicU64::new(0),
+            events: Arc::new(EventQueue::default()),
+            activity: std::sync::Mutex::new(Activity::default()),
+            project_cache: Arc::new(Mutex::new(ProjectCache::default())),
+            project_branches: Mutex::new(HashMap::new()),
+            path_mapper: ArcSwap::from_pointee(PathMapper::default()),
+            folder_profiles: Arc::new(ArcSwap::from_pointee(Vec::new())),
+            recent_heartbeats,
+            current_project: Arc::new(ArcSwapOption::empty()),
+            status_bar_text: Arc::new(ArcSwapOption::empty()),
+            #[cfg(feature = "http")]
+            goals: Arc::new(ArcSwapOption::empty()),
+            below_target_on: Arc::new(std::sync::Mutex::new(None)),
+            session: Arc::new(std::sync::Mutex::new(Session::default())),
+            streak: std::sync::Mutex::new(
+                streak::path()
+                    .map(|path| Streak::load(&path))
+                    .unwrap_or_default(),
+            ),
+            heartbeat_counters: Arc::new(HeartbeatCounters::default()),
+            last_failure: delivery.last_failure.clone(),
+            queue_full: delivery.queue_full.clone(),
+            reload_requested: Arc::new(AtomicBool::new(false)),
+            tasks: delivery.tasks.clone(),
+            configured_tasks: std::sync::Mutex::new(HashMap::new()),
+            clock,
+            delivery,
+        }
+    })
+    .custom_method("wakatime/status", WakatimeLanguageServer::status)
+    .custom_method("wakatime/dumpRecent", WakatimeLanguageServer::dump_recent)
+    .custom_method("wakatime/projects", WakatimeLanguageServer::projects)
+    .custom_method("wakatime/languages", WakatimeLanguageServer::languages)
+    .custom_method("wakatime/session", WakatimeLanguageServer::session)
+    .custom_method(Focus::METHOD, WakatimeLanguageServer::focus);
+    #[cfg(feature = "http")]
+    let service = service
+        .custom_method("wakatime/goals", WakatimeLanguageServer::goals)
+        .custom_method("wakatime/leaderboard", WakatimeLanguageServer::leaderboard)
+        .custom_method("wakatime/durations", WakatimeLanguageServer::durations);
+    let (service, socket) = service.finish();
+
+    (service, socket, dispatch)
+}
+
 #[tokio::main]
-async fn main() {
-    let matches = Command::new("wakatime_ls")
+async fn main() -> ExitCode {
+    reports::install_panic_hook();
+    crash::install_panic_hook();
+
+    let command = Command::new("wakatime_ls")
         .version(env!("CARGO_PKG_VERSION"))
         .author("bestgopher <84328409@qq.com>")
         .about("A simple WakaTime language server tool")
@@ -427,32 +3886,215 @@ async fn main() {
             Arg::new("wakatime-cli")
                 .short('p')
                 .long("wakatime-cli")
-                .help("wakatime-cli path")
-                .required(true),
+                .help("wakatime-cli path (default: the install shared with other WakaTime plugins, downloaded when missing)")
+                .global(true),
+        )
+        .arg(
+            Arg::new("log-file")
+                .long("log-file")
+                .value_name("PATH")
+                .help("Also write logs to this file (default: the log_file setting)"),
+        )
+        .arg(
+            Arg::new("listen")
+                .long("listen")
+                .value_name("URL")
+                .value_parser(Listen::parse)
+                .help("Accept clients on tcp://HOST:PORT or ws://HOST:PORT instead of talking over stdio"),
+        )
+        .arg(
+            Arg::new("listen-token")
+                .long("listen-token")
+                .value_name("TOKEN")
+                .requires("listen")
+                .help("The token --listen clients must give first (default: WAKATIME_LS_LISTEN_TOKEN)"),
+        )
+        .arg(
+            Arg::new("trust-clients")
+                .long("trust-clients")
+                .action(ArgAction::SetTrue)
+                .requires("listen")
+                .help("Let --listen clients set api-url, error_report_url, heartbeat_spool, log_file and export paths"),
+        )
+        .arg(
+            Arg::new("pipe")
+                .long("pipe")
+                .value_name("PATH")
+                .conflicts_with("listen")
+                .help("Accept clients on a Unix socket, or a named pipe on Windows, instead of talking over stdio"),
+        )
+        .arg(
+            Arg::new("record")
+                .long("record")
+                .value_name("PATH")
+                .help("Append the editor's messages to this file, without the text of files or API keys, to replay them with --replay"),
+        )
+        .arg(
+            Arg::new("replay")
+                .long("replay")
+                .value_name("PATH")
+                .conflicts_with_all(["listen", "pipe", "record"])
+                .help("Replay messages recorded with --record as fast as possible, print the heartbeats that would be sent and exit"),
+        )
+        .subcommand(commands::heartbeat_command())
+        .subcommand(commands::doctor_command())
+        .subcommand(commands::today_command())
+        .subcommand(commands::export_command())
+        .subcommand(commands::queue_command());
+    #[cfg(feature = "http")]
+    let command = command.arg(
+        Arg::new("self-update")
+            .long("self-update")
+            .action(ArgAction::SetTrue)
+            .help("Replace this binary with the latest wakatime-ls release and exit"),
+    );
+    #[cfg(feature = "metrics")]
+    let command = command
+        .arg(
+            Arg::new("metrics-listen")
+                .long("metrics-listen")
+                .value_name("HOST:PORT")
+                .value_parser(metrics::parse_address)
+                .help("Serve Prometheus metrics on http://HOST:PORT/metrics"),
         )
-        .get_matches();
+        .arg(
+            Arg::new("statsd")
+                .long("statsd")
+                .value_name("HOST:PORT")
+                .value_parser(metrics::parse_address)
+                .help("Send metrics to a StatsD or DogStatsD agent on HOST:PORT over UDP"),
+        );
+    #[cfg(feature = "otel")]
+    let command = command.arg(
+        Arg::new("otlp-endpoint")
+            .long("otlp-endpoint")
+            .value_name("URL")
+            .help("Export traces of heartbeats to an OpenTelemetry collector taking OTLP over HTTP, such as http://127.0.0.1:4318"),
+    );
+    let matches = command.get_matches();
+
+    let wakatime_cli = matches
+        .get_one::<String>("wakatime-cli")
+        .map(|path| Cli::new(PathBuf::from(path)));
+
+    #[cfg(feature = "http")]
+    if matches.get_flag("self-update") {
+        return match self_update::update().await {
+            Ok(Some(version)) => {
+                eprintln!("wakatime-ls: updated to {version}");
+                ExitCode::SUCCESS
+            }
+            Ok(None) => {
+                eprintln!("wakatime-ls: already up to date");
+                ExitCode::SUCCESS
+            }
+            Err(err) => {
+                eprintln!("wakatime-ls: {err}");
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    match matches.subcommand() {
+        Some(("heartbeat", matches)) => return commands::heartbeat(matches, wakatime_cli).await,
+        Some(("doctor", _)) => return commands::doctor(wakatime_cli).await,
+        Some(("today", matches)) => return commands::today(matches, wakatime_cli).await,
+        Some(("export", matches)) => return commands::export(matches),
+        Some(("queue", matches)) => return commands::queue(matches, wakatime_cli).await,
+        _ => {}
+    }
+    if let Some(path) = matches.get_one::<String>("replay") {
+        return replay::replay(Path::new(path), wakatime_cli).await;
+    }
+    #[cfg(feature = "metrics")]
+    if let Some(&address) = matches.get_one::<std::net::SocketAddr>("metrics-listen") {
+        if let Err(err) = metrics::listen(address).await {
+            eprintln!("wakatime-ls: could not serve metrics on {address}: {err}");
+            return ExitCode::FAILURE;
+        }
+    }
+    #[cfg(feature = "metrics")]
+    if let Some(&address) = matches.get_one::<std::net::SocketAddr>("statsd") {
+        if let Err(err) = metrics::statsd(address).await {
+            eprintln!("wakatime-ls: could not send metrics to {address}: {err}");
+            return ExitCode::FAILURE;
+        }
+    }
 
-    let wakatime_cli = if let Some(s) = matches.get_one::<String>("wakatime-cli") {
-        s.to_string()
-    } else {
-        "wakatime-cli".to_string()
+    #[cfg(feature = "otel")]
+    if let Some(endpoint) = matches.get_one::<String>("otlp-endpoint") {
+        if let Err(err) = otel::export(endpoint) {
+            eprintln!("wakatime-ls: could not export traces to {endpoint}: {err}");
+            return ExitCode::FAILURE;
+        }
+    }
+
+    let wakatime_cli = wakatime_cli.map(Arc::new);
+    let log_file = matches
+        .get_one::<String>("log-file")
+        .map(|path| paths::canonicalize(Path::new(path)));
+    let recorder = match matches.get_one::<String>("record") {
+        Some(path) => match Recorder::create(&paths::canonicalize(Path::new(path))) {
+            Ok(recorder) => Some(recorder),
+            Err(err) => {
+                eprintln!("wakatime-ls: could not record to {path}: {err}");
+                return ExitCode::FAILURE;
+            }
+        },
+        None => None,
     };
 
+    if let Some(&listen) = matches.get_one::<Listen>("listen") {
+        let token = matches
+            .get_one::<String>("listen-token")
+            .cloned()
+            .or_else(|| std::env::var("WAKATIME_LS_LISTEN_TOKEN").ok())
+            .filter(|token| !token.is_empty());
+        let Some(token) = token else {
+            eprintln!("wakatime-ls: --listen needs a token, given with --listen-token or WAKATIME_LS_LISTEN_TOKEN");
+            return ExitCode::FAILURE;
+        };
+        let untrusted = !matches.get_flag("trust-clients");
+
+        let result = transport::listen(listen, token, move |read, write| {
+            let (service, socket, dispatch) =
+                service(wakatime_cli.clone(), log_file.clone(), untrusted);
+            Server::new(read, write, socket)
+                .serve(replay::record(service, recorder.clone()))
+                .with_subscriber(dispatch)
+        })
+        .await;
+
+        if let Err(err) = result {
+            eprintln!("wakatime-ls: could not listen on {listen}: {err}");
+        }
+        return ExitCode::FAILURE;
+    }
+
+    if let Some(path) = matches.get_one::<String>("pipe") {
+        let result = transport::pipe(Path::new(path), move |read, write| {
+            let (service, socket, dispatch) =
+                service(wakatime_cli.clone(), log_file.clone(), false);
+            Server::new(read, write, socket)
+                .serve(replay::record(service, recorder.clone()))
+                .with_subscriber(dispatch)
+        })
+        .await;
+
+        if let Err(err) = result {
+            eprintln!("wakatime-ls: could not listen on {path}: {err}");
+        }
+        return ExitCode::FAILURE;
+    }
+
     let stdin = tokio::io::stdin();
     let stdout = tokio::io::stdout();
 
-    let (service, socket) = LspService::new(|client| {
-        Arc::new(WakatimeLanguageServer {
-            client,
-            settings: ArcSwap::from_pointee(Settings::default()),
-            wakatime_path: wakatime_cli,
-            platform: ArcSwap::from_pointee(String::new()),
-            current_file: Mutex::new(CurrentFile {
-                uri: String::new(),
-                timestamp: Local::now(),
-            }),
-            file_cache: Arc::new(Mutex::new(FileCache::default())),
-        })
-    });
-    Server::new(stdin, stdout, socket).serve(service).await;
+    let (service, socket, dispatch) = service(wakatime_cli, log_file, false);
+    Server::new(stdin, stdout, socket)
+        .serve(replay::record(service, recorder))
+        .with_subscriber(dispatch)
+        .await;
+
+    ExitCode::SUCCESS
 }
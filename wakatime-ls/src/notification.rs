@@ -0,0 +1,76 @@
+use serde::{Deserialize, Serialize};
+use tower_lsp::lsp_types::notification::Notification;
+
+#[cfg(feature = "http")]
+use wakatime_ls_core::{goals::Goal, weekly::WeeklySummary};
+
+// `wakatime/statusBarText`: today's coding time, pushed whenever it changes so
+// the extension can show it without asking the API itself. The text follows
+// the `status_bar_format` setting, and is empty while it should be hidden.
+//
+// Example:
+// {"text":"3 hrs 7 mins","total_seconds":11220}
+pub enum StatusBarText {}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StatusBarTextParams {
+    pub text: String,
+    pub total_seconds: u64,
+}
+
+impl Notification for StatusBarText {
+    type Params = StatusBarTextParams;
+    const METHOD: &'static str = "wakatime/statusBarText";
+}
+
+// `wakatime/weeklySummary`: the last 7 days' coding time, pushed once a week
+// along with the message shown for it, for extensions that render their own.
+//
+// Example:
+// {"text":"14 hrs 3 mins","total_seconds":50580,"top_language":"Rust"}
+#[cfg(feature = "http")]
+pub enum WeeklySummaryNotification {}
+
+#[cfg(feature = "http")]
+impl Notification for WeeklySummaryNotification {
+    type Params = WeeklySummary;
+    const METHOD: &'static str = "wakatime/weeklySummary";
+}
+
+// `wakatime/goalProgress`: every goal with its progress in the current day or
+// week, pushed whenever any of it changes.
+//
+// Example:
+// {"goals":[{"id":"5a1f...","title":"Code 2 hrs per day in Rust","delta":"day","period":"2026-10-14","actual_seconds":4320,"actual_text":"1 hr 12 mins","goal_seconds":7200,"goal_text":"2 hrs"}]}
+#[cfg(feature = "http")]
+pub enum GoalProgress {}
+
+#[cfg(feature = "http")]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GoalProgressParams {
+    pub goals: Vec<Goal>,
+}
+
+#[cfg(feature = "http")]
+impl Notification for GoalProgress {
+    type Params = GoalProgressParams;
+    const METHOD: &'static str = "wakatime/goalProgress";
+}
+
+// `wakatime/focus`: sent by the client, unlike the others, whenever the editor
+// gains or loses focus, so `app_heartbeats` knows Zed is being used while no
+// file is edited.
+//
+// Example:
+// {"focused":true}
+pub enum Focus {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FocusParams {
+    pub focused: bool,
+}
+
+impl Notification for Focus {
+    type Params = FocusParams;
+    const METHOD: &'static str = "wakatime/focus";
+}
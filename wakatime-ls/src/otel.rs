@@ -0,0 +1,284 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    mem,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        LazyLock, Mutex, OnceLock,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::{json, Value};
+use tracing::{
+    field::{Field, Visit},
+    info,
+    instrument::WithSubscriber,
+    span::{Attributes, Id, Record},
+    warn, Metadata, Subscriber,
+};
+use tracing_subscriber::{
+    filter::dynamic_filter_fn,
+    layer::{Context, Filter},
+    registry::LookupSpan,
+    Layer,
+};
+
+use wakatime_ls_core::cli;
+
+// How often the finished spans are posted to the collector.
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+// Spans kept while the collector can't be reached; newer ones are dropped.
+const MAX_SPANS: usize = 2048;
+
+// Where the spans go, from `--otlp-endpoint`.
+static COLLECTOR: OnceLock<(String, reqwest::Client)> = OnceLock::new();
+
+// Set once a server started posting the spans.
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+// The spans of every server in this process, exported with
+// `--otlp-endpoint`.
+static EXPORTER: LazyLock<Exporter> = LazyLock::new(|| Exporter {
+    enabled: AtomicBool::new(false),
+    spans: Mutex::new(Vec::new()),
+    ids: RandomState::new(),
+    next_id: AtomicU64::new(0),
+});
+
+struct Exporter {
+    enabled: AtomicBool,
+    spans: Mutex<Vec<Value>>,
+    // Ids are a counter hashed with a key of this process, unique within
+    // it and unlikely to be those of another's.
+    ids: RandomState,
+    next_id: AtomicU64,
+}
+
+// What a span of this crate is while open, kept in its extensions.
+struct OpenSpan {
+    trace_id: String,
+    span_id: String,
+    parent_span_id: String,
+    start: SystemTime,
+    attributes: Vec<Value>,
+}
+
+// Export the spans of this crate, such as each heartbeat's with its
+// `project` and `wakatime_cli` children, to an OpenTelemetry collector
+// taking OTLP over HTTP with JSON, once a server starts.
+//
+// Example:
+// http://127.0.0.1:4318 -> spans posted to http://127.0.0.1:4318/v1/traces
+pub fn export(endpoint: &str) -> Result<(), String> {
+    let url = format!("{}/v1/traces", endpoint.trim_end_matches('/'));
+    let client = cli::http_client()?;
+    COLLECTOR.set((url, client)).ok();
+    EXPORTER.enabled.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+// Post the spans every `EXPORT_INTERVAL`, from the first server to start, so
+// that a collector that can't be reached is reported in its log: once when
+// it goes down, and once it is back.
+pub fn start() {
+    let Some((url, client)) = COLLECTOR.get() else {
+        return;
+    };
+    if STARTED.swap(true, Ordering::Relaxed) {
+        return;
+    }
+
+    tokio::spawn(
+        async move {
+            let mut interval = tokio::time::interval(EXPORT_INTERVAL);
+            let mut failing = false;
+
+            loop {
+                interval.tick().await;
+
+                let spans = match EXPORTER.spans.lock() {
+                    Ok(mut spans) if !spans.is_empty() => mem::take(&mut *spans),
+                    _ => continue,
+                };
+
+                let Ok(body) = serde_json::to_vec(&request(spans)) else {
+                    continue;
+                };
+                let response = client
+                    .post(url)
+                    .header(reqwest::header::CONTENT_TYPE, "application/json")
+                    .body(body)
+                    .send()
+                    .await;
+                match response.and_then(|response| response.error_for_status()) {
+                    Ok(_) if failing => {
+                        failing = false;
+                        info!("exporting spans to {url} again");
+                    }
+                    Ok(_) => {}
+                    Err(err) if !failing => {
+                        failing = true;
+                        warn!("could not export spans to {url}, dropping them until it can be reached: {err}");
+                    }
+                    Err(_) => {}
+                }
+            }
+        }
+        .with_current_subscriber(),
+    );
+}
+
+pub fn layer<S>() -> impl Layer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    SpanLayer.with_filter(filter())
+}
+
+fn filter<S>() -> impl Filter<S> {
+    dynamic_filter_fn(|metadata: &Metadata<'_>, _| {
+        metadata.is_span()
+            && metadata.target().starts_with(env!("CARGO_CRATE_NAME"))
+            && EXPORTER.enabled.load(Ordering::Relaxed)
+    })
+}
+
+// Example:
+// {"resourceSpans":[{"resource":{"attributes":[{"key":"service.name","value":{"stringValue":"wakatime-ls"}}]},"scopeSpans":[{"scope":{"name":"wakatime-ls"},"spans":[{"traceId":"5b8efff798038103d269b633813fc60c","spanId":"eee19b7ec3c1b174","parentSpanId":"","name":"heartbeat","kind":1,"startTimeUnixNano":"1792335939202000000","endTimeUnixNano":"1792335939414000000","attributes":[{"key":"outcome","value":{"stringValue":"sent"}}]}]}]}]}
+fn request(spans: Vec<Value>) -> Value {
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [
+                    attribute("service.name", json!({"stringValue": "wakatime-ls"})),
+                    attribute("service.version", json!({"stringValue": env!("CARGO_PKG_VERSION")})),
+                ],
+            },
+            "scopeSpans": [{
+                "scope": {"name": "wakatime-ls"},
+                "spans": spans,
+            }],
+        }],
+    })
+}
+
+fn attribute(key: &str, value: Value) -> Value {
+    json!({"key": key, "value": value})
+}
+
+fn random_id(bytes: usize) -> String {
+    (0..bytes / 8)
+        .map(|_| {
+            let mut hasher = EXPORTER.ids.build_hasher();
+            hasher.write_u64(EXPORTER.next_id.fetch_add(1, Ordering::Relaxed));
+            format!("{:016x}", hasher.finish())
+        })
+        .collect()
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+struct SpanLayer;
+
+impl<S> Layer<S> for SpanLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+
+        // Children are part of the trace of the span they are in.
+        let parent = span.parent().and_then(|parent| {
+            parent
+                .extensions()
+                .get::<OpenSpan>()
+                .map(|open| (open.trace_id.clone(), open.span_id.clone()))
+        });
+        let (trace_id, parent_span_id) = parent.unwrap_or_else(|| (random_id(16), String::new()));
+
+        let mut open = OpenSpan {
+            trace_id,
+            span_id: random_id(8),
+            parent_span_id,
+            start: SystemTime::now(),
+            attributes: Vec::new(),
+        };
+        attrs.record(&mut Fields(&mut open.attributes));
+        span.extensions_mut().insert(open);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(open) = span.extensions_mut().get_mut::<OpenSpan>() {
+                values.record(&mut Fields(&mut open.attributes));
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let Some(open) = span.extensions_mut().remove::<OpenSpan>() else {
+            return;
+        };
+
+        let span = json!({
+            "traceId": open.trace_id,
+            "spanId": open.span_id,
+            "parentSpanId": open.parent_span_id,
+            "name": span.name(),
+            "kind": 1,
+            "startTimeUnixNano": unix_nanos(open.start),
+            "endTimeUnixNano": unix_nanos(SystemTime::now()),
+            "attributes": open.attributes,
+        });
+        if let Ok(mut spans) = EXPORTER.spans.lock() {
+            if spans.len() < MAX_SPANS {
+                spans.push(span);
+            }
+        }
+    }
+}
+
+// The fields of a span as OTLP attributes, a field recorded again replacing
+// its earlier value.
+struct Fields<'a>(&'a mut Vec<Value>);
+
+impl Fields<'_> {
+    fn set(&mut self, field: &Field, value: Value) {
+        self.0.retain(|attribute| attribute["key"] != field.name());
+        self.0.push(attribute(field.name(), value));
+    }
+}
+
+impl Visit for Fields<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.set(field, json!({"stringValue": value}));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.set(field, json!({"boolValue": value}));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.set(field, json!({"intValue": value.to_string()}));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.set(field, json!({"intValue": value.to_string()}));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.set(field, json!({"stringValue": format!("{value:?}")}));
+    }
+}
@@ -0,0 +1,240 @@
+use std::{
+    env,
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::Path,
+    process::ExitCode,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use chrono::{DateTime, Local};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tower::{Service, ServiceExt};
+use tower_lsp::jsonrpc::Request;
+use wakatime_ls_core::{cli::Cli, clock::ManualClock, sink::RecordingSink};
+
+use crate::service_with;
+
+// Settings that would reach out of a replay, whatever the recorded session
+// had: nothing is fetched, installed, uploaded or written to the user's
+// records.
+fn replay_options() -> Value {
+    json!({
+        "status_bar_interval": 0,
+        "update_notifications": false,
+        "self_update": false,
+        "cli_auto_update": false,
+        "error_reports": false,
+        "goal_notifications": false,
+        "weekly_summary": false,
+        "activity_log": false,
+        "heartbeat_spool": null,
+    })
+}
+
+// One message from the client as written with `--record`, without the text
+// of documents or API keys.
+//
+// Example:
+// {"time":"2026-10-14T15:05:39.202+02:00","message":{"jsonrpc":"2.0","method":"textDocument/didSave","params":{"textDocument":{"uri":"file:///home/alice/src/app/main.rs"}}}}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    time: DateTime<Local>,
+    message: Value,
+}
+
+// Appends the messages of every client to the file given with `--record`.
+#[derive(Debug, Clone)]
+pub struct Recorder {
+    file: Arc<Mutex<BufWriter<File>>>,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(Self {
+            file: Arc::new(Mutex::new(BufWriter::new(file))),
+        })
+    }
+
+    // A message that can't be written is left out of the recording.
+    fn write(&self, request: &Request) {
+        let Ok(mut message) = serde_json::to_value(request) else {
+            return;
+        };
+        scrub(&mut message);
+
+        let entry = Entry {
+            time: Local::now(),
+            message,
+        };
+        if let (Ok(mut file), Ok(line)) = (self.file.lock(), serde_json::to_string(&entry)) {
+            writeln!(file, "{line}").and_then(|_| file.flush()).ok();
+        }
+    }
+}
+
+// A language server whose incoming messages are recorded before it handles
+// them, with a recorder.
+pub struct Recording<S> {
+    service: S,
+    recorder: Option<Recorder>,
+}
+
+pub fn record<S>(service: S, recorder: Option<Recorder>) -> Recording<S> {
+    Recording { service, recorder }
+}
+
+impl<S: Service<Request>> Service<Request> for Recording<S> {
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        if let Some(ref recorder) = self.recorder {
+            recorder.write(&request);
+        }
+        self.service.call(request)
+    }
+}
+
+// Only the positions of edits matter to heartbeats, not what was typed, and
+// a recording may be attached to an issue.
+fn scrub(value: &mut Value) {
+    match value {
+        Value::Object(object) => {
+            object.retain(|key, _| !key.contains("api-key") && !key.contains("api_key"));
+            for (key, value) in object.iter_mut() {
+                if key == "text" && value.is_string() {
+                    *value = Value::String(String::new());
+                } else {
+                    scrub(value);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(scrub),
+        _ => {}
+    }
+}
+
+// Replay a recording against a language server of its own, with its clock
+// moved to the time of each message instead of waiting for it, and print
+// the heartbeats it would have sent. Nothing is sent, and the server's
+// files are kept in a temporary `WAKATIME_HOME`.
+pub async fn replay(path: &Path, wakatime_cli: Option<Cli>) -> ExitCode {
+    let entries = match read(path) {
+        Ok(entries) => entries,
+        Err(err) => {
+            eprintln!("wakatime-ls: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let Some(start) = entries.first().map(|entry| entry.time) else {
+        eprintln!("wakatime-ls: {} has nothing to replay", path.display());
+        return ExitCode::FAILURE;
+    };
+
+    let home = env::temp_dir().join(format!("wakatime-ls-replay-{}", std::process::id()));
+    if let Err(err) = fs::create_dir_all(&home) {
+        eprintln!("wakatime-ls: could not create {}: {err}", home.display());
+        return ExitCode::FAILURE;
+    }
+    env::set_var("WAKATIME_HOME", &home);
+
+    let clock = ManualClock::new(start);
+    let sink = RecordingSink::default();
+    let cli = wakatime_cli.unwrap_or_else(|| Cli::new("wakatime-cli".into()));
+    let (mut service, socket, _) = service_with(
+        Some(Arc::new(cli)),
+        None,
+        false,
+        Arc::new(clock.clone()),
+        Some(sink.clone()),
+    );
+    tokio::spawn(socket.for_each(|_| async {}));
+
+    let started = Instant::now();
+    let mut previous = start;
+    let mut replayed = 0;
+    for entry in &entries {
+        if entry.time > previous {
+            clock.advance(entry.time - previous);
+            previous = entry.time;
+        }
+
+        let Some(request) = prepare(entry.message.clone()) else {
+            continue;
+        };
+        let Ok(ready) = service.ready().await else {
+            break;
+        };
+        ready.call(request).await.ok();
+        replayed += 1;
+    }
+
+    let heartbeats = sink.heartbeats();
+    for heartbeat in &heartbeats {
+        println!("{}", heartbeat.to_json());
+    }
+    eprintln!(
+        "wakatime-ls: replayed {replayed} messages over {} minutes in {} ms: {}",
+        (previous - start).num_minutes(),
+        started.elapsed().as_millis(),
+        json!(service.inner().heartbeat_counters.counts()),
+    );
+
+    fs::remove_dir_all(&home).ok();
+    ExitCode::SUCCESS
+}
+
+fn read(path: &Path) -> Result<Vec<Entry>, String> {
+    let content = fs::read_to_string(path)
+        .map_err(|err| format!("could not read {}: {err}", path.display()))?;
+
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| {
+            serde_json::from_str(line)
+                .map_err(|err| format!("{}:{}: {err}", path.display(), index + 1))
+        })
+        .collect()
+}
+
+// The recorded message as the replay's server gets it, or None for one that
+// would stop it early. The client's capabilities are left out, as nothing
+// would answer the server's requests to it.
+fn prepare(mut message: Value) -> Option<Request> {
+    match message["method"].as_str()? {
+        "shutdown" | "exit" => return None,
+        "initialize" => {
+            let params = message["params"].as_object_mut()?;
+            params.insert("capabilities".to_string(), json!({}));
+
+            let options = params
+                .entry("initializationOptions")
+                .or_insert_with(|| json!({}));
+            if !options.is_object() {
+                *options = json!({});
+            }
+            if let (Some(options), Value::Object(replay)) =
+                (options.as_object_mut(), replay_options())
+            {
+                options.extend(replay);
+            }
+        }
+        _ => {}
+    }
+
+    serde_json::from_value(message).ok()
+}
@@ -0,0 +1,378 @@
+use std::{
+    env,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    panic,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        LazyLock, Mutex,
+    },
+};
+
+use chrono::{DateTime, Local, SubsecRound};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "http")]
+use wakatime_ls_core::cli;
+use wakatime_ls_core::{config, redact::Secrets};
+
+// Kept in the file while nothing uploads them, the oldest dropped first.
+const MAX_REPORTS: usize = 100;
+
+// Absolute paths, `file://` URIs and paths in the home directory, which name
+// the user and their projects, wherever they start a word, without the
+// punctuation after them.
+//
+// Example:
+// failed to read /home/alice/src/acme/main.rs: denied -> failed to read <path>: denied
+// no project for file:///C:/Users/alice/main.rs       -> no project for <path>
+// could not open ~/src/acme/.wakatime-project          -> could not open <path>
+static PATHS: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"(^|[\s"'=(])(?:file://|~|[A-Za-z]:)?[/\\](?:[^\s"']*[^\s"':;,.)])?"#).unwrap()
+});
+
+// Held while the reports file is rewritten, so a report added by another
+// server of this process in the meantime isn't lost.
+static FILE: Mutex<()> = Mutex::new(());
+
+// Set by the `error_reports` setting, and shared by every server in this
+// process like the panic hook is.
+static REPORTER: LazyLock<Reporter> = LazyLock::new(|| Reporter {
+    enabled: AtomicBool::new(false),
+    secrets: Mutex::new(Secrets::default()),
+    context: Mutex::new(Context::default()),
+});
+
+struct Reporter {
+    enabled: AtomicBool,
+    secrets: Mutex<Secrets>,
+    context: Mutex<Context>,
+}
+
+// What a report says about the setup it comes from, besides this server's
+// version and platform.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Context {
+    pub editor: Option<String>,
+    pub wakatime_cli: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    Panic,
+    // Sends of heartbeats that failed several times in a row, rather than queued.
+    SendFailures,
+}
+
+// One error, with its message stripped of keys and paths, as written to the
+// reports file and uploaded.
+//
+// Example:
+// {"time":"2026-10-14T15:05:39+02:00","kind":"send_failures","message":"5 sends in a row failed: could not run wakatime-cli: No such file or directory (os error 2)","version":"0.3.1","os":"linux","arch":"x86_64","editor":"Zed/0.200.0 Zed-hackatime/0.3.1","wakatime_cli":"v1.102.1"}
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Report {
+    pub time: DateTime<Local>,
+    pub kind: Kind,
+    pub message: String,
+    pub version: String,
+    pub os: String,
+    pub arch: String,
+    pub editor: Option<String>,
+    pub wakatime_cli: Option<String>,
+}
+
+pub fn path() -> Option<PathBuf> {
+    Some(
+        config::wakatime_home()?
+            .join(".wakatime")
+            .join("zed-wakatime-errors.jsonl"),
+    )
+}
+
+pub fn configure(enabled: bool, secrets: Secrets) {
+    REPORTER.enabled.store(enabled, Ordering::Relaxed);
+    if let Ok(mut current) = REPORTER.secrets.lock() {
+        *current = secrets;
+    }
+}
+
+pub fn set_context(context: Context) {
+    if let Ok(mut current) = REPORTER.context.lock() {
+        *current = context;
+    }
+}
+
+// Report panics of any thread once reports are enabled, after the usual
+// message on stderr.
+pub fn install_panic_hook() {
+    let previous = panic::take_hook();
+
+    panic::set_hook(Box::new(move |info| {
+        previous(info);
+        record(Kind::Panic, &panic_message(info));
+    }));
+}
+
+// Example:
+// index out of bounds: the len is 0 but the index is 0 at wakatime-ls/src/main.rs:123
+pub fn panic_message(info: &panic::PanicHookInfo<'_>) -> String {
+    let payload = info
+        .payload()
+        .downcast_ref::<&str>()
+        .map(|payload| payload.to_string())
+        .or_else(|| info.payload().downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "panicked".to_string());
+
+    match info.location() {
+        Some(location) => format!("{payload} at {}:{}", location.file(), location.line()),
+        None => payload,
+    }
+}
+
+// Write a report when they are enabled, returning it.
+pub fn record(kind: Kind, message: &str) -> Option<Report> {
+    if !REPORTER.enabled.load(Ordering::Relaxed) {
+        return None;
+    }
+
+    let report = match REPORTER.secrets.lock() {
+        Ok(secrets) => new_report(
+            kind,
+            message,
+            &secrets,
+            REPORTER.context.lock().ok()?.clone(),
+        ),
+        Err(_) => return None,
+    };
+
+    // Nothing is logged from here, as it may run in the panic hook.
+    if let Some(path) = path() {
+        append(&path, &report).ok();
+    }
+
+    Some(report)
+}
+
+fn new_report(kind: Kind, message: &str, secrets: &Secrets, context: Context) -> Report {
+    let message = secrets.redact(message);
+
+    Report {
+        time: Local::now().trunc_subsecs(0),
+        kind,
+        message: PATHS.replace_all(&message, "${1}<path>").into_owned(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        os: env::consts::OS.to_string(),
+        arch: env::consts::ARCH.to_string(),
+        editor: context.editor,
+        wakatime_cli: context.wakatime_cli,
+    }
+}
+
+fn append(path: &Path, report: &Report) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let _file = FILE.lock().unwrap_or_else(|err| err.into_inner());
+
+    let reports = read(path);
+    if reports.len() >= MAX_REPORTS {
+        let kept = &reports[reports.len() + 1 - MAX_REPORTS..];
+        return write(path, &[kept, std::slice::from_ref(report)].concat());
+    }
+
+    let mut line = serde_json::to_string(report)?;
+    line.push('\n');
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?
+        .write_all(line.as_bytes())
+}
+
+// Lines that can't be read, such as one cut short by a crash, are skipped.
+fn read(path: &Path) -> Vec<Report> {
+    fs::read_to_string(path)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect()
+}
+
+fn write(path: &Path, reports: &[Report]) -> io::Result<()> {
+    let mut content = String::new();
+    for report in reports {
+        content.push_str(&serde_json::to_string(report)?);
+        content.push('\n');
+    }
+    fs::write(path, content)
+}
+
+// Post the reports in the file to `url` one by one as JSON, removing those
+// that were received, and return how many were.
+#[cfg(feature = "http")]
+pub async fn upload(url: &str) -> Result<usize, String> {
+    let path = path().ok_or("no home directory to find the error reports in")?;
+    let client = cli::http_client()?;
+
+    let mut received = Vec::new();
+    for report in read(&path) {
+        let body = serde_json::to_vec(&report)
+            .map_err(|err| format!("failed to encode a report: {err}"))?;
+        let response = client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body)
+            .send()
+            .await;
+
+        if response.is_ok_and(|response| response.status().is_success()) {
+            received.push(report);
+        }
+    }
+
+    if !received.is_empty() {
+        remove(&path, &received)
+            .map_err(|err| format!("could not write {}: {err}", path.display()))?;
+    }
+
+    Ok(received.len())
+}
+
+// Remove reports from the file as it is now, keeping those added since it
+// was read.
+#[cfg(feature = "http")]
+fn remove(path: &Path, reports: &[Report]) -> io::Result<()> {
+    let _file = FILE.lock().unwrap_or_else(|err| err.into_inner());
+
+    let mut left = read(path);
+    for report in reports {
+        if let Some(index) = left.iter().position(|left| left == report) {
+            left.remove(index);
+        }
+    }
+    write(path, &left)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!(
+            "wakatime-ls-reports-{}-{name}.jsonl",
+            std::process::id()
+        ));
+        fs::remove_file(&path).ok();
+        path
+    }
+
+    fn message(message: &str) -> String {
+        new_report(
+            Kind::Panic,
+            message,
+            &Secrets::default(),
+            Context::default(),
+        )
+        .message
+    }
+
+    #[test]
+    fn reports_are_off_until_enabled() {
+        assert_eq!(record(Kind::Panic, "a panic nobody asked to report"), None);
+    }
+
+    #[test]
+    fn paths_are_stripped() {
+        assert_eq!(
+            message("failed to read /home/alice/src/acme/main.rs: denied"),
+            "failed to read <path>: denied"
+        );
+        assert_eq!(
+            message(r"failed to read C:\Users\alice\main.rs"),
+            "failed to read <path>"
+        );
+        assert_eq!(
+            message("no project for file:///C:/Users/alice/main.rs"),
+            "no project for <path>"
+        );
+        assert_eq!(
+            message("could not open ~/src/acme/.wakatime-project"),
+            "could not open <path>"
+        );
+        assert_eq!(
+            message("entity=\"/srv/acme/main.rs\" (~\\src\\main.rs)"),
+            "entity=\"<path>\" (<path>)"
+        );
+        assert_eq!(
+            message("5 heartbeats in a row failed: exited with code 1/2"),
+            "5 heartbeats in a row failed: exited with code 1/2"
+        );
+    }
+
+    #[test]
+    fn keys_are_hidden() {
+        let secrets = Secrets::new(["0123456789abcdef"]);
+        let report = new_report(
+            Kind::SendFailures,
+            "wakatime-cli --key 0b6f1c3e refused 0123456789abcdef",
+            &secrets,
+            Context {
+                editor: Some("Zed/0.200.0".to_string()),
+                wakatime_cli: None,
+            },
+        );
+
+        assert_eq!(
+            report.message,
+            "wakatime-cli --key <hidden> refused <hidden>"
+        );
+        assert_eq!(report.editor.as_deref(), Some("Zed/0.200.0"));
+        assert_eq!(report.version, env!("CARGO_PKG_VERSION"));
+    }
+
+    #[test]
+    fn only_the_latest_reports_are_kept() {
+        let path = file("latest");
+        let reports: Vec<_> = (0..MAX_REPORTS + 2)
+            .map(|index| {
+                new_report(
+                    Kind::Panic,
+                    &format!("panic {index}"),
+                    &Secrets::default(),
+                    Context::default(),
+                )
+            })
+            .collect();
+
+        for report in &reports {
+            append(&path, report).unwrap();
+        }
+
+        assert_eq!(read(&path), reports[2..]);
+    }
+
+    #[cfg(feature = "http")]
+    #[test]
+    fn reports_added_during_an_upload_are_kept() {
+        let path = file("upload");
+        let report = |message: &str| {
+            new_report(
+                Kind::Panic,
+                message,
+                &Secrets::default(),
+                Context::default(),
+            )
+        };
+        let (sent, failed, added) = (report("sent"), report("failed"), report("added"));
+        append(&path, &sent).unwrap();
+        append(&path, &failed).unwrap();
+
+        append(&path, &added).unwrap();
+        remove(&path, std::slice::from_ref(&sent)).unwrap();
+
+        assert_eq!(read(&path), [failed, added]);
+    }
+}
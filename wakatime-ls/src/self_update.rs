@@ -0,0 +1,181 @@
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+
+use sha2::{Digest, Sha256};
+
+use wakatime_ls_core::cli;
+
+pub const REPOSITORY: &str = "espcaa/zed-hackatime";
+
+// The build of this server for this machine, named like the release assets
+// the Zed extension downloads.
+//
+// Example:
+// linux x86_64  -> wakatime-ls-x86_64-unknown-linux-gnu
+// macos aarch64 -> wakatime-ls-aarch64-apple-darwin
+fn build_name() -> Option<String> {
+    let arch = match env::consts::ARCH {
+        arch @ ("x86_64" | "aarch64") => arch,
+        _ => return None,
+    };
+    let os = match env::consts::OS {
+        "macos" => "apple-darwin",
+        "linux" => "unknown-linux-gnu",
+        "windows" => "pc-windows-msvc",
+        _ => return None,
+    };
+
+    Some(format!("wakatime-ls-{arch}-{os}"))
+}
+
+fn binary_name() -> &'static str {
+    if cfg!(windows) {
+        "wakatime-ls.exe"
+    } else {
+        "wakatime-ls"
+    }
+}
+
+// Replace the binary of this process with the latest release if the binary is
+// older, returning the new version, which the next start of the server runs.
+// As for wakatime-cli, the new build is only swapped in once it runs, and the
+// old one is put back if the swap fails.
+pub async fn update() -> Result<Option<String>, String> {
+    let path = env::current_exe()
+        .map_err(|err| format!("could not find the wakatime-ls binary: {err}"))?;
+    let client = cli::http_client()?;
+    let tag = cli::latest_release(&client, REPOSITORY).await?;
+
+    // Another server of the same binary may have updated it already.
+    let installed = version(&path).await;
+    let installed = installed.as_deref().unwrap_or(env!("CARGO_PKG_VERSION"));
+    if !cli::is_newer(&tag, installed) {
+        return Ok(None);
+    }
+
+    let archive = download(&client, &tag).await?;
+
+    let staged = staged_path(&path);
+    let target = staged.clone();
+    tokio::task::spawn_blocking(move || extract(&archive, &target))
+        .await
+        .map_err(|err| format!("failed to extract wakatime-ls: {err}"))??;
+
+    if version(&staged).await.is_none() {
+        fs::remove_file(&staged).ok();
+        return Err(format!("the downloaded wakatime-ls {tag} does not run"));
+    }
+
+    // A running binary can be renamed, but not replaced on Windows.
+    let backup = path.with_extension("old");
+    fs::rename(&path, &backup)
+        .map_err(|err| format!("failed to move {} aside: {err}", path.display()))?;
+
+    if let Err(err) = fs::rename(&staged, &path) {
+        fs::rename(&backup, &path).ok();
+        fs::remove_file(&staged).ok();
+        return Err(format!("failed to replace {}: {err}", path.display()));
+    }
+
+    // Still running on Windows; the backup is then overwritten by the next
+    // update.
+    fs::remove_file(&backup).ok();
+
+    Ok(Some(tag))
+}
+
+// Keeps the `.exe` extension, so it can be run on Windows.
+fn staged_path(path: &Path) -> PathBuf {
+    match path.extension() {
+        Some(extension) => path.with_extension(format!("new.{}", extension.to_string_lossy())),
+        None => path.with_extension("new"),
+    }
+}
+
+// The version a wakatime-ls binary reports, such as `0.3.1` from
+// `wakatime_ls 0.3.1`.
+async fn version(path: &Path) -> Option<String> {
+    let output = tokio::process::Command::new(path)
+        .arg("--version")
+        .output()
+        .await
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    let version = String::from_utf8_lossy(&output.stdout);
+    Some(version.split_whitespace().last()?.to_string())
+}
+
+// Download the archive of a release for this machine, checked against the
+// SHA256 checksum published next to it.
+async fn download(client: &reqwest::Client, tag: &str) -> Result<Vec<u8>, String> {
+    let name = build_name().ok_or_else(|| {
+        format!(
+            "no wakatime-ls build for {} {}",
+            env::consts::OS,
+            env::consts::ARCH
+        )
+    })?;
+
+    let base = format!("https://github.com/{REPOSITORY}/releases/download/{tag}");
+    let archive_name = format!("{name}.zip");
+
+    let checksum = cli::get(client, &format!("{base}/{archive_name}.sha256")).await?;
+    let expected = String::from_utf8_lossy(&checksum)
+        .split_whitespace()
+        .next()
+        .map(str::to_ascii_lowercase)
+        .ok_or_else(|| format!("no checksum published for {archive_name}"))?;
+
+    let archive = cli::get(client, &format!("{base}/{archive_name}")).await?;
+    let actual: String = Sha256::digest(&archive)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+
+    if actual != expected {
+        return Err(format!(
+            "checksum mismatch for {archive_name}: expected {expected}, got {actual}"
+        ));
+    }
+
+    Ok(archive)
+}
+
+// The binary may be at the root of the archive or in a folder of it.
+fn extract(archive: &[u8], path: &Path) -> Result<(), String> {
+    let mut archive = zip::ZipArchive::new(io::Cursor::new(archive))
+        .map_err(|err| format!("failed to open the archive: {err}"))?;
+
+    let name = archive
+        .file_names()
+        .filter_map(Result::ok)
+        .find(|name| Path::new(name.as_ref()).file_name() == Some(binary_name().as_ref()))
+        .map(|name| name.to_string())
+        .ok_or_else(|| format!("failed to find {} in the archive", binary_name()))?;
+    let mut binary = archive
+        .by_name(&name)
+        .map_err(|err| format!("failed to read {name} from the archive: {err}"))?;
+
+    let mut write = || -> io::Result<()> {
+        let mut file = fs::File::create(path)?;
+        io::copy(&mut binary, &mut file)?;
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(fs::Permissions::from_mode(0o755))?;
+        }
+
+        Ok(())
+    };
+
+    write().map_err(|err| {
+        fs::remove_file(path).ok();
+        format!("failed to write {}: {err}", path.display())
+    })
+}
@@ -0,0 +1,42 @@
+use std::{future::Future, panic::AssertUnwindSafe, time::Duration};
+
+use futures_util::FutureExt;
+use tokio::time::{sleep, Instant};
+use tracing::error;
+
+// The wait before a task that panicked is started again, doubled for each
+// panic in a row up to `MAX_BACKOFF`.
+const FIRST_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+// Run the task `start` makes until it returns, and start it again when it
+// panics, so one bad response doesn't stop it until the editor restarts. A
+// task that ran for longer than `MAX_BACKOFF` before panicking isn't
+// panicking in a row, and waits `FIRST_BACKOFF` again.
+//
+// Example:
+// the status bar task panicked, starting it again in 4 seconds
+pub async fn supervise<T, F>(name: &'static str, start: T)
+where
+    T: Fn() -> F,
+    F: Future<Output = ()>,
+{
+    let mut backoff = FIRST_BACKOFF;
+
+    loop {
+        let started = Instant::now();
+        if AssertUnwindSafe(start()).catch_unwind().await.is_ok() {
+            return;
+        }
+
+        if started.elapsed() > MAX_BACKOFF {
+            backoff = FIRST_BACKOFF;
+        }
+        error!(
+            "the {name} task panicked, starting it again in {} seconds",
+            backoff.as_secs()
+        );
+        sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
@@ -0,0 +1,397 @@
+use std::{
+    fmt,
+    future::Future,
+    io,
+    net::{SocketAddr, ToSocketAddrs},
+    path::Path,
+};
+
+use futures_util::{SinkExt, StreamExt};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+use tokio_tungstenite::tungstenite::{
+    handshake::server::{Callback, ErrorResponse, Request, Response},
+    http::{header, StatusCode},
+    Message,
+};
+
+// The longest first line a TCP client may send its token on.
+const MAX_TOKEN_LINE: u64 = 1024;
+
+// Where the language server accepts clients, instead of talking LSP over
+// stdio to the editor that started it.
+//
+// Example:
+// tcp://127.0.0.1:9257  -> LSP over plain TCP
+// ws://0.0.0.0:9257     -> one JSON-RPC message per WebSocket message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Listen {
+    Tcp(SocketAddr),
+    WebSocket(SocketAddr),
+}
+
+impl Listen {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let (scheme, address) = value
+            .split_once("://")
+            .ok_or_else(|| format!("expected tcp://HOST:PORT or ws://HOST:PORT, got {value}"))?;
+
+        let listen: fn(SocketAddr) -> Self = match scheme {
+            "tcp" => Self::Tcp,
+            "ws" => Self::WebSocket,
+            _ => {
+                return Err(format!(
+                    "unsupported transport {scheme}, expected tcp or ws"
+                ))
+            }
+        };
+
+        // Host names such as `localhost` are resolved once, at startup.
+        let address = address.trim_end_matches('/');
+        let address = address
+            .to_socket_addrs()
+            .map_err(|err| format!("invalid address {address}: {err}"))?
+            .next()
+            .ok_or_else(|| format!("{address} resolves to no address"))?;
+
+        Ok(listen(address))
+    }
+
+    fn address(self) -> SocketAddr {
+        match self {
+            Self::Tcp(address) | Self::WebSocket(address) => address,
+        }
+    }
+}
+
+impl fmt::Display for Listen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(address) => write!(f, "tcp://{address}"),
+            Self::WebSocket(address) => write!(f, "ws://{address}"),
+        }
+    }
+}
+
+// Accept clients until the process is stopped, calling `serve` with the
+// input and output of each one. Every client gets its own server. Clients
+// must give `token` first, since whoever connects sends heartbeats with the
+// user's key: on the first line over TCP, and in the handshake over
+// WebSocket, as `Authorization: Bearer <token>` or a `token` query parameter.
+// WebSocket handshakes from browsers, which send an `Origin`, are refused, so
+// a web page can't connect to a server on localhost.
+//
+// Example:
+// $ wakatime-ls --listen tcp://127.0.0.1:9257 --listen-token s3cret
+// client: "s3cret\n" then LSP
+pub async fn listen<F, Fut>(listen: Listen, token: String, serve: F) -> io::Result<()>
+where
+    F: Fn(Box<dyn AsyncRead + Send + Unpin>, Box<dyn AsyncWrite + Send + Unpin>) -> Fut
+        + Clone
+        + Send
+        + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let listener = TcpListener::bind(listen.address()).await?;
+    eprintln!("wakatime-ls: listening on {listen}");
+    if !listen.address().ip().is_loopback() {
+        eprintln!(
+            "wakatime-ls: warning: {listen} can be reached from other machines, anyone with the token can send heartbeats with your API key"
+        );
+    }
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        eprintln!("wakatime-ls: client connected from {peer}");
+
+        let serve = serve.clone();
+        let token = token.clone();
+        tokio::spawn(async move {
+            match listen {
+                Listen::Tcp(_) => {
+                    let (read, write) = stream.into_split();
+                    let mut read = BufReader::new(read);
+                    let mut line = String::new();
+                    let given = (&mut read)
+                        .take(MAX_TOKEN_LINE)
+                        .read_line(&mut line)
+                        .await
+                        .is_ok()
+                        && token_matches(line.trim_end_matches(['\r', '\n']), &token);
+                    if !given {
+                        eprintln!("wakatime-ls: {peer} didn't give the token, disconnecting");
+                        return;
+                    }
+                    serve(Box::new(read), Box::new(write)).await;
+                }
+                Listen::WebSocket(_) => match websocket(stream, &token).await {
+                    Ok((read, write)) => serve(Box::new(read), Box::new(write)).await,
+                    Err(err) => {
+                        eprintln!("wakatime-ls: WebSocket handshake with {peer} failed: {err}");
+                    }
+                },
+            }
+        });
+    }
+}
+
+// Accept clients on a Unix socket, or a named pipe such as `\\.\pipe\wakatime-ls`
+// on Windows, until the process is stopped. Like `listen`, every client gets
+// its own server, so several tools can share one long-running process.
+#[cfg(unix)]
+pub async fn pipe<F, Fut>(path: &Path, serve: F) -> io::Result<()>
+where
+    F: Fn(Box<dyn AsyncRead + Send + Unpin>, Box<dyn AsyncWrite + Send + Unpin>) -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    use std::{
+        fs,
+        os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+    };
+
+    use tokio::net::{UnixListener, UnixStream};
+
+    // A socket nothing listens on anymore is left behind by a server that
+    // was killed.
+    if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        if UnixStream::connect(path).await.is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "another server is listening on it",
+            ));
+        }
+        fs::remove_file(path)?;
+    }
+
+    // Only the user may connect, since clients send heartbeats with their key.
+    // The socket is bound in a folder only the user may enter and moved into
+    // place once it is private, so no one can connect before.
+    let parent = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let private = parent.join(format!(".wakatime-ls-{}", std::process::id()));
+    fs::DirBuilder::new().mode(0o700).create(&private)?;
+    let bound = private.join("socket");
+    let listener = UnixListener::bind(&bound).and_then(|listener| {
+        fs::set_permissions(&bound, fs::Permissions::from_mode(0o600))?;
+        fs::rename(&bound, path)?;
+        Ok(listener)
+    });
+    fs::remove_file(&bound).ok();
+    fs::remove_dir(&private)?;
+    let listener = listener?;
+    eprintln!("wakatime-ls: listening on {}", path.display());
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        eprintln!("wakatime-ls: client connected");
+
+        let (read, write) = stream.into_split();
+        tokio::spawn(serve(Box::new(read), Box::new(write)));
+    }
+}
+
+#[cfg(windows)]
+pub async fn pipe<F, Fut>(path: &Path, serve: F) -> io::Result<()>
+where
+    F: Fn(Box<dyn AsyncRead + Send + Unpin>, Box<dyn AsyncWrite + Send + Unpin>) -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    use tokio::net::windows::named_pipe::ServerOptions;
+
+    // Named pipes reject remote clients and are only open to the user and
+    // administrators by default.
+    let mut server = ServerOptions::new()
+        .first_pipe_instance(true)
+        .reject_remote_clients(true)
+        .create(path)?;
+    eprintln!("wakatime-ls: listening on {}", path.display());
+
+    loop {
+        server.connect().await?;
+        eprintln!("wakatime-ls: client connected");
+
+        // The next client connects to a new instance of the pipe.
+        let client = std::mem::replace(
+            &mut server,
+            ServerOptions::new()
+                .reject_remote_clients(true)
+                .create(path)?,
+        );
+        let (read, write) = tokio::io::split(client);
+        tokio::spawn(serve(Box::new(read), Box::new(write)));
+    }
+}
+
+// Whether a client gave the token, compared in constant time.
+fn token_matches(given: &str, token: &str) -> bool {
+    given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+// Accepts a WebSocket handshake with the token and without an `Origin`.
+struct Handshake<'a> {
+    token: &'a str,
+}
+
+impl Callback for Handshake<'_> {
+    fn on_request(self, request: &Request, response: Response) -> Result<Response, ErrorResponse> {
+        let refuse = |status: StatusCode, reason: &str| {
+            let mut response = ErrorResponse::new(Some(reason.to_string()));
+            *response.status_mut() = status;
+            response
+        };
+
+        if request.headers().contains_key(header::ORIGIN) {
+            return Err(refuse(StatusCode::FORBIDDEN, "browsers may not connect"));
+        }
+
+        let bearer = request
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        let query = request.uri().query().and_then(|query| {
+            query
+                .split('&')
+                .find_map(|pair| pair.strip_prefix("token="))
+        });
+        match bearer.or(query) {
+            Some(given) if token_matches(given, self.token) => Ok(response),
+            _ => Err(refuse(StatusCode::UNAUTHORIZED, "missing or wrong token")),
+        }
+    }
+}
+
+// Bridge a WebSocket connection to the `Content-Length` framed stream the
+// server reads and writes. Messages that already carry the header, as sent by
+// tools forwarding a raw LSP stream, are passed through unchanged.
+async fn websocket(
+    stream: TcpStream,
+    token: &str,
+) -> Result<
+    (
+        impl AsyncRead + Send + Unpin,
+        impl AsyncWrite + Send + Unpin,
+    ),
+    String,
+> {
+    let socket = tokio_tungstenite::accept_hdr_async(stream, Handshake { token })
+        .await
+        .map_err(|err| err.to_string())?;
+    let (mut sink, mut source) = socket.split();
+
+    // One pipe per direction, so each side sees the end of its input as soon
+    // as the other one is done.
+    let (mut to_server, server_read) = tokio::io::duplex(64 * 1024);
+    let (server_write, from_server) = tokio::io::duplex(64 * 1024);
+
+    tokio::spawn(async move {
+        while let Some(Ok(message)) = source.next().await {
+            let body = match message {
+                Message::Text(text) => text.as_bytes().to_vec(),
+                Message::Binary(bytes) => bytes.to_vec(),
+                Message::Close(_) => break,
+                _ => continue,
+            };
+
+            let framed = if body.starts_with(b"Content-Length:") {
+                body
+            } else {
+                let mut framed = format!("Content-Length: {}\r\n\r\n", body.len()).into_bytes();
+                framed.extend(body);
+                framed
+            };
+
+            if to_server.write_all(&framed).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    tokio::spawn(async move {
+        let mut from_server = BufReader::new(from_server);
+
+        while let Ok(Some(body)) = read_message(&mut from_server).await {
+            let text = String::from_utf8_lossy(&body).into_owned();
+            if sink.send(Message::text(text)).await.is_err() {
+                break;
+            }
+        }
+
+        sink.close().await.ok();
+    });
+
+    Ok((server_read, server_write))
+}
+
+// Read one `Content-Length` framed message, or `None` at the end of the
+// stream.
+async fn read_message(reader: &mut (impl AsyncBufReadExt + Unpin)) -> io::Result<Option<Vec<u8>>> {
+    let mut length = None;
+
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            return Ok(None);
+        }
+
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+
+    let length = length
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing Content-Length"))?;
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).await?;
+
+    Ok(Some(body))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{env, fs, os::unix::fs::PermissionsExt, time::Duration};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn sockets_are_only_open_to_the_user() {
+        let dir = env::temp_dir().join(format!("wakatime-ls-pipe-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("wakatime-ls.sock");
+
+        let server = tokio::spawn({
+            let path = path.clone();
+            async move { pipe(&path, |_, _| async {}).await }
+        });
+        for _ in 0..500 {
+            if path.exists() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        assert!(tokio::net::UnixStream::connect(&path).await.is_ok());
+
+        server.abort();
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,104 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+
+use wakatime_ls_core::{cli, config};
+
+use crate::self_update;
+
+// A release newer than the one in use, of this server or of wakatime-cli.
+//
+// Example:
+// wakatime-cli v1.102.1 -> "wakatime-cli v1.102.1 is available: https://github.com/wakatime/wakatime-cli/releases/tag/v1.102.1"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Release {
+    pub name: &'static str,
+    pub repository: &'static str,
+    pub tag: String,
+}
+
+impl Release {
+    pub fn url(&self) -> String {
+        format!(
+            "https://github.com/{}/releases/tag/{}",
+            self.repository, self.tag
+        )
+    }
+
+    pub fn message(&self) -> String {
+        format!("{} {} is available: {}", self.name, self.tag, self.url())
+    }
+}
+
+// The newer releases of what isn't kept up to date already: this server with
+// `ls`, and wakatime-cli when its `cli_version` is given.
+pub async fn check(ls: bool, cli_version: Option<&str>) -> Result<Vec<Release>, String> {
+    let client = cli::http_client()?;
+    let mut releases = Vec::new();
+
+    if ls {
+        let tag = cli::latest_release(&client, self_update::REPOSITORY).await?;
+        if cli::is_newer(&tag, env!("CARGO_PKG_VERSION")) {
+            releases.push(Release {
+                name: "wakatime-ls",
+                repository: self_update::REPOSITORY,
+                tag,
+            });
+        }
+    }
+
+    if let Some(installed) = cli_version {
+        let tag = cli::latest_release(&client, cli::REPOSITORY).await?;
+        if cli::is_newer(&tag, installed) {
+            releases.push(Release {
+                name: "wakatime-cli",
+                repository: cli::REPOSITORY,
+                tag,
+            });
+        }
+    }
+
+    Ok(releases)
+}
+
+// The day the last update message was shown, shared by every server on this
+// machine so that restarting the editor doesn't show it again.
+//
+// Example:
+// {"last_day":"2026-10-14"}
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+struct Notice {
+    last_day: Option<NaiveDate>,
+}
+
+pub fn path() -> Option<PathBuf> {
+    Some(
+        config::wakatime_home()?
+            .join(".wakatime")
+            .join("zed-wakatime-update-notice.json"),
+    )
+}
+
+// Take `today`'s update message, returning false when one was already shown.
+pub fn claim(path: &Path, today: NaiveDate) -> io::Result<bool> {
+    let notice: Notice = fs::read_to_string(path)
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default();
+    if notice.last_day == Some(today) {
+        return Ok(false);
+    }
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let notice = Notice {
+        last_day: Some(today),
+    };
+    fs::write(path, serde_json::to_string(&notice)?)?;
+    Ok(true)
+}
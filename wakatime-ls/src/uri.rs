@@ -0,0 +1,225 @@
+use std::path::PathBuf;
+
+use percent_encoding::percent_decode_str;
+use url::Url;
+
+use wakatime_ls_core::paths;
+
+use crate::collab;
+
+// Convert a document URI to the path reported as the heartbeat entity.
+//
+// Query strings and fragments never belong to the file, and percent-encoded
+// characters are decoded even when the URI can't be turned into a path on this
+// platform (e.g. a UNC URI on Unix).
+//
+// Outside of Windows the path is decoded directly: `Url::to_file_path` treats
+// a trailing `x:` segment as a drive letter and appends a slash to it.
+//
+// Example:
+// file:///var/log/test.txt         -> /var/log/test.txt
+// file:///C:/path/to/file.txt      -> C:\path\to\file.txt
+// file:///home/me/my%20notes.md    -> /home/me/my notes.md
+// file:///home/me/main.rs?v=2#L10  -> /home/me/main.rs
+// file://server/share/main.rs      -> //server/share/main.rs (on Unix)
+// untitled:Untitled-1              -> Untitled-1
+// zed://channel/design%20review    -> zed://channel/design review
+pub fn to_entity(uri: &Url) -> PathBuf {
+    let mut uri = uri.clone();
+    uri.set_query(None);
+    uri.set_fragment(None);
+
+    if uri.scheme() == "file" && cfg!(windows) {
+        if let Ok(path) = uri.to_file_path() {
+            return paths::normalize(path);
+        }
+    }
+
+    let mut path: Vec<u8> = match uri.host_str() {
+        Some(host) if !host.is_empty() && host != "localhost" => format!("//{host}").into(),
+        _ => Vec::new(),
+    };
+    path.extend(percent_decode_str(uri.path()));
+
+    // Collaboration buffers keep their scheme, so they aren't taken for a
+    // file at the same path, and aren't paths of any platform.
+    if uri.scheme() == collab::SCHEME {
+        let mut entity = format!("{}:", collab::SCHEME).into_bytes();
+        entity.extend(path);
+        return bytes_to_path(entity);
+    }
+
+    paths::normalize(bytes_to_path(path))
+}
+
+// Percent-decoded bytes are kept as they are on Unix, where paths don't have
+// to be UTF-8. Elsewhere paths are Unicode, so invalid sequences are replaced.
+#[cfg(unix)]
+fn bytes_to_path(bytes: Vec<u8>) -> PathBuf {
+    use std::os::unix::ffi::OsStringExt;
+
+    PathBuf::from(std::ffi::OsString::from_vec(bytes))
+}
+
+#[cfg(not(unix))]
+fn bytes_to_path(bytes: Vec<u8>) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt, path::Path};
+
+    use proptest::prelude::*;
+
+    use super::*;
+
+    fn entity(uri: &str) -> PathBuf {
+        to_entity(&Url::parse(uri).unwrap())
+    }
+
+    #[test]
+    fn decodes_percent_encoded_characters() {
+        assert_eq!(
+            entity("file:///home/me/my%20notes%20%232.md"),
+            Path::new("/home/me/my notes #2.md")
+        );
+        assert_eq!(
+            entity("file:///home/me/caf%C3%A9.rs"),
+            Path::new("/home/me/café.rs")
+        );
+    }
+
+    #[test]
+    fn strips_query_and_fragment() {
+        assert_eq!(
+            entity("file:///home/me/main.rs?v=2#L10"),
+            Path::new("/home/me/main.rs")
+        );
+        assert_eq!(
+            entity("file:///home/me/main.rs#"),
+            Path::new("/home/me/main.rs")
+        );
+    }
+
+    #[test]
+    fn keeps_reserved_characters_that_are_encoded() {
+        assert_eq!(
+            entity("file:///home/me/what%3F%23.rs"),
+            Path::new("/home/me/what?#.rs")
+        );
+    }
+
+    #[test]
+    fn keeps_trailing_colons() {
+        assert_eq!(
+            entity("file:///home/me/notes/a:"),
+            Path::new("/home/me/notes/a:")
+        );
+    }
+
+    #[test]
+    fn falls_back_to_decoded_unc_path() {
+        assert_eq!(
+            entity("file://server/my%20share/main.rs"),
+            Path::new("//server/my share/main.rs")
+        );
+    }
+
+    #[test]
+    fn decodes_non_file_uris() {
+        assert_eq!(entity("untitled:Untitled%201"), Path::new("Untitled 1"));
+        assert_eq!(
+            entity("zed://channel/design%20review?x=1"),
+            Path::new("zed://channel/design review")
+        );
+    }
+
+    #[test]
+    fn keeps_invalid_utf8() {
+        assert_eq!(
+            entity("file:///home/me/%FF%FE.rs").as_os_str(),
+            OsStr::from_bytes(b"/home/me/\xFF\xFE.rs")
+        );
+        assert_eq!(
+            entity("untitled:%FF%ZZ").as_os_str(),
+            OsStr::from_bytes(b"\xFF%ZZ")
+        );
+    }
+
+    // Dot segments are resolved by URL parsing, so they can't round-trip.
+    fn segments() -> impl Strategy<Value = Vec<String>> {
+        prop::collection::vec(
+            "[^/\0]{1,12}".prop_filter("dot segment", |segment| segment != "." && segment != ".."),
+            1..6,
+        )
+    }
+
+    proptest! {
+        #[test]
+        fn round_trips_file_paths(segments in segments()) {
+            let path: PathBuf = std::iter::once("/".to_string()).chain(segments).collect();
+            let uri = Url::from_file_path(&path).unwrap();
+
+            prop_assert_eq!(to_entity(&uri), path);
+        }
+
+        #[test]
+        fn round_trips_non_utf8_paths(segments in prop::collection::vec(
+            prop::collection::vec(any::<u8>().prop_filter("separator", |b| *b != b'/' && *b != 0), 1..12),
+            1..6,
+        )) {
+            let mut path = PathBuf::from("/");
+            for segment in segments.iter().filter(|segment| !matches!(segment.as_slice(), b"." | b"..")) {
+                path.push(OsStr::from_bytes(segment));
+            }
+            let uri = Url::from_file_path(&path).unwrap();
+
+            prop_assert_eq!(to_entity(&uri), path);
+        }
+
+        #[test]
+        fn round_trips_paths_with_query_and_fragment(
+            segments in segments(),
+            query in "[a-z0-9=&]{0,8}",
+            fragment in "[A-Za-z0-9]{0,8}",
+        ) {
+            let path: PathBuf = std::iter::once("/".to_string()).chain(segments).collect();
+            let mut uri = Url::from_file_path(&path).unwrap();
+            uri.set_query(Some(&query));
+            uri.set_fragment(Some(&fragment));
+
+            prop_assert_eq!(to_entity(&uri), path);
+        }
+
+        #[test]
+        fn never_panics(input in "\\PC*") {
+            if let Ok(uri) = Url::parse(&input) {
+                to_entity(&uri);
+            }
+        }
+    }
+}
+
+#[cfg(all(test, windows))]
+mod windows_tests {
+    use std::path::Path;
+
+    use super::*;
+
+    fn entity(uri: &str) -> PathBuf {
+        to_entity(&Url::parse(uri).unwrap())
+    }
+
+    #[test]
+    fn converts_drive_letters_and_unc_paths() {
+        assert_eq!(
+            entity("file:///c:/Users/me/my%20notes.md"),
+            Path::new(r"C:\Users\me\my notes.md")
+        );
+        assert_eq!(
+            entity("file://server/share/main.rs?x#y"),
+            Path::new(r"\\server\share\main.rs")
+        );
+    }
+}